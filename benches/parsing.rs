@@ -0,0 +1,79 @@
+//! How long decoding a request's head takes, for a bare request and one with the headers a
+//! browser sends, whole and in the pieces a socket might read it in
+//!
+//! `cargo bench --bench parsing`
+
+use codecrafters_http_server::{
+    parser::Parser,
+    request::{Limits, ReadPolicy, Request},
+};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::{hint::black_box, io::Cursor, task::Poll};
+
+const BARE: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+const BROWSER: &[u8] = b"GET /files/reports/q3.pdf?download=1 HTTP/1.1\r\n\
+    Host: localhost:4221\r\n\
+    User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0\r\n\
+    Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n\
+    Accept-Language: en-GB,en;q=0.5\r\n\
+    Accept-Encoding: gzip, deflate, br, zstd\r\n\
+    Connection: keep-alive\r\n\
+    Cookie: session=3f6c2a9e8b1d4c7f; theme=dark\r\n\
+    Upgrade-Insecure-Requests: 1\r\n\
+    Sec-Fetch-Dest: document\r\n\
+    Sec-Fetch-Mode: navigate\r\n\
+    Sec-Fetch-Site: none\r\n\
+    If-None-Match: \"5f2b-1700000000\"\r\n\
+    Priority: u=0, i\r\n\r\n";
+
+/// Feeds `head` to a parser `piece` bytes at a time
+fn feed(parser: &mut Parser, head: &[u8], piece: usize) -> Request {
+    for piece in head.chunks(piece) {
+        if let Poll::Ready(request) = parser.feed(piece).unwrap() {
+            return request;
+        }
+    }
+    panic!("the head never ended");
+}
+
+fn parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, head) in [("bare", BARE), ("browser", BROWSER)] {
+        group.throughput(Throughput::Bytes(head.len() as u64));
+        group.bench_with_input(BenchmarkId::new("whole", name), head, |b, head| {
+            let mut parser = Parser::new(Limits::default());
+            b.iter(|| black_box(feed(&mut parser, black_box(head), head.len())));
+        });
+        group.bench_with_input(
+            BenchmarkId::new("in 64 byte reads", name),
+            head,
+            |b, head| {
+                let mut parser = Parser::new(Limits::default());
+                b.iter(|| black_box(feed(&mut parser, black_box(head), 64)));
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("decode_head", name), head, |b, head| {
+            b.iter(|| {
+                let reader = Cursor::new(black_box(head));
+                black_box(Request::decode_head(
+                    reader,
+                    ReadPolicy::BLOCKING,
+                    Limits::default(),
+                ))
+            });
+        });
+    }
+    group.finish();
+
+    c.bench_function("look up a header", |b| {
+        let request = Parser::new(Limits::default()).feed(BROWSER).unwrap();
+        let Poll::Ready(request) = request else {
+            panic!("the head never ended");
+        };
+        b.iter(|| black_box(request.headers.get(black_box("accept-encoding"))));
+    });
+}
+
+criterion_group!(benches, parsing);
+criterion_main!(benches);
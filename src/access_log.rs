@@ -0,0 +1,212 @@
+//! One line per request, in Common Log Format or Apache's combined variant, written once the
+//! response has gone out so the status and size are what the client actually got
+
+use crate::{http, request::Request};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    fmt::{self, Write as _},
+    fs::OpenOptions,
+    io::{self, Write},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tracing::error;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Client, request line, status and size
+    Common,
+    /// Common, plus the `Referer` and `User-Agent`
+    #[default]
+    Combined,
+}
+
+/// What happened to a request, as logged
+pub struct Entry<'a> {
+    pub client: &'a str,
+    /// `None` when the request couldn't be parsed
+    pub request: Option<&'a Request>,
+    pub status: u16,
+    /// Of the body, not counting the head
+    pub bytes: u64,
+    pub duration: Duration,
+    pub time: SystemTime,
+}
+
+pub struct AccessLog {
+    format: LogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(out: impl Write + Send + 'static, format: LogFormat) -> Self {
+        Self {
+            format,
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    pub fn stdout(format: LogFormat) -> Self {
+        Self::new(io::stdout(), format)
+    }
+
+    /// Appends to `path`, which is created if need be
+    pub fn file(path: &str, format: LogFormat) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self::new(file, format))
+    }
+
+    pub fn record(&self, entry: &Entry) {
+        let mut line = format(entry, self.format);
+        line.push('\n');
+
+        // Logging must never take a connection down with it
+        let mut out = self
+            .out
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Err(err) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            error!("Error writing access log: {err}");
+        }
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::stdout(LogFormat::default())
+    }
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Formats `entry`, with the duration in microseconds (like Apache's `%D`) and then the request
+/// ID on the end
+pub fn format(entry: &Entry, format: LogFormat) -> String {
+    let request_line = entry.request.map_or_else(
+        || "-".to_string(),
+        |request| {
+            let mut target = request.path.clone();
+            if let Some(query) = &request.query {
+                target.push('?');
+                target.push_str(query);
+            }
+            format!("{} {target} HTTP/1.1", request.method.as_str())
+        },
+    );
+    let bytes = match entry.bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+
+    let mut line = format!(
+        "{} - - [{}] \"{}\" {} {bytes}",
+        entry.client,
+        http::log_date(entry.time),
+        escape(&request_line),
+        entry.status,
+    );
+    if format == LogFormat::Combined {
+        let header = |name| {
+            entry
+                .request
+                .and_then(|request| request.headers.get(name))
+                .map_or_else(|| "-".to_string(), escape)
+        };
+        let _ = write!(
+            line,
+            " \"{}\" \"{}\"",
+            header("referer"),
+            header("user-agent")
+        );
+    }
+    let _ = write!(
+        line,
+        " {} {}",
+        entry.duration.as_micros(),
+        entry.request.map_or("-", |request| &request.id)
+    );
+
+    line
+}
+
+/// Quotes and control characters would let a client forge log lines (or fields)
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry(request: Option<&Request>) -> Entry<'_> {
+        Entry {
+            client: "127.0.0.1",
+            request,
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+            time: UNIX_EPOCH + Duration::from_secs(971_182_536),
+        }
+    }
+
+    #[test]
+    fn common_and_combined() {
+        let request = Request::decode(
+            &b"GET /apache_pb.gif?x=1 HTTP/1.1\r\nHost: x\r\nReferer: http://example.com/\r\n\
+            User-Agent: curl/8.0\r\nX-Request-Id: abc\r\n\r\n"[..],
+        )
+        .unwrap();
+
+        assert_eq!(
+            format(&entry(Some(&request)), LogFormat::Common),
+            "127.0.0.1 - - [10/Oct/2000:12:55:36 +0000] \"GET /apache_pb.gif?x=1 HTTP/1.1\" 200 2326 1500 abc"
+        );
+        assert_eq!(
+            format(&entry(Some(&request)), LogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:12:55:36 +0000] \"GET /apache_pb.gif?x=1 HTTP/1.1\" 200 2326 \
+            \"http://example.com/\" \"curl/8.0\" 1500 abc"
+        );
+    }
+
+    #[test]
+    fn unparsed_requests_and_empty_bodies() {
+        let entry = Entry {
+            bytes: 0,
+            ..entry(None)
+        };
+
+        assert_eq!(
+            format(&entry, LogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:12:55:36 +0000] \"-\" 200 - \"-\" \"-\" 1500 -"
+        );
+    }
+
+    #[test]
+    fn quotes_and_newlines_are_escaped() {
+        assert_eq!(escape("a\"b\nc"), "a\\\"b\\x0ac");
+    }
+}
@@ -0,0 +1,380 @@
+//! `/__admin`: how the server is doing, and a few things about it that can be changed while it
+//! runs, for whoever runs it rather than its clients
+//!
+//! Until `--admin-token`s are given, only clients on the same machine (over loopback, or the Unix
+//! socket) may use it. Once there are some, anyone with one of them may.
+
+use crate::{
+    file_cache::Usage,
+    logging::LogLevel,
+    metrics::FileCacheCounts,
+    request::Request,
+    response::{Response, StatusCode},
+    router::RequestContext,
+    threadpool::ThreadPool,
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, OnceLock, Weak},
+    time::Instant,
+};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// What `--admin-token`s are added to, and the admin routes require
+pub const REALM: &str = "admin";
+
+/// What the admin routes need from outside the config, handed over as the server starts
+#[derive(Default)]
+pub struct Admin {
+    /// When the server started serving, for the uptime
+    started: OnceLock<Instant>,
+    /// The pool answering requests, which the async backend doesn't have
+    pool: OnceLock<Weak<ThreadPool>>,
+    /// How logging is filtered, unless logging was set up some other way (eg, by tests)
+    log_level: Option<LogLevel>,
+}
+
+impl fmt::Debug for Admin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Admin")
+            .field("started", &self.started.get())
+            .field("pool", &self.pool().is_some())
+            .field("log_level", &self.log_level)
+            .finish()
+    }
+}
+
+impl Admin {
+    pub const fn new(log_level: LogLevel) -> Self {
+        Self {
+            started: OnceLock::new(),
+            pool: OnceLock::new(),
+            log_level: Some(log_level),
+        }
+    }
+
+    /// Starts the uptime from now, reporting on (and resizing) `pool` if there is one
+    pub fn serving(&self, pool: Option<&Arc<ThreadPool>>) {
+        let _ = self.started.set(Instant::now());
+        if let Some(pool) = pool {
+            let _ = self.pool.set(Arc::downgrade(pool));
+        }
+    }
+
+    /// What changes the log level, if logging can be changed
+    pub const fn log_level(&self) -> Option<&LogLevel> {
+        self.log_level.as_ref()
+    }
+
+    fn pool(&self) -> Option<Arc<ThreadPool>> {
+        self.pool.get().and_then(Weak::upgrade)
+    }
+}
+
+/// What `GET /__admin/status` reports
+#[derive(Debug, Serialize)]
+struct Status {
+    uptime_seconds: f64,
+    active_connections: usize,
+    thread_pool: Option<Pool>,
+    file_cache: Option<FileCache>,
+    /// Requests by the method and path of the route that answered them
+    routes: BTreeMap<String, u64>,
+    log_level: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Pool {
+    /// Workers kept even when idle
+    size: usize,
+    workers: usize,
+    idle: usize,
+    queued: usize,
+    executed: u64,
+    panicked: u64,
+    /// The share of the workers busy with a connection, from 0 to 1
+    utilization: f64,
+    average_wait_seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct FileCache {
+    #[serde(flatten)]
+    usage: Usage,
+    #[serde(flatten)]
+    counts: FileCacheCounts,
+}
+
+/// `GET /__admin/status` reports how the server is doing, as JSON
+pub fn status(_: &Request, context: &RequestContext) -> Result<Response> {
+    let (Some(admin), Some(metrics)) = (context.admin, context.metrics) else {
+        return Ok(Response::not_found());
+    };
+    if !permitted(context) {
+        return Ok(Response::new(StatusCode::Forbidden));
+    }
+
+    let thread_pool = admin.pool().map(|pool| {
+        let stats = pool.stats();
+        Pool {
+            size: stats.size,
+            workers: stats.workers,
+            idle: stats.idle,
+            queued: stats.queued,
+            executed: stats.executed,
+            panicked: stats.panicked,
+            utilization: if stats.workers == 0 {
+                0.0
+            } else {
+                stats.workers.saturating_sub(stats.idle) as f64 / stats.workers as f64
+            },
+            average_wait_seconds: stats.average_wait.as_secs_f64(),
+        }
+    });
+    let status = Status {
+        uptime_seconds: admin
+            .started
+            .get()
+            .map_or(0.0, |started| started.elapsed().as_secs_f64()),
+        active_connections: metrics.active_connections(),
+        thread_pool,
+        file_cache: context.file_cache.map(|cache| FileCache {
+            usage: cache.usage(),
+            counts: metrics.file_cache_counts(),
+        }),
+        routes: metrics.route_requests(),
+        log_level: admin.log_level.as_ref().and_then(LogLevel::current),
+    };
+
+    Ok(Response::ok().body_json(&status)?)
+}
+
+#[derive(Debug, Serialize)]
+struct Flushed {
+    files: usize,
+}
+
+/// `POST /__admin/file-cache/flush` drops everything in the file cache, saying how many files
+/// that was
+pub fn flush_file_cache(_: &Request, context: &RequestContext) -> Result<Response> {
+    let Some(cache) = context.file_cache else {
+        return Ok(Response::not_found());
+    };
+    if !permitted(context) {
+        return Ok(Response::new(StatusCode::Forbidden));
+    }
+
+    let files = cache.clear();
+    info!(files, "Flushed the file cache");
+    Ok(Response::ok().body_json(&Flushed { files })?)
+}
+
+/// `POST /__admin/log-level` filters the logs with the level or `RUST_LOG` style directives in
+/// the body from now on, until the server restarts
+pub fn set_log_level(request: &Request, context: &RequestContext) -> Result<Response> {
+    let Some(admin) = context.admin else {
+        return Ok(Response::not_found());
+    };
+    if !permitted(context) {
+        return Ok(Response::new(StatusCode::Forbidden));
+    }
+
+    let directives = String::from_utf8_lossy(request.body.as_deref().unwrap_or_default());
+    let filter = match EnvFilter::try_new(directives.trim()) {
+        Ok(filter) => filter,
+        Err(err) => return Ok(bad_request(&format!("Invalid log level: {err}"))),
+    };
+    let Some(log_level) = &admin.log_level else {
+        return Ok(Response::new(StatusCode::NotImplemented));
+    };
+    log_level.set(filter)?;
+    // Logged at warn so that it is seen whatever the new level
+    warn!(directives = %directives.trim(), "Log level changed");
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+/// `POST /__admin/threads` keeps as many workers as the body says, even when they are idle
+pub fn resize_pool(request: &Request, context: &RequestContext) -> Result<Response> {
+    let Some(admin) = context.admin else {
+        return Ok(Response::not_found());
+    };
+    if !permitted(context) {
+        return Ok(Response::new(StatusCode::Forbidden));
+    }
+
+    let size = String::from_utf8_lossy(request.body.as_deref().unwrap_or_default())
+        .trim()
+        .parse::<usize>();
+    let size = match size {
+        Ok(size) if size > 0 => size,
+        _ => return Ok(bad_request("Expected a number of threads, at least 1")),
+    };
+    let Some(pool) = admin.pool() else {
+        return Ok(Response::new(StatusCode::NotImplemented));
+    };
+    pool.resize(size);
+    info!(size, "Resized the thread pool");
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+fn bad_request(reason: &str) -> Response {
+    Response::new(StatusCode::BadRequest)
+        .content_type("text/plain")
+        .body_str(reason)
+}
+
+/// Whether the client may use the admin routes: once there are `--admin-token`s the router has
+/// already checked it has one, and until then only local clients may. The connection has to be
+/// local too, as a trusted proxy only vouches for the last hop, and can pass on whatever a
+/// client further away claims before it.
+fn permitted(context: &RequestContext) -> bool {
+    context
+        .credentials
+        .is_some_and(|credentials| credentials.has_realm(REALM))
+        || (context.peer.is_some_and(is_local) && context.client.is_some_and(is_local))
+}
+
+/// Whether `client` is on the same machine, as the peer of a Unix socket always is
+fn is_local(client: &str) -> bool {
+    client == "unix"
+        || client
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.to_canonical().is_loopback())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{auth::Credentials, file_cache::Cached, metrics::Metrics, testing::TestRequest};
+    use std::path::Path;
+
+    #[test]
+    fn only_local_clients_until_there_are_tokens() {
+        let admin = Admin::default();
+        let metrics = Metrics::default();
+        let context = |client| RequestContext {
+            client: Some(client),
+            peer: Some(client),
+            admin: Some(&admin),
+            metrics: Some(&metrics),
+            ..RequestContext::default()
+        };
+
+        for client in ["127.0.0.1", "::1", "::ffff:127.0.0.1", "unix"] {
+            TestRequest::get("/__admin/status")
+                .send(&context(client))
+                .assert_status(StatusCode::Ok);
+        }
+        TestRequest::get("/__admin/status")
+            .send(&context("192.0.2.1"))
+            .assert_status(StatusCode::Forbidden);
+        // Forwarded for a local client by a remote proxy, or for a remote one by a local proxy
+        for (peer, client) in [("10.0.0.1", "127.0.0.1"), ("127.0.0.1", "192.0.2.1")] {
+            TestRequest::get("/__admin/status")
+                .send(&RequestContext {
+                    peer: Some(peer),
+                    ..context(client)
+                })
+                .assert_status(StatusCode::Forbidden);
+        }
+
+        let mut credentials = Credentials::default();
+        credentials.add_token(REALM, "ops:s3cret").unwrap();
+        let remote = RequestContext {
+            credentials: Some(&credentials),
+            ..context("192.0.2.1")
+        };
+        TestRequest::get("/__admin/status")
+            .send(&remote)
+            .assert_status(StatusCode::Unauthorized);
+        TestRequest::get("/__admin/status")
+            .header("Authorization", "Bearer s3cret")
+            .send(&remote)
+            .assert_status(StatusCode::Ok);
+    }
+
+    #[test]
+    fn status_reports_the_pool_cache_and_routes() -> std::io::Result<()> {
+        let admin = Admin::default();
+        let pool = Arc::new(ThreadPool::builder(2).build()?);
+        admin.serving(Some(&pool));
+        let metrics = Metrics::default();
+        let cache = crate::file_cache::FileCache::new(1024);
+        cache.insert(
+            Path::new("a"),
+            Cached {
+                contents: b"hello".as_slice().into(),
+                content_type: "text/plain",
+                etag: "\"1\"".to_string(),
+            },
+        );
+        let context = RequestContext {
+            client: Some("127.0.0.1"),
+            peer: Some("127.0.0.1"),
+            admin: Some(&admin),
+            metrics: Some(&metrics),
+            file_cache: Some(&cache),
+            ..RequestContext::default()
+        };
+        TestRequest::get("/echo/hi").send(&context);
+
+        let response = TestRequest::get("/__admin/status").send(&context);
+        response
+            .assert_status(StatusCode::Ok)
+            .assert_header("Content-Type", "application/json");
+        let json = response.json();
+        assert_eq!(json["thread_pool"]["size"], 2);
+        assert_eq!(json["file_cache"]["files"], 1);
+        assert_eq!(json["file_cache"]["size"], 5);
+        assert_eq!(json["routes"]["GET /echo/*"], 1);
+        assert_eq!(json["routes"]["GET /__admin/status"], 1);
+        assert!(json["uptime_seconds"].is_f64());
+
+        TestRequest::post("/__admin/threads")
+            .body("3")
+            .send(&context)
+            .assert_status(StatusCode::NoContent);
+        assert_eq!(pool.stats().size, 3);
+        TestRequest::post("/__admin/threads")
+            .body("0")
+            .send(&context)
+            .assert_status(StatusCode::BadRequest);
+
+        TestRequest::post("/__admin/file-cache/flush")
+            .send(&context)
+            .assert_status(StatusCode::Ok)
+            .assert_body(r#"{"files":1}"#);
+        assert_eq!(cache.usage().files, 0);
+
+        pool.shutdown();
+        Ok(())
+    }
+
+    #[test]
+    fn log_levels_are_checked() {
+        let admin = Admin::default();
+        let context = RequestContext {
+            client: Some("127.0.0.1"),
+            peer: Some("127.0.0.1"),
+            admin: Some(&admin),
+            ..RequestContext::default()
+        };
+
+        TestRequest::post("/__admin/log-level")
+            .body("h2=loud")
+            .send(&context)
+            .assert_status(StatusCode::BadRequest);
+        // Tests don't install the subscriber, so there is nothing to change
+        TestRequest::post("/__admin/log-level")
+            .body("debug")
+            .send(&context)
+            .assert_status(StatusCode::NotImplemented);
+    }
+}
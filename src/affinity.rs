@@ -0,0 +1,62 @@
+use anyhow::{Result, bail};
+use std::fs;
+
+/// Parses a Linux style CPU list, eg `0-3,8,10-11`
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = vec![];
+    for part in list.trim().split(',').filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (first.parse::<usize>()?, last.parse::<usize>()?);
+                if first > last {
+                    bail!("Invalid CPU range: {part}");
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(part.parse()?),
+        }
+    }
+
+    if cpus.is_empty() {
+        bail!("Empty CPU list");
+    }
+
+    Ok(cpus)
+}
+
+/// The CPUs local to a NUMA node, so workers (and the memory they touch) stay on that node
+pub fn numa_node_cpus(node: usize) -> Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{node}/cpulist");
+    match fs::read_to_string(&path) {
+        Ok(list) => parse_cpu_list(&list),
+        Err(err) => bail!("Unable to read CPUs for NUMA node {node} from {path}: {err}"),
+    }
+}
+
+/// Pins the calling thread to `cpu`, returning whether the platform allowed it
+pub fn pin_current_thread(cpu: usize) -> bool {
+    core_affinity::set_for_current(core_affinity::CoreId { id: cpu })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() -> Result<()> {
+        assert_eq!(parse_cpu_list("0")?, vec![0]);
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n")?,
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_cpu_lists() {
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! A counting global allocator (enabled with the `alloc-tracking` feature), so the effect of
+//! buffer reuse and avoiding copies can be measured per request and guarded against regressions
+
+use crate::{http::Header, request::Request, response::Response};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+struct Counting;
+
+thread_local! {
+    // Each worker handles one request at a time, so per thread counts are per request counts
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    static BYTES: Cell<u64> = const { Cell::new(0) };
+}
+
+fn record(bytes: usize) {
+    // Fails (harmlessly) while the thread is being torn down
+    let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+    let _ = BYTES.try_with(|total| total.set(total.get() + bytes as u64));
+}
+
+// SAFETY: Defers to `System` for the actual allocation, only counting on the way through
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(new_size);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Allocations made by the current thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+impl Snapshot {
+    pub fn now() -> Self {
+        Self {
+            allocations: ALLOCATIONS.with(Cell::get),
+            bytes: BYTES.with(Cell::get),
+        }
+    }
+
+    /// Allocations made by the current thread since this snapshot was taken
+    pub fn elapsed(&self) -> Self {
+        let now = Self::now();
+
+        Self {
+            allocations: now.allocations - self.allocations,
+            bytes: now.bytes - self.bytes,
+        }
+    }
+}
+
+/// Reports the allocations made handling the request in debug headers, if the client asked
+/// for them with an `X-Debug-Allocations` request header
+pub fn debug_headers(request: &Request, response: Response, since: &Snapshot) -> Response {
+    if !request.headers.contains_key("x-debug-allocations") {
+        return response;
+    }
+    let used = since.elapsed();
+
+    response
+        .header(Header::Custom(
+            "X-Alloc-Count".to_string(),
+            used.allocations.to_string(),
+        ))
+        .header(Header::Custom(
+            "X-Alloc-Bytes".to_string(),
+            used.bytes.to_string(),
+        ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_allocations() {
+        let before = Snapshot::now();
+        let buffer = std::hint::black_box(Vec::<u8>::with_capacity(100));
+        let used = before.elapsed();
+        drop(buffer);
+
+        assert_eq!(used.allocations, 1);
+        assert_eq!(used.bytes, 100);
+    }
+
+    /// Guards against regressions in how much parsing a simple request allocates
+    #[test]
+    fn decoding_a_request_allocates_little() {
+        let input = b"GET /echo/abc HTTP/1.1\r\nHost: localhost\r\nUser-Agent: test\r\n\r\n";
+        // Warm up the buffer pool
+        drop(Request::decode(&input[..]));
+
+        let before = Snapshot::now();
+        let request = Request::decode(&input[..]);
+        let used = before.elapsed();
+        drop(request);
+
+        assert!(used.allocations <= 9, "{used:?}");
+    }
+
+    /// Bodies that are thrown away (eg, a `DELETE`'s) are never held in memory
+    #[test]
+    fn discarded_bodies_are_not_buffered() {
+        let mut input =
+            b"DELETE /files/x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 60000\r\n\r\n"
+                .to_vec();
+        input.resize(input.len() + 60000, b'x');
+        drop(Request::decode(&input[..]));
+
+        let before = Snapshot::now();
+        let request = Request::decode(&input[..]);
+        let used = before.elapsed();
+        drop(request);
+
+        assert!(used.bytes < 1024, "{used:?}");
+    }
+
+    #[test]
+    fn debug_headers_are_opt_in() {
+        let request = Request::decode(
+            &b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Debug-Allocations: 1\r\n\r\n"[..],
+        )
+        .unwrap();
+        let response = debug_headers(&request, Response::ok(), &Snapshot::now()).encode();
+        assert!(
+            response
+                .windows(13)
+                .any(|window| window == b"X-Alloc-Count")
+        );
+
+        let request = Request::decode(&b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]).unwrap();
+        let response = debug_headers(&request, Response::ok(), &Snapshot::now()).encode();
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+}
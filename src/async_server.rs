@@ -0,0 +1,618 @@
+//! The `async` feature's backend (`--backend async`), where each connection is a tokio task
+//! reading its requests and writing its responses without a thread of its own, so there can be
+//! as many as there are sockets to spare
+//!
+//! Handlers block (eg, reading files), so requests are answered on tokio's blocking pool, as are
+//! bodies that are read or made as they are sent, which are handed back a chunk at a time for the
+//! task to write. HTTP/2 and upgraded connections are only spoken blocking, so they are handed to
+//! the blocking pool for as long as they last.
+
+use crate::{
+    config::Config,
+    connection::{self, Answer, CONTINUE},
+    dump::Dumped,
+    evented::{Arrival, Timing},
+    h2,
+    limit::Permit,
+    listener::{self, Stream},
+    profiling::{self, Phase},
+    request::{ReadPolicy, Request},
+    response::{self, Response, StatusCode},
+    server::{self, QueueFullPolicy, RECEIVE_TIMEOUT, SEND_TIMEOUT},
+};
+use anyhow::Result;
+use std::{
+    io::{self, Cursor, Write},
+    mem, net, panic,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime,
+    sync::mpsc,
+    task::{self, JoinSet},
+    time,
+};
+use tracing::{debug, error, info, warn};
+
+/// How often to check for room while there are `--max-connections` open
+const HELD_BACK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How much to read from a client at a time
+const READ_SIZE: usize = 8192;
+
+/// How much of a body made on the blocking pool is handed to the task at a time, and how many of
+/// those can be waiting to be written
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHUNKS_AHEAD: usize = 4;
+
+/// Serves the `listeners` on a runtime of its own until one of them fails or the server is
+/// stopping, answering requests on up to `blocking_threads` threads at once (tokio's default if
+/// `None`)
+///
+/// Requests already being answered are finished before it returns.
+pub fn serve(
+    listeners: Vec<net::TcpListener>,
+    config: &Arc<Config>,
+    blocking_threads: Option<usize>,
+) -> io::Result<()> {
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(blocking_threads) = blocking_threads {
+        builder.max_blocking_threads(blocking_threads);
+    }
+
+    builder.build()?.block_on(async {
+        let mut accepting = JoinSet::new();
+        for listener in listeners {
+            listener.set_nonblocking(true)?;
+            accepting.spawn(accept_all(
+                TcpListener::from_std(listener)?,
+                Arc::clone(config),
+            ));
+        }
+        config.health.set_accepting(true);
+
+        let mut result = Ok(());
+        while let Some(stopped) = accepting.join_next().await {
+            // Some clients can no longer connect
+            config.health.set_accepting(false);
+            match stopped {
+                Ok(Err(err)) => {
+                    error!("Stopped accepting: {err}");
+                    result = Err(err);
+                }
+                Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+                _ => {}
+            }
+        }
+
+        result
+    })
+}
+
+async fn accept_all(listener: TcpListener, config: Arc<Config>) -> io::Result<()> {
+    let connections = &config.connections;
+    loop {
+        // Leaves new connections in the listen backlog until there is room
+        while connections.when_full() == QueueFullPolicy::Block && connections.is_full() {
+            time::sleep(HELD_BACK_INTERVAL).await;
+        }
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            // Connections already accepted carry on, and closing frees up what ran short
+            Err(err) if listener::is_transient(&err) => {
+                warn!("Error accepting a connection: {err}");
+                time::sleep(listener::ACCEPT_BACKOFF).await;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if config.health.is_stopping() {
+            return Ok(());
+        }
+        let stream = match stream.into_std() {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Dropping connection that can't be taken from tokio: {err}");
+                continue;
+            }
+        };
+        let peer = stream.peer();
+        // Only this connection is given up on, the listener carries on
+        if let Err(err) = stream.set_timeouts(
+            Duration::from_secs(RECEIVE_TIMEOUT),
+            Duration::from_secs(SEND_TIMEOUT),
+        ) {
+            warn!(peer, "Dropping connection without timeouts: {err}");
+            continue;
+        }
+        if !config.trusted_proxies.trusts(&peer) && !config.ip_filter.load().permits(&peer) {
+            debug!(peer, "Refusing connection");
+            server::refuse(stream, StatusCode::Forbidden);
+            continue;
+        }
+        let Some(permit) = connections.try_acquire() else {
+            warn!(peer, "Too many connections, shedding connection");
+            server::shed(stream);
+            continue;
+        };
+
+        tokio::spawn(serve_connection(stream, peer, permit, Arc::clone(&config)));
+    }
+}
+
+/// Answers the requests on a connection until either side is done with it
+async fn serve_connection(
+    stream: net::TcpStream,
+    peer: String,
+    permit: Permit,
+    config: Arc<Config>,
+) {
+    let Ok(stream) = TcpStream::from_std(stream) else {
+        return;
+    };
+    config.metrics.connection_opened();
+    let connection = Connection {
+        stream: Dumped::new(stream, connection::dump(&config)),
+        config: Arc::clone(&config),
+        peer,
+        received: Vec::with_capacity(READ_SIZE),
+        served: 0,
+        timing: Timing::now(),
+        arrival: Arrival::new(&config),
+    };
+    if let Err(err) = connection.serve().await {
+        error!("Connection error: {err}");
+        config.metrics.connection_aborted();
+    }
+    config.metrics.connection_closed();
+    drop(permit);
+}
+
+/// What becomes of a connection once a request on it has been answered
+enum Next {
+    Request,
+    Close,
+    /// Over to a protocol that is only spoken blocking
+    HandOver(Blocking),
+}
+
+/// Speaks a protocol on a connection handed over to the blocking pool, until it is done
+type Blocking = Box<dyn FnOnce(&mut Dumped<net::TcpStream>) -> Result<()> + Send>;
+
+/// A connection whose requests are read, and responses written, by its task
+struct Connection {
+    stream: Dumped<TcpStream>,
+    config: Arc<Config>,
+    /// Who is on the other end, for the access log
+    peer: String,
+    /// What has been read from the client but not yet decoded, including pipelined requests
+    received: Vec<u8>,
+    /// How many requests have been answered on it
+    served: usize,
+    timing: Timing,
+    arrival: Arrival,
+}
+
+impl Connection {
+    /// Answers requests until the client is done, or the connection can't be kept open
+    async fn serve(mut self) -> Result<()> {
+        loop {
+            match self.exchange().await? {
+                Next::Request => {
+                    self.served += 1;
+                    self.arrival.next(&self.received);
+                    self.timing.answered(!self.received.is_empty());
+                }
+                Next::Close => break,
+                Next::HandOver(serve) => return self.hand_over(serve).await,
+            }
+        }
+        if let Err(err) = self.stream.shutdown().await {
+            debug!("Error shutting down connection: {err}");
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the next request, then answers it
+    async fn exchange(&mut self) -> Result<Next> {
+        while !self.arrival.is_ready() {
+            if !self.receive().await {
+                return Ok(Next::Close);
+            }
+        }
+        if self.served == 0 && self.config.http2 && self.received.starts_with(h2::PREFACE) {
+            let (config, peer) = (Arc::clone(&self.config), self.peer.clone());
+            let buffered = mem::take(&mut self.received);
+            return Ok(Next::HandOver(Box::new(move |stream| {
+                connection::serve_h2(&config, stream, buffered, &peer)
+            })));
+        }
+
+        let (started, received) = (Instant::now(), SystemTime::now());
+        let overall = self
+            .config
+            .request_timeout
+            .map(|request_timeout| started + request_timeout);
+        let request = match self.decode().await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(Next::Close),
+            Err(e) => {
+                let peer = self.peer.clone();
+                self.send(connection::malformed(&e), None, &peer, started, received)
+                    .await?;
+                return Ok(Next::Close);
+            }
+        };
+
+        let (config, peer, served) = (Arc::clone(&self.config), self.peer.clone(), self.served);
+        let (request, client, answer) = task::spawn_blocking(move || {
+            #[cfg(feature = "alloc-tracking")]
+            let allocations = crate::alloc_tracking::Snapshot::now();
+            profiling::start_request();
+            let client = config.trusted_proxies.client(&peer, &request);
+            let answer = connection::answer(&config, &request, &peer, &client, served, overall)
+                .map(|answer| {
+                    answer.map(|answer| {
+                        #[cfg(feature = "alloc-tracking")]
+                        let answer = Answer {
+                            response: crate::alloc_tracking::debug_headers(
+                                &request,
+                                answer.response,
+                                &allocations,
+                            ),
+                            ..answer
+                        };
+                        #[cfg(feature = "profiling")]
+                        let answer = Answer {
+                            response: profiling::debug_headers(&request, answer.response),
+                            ..answer
+                        };
+                        answer
+                    })
+                });
+
+            (request, client, answer)
+        })
+        .await?;
+        let Some(Answer {
+            response,
+            upgrade,
+            keep_alive,
+        }) = answer?
+        else {
+            return Ok(Next::Close);
+        };
+        debug!("Sending: {response:?}");
+        match self
+            .send(response, Some(&request), &client, started, received)
+            .await
+        {
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                warn!(id = %request.id, "Response took longer than --request-timeout to send");
+                self.config.metrics.connection_aborted();
+                return Ok(Next::Close);
+            }
+            Err(error) if response::is_disconnect(&error) => {
+                info!("Client disconnected mid-response: {error}");
+                self.config.metrics.connection_aborted();
+                return Ok(Next::Close);
+            }
+            result => result?,
+        }
+
+        // The connection now belongs to the protocol switched to, until it is done
+        if let Some(upgrade) = upgrade {
+            return Ok(Next::HandOver(Box::new(|stream| {
+                Ok(connection::switch(upgrade, stream)?)
+            })));
+        }
+
+        Ok(if keep_alive {
+            Next::Request
+        } else {
+            Next::Close
+        })
+    }
+
+    /// Reads more of what the client is sending, saying whether any came before it closed the
+    /// connection or ran out of time (which it is told, if it was part way through a request)
+    async fn receive(&mut self) -> bool {
+        let between_requests = self.served > 0 && self.received.is_empty();
+        let time_left = self.timing.time_left(&self.config, between_requests);
+        self.received.reserve(READ_SIZE);
+        match time::timeout(time_left, self.stream.read_buf(&mut self.received)).await {
+            Ok(Ok(0) | Err(_)) => false,
+            Ok(Ok(read)) => {
+                let arrived = self.received.len() - read;
+                self.arrival.feed(&self.received[arrived..]);
+                self.timing.received(self.arrival.head_arrived());
+                true
+            }
+            Err(_) => {
+                self.time_out().await;
+                false
+            }
+        }
+    }
+
+    /// Gives up on the client, telling it why if it was part way through a request
+    async fn time_out(&mut self) {
+        if self.received.is_empty() {
+            debug!("Closing idle connection");
+            return;
+        }
+        debug!("Timed out waiting for request");
+        let response = Response::new(StatusCode::RequestTimeout)
+            .header(connection::close())
+            .encode();
+        let sending = self.stream.write_all(&response);
+        match time::timeout(Duration::from_secs(SEND_TIMEOUT), sending).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => debug!("Error timing out connection: {err}"),
+            Err(_) => debug!("Timed out timing out connection"),
+        }
+    }
+
+    /// Decodes the next request from what has been received, reading more while its body is
+    /// still on its way (chunked, or after `100 Continue`). `None` if the client stopped sending
+    /// before it was all there.
+    async fn decode(&mut self) -> Result<Option<Request>> {
+        let limits = self.config.limits;
+        let mut continued = false;
+        loop {
+            let decoded = profiling::time(Phase::Parse, || {
+                let mut reader = Cursor::new(self.received.as_slice());
+                let mut request = Request::decode_head(&mut reader, ReadPolicy::BLOCKING, limits)?;
+                let expects_continue = request.expects_continue()?;
+                let read = request.read_body(&mut reader, ReadPolicy::BLOCKING, limits);
+                let used = usize::try_from(reader.position()).unwrap_or(usize::MAX);
+                match read {
+                    Ok(()) => anyhow::Ok(Ok((request, used))),
+                    // Going wrong at the end of what has arrived may only mean the rest hasn't
+                    Err(_) if used >= self.received.len() => Ok(Err(expects_continue)),
+                    Err(err) => Err(err),
+                }
+            })?;
+            match decoded {
+                Ok((request, used)) => {
+                    self.received.drain(..used);
+                    return Ok(Some(request));
+                }
+                // Otherwise the client waits a while before sending the body anyway
+                Err(true) if !continued => {
+                    write_all(&mut self.stream, CONTINUE, None, &mut 0).await?;
+                    continued = true;
+                }
+                Err(_) => {}
+            }
+            if !self.receive().await {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Writes `response` to the client, then records it in the access log and metrics, as
+    /// answered to `client`
+    async fn send(
+        &mut self,
+        response: Response,
+        request: Option<&Request>,
+        client: &str,
+        started: Instant,
+        received: SystemTime,
+    ) -> io::Result<()> {
+        let mut response = response;
+        connection::stamp(&self.config, &mut response);
+        let status = response.status_code().code();
+        let head_len = response.head_len() as u64;
+        // Telling the client what was wrong with its request is worth being late for
+        let deadline = request.and(
+            self.config
+                .request_timeout
+                .map(|request_timeout| started + request_timeout),
+        );
+        let mut sent = 0;
+        let result = if response.is_in_memory() {
+            write_all(&mut self.stream, &response.encode(), deadline, &mut sent).await
+        } else {
+            self.send_made(response, deadline, &mut sent).await
+        };
+
+        let bytes = sent.saturating_sub(head_len);
+        connection::record(
+            &self.config,
+            request,
+            client,
+            status,
+            bytes,
+            started,
+            received,
+        );
+
+        result
+    }
+
+    /// Sends a response whose body has to be read or made as it goes (eg, a file or a stream),
+    /// which is done on the blocking pool while the task writes what it has made so far
+    async fn send_made(
+        &mut self,
+        response: Response,
+        deadline: Option<Instant>,
+        sent: &mut u64,
+    ) -> io::Result<()> {
+        let (sender, mut chunks) = mpsc::channel(CHUNKS_AHEAD);
+        let making =
+            task::spawn_blocking(move || response.write_with(&mut Chunks(sender), |_, _, _| None));
+        while let Some(chunk) = chunks.recv().await {
+            // Dropping `chunks` stops the body being made
+            write_all(&mut self.stream, &chunk, deadline, sent).await?;
+        }
+
+        making.await.map_err(io::Error::other)?
+    }
+
+    /// Gives the connection to `serve` on the blocking pool, until it is done with it
+    async fn hand_over(self, serve: Blocking) -> Result<()> {
+        let (stream, dump) = self.stream.into_parts();
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let mut stream = Dumped::new(stream, dump);
+
+        task::spawn_blocking(move || serve(&mut stream)).await?
+    }
+}
+
+/// Writes all of `bytes` to the client, adding them to what has been `sent`, unless it stops
+/// reading for longer than the send timeout, or the `deadline` (if any) passes
+async fn write_all(
+    stream: &mut Dumped<TcpStream>,
+    mut bytes: &[u8],
+    deadline: Option<Instant>,
+    sent: &mut u64,
+) -> io::Result<()> {
+    while !bytes.is_empty() {
+        let mut patience = Duration::from_secs(SEND_TIMEOUT);
+        if let Some(deadline) = deadline {
+            patience = patience.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        if patience.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let written = match time::timeout(patience, stream.write(bytes)).await {
+            Ok(written) => written?,
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        *sent += written as u64;
+        bytes = &bytes[written..];
+    }
+
+    Ok(())
+}
+
+/// Hands what a body writes on the blocking pool to the connection's task, a chunk at a time,
+/// failing once the task has stopped taking them (eg, as the client has gone)
+struct Chunks(mpsc::Sender<Vec<u8>>);
+
+impl Write for Chunks {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk = buf[..buf.len().min(CHUNK_SIZE)].to_vec();
+        let written = chunk.len();
+        self.0
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::KeepAlive;
+    use std::{
+        io::{Read, Write},
+        thread,
+    };
+
+    fn start(config: Config) -> String {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(vec![listener], &Arc::new(config), Some(1)));
+
+        address
+    }
+
+    #[test]
+    fn answers_keep_alive_and_pipelined_requests() {
+        let address = start(Config {
+            keep_alive: Some(KeepAlive {
+                timeout: Duration::from_secs(5),
+                max_requests: 3,
+            }),
+            date: false,
+            ..Config::default()
+        });
+        // Waiting on this doesn't take the only blocking thread
+        let mut idle = net::TcpStream::connect(&address).unwrap();
+        idle.write_all(b"GET /echo/idle HTTP/1.1\r\n").unwrap();
+
+        let mut client = net::TcpStream::connect(&address).unwrap();
+        client
+            .write_all(b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        client
+            .write_all(b"GET /echo/two HTTP/1.1\r\nHost: loc")
+            .unwrap();
+        client
+            .write_all(
+                b"alhost\r\n\r\n\
+                GET /echo/three HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .unwrap();
+
+        // The third request is the last the connection may make
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        assert_eq!(responses.matches("HTTP/1.1 200 OK").count(), 3);
+        assert!(responses.ends_with("\r\n\r\nthree"));
+    }
+
+    #[test]
+    fn reads_bodies_as_they_arrive_and_streams_responses() {
+        let address = start(Config {
+            date: false,
+            ..Config::default()
+        });
+        let mut client = net::TcpStream::connect(&address).unwrap();
+        client
+            .write_all(
+                b"POST /echo HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+                Transfer-Encoding: chunked\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut continued = [0; CONTINUE.len()];
+        client.read_exact(&mut continued).unwrap();
+        assert_eq!(continued, CONTINUE);
+        client.write_all(b"5\r\nhel").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        client.write_all(b"lo\r\n0\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(
+            response.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn malformed_requests_are_answered_and_closed() {
+        let address = start(Config::default());
+        let mut client = net::TcpStream::connect(&address).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: x\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("Connection: close\r\n"), "{response}");
+    }
+}
@@ -0,0 +1,155 @@
+//! Checks for RFC 9110 semantics the server otherwise lets slide, so clients (and handlers)
+//! can be tested against a stricter peer
+//!
+//! `CONNECT` needs no check here, as no route registers it and so the router answers 501.
+
+use crate::{
+    http::Header,
+    request::Request,
+    response::{Response, StatusCode},
+};
+use clap::ValueEnum;
+use serde::Serialize;
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strictness {
+    /// Don't check
+    #[default]
+    Off,
+    /// Log violations, but carry on as normal
+    Log,
+    /// Reject requests with 400 and strip bodies that responses must not have
+    Reject,
+}
+
+pub fn request_violations(request: &Request) -> Vec<&'static str> {
+    let mut violations = vec![];
+
+    // RFC 9112 section 3.2. The parser already rejects HTTP/1.1 requests without one, so this
+    // catches h2 requests without an `:authority`.
+    if !request.headers.contains_key("host") {
+        violations.push("HTTP/1.1 requests must have a Host header");
+    }
+
+    violations
+}
+
+pub fn response_violations(response: &Response) -> Vec<&'static str> {
+    let mut violations = vec![];
+
+    // RFC 9110 sections 15.3.5 and 15.4.5
+    let status_code = response.status_code();
+    let bodiless = matches!(status_code, StatusCode::NoContent | StatusCode::NotModified)
+        || (100..200).contains(&status_code.code());
+    if bodiless && response.has_body() {
+        violations.push("1xx, 204 and 304 responses must not have a body");
+    }
+
+    violations
+}
+
+/// Checks the request, returning the response to send instead of routing it if rejected
+pub fn request(request: &Request, strictness: Strictness) -> Option<Response> {
+    if strictness == Strictness::Off {
+        return None;
+    }
+
+    let violations = request_violations(request);
+    for violation in &violations {
+        warn!(%violation, "RFC 9110 violation in request");
+    }
+
+    (strictness == Strictness::Reject && !violations.is_empty()).then(|| {
+        Response::bad_request()
+            .header(Header::ContentType("text/plain".to_string()))
+            .body_str(&violations.join("\n"))
+    })
+}
+
+/// Checks the response a handler produced, removing the body if it must not have one
+pub fn response(response: Response, strictness: Strictness) -> Response {
+    if strictness == Strictness::Off {
+        return response;
+    }
+
+    let violations = response_violations(&response);
+    for violation in &violations {
+        warn!(%violation, "RFC 9110 violation in response");
+    }
+
+    if strictness == Strictness::Reject && !violations.is_empty() {
+        return response.without_body();
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TestRequest;
+
+    fn decode(input: &[u8]) -> Request {
+        Request::decode(input).unwrap()
+    }
+
+    #[test]
+    fn host_is_required() {
+        let request = TestRequest::get("/").build();
+        assert_eq!(
+            request_violations(&request),
+            vec!["HTTP/1.1 requests must have a Host header"]
+        );
+
+        let request = decode(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(request_violations(&request).is_empty());
+    }
+
+    #[test]
+    fn get_with_a_body() {
+        // Without a Content-Length what follows is the next request (RFC 9112 section 6.3)
+        let request = decode(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nhi");
+        assert_eq!(request.body, None);
+        assert!(request_violations(&request).is_empty());
+
+        let request = decode(b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\nhi");
+        assert!(request_violations(&request).is_empty());
+    }
+
+    #[test]
+    fn violations_are_only_rejected_when_asked() {
+        let request = TestRequest::get("/").build();
+
+        assert!(super::request(&request, Strictness::Off).is_none());
+        assert!(super::request(&request, Strictness::Log).is_none());
+        assert!(
+            super::request(&request, Strictness::Reject)
+                .unwrap()
+                .encode()
+                .starts_with(b"HTTP/1.1 400 Bad Request\r\n")
+        );
+    }
+
+    #[test]
+    fn no_content_with_a_body_is_stripped() {
+        let response = Response::no_content().body_str("oops");
+        assert_eq!(
+            response_violations(&response),
+            vec!["1xx, 204 and 304 responses must not have a body"]
+        );
+
+        assert_eq!(
+            super::response(response, Strictness::Reject).encode(),
+            b"HTTP/1.1 204 No Content\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn not_modified_without_a_body_is_fine() {
+        let response = Response::new(StatusCode::NotModified);
+
+        assert!(response_violations(&response).is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+//! Who may use which routes, declared when the routes are registered and enforced by the
+//! router before any handler runs
+//!
+//! Credentials come from the command line, for a realm: `--user`, `--token` and `--token-file`
+//! for `api`, `--files-auth` for `files` and `--admin-token` for `admin`. Until a realm has some
+//! the server is open, as it always has been, and requirements in that realm aren't enforced.
+//! TLS is only for development, so there are no client certificates either.
+
+use crate::{
+    http::Header,
+    request::Request,
+    response::{Response, StatusCode},
+};
+use anyhow::{Context, Result, bail};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use std::{collections::HashMap, fs, path::Path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// RFC 7617, a user name and password
+    Basic,
+    /// RFC 6750, an opaque token
+    Bearer,
+}
+
+impl Scheme {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Basic => "Basic",
+            Self::Bearer => "Bearer",
+        }
+    }
+}
+
+/// The users and tokens the server accepts, by realm
+#[derive(Debug, Default)]
+pub struct Credentials {
+    realms: HashMap<String, Users>,
+}
+
+impl Credentials {
+    /// Adds a `name:password` user for Basic authentication in `realm`
+    pub fn add_user(&mut self, realm: &str, user: &str) -> Result<()> {
+        let (name, password) = split(user)?;
+        self.realm(realm).passwords.insert(name, password);
+
+        Ok(())
+    }
+
+    /// Adds a `name:token` token for Bearer authentication in `realm`, acting as user `name`
+    pub fn add_token(&mut self, realm: &str, token: &str) -> Result<()> {
+        let (name, token) = split(token)?;
+        self.realm(realm).tokens.insert(token, name);
+
+        Ok(())
+    }
+
+    /// Adds the tokens in a file with a `name:token` per line, like `add_token`, so they needn't
+    /// be on the command line. Blank lines and those starting with `#` are skipped.
+    pub fn add_token_file(&mut self, realm: &str, path: &Path) -> Result<()> {
+        let tokens = fs::read_to_string(path)?;
+        for (number, line) in tokens.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.add_token(realm, line)
+                .with_context(|| format!("line {}", number + 1))?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether anyone has been given credentials for `realm`, so its requirements are enforced
+    pub fn has_realm(&self, realm: &str) -> bool {
+        self.realms.contains_key(realm)
+    }
+
+    fn realm(&mut self, realm: &str) -> &mut Users {
+        self.realms.entry(realm.to_string()).or_default()
+    }
+}
+
+/// One realm's users, each token standing in for a user
+#[derive(Debug, Default)]
+struct Users {
+    passwords: HashMap<String, String>,
+    tokens: HashMap<String, String>,
+}
+
+impl Users {
+    /// The user the `Authorization` header proves the client to be, if it uses `scheme`
+    fn authenticate(&self, scheme: Scheme, authorization: &str) -> Option<&str> {
+        let (given, credentials) = authorization.trim().split_once(' ')?;
+        if !given.eq_ignore_ascii_case(scheme.as_str()) {
+            return None;
+        }
+        let credentials = credentials.trim();
+
+        match scheme {
+            Scheme::Basic => {
+                let decoded = BASE64_STANDARD.decode(credentials).ok()?;
+                let (name, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+                let (name, expected) = self.passwords.get_key_value(name)?;
+                constant_time_eq(password.as_bytes(), expected.as_bytes()).then_some(name)
+            }
+            Scheme::Bearer => self
+                .tokens
+                .iter()
+                .find(|(token, _)| constant_time_eq(credentials.as_bytes(), token.as_bytes()))
+                .map(|(_, name)| name.as_str()),
+        }
+    }
+}
+
+fn split(credential: &str) -> Result<(String, String)> {
+    match credential.split_once(':') {
+        Some((name, secret)) if !name.is_empty() && !secret.is_empty() => {
+            Ok((name.to_string(), secret.to_string()))
+        }
+        _ => bail!("Expected NAME:SECRET"),
+    }
+}
+
+/// Compares secrets without giving away how much of a guess was right through timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// What a client must present to use a route
+#[derive(Debug)]
+pub struct Requirement {
+    realm: &'static str,
+    schemes: &'static [Scheme],
+    /// Empty for any authenticated user
+    users: &'static [&'static str],
+}
+
+impl Requirement {
+    pub const fn new(realm: &'static str, schemes: &'static [Scheme]) -> Self {
+        Self {
+            realm,
+            schemes,
+            users: &[],
+        }
+    }
+
+    /// Only lets these users through, anyone else authenticating is refused with 403
+    // No route needs a particular user yet, but they should be able to say so
+    #[allow(dead_code)]
+    #[must_use]
+    pub const fn users(mut self, users: &'static [&'static str]) -> Self {
+        self.users = users;
+        self
+    }
+
+    /// The response to send instead of running the handler, if the request doesn't qualify
+    pub fn check(&self, request: &Request, credentials: &Credentials) -> Option<Response> {
+        let users = credentials.realms.get(self.realm)?;
+
+        let user = request
+            .headers
+            .get("authorization")
+            .and_then(|authorization| {
+                self.schemes
+                    .iter()
+                    .find_map(|&scheme| users.authenticate(scheme, authorization))
+            });
+        match user {
+            None => Some(self.challenge()),
+            Some(user) if !self.users.is_empty() && !self.users.contains(&user) => {
+                Some(Response::new(StatusCode::Forbidden))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// 401, listing every scheme that would do in one `WWW-Authenticate`
+    fn challenge(&self) -> Response {
+        let challenges = self
+            .schemes
+            .iter()
+            .map(|scheme| format!("{} realm=\"{}\"", scheme.as_str(), self.realm))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        Response::new(StatusCode::Unauthorized)
+            .header(Header::Custom("WWW-Authenticate".to_string(), challenges))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{TestRequest, test_directory};
+
+    const ADMIN: Requirement = Requirement::new("admin", &[Scheme::Basic, Scheme::Bearer]);
+
+    fn credentials() -> Credentials {
+        let mut credentials = Credentials::default();
+        credentials.add_user("admin", "alice:secret").unwrap();
+        credentials.add_user("admin", "bob:hunter2").unwrap();
+        credentials.add_token("admin", "alice:t0ken").unwrap();
+        credentials.add_user("other", "carol:pa55").unwrap();
+        credentials
+    }
+
+    fn check(requirement: &Requirement, authorization: Option<&str>) -> Option<u16> {
+        let mut request = TestRequest::get("/admin");
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+
+        requirement
+            .check(&request.build(), &credentials())
+            .map(|response| response.status_code().code())
+    }
+
+    #[test]
+    fn basic_and_bearer() {
+        // alice:secret
+        assert_eq!(check(&ADMIN, Some("Basic YWxpY2U6c2VjcmV0")), None);
+        assert_eq!(check(&ADMIN, Some("bearer t0ken")), None);
+
+        // alice:wrong
+        assert_eq!(check(&ADMIN, Some("Basic YWxpY2U6d3Jvbmc=")), Some(401));
+        assert_eq!(check(&ADMIN, Some("Bearer nope")), Some(401));
+        assert_eq!(check(&ADMIN, None), Some(401));
+    }
+
+    #[test]
+    fn unlisted_users_are_forbidden() {
+        let alice_only = Requirement::new("admin", &[Scheme::Basic]).users(&["alice"]);
+
+        // bob:hunter2
+        assert_eq!(
+            check(&alice_only, Some("Basic Ym9iOmh1bnRlcjI=")),
+            Some(403)
+        );
+        assert_eq!(check(&alice_only, Some("Basic YWxpY2U6c2VjcmV0")), None);
+    }
+
+    #[test]
+    fn challenge_lists_every_scheme() {
+        assert_eq!(
+            ADMIN.challenge().encode(),
+            b"HTTP/1.1 401 Unauthorized\r\n\
+            WWW-Authenticate: Basic realm=\"admin\", Bearer realm=\"admin\"\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn nothing_is_enforced_without_credentials() {
+        let request = TestRequest::get("/admin").build();
+
+        assert!(ADMIN.check(&request, &Credentials::default()).is_none());
+        assert!(
+            Requirement::new("open", &[Scheme::Basic])
+                .check(&request, &credentials())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn users_only_get_into_their_realm() {
+        // carol:pa55
+        assert_eq!(check(&ADMIN, Some("Basic Y2Fyb2w6cGE1NQ==")), Some(401));
+    }
+
+    #[test]
+    fn tokens_from_a_file() -> Result<()> {
+        let path = Path::new(&test_directory("tokens_from_a_file")).join("tokens");
+        fs::write(&path, "# Deploys\nci:t0ken\n\n  ops:s3cret  \n")?;
+        let mut credentials = Credentials::default();
+        let loaded = credentials.add_token_file("admin", &path);
+        fs::write(&path, "ci:t0ken\nbroken\n")?;
+        let broken = credentials.add_token_file("admin", &path);
+        fs::remove_file(&path)?;
+
+        loaded?;
+        let users = &credentials.realms["admin"];
+        assert_eq!(
+            users.authenticate(Scheme::Bearer, "Bearer s3cret"),
+            Some("ops")
+        );
+        assert_eq!(
+            users.authenticate(Scheme::Bearer, "Bearer t0ken"),
+            Some("ci")
+        );
+        assert_eq!(broken.unwrap_err().to_string(), "line 2");
+        Ok(())
+    }
+
+    #[test]
+    fn credentials_need_a_name_and_secret() {
+        let mut credentials = Credentials::default();
+
+        assert!(credentials.add_user("admin", "alice").is_err());
+        assert!(credentials.add_token("admin", ":token").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::{
+    LazyLock, Mutex,
+    atomic::{AtomicU64, Ordering},
+};
+
+/// Reusable byte buffers, so parsing, file copying and compression don't allocate (and fault
+/// in) large buffers on every request
+static POOL: LazyLock<BufferPool> = LazyLock::new(BufferPool::default);
+
+/// A buffer with at least `capacity` bytes of capacity (and a length of zero)
+pub fn take(capacity: usize) -> Vec<u8> {
+    POOL.take(capacity)
+}
+
+/// Returns a buffer to the pool once it is no longer needed
+pub fn give(buffer: Vec<u8>) {
+    POOL.give(buffer);
+}
+
+/// How often the pool has had a buffer to hand out, for `/metrics`
+pub fn stats() -> Stats {
+    POOL.stats()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Buffers handed out from the pool
+    pub hits: u64,
+    /// Buffers that had to be allocated
+    pub misses: u64,
+    /// Buffers currently sitting in the pool
+    pub pooled: usize,
+}
+
+struct BufferPool {
+    classes: [Mutex<Vec<Vec<u8>>>; BufferPool::CLASSES.len()],
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self {
+            classes: Default::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+impl BufferPool {
+    const CLASSES: [usize; 4] = [1024, 8 * 1024, 64 * 1024, 256 * 1024];
+
+    /// Stop pooling buffers beyond this, so a burst doesn't pin memory forever
+    const MAX_PER_CLASS: usize = 64;
+
+    fn take(&self, capacity: usize) -> Vec<u8> {
+        let Some(class) = Self::CLASSES.iter().position(|size| *size >= capacity) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Vec::with_capacity(capacity);
+        };
+
+        if let Some(buffer) = self.classes[class].lock().unwrap().pop() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return buffer;
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        Vec::with_capacity(Self::CLASSES[class])
+    }
+
+    fn give(&self, mut buffer: Vec<u8>) {
+        // Anything much bigger than the largest class is more memory than it is worth keeping
+        if buffer.capacity() > Self::CLASSES[Self::CLASSES.len() - 1] * 2 {
+            return;
+        }
+        let Some(class) = Self::CLASSES
+            .iter()
+            .rposition(|size| *size <= buffer.capacity())
+        else {
+            return;
+        };
+
+        let mut pooled = self.classes[class].lock().unwrap();
+        if pooled.len() < Self::MAX_PER_CLASS {
+            buffer.clear();
+            pooled.push(buffer);
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            pooled: self
+                .classes
+                .iter()
+                .map(|class| class.lock().unwrap().len())
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = BufferPool::default();
+
+        let mut buffer = pool.take(100);
+        assert!(buffer.capacity() >= 100);
+        buffer.extend_from_slice(b"abc");
+        pool.give(buffer);
+
+        let buffer = pool.take(500);
+        assert!(buffer.is_empty());
+        assert_eq!(
+            pool.stats(),
+            Stats {
+                hits: 1,
+                misses: 1,
+                pooled: 0
+            }
+        );
+    }
+
+    #[test]
+    fn buffers_come_from_the_right_class() {
+        let pool = BufferPool::default();
+        pool.give(Vec::with_capacity(8 * 1024));
+
+        assert!(pool.take(10 * 1024).capacity() >= 10 * 1024);
+        assert_eq!(pool.stats().misses, 1);
+        assert!(pool.take(8 * 1024).capacity() >= 8 * 1024);
+        assert_eq!(pool.stats().hits, 1);
+    }
+
+    #[test]
+    fn small_and_huge_buffers_are_not_pooled() {
+        let pool = BufferPool::default();
+        pool.give(Vec::with_capacity(16));
+        pool.give(Vec::with_capacity(10 * 1024 * 1024));
+
+        assert_eq!(pool.stats().pooled, 0);
+    }
+
+    #[test]
+    fn global_pool_counts_takes() {
+        let before = stats();
+        give(take(256 * 1024));
+        let after = stats();
+
+        assert!(after.hits + after.misses > before.hits + before.misses);
+    }
+}
@@ -0,0 +1,348 @@
+//! Management of the files in `--directory`, under `/api/files`
+//!
+//! Every bulk operation reports how each file fared rather than stopping at the first failure,
+//! and can be asked to only report what it would do with `dry_run`.
+
+use crate::{
+    files::{self, Symlinks},
+    request::Request,
+    response::{Response, StatusCode},
+    router::RequestContext,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// The body of a copy or move
+#[derive(Debug, Deserialize)]
+struct Transfers {
+    items: Vec<Transfer>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Transfer {
+    from: String,
+    to: String,
+}
+
+/// What `stat` reports, times being seconds since the Unix epoch
+#[derive(Debug, Serialize)]
+struct Stat {
+    name: String,
+    size: u64,
+    mtime: Option<u64>,
+    ctime: Option<i64>,
+    mode: Option<String>,
+    content_type: &'static str,
+    etag: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    dry_run: bool,
+    results: Vec<Outcome>,
+}
+
+#[derive(Debug, Serialize)]
+struct Outcome {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Outcome {
+    fn new(name: &str, to: Option<&str>, result: io::Result<()>) -> Self {
+        Self {
+            name: name.to_string(),
+            to: to.map(str::to_string),
+            ok: result.is_ok(),
+            error: result.err().map(|err| describe(&err)),
+        }
+    }
+}
+
+/// `GET /api/files/<name>/stat` describes a file without sending it
+pub fn stat(request: &Request, context: &RequestContext) -> Result<Response> {
+    // Safety: Router has already checked target starts_with and ends_with
+    let name = request
+        .path
+        .strip_prefix("/api/files/")
+        .and_then(|path| path.strip_suffix("/stat"))
+        .unwrap();
+    let path = match files::confined(root(context), name, context.follow_symlinks) {
+        Ok(path) if path.is_file() => path,
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            return Ok(Response::new(StatusCode::Forbidden));
+        }
+        _ => return Ok(Response::not_found()),
+    };
+    let metadata = fs::metadata(&path)?;
+
+    #[cfg(unix)]
+    let (ctime, mode) = {
+        use std::os::unix::fs::MetadataExt;
+        (
+            Some(metadata.ctime()),
+            Some(format!("{:o}", metadata.mode() & 0o7777)),
+        )
+    };
+    #[cfg(not(unix))]
+    let (ctime, mode) = (None, None);
+
+    Ok(Response::ok().body_json(&Stat {
+        name: name.to_string(),
+        size: metadata.len(),
+        mtime: metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs()),
+        ctime,
+        mode,
+        content_type: files::content_type(&path),
+        etag: files::etag(&metadata),
+    })?)
+}
+
+/// `DELETE /api/files?glob=<pattern>[&dry_run]` removes the matching files in the directory
+pub fn delete(request: &Request, context: &RequestContext) -> Result<Response> {
+    let Some(glob) = request
+        .query_param("glob")
+        .filter(|glob| !glob.contains('/'))
+    else {
+        return Ok(Response::bad_request());
+    };
+    let dry_run = request.query_param("dry_run").is_some();
+    let root = root(context).canonicalize()?;
+
+    let mut names: Vec<String> = fs::read_dir(&root)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| files::glob_matches(&glob, name))
+        .collect();
+    names.sort();
+
+    let results = names
+        .iter()
+        .map(|name| {
+            let result = if dry_run {
+                Ok(())
+            } else {
+                fs::remove_file(root.join(name))
+            };
+            Outcome::new(name, None, result)
+        })
+        .collect();
+
+    Ok(Response::ok().body_json(&Report { dry_run, results })?)
+}
+
+/// `POST /api/files/copy` with `{"items": [{"from": .., "to": ..}], "dry_run": bool}`
+pub fn copy(request: &Request, context: &RequestContext) -> Result<Response> {
+    transfer(request, context, |from, to| {
+        files::write_replacing(to, true, |file| {
+            io::copy(&mut File::open(from)?, file).map(|_| ())
+        })
+    })
+}
+
+/// `POST /api/files/move`, taking the same body as copy
+pub fn rename(request: &Request, context: &RequestContext) -> Result<Response> {
+    transfer(request, context, |from, to| fs::rename(from, to))
+}
+
+fn transfer(
+    request: &Request,
+    context: &RequestContext,
+    operation: fn(&Path, &Path) -> io::Result<()>,
+) -> Result<Response> {
+    let Ok(transfers) =
+        serde_json::from_slice::<Transfers>(request.body.as_deref().unwrap_or_default())
+    else {
+        return Ok(Response::bad_request());
+    };
+    let root = root(context);
+
+    let results = transfers
+        .items
+        .iter()
+        .map(|item| {
+            let result = endpoints(root, item, context.follow_symlinks).and_then(|(from, to)| {
+                if transfers.dry_run {
+                    Ok(())
+                } else {
+                    operation(&from, &to)
+                }
+            });
+            Outcome::new(&item.from, Some(&item.to), result)
+        })
+        .collect();
+
+    Ok(Response::ok().body_json(&Report {
+        dry_run: transfers.dry_run,
+        results,
+    })?)
+}
+
+/// Both ends of a transfer, checked so that nothing outside the directory is read or written
+/// and nothing is overwritten
+fn endpoints(root: &Path, item: &Transfer, symlinks: Symlinks) -> io::Result<(PathBuf, PathBuf)> {
+    let from = files::confined(root, &item.from, symlinks)?;
+    if !from.is_file() {
+        return Err(ErrorKind::NotFound.into());
+    }
+
+    let to = files::confined(root, &item.to, symlinks)?;
+    if to.exists() {
+        return Err(ErrorKind::AlreadyExists.into());
+    }
+
+    Ok((from, to))
+}
+
+fn root<'a>(context: &RequestContext<'a>) -> &'a Path {
+    Path::new(context.directory.unwrap_or("."))
+}
+
+/// Reports why an item failed without revealing where the directory is
+fn describe(err: &io::Error) -> String {
+    match err.kind() {
+        ErrorKind::NotFound => "not found".to_string(),
+        ErrorKind::AlreadyExists => "already exists".to_string(),
+        ErrorKind::PermissionDenied => "outside the directory".to_string(),
+        _ => err.kind().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{TestRequest, test_directory};
+
+    fn context(directory: &str) -> RequestContext<'_> {
+        RequestContext {
+            directory: Some(directory),
+            ..RequestContext::default()
+        }
+    }
+
+    #[test]
+    fn stat_describes_the_file() -> Result<()> {
+        let directory = test_directory("stat_describes_the_file");
+        fs::write(format!("{directory}/page.html"), "<p>")?;
+        let etag = files::etag(&fs::metadata(format!("{directory}/page.html"))?);
+
+        let response =
+            TestRequest::get("/api/files/page.html/stat").call(stat, &context(&directory));
+        response.assert_status(StatusCode::Ok);
+
+        let json = response.json();
+        assert_eq!(json["name"], "page.html");
+        assert_eq!(json["size"], 3);
+        assert_eq!(json["content_type"], "text/html; charset=utf-8");
+        assert_eq!(json["etag"], etag);
+        assert!(json["mtime"].is_u64());
+        Ok(())
+    }
+
+    #[test]
+    fn stat_missing_or_outside() {
+        let directory = test_directory("stat_missing_or_outside");
+
+        TestRequest::get("/api/files/missing/stat")
+            .call(stat, &context(&directory))
+            .assert_status(StatusCode::NotFound);
+        TestRequest::get("/api/files/../escaped/stat")
+            .call(stat, &context(&directory))
+            .assert_status(StatusCode::Forbidden);
+    }
+
+    #[test]
+    fn delete_by_glob() -> Result<()> {
+        let directory = test_directory("delete_by_glob");
+        for name in ["a.tmp", "b.tmp", "keep.txt"] {
+            fs::write(format!("{directory}/{name}"), name)?;
+        }
+
+        TestRequest::delete("/api/files?glob=*.tmp&dry_run")
+            .call(delete, &context(&directory))
+            .assert_status(StatusCode::Ok)
+            .assert_body(
+                r#"{"dry_run":true,"results":[{"name":"a.tmp","ok":true},{"name":"b.tmp","ok":true}]}"#,
+            );
+        assert!(Path::new(&directory).join("a.tmp").exists());
+
+        TestRequest::delete("/api/files?glob=*.tmp").call(delete, &context(&directory));
+        assert!(!Path::new(&directory).join("a.tmp").exists());
+        assert!(Path::new(&directory).join("keep.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn delete_needs_a_glob() {
+        let directory = test_directory("delete_needs_a_glob");
+
+        for target in ["/api/files", "/api/files?glob=../*"] {
+            TestRequest::delete(target)
+                .call(delete, &context(&directory))
+                .assert_status(StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn copy_and_move_report_each_item() -> Result<()> {
+        let directory = test_directory("copy_and_move_report_each_item");
+        fs::write(format!("{directory}/a"), "a")?;
+        fs::write(format!("{directory}/b"), "b")?;
+
+        TestRequest::post("/api/files/copy")
+            .body(r#"{"items":[{"from":"a","to":"c"},{"from":"b","to":"a"},{"from":"a","to":"../escaped"},{"from":"missing","to":"d"}]}"#)
+            .call(copy, &context(&directory))
+            .assert_body(
+                r#"{"dry_run":false,"results":[{"name":"a","to":"c","ok":true},{"name":"b","to":"a","ok":false,"error":"already exists"},{"name":"a","to":"../escaped","ok":false,"error":"outside the directory"},{"name":"missing","to":"d","ok":false,"error":"not found"}]}"#,
+            );
+        assert_eq!(fs::read_to_string(format!("{directory}/c"))?, "a");
+
+        TestRequest::post("/api/files/move")
+            .body(r#"{"items":[{"from":"c","to":"d"}]}"#)
+            .call(rename, &context(&directory));
+        assert!(!Path::new(&directory).join("c").exists());
+        assert_eq!(fs::read_to_string(format!("{directory}/d"))?, "a");
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() -> Result<()> {
+        let directory = test_directory("dry_run_changes_nothing");
+        fs::write(format!("{directory}/a"), "a")?;
+
+        TestRequest::post("/api/files/move")
+            .body(r#"{"items":[{"from":"a","to":"b"}],"dry_run":true}"#)
+            .call(rename, &context(&directory))
+            .assert_body(r#"{"dry_run":true,"results":[{"name":"a","to":"b","ok":true}]}"#);
+        assert!(Path::new(&directory).join("a").exists());
+        assert!(!Path::new(&directory).join("b").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_json_is_400() {
+        let directory = test_directory("invalid_json_is_400");
+
+        TestRequest::post("/api/files/copy")
+            .body("{[")
+            .call(copy, &context(&directory))
+            .assert_status(StatusCode::BadRequest);
+    }
+}
@@ -0,0 +1,118 @@
+//! What `Cache-Control` says on the responses for `/files` and the static sites
+//! (`--cache-control PATTERNS=VALUE`), so browsers needn't fetch everything again on every visit
+//!
+//! PATTERNS are globs, matched against the name of the file requested (`index.html` for a
+//! directory) or, for those with a `/` in them, the whole path. The first rule with a pattern
+//! that matches decides, so a last `*=no-store` covers anything the others don't. Files that no
+//! rule matches are sent without `Cache-Control`, leaving browsers to guess.
+
+use crate::{files, http::Header, response::Response};
+use anyhow::{Result, bail};
+
+#[derive(Debug, Default)]
+pub struct CacheControl(Vec<Rule>);
+
+#[derive(Debug)]
+struct Rule {
+    patterns: Vec<String>,
+    value: String,
+}
+
+impl CacheControl {
+    /// Adds a rule after the others, from `PATTERNS=VALUE` with the patterns separated by commas.
+    /// The value may have commas and `=`s of its own, eg `*.css,*.js=max-age=31536000, immutable`.
+    pub fn add(mut self, rule: &str) -> Result<Self> {
+        let Some((patterns, value)) = rule.split_once('=') else {
+            bail!("--cache-control {rule} should be PATTERNS=VALUE");
+        };
+        let patterns: Vec<_> = patterns
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(ToString::to_string)
+            .collect();
+        let value = value.trim();
+        if patterns.is_empty() || value.is_empty() {
+            bail!("--cache-control {rule} should be PATTERNS=VALUE");
+        }
+        self.0.push(Rule {
+            patterns,
+            value: value.to_string(),
+        });
+
+        Ok(self)
+    }
+
+    /// What `Cache-Control` should say for the file at `path`, as requested, if anything
+    pub fn value(&self, path: &str) -> Option<&str> {
+        let name = match path.rsplit('/').next() {
+            Some("") | None => files::INDEX,
+            Some(name) => name,
+        };
+        self.0
+            .iter()
+            .find(|rule| {
+                rule.patterns.iter().any(|pattern| {
+                    let subject = if pattern.contains('/') { path } else { name };
+                    files::glob_matches(pattern, subject)
+                })
+            })
+            .map(|rule| rule.value.as_str())
+    }
+
+    /// Sets `Cache-Control` on `response` for the file at `path`, when it is the file (or part of
+    /// it), or says the copy the client has is still good
+    pub fn apply(&self, path: &str, response: &mut Response) {
+        if !matches!(response.status_code().code(), 200 | 206 | 304) {
+            return;
+        }
+        if let Some(value) = self.value(path) {
+            response.set_header(Header::Custom(
+                "Cache-Control".to_string(),
+                value.to_string(),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_first_matching_rule_decides() -> Result<()> {
+        let cache_control = CacheControl::default()
+            .add("*.css, *.js=max-age=31536000, immutable")?
+            .add("/files/reports/*=private, no-cache")?
+            .add("*=no-store")?;
+
+        assert_eq!(
+            cache_control.value("/assets/site.css"),
+            Some("max-age=31536000, immutable")
+        );
+        assert_eq!(
+            cache_control.value("/files/reports/app.js"),
+            Some("max-age=31536000, immutable")
+        );
+        assert_eq!(
+            cache_control.value("/files/reports/q3.pdf"),
+            Some("private, no-cache")
+        );
+        assert_eq!(cache_control.value("/docs/"), Some("no-store"));
+        assert_eq!(
+            CacheControl::default()
+                .add("*.html=no-cache")?
+                .value("/docs/"),
+            Some("no-cache")
+        );
+        assert_eq!(CacheControl::default().value("/index.html"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn rules_need_patterns_and_a_value() {
+        for rule in ["no-store", "=no-store", "*.css=", " , =max-age=60"] {
+            assert!(CacheControl::default().add(rule).is_err(), "{rule}");
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! Compression for response bodies: quickly for responses made per request, and as small as
+//! possible for static files, which can be gzipped once at startup (`--precompress`)
+//!
+//! Gzip is always on offer, and deflate, Brotli and Zstandard with the features of the same names.
+
+use crate::{
+    buffers, files,
+    http::{self, Header, Negotiated, SUPPORTED_ENCODINGS},
+    request::Request,
+    response::{Response, StatusCode},
+    router,
+};
+use flate2::{Compression, write::GzEncoder};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::debug;
+
+/// Bodies sent from a file or reader are read into memory to be compressed up to this long, and
+/// sent as they are when they are any longer
+const MAX_READ: u64 = 8 * 1024 * 1024;
+
+/// How hard to work at making a body smaller
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Level {
+    /// For responses made per request, where the time spent is added to every one
+    Fast,
+    #[default]
+    Default,
+    /// For bodies compressed once and sent many times
+    Best,
+}
+
+impl From<Level> for Compression {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Fast => Self::fast(),
+            Level::Default => Self::default(),
+            Level::Best => Self::best(),
+        }
+    }
+}
+
+/// How the client wants the response encoded, out of `offered` (eg, the encodings there are copies
+/// of a file in)
+pub fn negotiate(request: &Request, offered: &[&'static str]) -> Negotiated {
+    http::negotiate_encoding(request.headers.get("accept-encoding"), offered)
+}
+
+/// How the client wants a response that can be compressed with anything supported encoded
+pub fn negotiate_any(request: &Request) -> Negotiated {
+    negotiate(request, SUPPORTED_ENCODINGS)
+}
+
+/// Compresses `body` with one of `SUPPORTED_ENCODINGS`
+pub fn encode(body: &[u8], encoding: &str, level: Level) -> io::Result<Vec<u8>> {
+    match encoding {
+        "gzip" => gzip(body, level),
+        #[cfg(feature = "deflate")]
+        "deflate" => {
+            // HTTP's deflate is the zlib format, not raw deflate
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::with_capacity(body.len() / 2), level.into());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        #[cfg(feature = "brotli")]
+        "br" => {
+            let quality = match level {
+                Level::Fast => 1,
+                Level::Default => 5,
+                Level::Best => 11,
+            };
+            let mut encoder = brotli::CompressorWriter::new(
+                Vec::with_capacity(body.len() / 2),
+                4096,
+                quality,
+                22,
+            );
+            encoder.write_all(body)?;
+            Ok(encoder.into_inner())
+        }
+        #[cfg(feature = "zstd")]
+        "zstd" => zstd::bulk::compress(
+            body,
+            match level {
+                Level::Fast => 1,
+                Level::Default => zstd::DEFAULT_COMPRESSION_LEVEL,
+                Level::Best => 19,
+            },
+        ),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported content coding {encoding}"),
+        )),
+    }
+}
+
+pub fn gzip(body: &[u8], level: Level) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), level.into());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Whether a body of this media type is worth compressing, which images and the like aren't
+pub fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+
+    media_type.starts_with("text/")
+        || matches!(
+            media_type,
+            "application/json" | "application/wasm" | "image/svg+xml"
+        )
+}
+
+/// When responses are compressed on their way out, for routes that haven't opted out
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Smaller bodies are sent as they are, as compressing them saves next to nothing
+    pub min_size: usize,
+    /// Media types to compress (eg `text/*`), or those `is_compressible` picks when empty
+    pub types: Vec<String>,
+}
+
+impl Policy {
+    fn applies_to(&self, content_type: &str) -> bool {
+        if self.types.is_empty() {
+            return is_compressible(content_type);
+        }
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        self.types
+            .iter()
+            .any(|pattern| router::media_type_matches(pattern, &media_type))
+    }
+
+    /// Compresses the response as the client prefers, if the policy covers it. Bodies from a
+    /// file or reader are read into memory for it, up to `MAX_READ`, and streams go out as they
+    /// are.
+    pub fn apply(
+        &self,
+        request: &Request,
+        response: Response,
+        level: Level,
+    ) -> io::Result<Response> {
+        let length = response
+            .body_len_in_memory()
+            .map(|length| length as u64)
+            .or_else(|| response.body_len().filter(|&length| length <= MAX_READ));
+        let covered = *response.status_code() != StatusCode::PartialContent
+            && response.headers().content_encoding().is_none()
+            && length.is_some_and(|length| length >= self.min_size as u64)
+            && response
+                .headers()
+                .content_type()
+                .is_some_and(|content_type| self.applies_to(content_type));
+        if !covered {
+            return Ok(response);
+        }
+
+        // Shared caches mustn't hand one client's encoding to another that can't take it
+        let mut response = response;
+        let varies = response
+            .headers()
+            .get_all("vary")
+            .flat_map(|vary| vary.split(','))
+            .any(|name| name.trim().eq_ignore_ascii_case("accept-encoding"));
+        if !varies {
+            response.add_header(Header::Custom(
+                "Vary".to_string(),
+                "Accept-Encoding".to_string(),
+            ));
+        }
+        match negotiate_any(request) {
+            Negotiated::Encoding(encoding) => {
+                // Each encoding is a representation of its own, with its own validator
+                if let Some(etag) = response.headers().get("etag").map(|etag| {
+                    let mut etag = etag.to_string();
+                    etag.insert_str(etag.len().saturating_sub(1), &format!("-{encoding}"));
+                    etag
+                }) {
+                    if request
+                        .headers
+                        .get("if-none-match")
+                        .is_some_and(|tags| http::if_none_match(tags, &etag))
+                    {
+                        return Ok(not_modified(&response, etag));
+                    }
+                    response.set_header(Header::ETag(etag));
+                }
+
+                response
+                    .header(Header::ContentEncoding(encoding.to_string()))
+                    .map_body(|body| {
+                        let encoded = encode(&body, encoding, level);
+                        buffers::give(body);
+                        encoded
+                    })
+            }
+            Negotiated::Identity => Ok(response),
+            Negotiated::NotAcceptable => Ok(Response::new(StatusCode::NotAcceptable)),
+        }
+    }
+}
+
+/// What to send instead of `response` when the client already has the representation tagged
+/// `etag`, with the headers RFC 9110 says a 304 should still carry
+fn not_modified(response: &Response, etag: String) -> Response {
+    let mut not_modified = Response::new(StatusCode::NotModified).header(Header::ETag(etag));
+    for name in ["Cache-Control", "Vary"] {
+        for value in response.headers().get_all(name) {
+            not_modified.add_header(Header::Custom(name.to_string(), value.to_string()));
+        }
+    }
+
+    not_modified
+}
+
+/// Gzipped copies of the compressible files under a directory, made at startup so they cost
+/// nothing to serve
+#[derive(Debug, Default)]
+pub struct Precompressed {
+    /// By path, with when the file was modified so a copy of an older version isn't sent
+    files: HashMap<PathBuf, (SystemTime, Vec<u8>)>,
+}
+
+impl Precompressed {
+    pub fn build(root: &Path) -> io::Result<Self> {
+        let mut precompressed = Self::default();
+        precompressed.add_directory(root)?;
+
+        Ok(precompressed)
+    }
+
+    fn add_directory(&mut self, directory: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            let metadata = fs::metadata(&path)?;
+            if metadata.is_dir() {
+                self.add_directory(&path)?;
+            } else if metadata.is_file() && is_compressible(files::content_type(&path)) {
+                let contents = fs::read(&path)?;
+                let compressed = gzip(&contents, Level::Best)?;
+                // Tiny files can come out bigger
+                if compressed.len() < contents.len() {
+                    debug!(path = %path.display(), "Precompressed");
+                    self.files.insert(path, (metadata.modified()?, compressed));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The gzipped copy of the file at `path`, if it hasn't changed since the copy was made
+    pub fn get(&self, path: &Path, modified: SystemTime) -> Option<&[u8]> {
+        self.files
+            .get(path)
+            .filter(|(copied, _)| *copied == modified)
+            .map(|(_, compressed)| compressed.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{TestRequest, TestResponse, test_directory};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn every_supported_encoding_round_trips() -> io::Result<()> {
+        let body = "hello hello hello hello".repeat(10);
+        for &encoding in SUPPORTED_ENCODINGS {
+            let encoded = encode(body.as_bytes(), encoding, Level::Fast)?;
+            let mut decoded = String::new();
+            match encoding {
+                "gzip" => GzDecoder::new(&encoded[..]).read_to_string(&mut decoded)?,
+                #[cfg(feature = "deflate")]
+                "deflate" => {
+                    flate2::read::ZlibDecoder::new(&encoded[..]).read_to_string(&mut decoded)?
+                }
+                #[cfg(feature = "brotli")]
+                "br" => {
+                    brotli::Decompressor::new(&encoded[..], 4096).read_to_string(&mut decoded)?
+                }
+                #[cfg(feature = "zstd")]
+                "zstd" => zstd::Decoder::new(&encoded[..])?.read_to_string(&mut decoded)?,
+                _ => unreachable!("{encoding} has no decoder"),
+            };
+            assert_eq!(decoded, body, "{encoding}");
+        }
+        assert!(encode(b"", "compress", Level::Fast).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn policy_limits_what_is_compressed() -> io::Result<()> {
+        let request = TestRequest::get("/")
+            .header("Accept-Encoding", "gzip")
+            .build();
+        let response = |content_type: &str, body: &str| {
+            Response::ok().content_type(content_type).body_str(body)
+        };
+        let policy = Policy {
+            min_size: 4,
+            types: vec!["text/*".to_string()],
+        };
+        let apply = |response| {
+            policy
+                .apply(&request, response, Level::Fast)
+                .map(TestResponse::new)
+        };
+
+        let compressed = apply(response("text/html", "hello"))?;
+        assert_eq!(compressed.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(compressed.header("Vary"), Some("Accept-Encoding"));
+        for uncompressed in [
+            apply(response("text/html", "hi"))?,
+            apply(response("application/json", "{\"a\": 1}"))?,
+            apply(
+                response("text/html", "hello").header(Header::ContentEncoding("br".to_string())),
+            )?,
+        ] {
+            assert_ne!(uncompressed.header("Content-Encoding"), Some("gzip"));
+            assert_eq!(uncompressed.header("Vary"), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn file_bodies_are_compressed_with_a_validator_of_their_own() -> io::Result<()> {
+        let directory = test_directory("file_bodies_are_compressed_with_a_validator_of_their_own");
+        let path = Path::new(&directory).join("site.css");
+        let css = "p { color: red }\n".repeat(20);
+        fs::write(&path, &css)?;
+        let response = || -> io::Result<Response> {
+            Response::ok()
+                .content_type("text/css")
+                .header(Header::ETag("\"1\"".to_string()))
+                .body_file(&path)
+        };
+        let get = |if_none_match: Option<&str>| -> io::Result<TestResponse> {
+            let mut request = TestRequest::get("/site.css").header("Accept-Encoding", "gzip");
+            if let Some(tags) = if_none_match {
+                request = request.header("If-None-Match", tags);
+            }
+            Policy::default()
+                .apply(&request.build(), response()?, Level::Fast)
+                .map(TestResponse::new)
+        };
+
+        let compressed = get(None)?;
+        fs::remove_file(&path)?;
+        compressed
+            .assert_status(StatusCode::Ok)
+            .assert_header("Content-Encoding", "gzip")
+            .assert_header("ETag", "\"1-gzip\"")
+            .assert_header("Content-Length", &compressed.body.len().to_string());
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed.body[..]).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, css);
+
+        fs::write(&path, &css)?;
+        let revalidated = get(Some("\"1-gzip\""));
+        // The identity representation's validator doesn't match the gzipped one's
+        let refetched = get(Some("\"1\""));
+        fs::remove_file(&path)?;
+        revalidated?
+            .assert_status(StatusCode::NotModified)
+            .assert_header("Vary", "Accept-Encoding");
+        refetched?.assert_status(StatusCode::Ok);
+        Ok(())
+    }
+
+    #[test]
+    fn only_text_like_types_are_compressed() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/octet-stream"));
+    }
+
+    #[test]
+    fn files_are_compressed_at_startup() -> io::Result<()> {
+        let root = PathBuf::from(test_directory("files_are_compressed_at_startup"));
+        fs::create_dir_all(root.join("css"))?;
+        let css = root.join("css/site.css");
+        fs::write(&css, "body { margin: 0 }\n".repeat(50))?;
+        fs::write(root.join("logo.png"), [0; 1000])?;
+        fs::write(root.join("tiny.txt"), "a")?;
+
+        let precompressed = Precompressed::build(&root);
+        let modified = fs::metadata(&css)?.modified()?;
+        fs::remove_dir_all(&root)?;
+        let precompressed = precompressed?;
+
+        let mut decoded = String::new();
+        GzDecoder::new(precompressed.get(&css, modified).unwrap()).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, "body { margin: 0 }\n".repeat(50));
+        assert!(precompressed.get(&css, SystemTime::UNIX_EPOCH).is_none());
+        assert_eq!(precompressed.files.len(), 1);
+        Ok(())
+    }
+}
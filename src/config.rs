@@ -0,0 +1,112 @@
+use crate::{
+    access_log::AccessLog,
+    admin::Admin,
+    audit::Strictness,
+    auth::Credentials,
+    cache_control::CacheControl,
+    compression::{Policy, Precompressed},
+    connection::{Deadlines, KeepAlive},
+    cors::Cors,
+    file_cache::FileCache,
+    files::Symlinks,
+    forwarded::TrustedProxies,
+    health::Health,
+    ip_filter::IpFilter,
+    limit::ConnectionLimit,
+    metrics::Metrics,
+    middleware::Stack,
+    mirror::Mirror,
+    redirects::Redirects,
+    reload::Swap,
+    request::Limits,
+    session::Sessions,
+    vhost::VirtualHosts,
+};
+use std::{any::Any, sync::Arc, time::Duration};
+
+/// Settings shared by every connection
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Where `/files` reads and writes
+    pub directory: Option<String>,
+    /// Serves a static site from here for paths no other route handles
+    pub static_root: Option<String>,
+    /// Other sites served, by the host they are requested for
+    pub vhosts: Swap<VirtualHosts>,
+    /// Whether `PUT /files` creates any directories missing from the path
+    pub create_parents: bool,
+    /// Whether `/files` are always sent as downloads, for browsers to save rather than show
+    pub force_download: bool,
+    /// Whether requests that would change what is in `directory` are refused
+    pub read_only: bool,
+    /// Which symlinks `directory` and the sites are served through
+    pub follow_symlinks: Symlinks,
+    /// What `Cache-Control` says on `/files` and the sites, by file
+    pub cache_control: CacheControl,
+    /// How big requests may be
+    pub limits: Limits,
+    /// Whether connections starting with the HTTP/2 preface are served as h2c
+    pub http2: bool,
+    /// How long connections are kept open for more requests, or `None` to close them after
+    /// every response
+    pub keep_alive: Option<KeepAlive>,
+    /// How long clients have to send the headers, then the body, of each request
+    pub deadlines: Deadlines,
+    /// How long each request has from starting to arrive to its response being sent, or `None`
+    /// for as long as it takes
+    pub request_timeout: Option<Duration>,
+    /// Headers to mask in the logs, in lowercase, on top of `redact::ALWAYS`
+    pub redacted_headers: Vec<String>,
+    /// Where the bytes of every connection are copied to, if anywhere
+    pub dump_dir: Option<String>,
+    /// Whether the masked headers are masked in the dumps too
+    pub dump_redact: bool,
+    /// How RFC 9110 violations are treated
+    pub strictness: Strictness,
+    /// Where every request is recorded once answered
+    pub access_log: AccessLog,
+    /// Who may use the routes that require authentication
+    pub credentials: Credentials,
+    /// Served at `/metrics`, and shared with the thread pool
+    pub metrics: Arc<Metrics>,
+    /// Whether `/readyz` says to send traffic here
+    pub health: Health,
+    /// What `/__admin` reports on and changes
+    pub admin: Admin,
+    /// Which clients may connect
+    pub ip_filter: Swap<IpFilter>,
+    /// How many connections may be open at once
+    pub connections: Arc<ConnectionLimit>,
+    /// Whether connections start with a PROXY protocol header saying who the client is
+    pub proxy_protocol: bool,
+    /// Which peers are believed about the client they forward requests for
+    pub trusted_proxies: TrustedProxies,
+    /// Whether a 500 from a handler failing says what went wrong, for debugging
+    pub debug_errors: bool,
+    /// Whether responses carry the request's ID in `X-Request-Id`
+    pub echo_request_id: bool,
+    /// When responses are compressed for clients that accept it
+    pub compression: Policy,
+    /// Gzipped copies of the files under `static_root`, made at startup
+    pub precompressed: Option<Precompressed>,
+    /// The most used `/files`, kept in memory
+    pub file_cache: Option<Arc<FileCache>>,
+    /// Files at least this big are mapped into memory to be sent, or `None` to never map them
+    pub mmap_min_size: Option<u64>,
+    /// Which origins may use routes without CORS settings of their own
+    pub cors: Option<Cors>,
+    /// Where a share of requests is copied to, as well as being served
+    pub mirror: Option<Mirror>,
+    /// Paths that have moved, also a layer of `middleware` so a reload reaches it
+    pub redirects: Swap<Redirects>,
+    /// Layers every request goes through on its way to the router (eg, `--redirect`s)
+    pub middleware: Stack,
+    /// What the application's handlers share, given to each in `RequestContext::state`
+    pub state: Option<Arc<dyn Any + Send + Sync>>,
+    /// What handlers remember about clients between requests
+    pub sessions: Sessions,
+    /// Whether responses carry a `Date`, which only tests wanting the same bytes every time leave out
+    pub date: bool,
+    /// What responses say in `Server`, if anything
+    pub server: Option<String>,
+}
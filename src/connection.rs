@@ -1,16 +1,39 @@
 use crate::{
-    http::{Header, SUPPORTED_ENCODINGS},
-    request::{Error as RequestError, Method, Request},
-    response::{Response, StatusCode},
+    access_log::Entry,
+    audit,
+    compression::Level,
+    config::Config,
+    dump::{Dump, Dumped},
+    error_pages, h2,
+    http::{self, Header},
+    limit::Permit,
+    profiling::{self, Phase},
+    proxy_protocol,
+    redact::Redacted,
+    request::{Error as RequestError, Method, ReadPolicy, Request},
+    response::{self, Duplex, Response, StatusCode, Upgrade},
+    router::{RequestContext, Router},
+    routes,
+    sendfile::{self, SendFile},
+    server, threadpool,
 };
 use anyhow::Result;
-use flate2::{write::GzEncoder, Compression};
 use std::{
-    fs,
-    io::{prelude::*, BufReader},
+    fs::File,
+    io::{self, BufReader, prelude::*},
     net::{Shutdown, TcpStream},
-    path::PathBuf,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant, SystemTime},
 };
+use tracing::{debug, error, info, info_span, trace, warn};
+
+static ROUTER: LazyLock<Router> = LazyLock::new(routes::router);
+static SITE_ROUTER: LazyLock<Router> = LazyLock::new(routes::site);
+
+/// Tells a client that sent `Expect: 100-continue` to go ahead with the body
+pub const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
 
 pub trait Shutdownable {
     fn shutdown(&self, how: Shutdown) -> std::io::Result<()>;
@@ -23,131 +46,671 @@ impl Shutdownable for TcpStream {
     }
 }
 
+/// Streams whose reads can be made to give up, so a client can be given longer to start its
+/// next request than to finish sending one
+pub trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+#[cfg_attr(coverage_nightly, coverage(off))]
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+/// How long, and for how many requests, a connection is kept open for the client to send more
+#[derive(Clone, Copy, Debug)]
+pub struct KeepAlive {
+    /// How long to wait for the next request before closing
+    pub timeout: Duration,
+    /// How many requests to answer before closing, so no client holds on to a worker forever
+    pub max_requests: usize,
+}
+
+/// How long a client has to send each part of a request, however it spaces out what it sends,
+/// as the receive timeout starts again with every read
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deadlines {
+    /// From the request starting to the end of its headers, or `None` for no deadline
+    pub head: Option<Duration>,
+    /// From the end of the headers to the end of the body, or `None` for no deadline
+    pub body: Option<Duration>,
+}
+
 #[derive(Debug)]
 pub struct Connection<T>
 where
     T: Read + Write + Shutdownable,
 {
-    stream: T,
-    directory: Option<String>,
+    /// Buffered for as long as the connection lasts, so what is read past the end of one request
+    /// is there for the next
+    stream: BufReader<Dumped<T>>,
+    config: Arc<Config>,
+    /// Who is on the other end, for the access log
+    peer: String,
+    read_policy: ReadPolicy,
+    /// Counts the connection towards `--max-connections` until it is dropped
+    permit: Option<Permit>,
+}
+
+/// Counts the bytes going out, so the access log can say how many were sent, giving up once the
+/// request's deadline (if any) has passed
+struct Counting<'a, W> {
+    inner: &'a mut W,
+    count: u64,
+    deadline: Option<Instant>,
+}
+
+impl<W: Write> Write for Counting<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_deadline()?;
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W> Counting<'_, W> {
+    /// How much of a file to send at a time, so the deadline is checked between them
+    const SEND_FILE_CHUNK: u64 = 1024 * 1024;
+
+    fn check_deadline(&self) -> io::Result<()> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: SendFile> Counting<'_, W> {
+    /// Sends `length` bytes of `file` straight to the socket, if the stream is a plain one
+    fn send_file(&mut self, file: &File, length: u64) -> Option<io::Result<()>> {
+        let socket = self.inner.socket()?;
+        let mut remaining = length;
+        while remaining > 0 {
+            if let Err(err) = self.check_deadline() {
+                return Some(Err(err));
+            }
+            match sendfile::send(file, socket, remaining.min(Self::SEND_FILE_CHUNK)) {
+                // The Content-Length has already been promised, so the client can't be told
+                Ok(0) => return Some(Err(io::ErrorKind::UnexpectedEof.into())),
+                Ok(sent) => {
+                    self.count += sent;
+                    remaining -= sent;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        Some(Ok(()))
+    }
 }
 
 impl<T> Connection<T>
 where
-    T: Read + Write + Shutdownable + std::fmt::Debug,
+    T: Read + Write + Shutdownable + ReadTimeout + SendFile + std::fmt::Debug,
 {
-    pub fn new(stream: T, directory: Option<String>) -> Self {
-        println!("Accepting new connection: {stream:?}");
-        Self { stream, directory }
+    pub fn new(stream: T, config: Arc<Config>) -> Self {
+        debug!("Accepting new connection: {stream:?}");
+        config.metrics.connection_opened();
+        Self {
+            stream: BufReader::new(Dumped::new(stream, dump(&config))),
+            config,
+            peer: "-".to_string(),
+            read_policy: ReadPolicy::BLOCKING,
+            permit: None,
+        }
+    }
+
+    /// Counts the connection as open until it is dropped
+    #[must_use]
+    pub fn permit(mut self, permit: Permit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
+    /// The client's address, as it should appear in the access log
+    #[must_use]
+    pub fn peer(mut self, peer: String) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    /// How the stream's reads behave, for decoding requests from it
+    #[must_use]
+    pub const fn read_policy(mut self, read_policy: ReadPolicy) -> Self {
+        self.read_policy = read_policy;
+        self
     }
 
     pub fn process(&mut self) -> Result<()> {
-        let buf_reader = BufReader::new(&mut self.stream);
+        if self.config.proxy_protocol {
+            match proxy_protocol::read_header(&mut self.stream) {
+                Ok(Some(client)) => self.peer = client.ip().to_string(),
+                Ok(None) => {}
+                Err(err) => {
+                    debug!(peer = %self.peer, "Closing connection: {err}");
+                    return Ok(());
+                }
+            }
+            // Everyone else was checked when the connection was accepted
+            if !self.config.ip_filter.load().permits(&self.peer) {
+                debug!(peer = %self.peer, "Refusing connection");
+                self.stream
+                    .get_mut()
+                    .write_all(&Response::new(StatusCode::Forbidden).encode())?;
+                return Ok(());
+            }
+        }
+
+        let _span = info_span!("connection", peer = %self.peer).entered();
+        let mut served = 0;
+        while self.exchange(served)? {
+            served += 1;
+        }
 
-        let request = match Request::decode(buf_reader) {
+        Ok(())
+    }
+
+    /// The stream, for a backend that waits on it itself
+    pub fn stream(&self) -> &T {
+        self.stream.get_ref().get_ref()
+    }
+
+    pub fn stream_mut(&mut self) -> &mut T {
+        self.stream.get_mut().get_mut()
+    }
+
+    /// What has been read from the stream but not yet decoded (eg, a pipelined request)
+    pub fn buffered(&self) -> &[u8] {
+        self.stream.buffer()
+    }
+
+    /// Answers the next request, given how many have already been answered on this connection,
+    /// saying whether to wait for another
+    ///
+    /// Backends that wait for requests themselves call this once each has arrived.
+    pub fn exchange(&mut self, served: usize) -> Result<bool> {
+        if served > 0
+            && let Some(keep_alive) = self.config.keep_alive
+            && !wait_for_request(&mut self.stream, keep_alive.timeout)?
+        {
+            return Ok(false);
+        }
+        let (started, received) = (Instant::now(), SystemTime::now());
+        #[cfg(feature = "alloc-tracking")]
+        let allocations = crate::alloc_tracking::Snapshot::now();
+        profiling::start_request();
+
+        if served == 0 && self.config.http2 && self.stream.fill_buf()?.starts_with(h2::PREFACE) {
+            let buffered = self.stream.buffer().to_vec();
+            return serve_h2(&self.config, self.stream.get_mut(), buffered, &self.peer)
+                .map(|()| false);
+        }
+
+        let (deadlines, overall) = (self.config.deadlines, self.deadline(started));
+        let request = match profiling::time(Phase::Parse, || {
+            let mut request = Request::decode_head(
+                Deadline::new(&mut self.stream, deadlines.head, overall),
+                self.read_policy,
+                self.config.limits,
+            )?;
+            // Otherwise the client waits a while before sending the body anyway
+            if request.expects_continue()? {
+                self.stream.get_mut().write_all(CONTINUE)?;
+            }
+            request.read_body(
+                Deadline::new(&mut self.stream, deadlines.body, overall),
+                self.read_policy,
+                self.config.limits,
+            )?;
+
+            anyhow::Ok(request)
+        }) {
             Ok(req) => req,
             Err(e) => {
-                let status_code =
-                    e.downcast_ref::<RequestError>()
-                        .map_or(StatusCode::BadRequest, |req_err| match req_err {
-                            RequestError::RequestTimeout => StatusCode::RequestTimeout,
-                            RequestError::UnsupportedHTTPVersion => {
-                                StatusCode::HttpVersionNotSupported
-                            }
-                            RequestError::UnsupportedMethod => StatusCode::NotImplemented,
-                            _ => StatusCode::BadRequest,
-                        });
-
-                let mut response = Response::new(status_code);
-                response.add_header(Header::ContentType("text/plain".to_string()));
-                response.body(format!("Error: {e}").into_bytes());
-                self.stream.write_all(&response.encode())?;
-                return Ok(());
+                let peer = self.peer.clone();
+                self.send(malformed(&e), None, &peer, started, received)?;
+                return Ok(false);
             }
         };
-        println!("Received: {request:?}");
-
-        let response = match (request.method, request.target.as_str()) {
-            (Method::Get, "/") => Response::new(StatusCode::Ok),
-            (Method::Get, target) if target.starts_with("/echo/") => {
-                let mut response = Response::new(StatusCode::Ok);
-                response.add_header(Header::ContentType("text/plain".to_string()));
-
-                let gzip = request
-                    .headers
-                    .get("accept-encoding")
-                    .is_some_and(|encoding|
-                    // Presumably a real server would need to think about casing (or follow
-                    // the RFC assuming it was mentioned in there)
-                    encoding
-                        .split(", ")
-                        .any(|x| SUPPORTED_ENCODINGS.contains(&x)));
-
-                // Safety: Have already checked target starts_with
-                let body = target.strip_prefix("/echo/").unwrap();
-                if gzip {
-                    response.add_header(Header::ContentEncoding("gzip".to_string()));
-
-                    let mut encoder = GzEncoder::new(vec![], Compression::default());
-                    encoder.write_all(body.as_bytes())?;
-                    let compressed = encoder.finish()?;
-                    response.body(compressed);
-                } else {
-                    response.body(body.into());
-                }
-
-                response
+        let client = self.config.trusted_proxies.client(&self.peer, &request);
+        let Some(Answer {
+            response,
+            upgrade,
+            keep_alive,
+        }) = answer(&self.config, &request, &self.peer, &client, served, overall)?
+        else {
+            return Ok(false);
+        };
+        #[cfg(feature = "alloc-tracking")]
+        let response = crate::alloc_tracking::debug_headers(&request, response, &allocations);
+        #[cfg(feature = "profiling")]
+        let response = profiling::debug_headers(&request, response);
+        debug!("Sending: {response:?}");
+        match self.send(response, Some(&request), &client, started, received) {
+            Err(error) if response::is_disconnect(&error) => {
+                info!("Client disconnected mid-response: {error}");
+                self.config.metrics.connection_aborted();
+                return Ok(false);
             }
-            (Method::Get, "/user-agent") => request.headers.get("user-agent").map_or_else(
-                || Response::new(StatusCode::BadRequest),
-                |user_agent| {
-                    let mut response = Response::new(StatusCode::Ok);
-                    response.add_header(Header::ContentType("text/plain".to_string()));
-                    response.body(user_agent.to_owned().into());
-
-                    response
-                },
-            ),
-            (Method::Get, target) if target.starts_with("/files/") => {
-                let mut path_buf = PathBuf::new();
-                if let Some(path) = &self.directory {
-                    path_buf.push(path);
-                }
-
-                // Safety: Have already checked target starts_with
-                let filename = target.strip_prefix("/files/").unwrap();
-                path_buf.push(filename);
-                fs::read(path_buf).map_or_else(
-                    |_| Response::new(StatusCode::NotFound),
-                    |file_contents| {
-                        let mut response = Response::new(StatusCode::Ok);
-                        response.add_header(Header::ContentType(
-                            "application/octet-stream".to_string(),
-                        ));
-                        response.body(file_contents);
-
-                        response
-                    },
-                )
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                warn!(id = %request.id, "Response took longer than --request-timeout to send");
+                self.config.metrics.connection_aborted();
+                return Ok(false);
             }
-            (Method::Post, target) if target.starts_with("/files") => {
-                let mut path_buf = PathBuf::new();
-                if let Some(path) = &self.directory {
-                    path_buf.push(path);
-                }
+            result => result?,
+        }
 
-                // Safety: Have already checked target starts_with
-                let filename = target.strip_prefix("/files/").unwrap();
-                path_buf.push(filename);
-                let _ = fs::write(path_buf, request.body.unwrap());
-                Response::new(StatusCode::Created)
-            }
-            _ => Response::new(StatusCode::NotFound),
+        // The connection now belongs to the protocol switched to, until it is done
+        if let Some(upgrade) = upgrade {
+            switch(upgrade, self.stream.get_mut())?;
+        }
+
+        Ok(keep_alive)
+    }
+
+    /// When the request that started at `started` has to be answered by
+    fn deadline(&self, started: Instant) -> Option<Instant> {
+        self.config
+            .request_timeout
+            .map(|request_timeout| started + request_timeout)
+    }
+
+    /// Writes `response` to the client, then records it in the access log and metrics, as
+    /// answered to `client`
+    fn send(
+        &mut self,
+        response: Response,
+        request: Option<&Request>,
+        client: &str,
+        started: Instant,
+        received: SystemTime,
+    ) -> io::Result<()> {
+        let mut response = response;
+        stamp(&self.config, &mut response);
+        let status = response.status_code().code();
+        let head_len = response.head_len() as u64;
+        // Telling the client what was wrong with its request is worth being late for
+        let deadline = request.and(self.deadline(started));
+        let mut counting = Counting {
+            inner: self.stream.get_mut(),
+            count: 0,
+            deadline,
         };
-        println!("Sending: {response:?}");
-        self.stream.write_all(&response.encode())?;
+        let result = profiling::time(Phase::Write, || {
+            response.write_with(&mut counting, Counting::send_file)
+        });
 
-        Ok(())
+        let bytes = counting.count.saturating_sub(head_len);
+        record(
+            &self.config,
+            request,
+            client,
+            status,
+            bytes,
+            started,
+            received,
+        );
+
+        result
+    }
+}
+
+/// What to send back for a request, and what becomes of the connection after
+pub struct Answer {
+    /// Framed for the connection being kept open, or saying it won't be
+    pub response: Response,
+    /// The protocol the connection switches to once the response has been sent
+    pub upgrade: Option<Upgrade>,
+    /// Whether the client may send another request after this one
+    pub keep_alive: bool,
+}
+
+/// Answers the `served`th request on a connection from `peer`, for `client`, unless it took
+/// longer than the `overall` deadline (when the connection has to be closed instead)
+pub fn answer(
+    config: &Config,
+    request: &Request,
+    peer: &str,
+    client: &str,
+    served: usize,
+    overall: Option<Instant>,
+) -> Result<Option<Answer>> {
+    let mut response = respond(config, request, peer, client)?;
+    if overall.is_some_and(|overall| Instant::now() >= overall) {
+        warn!(id = %request.id, "Request took longer than --request-timeout, closing connection");
+        config.metrics.connection_aborted();
+        return Ok(None);
+    }
+    let upgrade = response.take_upgrade();
+    let keep_alive = upgrade.is_none()
+        && !request.has_token("connection", "close")
+        && !config.health.is_stopping()
+        && config
+            .keep_alive
+            .is_some_and(|keep_alive| served + 1 < keep_alive.max_requests);
+    if keep_alive {
+        if !matches!(request.method, Method::Head) {
+            response.frame_empty_body();
+        }
+    } else if config.keep_alive.is_some() && upgrade.is_none() {
+        response.add_header(close());
+    }
+
+    Ok(Some(Answer {
+        response,
+        upgrade,
+        keep_alive,
+    }))
+}
+
+/// Records a response with `status`, and `bytes` of body sent, in the access log and metrics
+pub fn record(
+    config: &Config,
+    request: Option<&Request>,
+    client: &str,
+    status: u16,
+    bytes: u64,
+    started: Instant,
+    received: SystemTime,
+) {
+    let duration = started.elapsed();
+    config.access_log.record(&Entry {
+        client,
+        request,
+        status,
+        bytes,
+        duration,
+        time: received,
+    });
+    config.metrics.record(
+        request.map_or("-", |request| request.method.as_str()),
+        status,
+        duration,
+        bytes,
+    );
+}
+
+/// Where the connection is dumped to with `--dump-dir`, if anywhere
+pub fn dump(config: &Config) -> Option<Dump> {
+    config.dump_dir.as_deref().and_then(|directory| {
+        Dump::create(
+            Path::new(directory),
+            config.dump_redact,
+            &config.redacted_headers,
+        )
+        .inspect_err(|err| warn!(directory, "Unable to dump the connection: {err}"))
+        .ok()
+    })
+}
+
+/// Serves HTTP/2 on a connection from `peer`, which has already sent the `buffered` bytes
+pub fn serve_h2<T: Read + Write>(
+    config: &Config,
+    stream: &mut T,
+    buffered: Vec<u8>,
+    peer: &str,
+) -> Result<()> {
+    h2::serve(stream, buffered, config.limits, |request| {
+        let client = config.trusted_proxies.client(peer, request);
+        respond(config, request, peer, &client).map(|mut response| {
+            stamp(config, &mut response);
+            response
+        })
+    })
+}
+
+/// Hands the connection to the protocol a response switched it to, until it is done
+pub fn switch(upgrade: Upgrade, stream: &mut dyn Duplex) -> io::Result<()> {
+    match upgrade(stream) {
+        Err(error)
+            if response::is_disconnect(&error) || error.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            info!("Client disconnected from upgraded connection: {error}");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Tells the client what was wrong with a request that couldn't be decoded
+pub fn malformed(error: &anyhow::Error) -> Response {
+    // Anything else (eg, a target that isn't UTF-8) is malformed too
+    let status_code = error
+        .downcast_ref::<RequestError>()
+        .map_or(StatusCode::BadRequest, RequestError::status_code);
+
+    let mut response = Response::new(status_code);
+    response.add_header(Header::ContentType("text/plain".to_string()));
+    response.body(format!("Error: {error}").into_bytes());
+    // Where the next request would start can't be told, so this is the last
+    response.add_header(close());
+
+    response
+}
+
+/// Routes a request from `client`, over a connection from `peer`, whichever version of HTTP it
+/// arrived over
+pub fn respond(config: &Config, request: &Request, peer: &str, client: &str) -> Result<Response> {
+    let _span = info_span!("request", id = %request.id).entered();
+    debug!(
+        "Received: {:?}",
+        Redacted::new(request, &config.redacted_headers)
+    );
+
+    // The connection was only let in as a trusted proxy, so who it's for has to be checked here
+    if !config.ip_filter.load().permits(client) {
+        debug!(client, "Refusing forwarded request");
+        return Ok(Response::new(StatusCode::Forbidden));
+    }
+    if let Some(rejected) = audit::request(request, config.strictness) {
+        return Ok(rejected);
+    }
+    // RFC 9112 section 3.2
+    let Some(host) = request.headers.get("host") else {
+        return Ok(Response::bad_request()
+            .header(Header::ContentType("text/plain".to_string()))
+            .body_str("Missing Host header"));
+    };
+    let vhosts = config.vhosts.load();
+    let site = vhosts.root(host);
+    if let Some(mirror) = &config.mirror {
+        mirror.offer(request);
+    }
+    request.set_session(config.sessions.load(&request.cookies()));
+
+    let context = RequestContext {
+        directory: config.directory.as_deref(),
+        static_root: site.or(config.static_root.as_deref()),
+        create_parents: config.create_parents,
+        force_download: config.force_download,
+        read_only: config.read_only,
+        follow_symlinks: config.follow_symlinks,
+        cache_control: Some(&config.cache_control),
+        client: Some(client),
+        peer: Some(peer),
+        credentials: Some(&config.credentials),
+        metrics: Some(&config.metrics),
+        health: Some(&config.health),
+        admin: Some(&config.admin),
+        cors: config.cors.as_ref(),
+        content_type: None,
+        compression: Level::default(),
+        compression_policy: Some(&config.compression),
+        precompressed: config.precompressed.as_ref(),
+        file_cache: config.file_cache.as_deref(),
+        mmap_min_size: config.mmap_min_size,
+        state: config.state.as_deref(),
+    };
+    let router = if site.is_some() {
+        &SITE_ROUTER
+    } else {
+        &ROUTER
+    };
+    let mut response = profiling::time(Phase::Route, || route(config, request, &context, router));
+    if let Some(directory) = &config.directory {
+        response = error_pages::fill(directory, request, response);
+    }
+    if config.echo_request_id {
+        response.add_header(Header::Custom(
+            "X-Request-Id".to_string(),
+            request.id.clone(),
+        ));
+    }
+    if let Some(session) = request.session() {
+        config.sessions.save(session, &mut response);
+    }
+
+    Ok(audit::response(response, config.strictness))
+}
+
+/// Runs `request` through the middleware to `router`, answering with 500 when a handler fails or
+/// panics, so the client still gets a response and the connection is still in step for the next
+fn route(
+    config: &Config,
+    request: &Request,
+    context: &RequestContext,
+    router: &Router,
+) -> Response {
+    let routed = panic::catch_unwind(AssertUnwindSafe(|| {
+        config.middleware.run(request, context, router)
+    }));
+    let reason = match routed {
+        Ok(Ok(response)) => return response,
+        Ok(Err(err)) => {
+            error!(path = %request.path, "Error handling request: {err:#}");
+            format!("{err:#}")
+        }
+        Err(payload) => {
+            let message = threadpool::panic_message(&*payload);
+            error!(path = %request.path, "Handler panicked: {message}");
+            format!("panicked: {message}")
+        }
+    };
+
+    let response = Response::new(StatusCode::InternalServerError);
+    if config.debug_errors {
+        response.content_type("text/plain").body_str(&reason)
+    } else {
+        response
+    }
+}
+
+/// Waits up to `timeout` for the client to start another request, then gives it the usual time
+/// to send the rest. False if it closed the connection or stayed idle.
+fn wait_for_request<T: Read + ReadTimeout>(
+    reader: &mut BufReader<T>,
+    timeout: Duration,
+) -> io::Result<bool> {
+    reader.get_ref().set_read_timeout(timeout)?;
+    let waited = reader.fill_buf().map(|buffered| !buffered.is_empty());
+    reader
+        .get_ref()
+        .set_read_timeout(Duration::from_secs(server::RECEIVE_TIMEOUT))?;
+
+    match waited {
+        Err(error)
+            if matches!(
+                error.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ) || response::is_disconnect(&error) =>
+        {
+            debug!("Closing idle connection");
+            Ok(false)
+        }
+        waited => waited,
+    }
+}
+
+/// Reads from the stream until a deadline, shortening the read timeout to whatever is left of it
+struct Deadline<'a, T: Read + ReadTimeout> {
+    reader: &'a mut BufReader<T>,
+    deadline: Option<Instant>,
+}
+
+impl<'a, T: Read + ReadTimeout> Deadline<'a, T> {
+    /// Reads for up to `within`, unless `overall` comes first
+    fn new(
+        reader: &'a mut BufReader<T>,
+        within: Option<Duration>,
+        overall: Option<Instant>,
+    ) -> Self {
+        let deadline = within.map(|within| Instant::now() + within);
+
+        Self {
+            reader,
+            deadline: deadline.into_iter().chain(overall).min(),
+        }
+    }
+}
+
+impl<T: Read + ReadTimeout> Read for Deadline<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.fill_buf()?.read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<T: Read + ReadTimeout> BufRead for Deadline<'_, T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if let Some(deadline) = self.deadline
+            && self.reader.buffer().is_empty()
+        {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let timeout = Duration::from_secs(server::RECEIVE_TIMEOUT);
+            self.reader.get_ref().set_read_timeout(left.min(timeout))?;
+        }
+
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+    }
+}
+
+impl<T: Read + ReadTimeout> Drop for Deadline<'_, T> {
+    fn drop(&mut self) {
+        // Whatever the deadline shortened it to
+        if self.deadline.is_some() {
+            let _ = self
+                .reader
+                .get_ref()
+                .set_read_timeout(Duration::from_secs(server::RECEIVE_TIMEOUT));
+        }
+    }
+}
+
+pub fn close() -> Header {
+    Header::Custom("Connection".to_string(), "close".to_string())
+}
+
+/// Adds the headers every response carries, whatever made it
+pub fn stamp(config: &Config, response: &mut Response) {
+    if config.date {
+        response.add_header(Header::Custom(
+            "Date".to_string(),
+            http::date(SystemTime::now()),
+        ));
+    }
+    if let Some(server) = &config.server
+        && !response.headers().contains("Server")
+    {
+        response.add_header(Header::Custom("Server".to_string(), server.clone()));
     }
 }
 
@@ -157,9 +720,10 @@ where
     T: Read + Write + Shutdownable,
 {
     fn drop(&mut self) {
-        println!("Shutting down connection");
-        if let Err(error) = self.stream.shutdown(Shutdown::Both) {
-            eprintln!("Error shutting down connection: {error}");
+        trace!("Shutting down connection");
+        self.config.metrics.connection_closed();
+        if let Err(error) = self.stream.get_ref().shutdown(Shutdown::Both) {
+            warn!("Error shutting down connection: {error}");
         }
     }
 }
@@ -167,7 +731,26 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{
+        access_log::{AccessLog, LogFormat},
+        audit::Strictness,
+        cache_control::CacheControl,
+        duplex, files,
+        forwarded::TrustedProxies,
+        http,
+        ip_filter::IpFilter,
+        middleware::{Next, ResponseHeader, Stack},
+        redirects::Redirects,
+        reload::Swap,
+        testing::test_directory,
+        vhost::VirtualHosts,
+    };
     use mockall::*;
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        thread,
+    };
 
     mock! {
         #[derive(Debug)]
@@ -184,7 +767,51 @@ mod test {
         }
     }
 
+    impl ReadTimeout for MockConnection {
+        fn set_read_timeout(&self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SendFile for MockConnection {}
+
+    /// Sends `input` over an in-memory connection, returning everything the server writes back
+    fn exchange(input: &[u8], config: Config) -> Vec<u8> {
+        exchange_shared(input, &Arc::new(config))
+    }
+
+    /// Like `exchange`, but with state kept between connections (eg, sessions)
+    fn exchange_shared(input: &[u8], config: &Arc<Config>) -> Vec<u8> {
+        let (mut client, server) = duplex::pair();
+        client.write_all(input).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        Connection::new(server, Arc::clone(config))
+            .process()
+            .unwrap();
+        client.read_all().unwrap()
+    }
+
     fn mock(input: &'static [u8], output: &'static [u8]) -> Result<()> {
+        mock_with_directory(input, output, None)
+    }
+
+    fn mock_with_directory(
+        input: &'static [u8],
+        output: &'static [u8],
+        directory: Option<String>,
+    ) -> Result<()> {
+        mock_with_config(
+            input,
+            output,
+            Config {
+                directory,
+                ..Config::default()
+            },
+        )
+    }
+
+    fn mock_with_config(input: &'static [u8], output: &'static [u8], config: Config) -> Result<()> {
         let mut mock = MockConnection::new();
         mock.expect_read().once().returning(|buf| {
             buf[..input.len()].copy_from_slice(input);
@@ -196,18 +823,21 @@ mod test {
             .returning(|buf| Ok(buf.len()));
         mock.expect_shutdown().once().returning(|_| Ok(()));
 
-        Connection::new(mock, None).process()
+        Connection::new(mock, Arc::new(config)).process()
     }
 
     #[test]
     fn get_known_request_target_returns_200() -> Result<()> {
-        mock(b"GET / HTTP/1.1\r\n\r\n", b"HTTP/1.1 200 OK\r\n\r\n")
+        mock(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\n\r\n",
+        )
     }
 
     #[test]
     fn getting_invalid_request_target_returns_404() -> Result<()> {
         mock(
-            b"GET /not_found HTTP/1.1\r\n\r\n",
+            b"GET /not_found HTTP/1.1\r\nHost: localhost\r\n\r\n",
             b"HTTP/1.1 404 Not Found\r\n\r\n",
         )
     }
@@ -215,99 +845,1528 @@ mod test {
     #[test]
     fn get_echo_returns_200() -> Result<()> {
         mock(
-            b"GET /echo/rust HTTP/1.1\r\n\r\n",
-            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nrust",
+            b"GET /echo/rust HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Type: text/plain\r\nContent-Length: 4\r\nVary: Accept-Encoding\r\n\r\nrust",
         )
     }
 
     #[test]
-    fn method_not_supported_is_unimplemented() {
-        let input = b"BOOM / HTTP/1.1\r\n\r\n";
-
-        let mut mock = MockConnection::new();
-        mock.expect_read().once().returning(|buf| {
-            buf[..input.len()].copy_from_slice(input);
-            Ok(input.len())
-        });
-        mock.expect_shutdown().once().returning(|_| Ok(()));
-
-        let result = Connection::new(mock, None).process();
-        assert!(result.is_err());
+    fn get_echo_decodes_path_and_ignores_query() -> Result<()> {
+        mock(
+            b"GET /echo/hello%20world?x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Type: text/plain\r\nContent-Length: 11\r\nVary: Accept-Encoding\r\n\r\nhello world",
+        )
     }
 
     #[test]
-    fn get_user_agent_returns_200() -> Result<()> {
+    fn get_echo_repeat() -> Result<()> {
+        mock(
+            b"GET /echo/hi?repeat=3 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Type: text/plain\r\nContent-Length: 6\r\nVary: Accept-Encoding\r\n\r\nhihihi",
+        )?;
         mock(
-            b"GET /user-agent HTTP/1.1\r\nUser-Agent: rust\r\n\r\n",
-            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nrust",
+            b"GET /echo/hi?repeat=lots HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 400 Bad Request\r\n\r\n",
         )
     }
 
     #[test]
-    fn get_user_agent_returns_400() -> Result<()> {
+    fn invalid_percent_encoding_is_400() -> Result<()> {
         mock(
-            b"GET /user-agent HTTP/1.1\r\n\r\n",
-            b"HTTP/1.1 400 Bad Request\r\n\r\n",
+            b"GET /echo/%zz HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: 53\r\nConnection: close\r\n\r\nError: Invalid percent-encoding in the request target",
         )
     }
 
     #[test]
-    fn get_missing_file_404() -> Result<()> {
-        // A lot of effort to cover the case where we need to fill two buffers!
-        // Argubly should be in own test, but given the experimental nature
-        // of this project ;-)
-        let input_1 = b"GET /files/random12345 HTTP/1.1\r";
-        let input_2 = b"\n\r\n";
-        let output: &[u8] = b"HTTP/1.1 404 Not Found\r\n\r\n";
+    fn unsupported_versions_are_505() -> Result<()> {
+        mock(
+            b"GET / HTTP/3.0\r\n\r\n",
+            b"HTTP/1.1 505 HTTP Version Not Supported\r\nContent-Type: text/plain\r\nContent-Length: 31\r\nConnection: close\r\n\r\nError: Unsupported HTTP version",
+        )?;
+        assert!(
+            exchange(b"GET /\r\n", Config::default())
+                .starts_with(b"HTTP/1.1 505 HTTP Version Not Supported\r\n")
+        );
+        Ok(())
+    }
 
-        let mut mock = MockConnection::new();
-        mock.expect_read().once().returning(|buf| {
-            buf[..input_1.len()].copy_from_slice(input_1);
-            Ok(input_1.len())
+    #[test]
+    fn malformed_requests_are_answered() {
+        for (input, status) in [
+            (&b"\r\n\r\n"[..], "400 Bad Request"),
+            (
+                b"G@T / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                "400 Bad Request",
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nNo colon\r\n\r\n",
+                "400 Bad Request",
+            ),
+            (
+                b"PUT /echo/x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\n\r\nshort",
+                "400 Bad Request",
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1048576\r\n\r\n",
+                "413 Content Too Large",
+            ),
+            (
+                b"BOOM / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                "501 Not Implemented",
+            ),
+        ] {
+            let response = exchange(input, Config::default());
+            let response = String::from_utf8_lossy(&response);
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {status}\r\n")),
+                "{response}"
+            );
+        }
+    }
+
+    #[test]
+    fn expecting_continue() {
+        let (mut client, server) = duplex::pair();
+        let connection =
+            thread::spawn(move || Connection::new(server, Arc::default()).process().unwrap());
+        client
+            .write_all(
+                b"POST /echo/x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nExpect: 100-Continue\r\n\r\n",
+            )
+            .unwrap();
+        let mut buf = [0; CONTINUE.len()];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, CONTINUE);
+
+        client.write_all(b"hi").unwrap();
+        connection.join().unwrap();
+        let response = client.read_all().unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        let response = exchange(
+            b"PUT /files/x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nExpect: something\r\n\r\nhi",
+            Config::default(),
+        );
+        assert!(response.starts_with(b"HTTP/1.1 417 Expectation Failed\r\n"));
+    }
+
+    #[test]
+    fn trailers_are_echoed_after_the_body() {
+        let response = exchange(
+            b"POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nTrailer: Checksum\r\n\r\n\
+            2\r\nhi\r\n0\r\nChecksum: abc\r\n\r\n",
+            Config::default(),
+        );
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.contains("Trailer: checksum\r\n"), "{response}");
+        assert!(
+            response.ends_with("\r\n\r\n2\r\nhi\r\n0\r\nchecksum: abc\r\n\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn slow_requests_are_408() {
+        let (mut client, server) = duplex::pair();
+        server.set_read_timeout(Duration::from_millis(10)).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: ").unwrap();
+
+        Connection::new(server, Arc::default()).process().unwrap();
+        let response = String::from_utf8(client.read_all().unwrap()).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout\r\nContent-Type: text/plain\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("\r\nConnection: close\r\n"), "{response}");
+    }
+
+    #[test]
+    fn dripped_headers_are_408_at_the_deadline() {
+        let (mut client, server) = duplex::pair();
+        let config = Config {
+            deadlines: Deadlines {
+                head: Some(Duration::from_millis(50)),
+                body: None,
+            },
+            ..Config::default()
+        };
+        let serving = thread::spawn(move || {
+            Connection::new(server, Arc::new(config)).process().unwrap();
         });
+
+        // Each byte well within the receive timeout, but never finishing the headers
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        for _ in 0..10 {
+            thread::sleep(Duration::from_millis(10));
+            if client.write_all(b"X").is_err() || serving.is_finished() {
+                break;
+            }
+        }
+        serving.join().unwrap();
+        let response = String::from_utf8(client.read_all().unwrap()).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn responses_past_the_request_timeout_are_cut_off() {
+        let config = Config {
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+
+        let response = String::from_utf8(exchange(
+            b"GET /progress/3 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            config,
+        ))
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("step 1 of 3"), "{response}");
+        assert!(!response.contains("step 3 of 3"), "{response}");
+    }
+
+    #[test]
+    fn method_not_supported_is_unimplemented() -> Result<()> {
+        let input = b"BOOM / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        let mut mock = MockConnection::new();
         mock.expect_read().once().returning(|buf| {
-            buf[..input_2.len()].copy_from_slice(input_2);
-            Ok(input_2.len())
+            buf[..input.len()].copy_from_slice(input);
+            Ok(input.len())
         });
         mock.expect_write()
-            .with(predicate::eq(output))
+            .withf(|buf| buf.starts_with(b"HTTP/1.1 501 Not Implemented\r\n"))
             .once()
             .returning(|buf| Ok(buf.len()));
         mock.expect_shutdown().once().returning(|_| Ok(()));
 
-        Connection::new(mock, None).process()
+        Connection::new(mock, Arc::default()).process()
     }
 
     #[test]
-    fn get_valid_file_200() -> Result<()> {
+    fn wrong_method_on_known_path_returns_405() -> Result<()> {
         mock(
-            b"GET /files/.gitattributes HTTP/1.1\r\n\r\n",
-            b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 12\r\n\r\n* text=auto\n",
+            b"POST /echo/foo HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD, OPTIONS\r\n\r\n",
         )
     }
 
     #[test]
-    fn post_file_201() -> Result<()> {
+    fn options_returns_204_with_allow() -> Result<()> {
         mock(
-            b"POST /files/junk HTTP/1.1\r\nContent-Type: application/octet-stream\r\nContent-Length: 12\r\n\r\nRust",
-            b"HTTP/1.1 201 Created\r\n\r\n",
+            b"OPTIONS /files/abc HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 204 No Content\r\nAllow: GET, HEAD, POST, PUT, DELETE, OPTIONS\r\n\r\n",
         )
     }
 
-    #[ignore] // Needs updating to reflect the gzip'd body
     #[test]
-    fn echo_with_gzip() -> Result<()> {
-        mock(
-            b"GET /echo/rust HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
-            b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nrust",
-        )
-    }
+    fn get_progress_streams_parts() {
+        let input = b"GET /progress/1 HTTP/1.1\r\nHost: localhost\r\n\r\n";
 
-    #[test]
-    fn echo_with_unsupported_encoding() -> Result<()> {
-        mock(
-            b"GET /echo/rust HTTP/1.1\r\nAccept-Encoding: br\r\n\r\n",
-            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nrust",
+        let mut mock = MockConnection::new();
+        mock.expect_read().once().returning(|buf| {
+            buf[..input.len()].copy_from_slice(input);
+            Ok(input.len())
+        });
+        let written = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = std::sync::Arc::clone(&written);
+        mock.expect_write().returning(move |buf| {
+            sink.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        });
+        mock.expect_flush().returning(|| Ok(()));
+        mock.expect_shutdown().once().returning(|_| Ok(()));
+
+        assert!(Connection::new(mock, Arc::default()).process().is_ok());
+
+        let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.contains("multipart/x-mixed-replace"));
+        assert!(written.contains("step 1 of 1"));
+        assert!(written.ends_with("0\r\n\r\n"));
+    }
+
+    #[test]
+    fn get_events_resumes_after_last_event_id() {
+        let written = exchange(
+            b"GET /events?count=1 HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 41\r\n\r\n",
+            Config::default(),
+        );
+
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("Content-Type: text/event-stream\r\n"));
+        assert!(written.contains("event: tick\nid: 42\ndata: 42\n\n"));
+        assert!(written.ends_with("0\r\n\r\n"));
+    }
+
+    #[test]
+    fn client_disconnecting_mid_stream_is_not_an_error() {
+        let input = b"GET /progress/3 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        let mut mock = MockConnection::new();
+        mock.expect_read().once().returning(|buf| {
+            buf[..input.len()].copy_from_slice(input);
+            Ok(input.len())
+        });
+        // The response head makes it out, but the client is gone by the first part
+        mock.expect_write().once().returning(|buf| Ok(buf.len()));
+        mock.expect_write()
+            .once()
+            .returning(|_| Err(std::io::ErrorKind::BrokenPipe.into()));
+        mock.expect_flush().returning(|| Ok(()));
+        mock.expect_shutdown().once().returning(|_| Ok(()));
+
+        assert!(Connection::new(mock, Arc::default()).process().is_ok());
+    }
+
+    #[test]
+    fn progress_steps_are_capped() {
+        let input = b"GET /progress/4294967295 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let first_part = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let mut mock = MockConnection::new();
+        mock.expect_read().once().returning(|buf| {
+            buf[..input.len()].copy_from_slice(input);
+            Ok(input.len())
+        });
+        mock.expect_write().once().returning(|buf| Ok(buf.len()));
+        let written = Arc::clone(&first_part);
+        mock.expect_write().once().returning(move |buf| {
+            written.lock().unwrap().extend_from_slice(buf);
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        });
+        mock.expect_flush().returning(|| Ok(()));
+        mock.expect_shutdown().once().returning(|_| Ok(()));
+
+        assert!(Connection::new(mock, Arc::default()).process().is_ok());
+        let first_part = String::from_utf8(first_part.lock().unwrap().clone()).unwrap();
+        assert!(first_part.contains("step 1 of 100"), "{first_part}");
+    }
+
+    #[test]
+    fn strict_mode_requires_host() -> Result<()> {
+        // Whatever the strictness, as the parser rejects it first
+        mock_with_config(
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: 48\r\nConnection: close\r\n\r\nError: HTTP/1.1 requests must have a Host header",
+            Config {
+                strictness: Strictness::Reject,
+                ..Config::default()
+            },
+        )
+    }
+
+    #[test]
+    fn strict_mode_logging_only() -> Result<()> {
+        // The request is still rejected, as it always is without a Host
+        mock_with_config(
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: 48\r\nConnection: close\r\n\r\nError: HTTP/1.1 requests must have a Host header",
+            Config {
+                strictness: Strictness::Log,
+                ..Config::default()
+            },
+        )
+    }
+
+    #[test]
+    fn get_user_agent_returns_200() -> Result<()> {
+        mock(
+            b"GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: rust\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 4\r\nVary: Accept-Encoding\r\n\r\nrust",
+        )
+    }
+
+    #[test]
+    fn get_user_agent_returns_400() -> Result<()> {
+        mock(
+            b"GET /user-agent HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 400 Bad Request\r\n\r\n",
+        )
+    }
+
+    #[test]
+    fn get_missing_file_404() -> Result<()> {
+        // A lot of effort to cover the case where we need to fill two buffers!
+        // Argubly should be in own test, but given the experimental nature
+        // of this project ;-)
+        let input_1 = b"GET /files/random12345 HTTP/1.1\r";
+        let input_2 = b"\nHost: localhost\r\n\r\n";
+        let output: &[u8] = b"HTTP/1.1 404 Not Found\r\n\r\n";
+
+        let mut mock = MockConnection::new();
+        mock.expect_read().once().returning(|buf| {
+            buf[..input_1.len()].copy_from_slice(input_1);
+            Ok(input_1.len())
+        });
+        mock.expect_read().once().returning(|buf| {
+            buf[..input_2.len()].copy_from_slice(input_2);
+            Ok(input_2.len())
+        });
+        mock.expect_write()
+            .with(predicate::eq(output))
+            .once()
+            .returning(|buf| Ok(buf.len()));
+        mock.expect_shutdown().once().returning(|_| Ok(()));
+
+        Connection::new(mock, Arc::default()).process()
+    }
+
+    /// The ETag depends on when the file was checked out, so can't be hardcoded
+    fn gitattributes_etag() -> String {
+        files::etag(&fs::metadata(".gitattributes").unwrap())
+    }
+
+    fn gitattributes_last_modified() -> String {
+        http::date(fs::metadata(".gitattributes").unwrap().modified().unwrap())
+    }
+
+    fn leak(output: String) -> &'static [u8] {
+        Box::leak(output.into_bytes().into_boxed_slice())
+    }
+
+    /// A response with the built-in error page, as sent when `--directory` has none of its own
+    fn error_page(status: &str) -> &'static [u8] {
+        let page = format!(
+            "<!DOCTYPE html>\n<html><head><title>{status}</title></head><body><h1>{status}</h1></body></html>\n"
+        );
+        leak(format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n{page}",
+            page.len()
+        ))
+    }
+
+    #[test]
+    fn get_valid_file_200() -> Result<()> {
+        mock(
+            b"GET /files/.gitattributes HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            leak(format!(
+                "HTTP/1.1 200 OK\r\nAccept-Ranges: bytes\r\nLast-Modified: {}\r\nContent-Type: application/octet-stream\r\nETag: {}\r\nContent-Length: 12\r\n\r\n* text=auto\n",
+                gitattributes_last_modified(),
+                gitattributes_etag()
+            )),
         )
     }
+
+    #[test]
+    fn head_file_200_without_body() -> Result<()> {
+        mock(
+            b"HEAD /files/.gitattributes HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            leak(format!(
+                "HTTP/1.1 200 OK\r\nAccept-Ranges: bytes\r\nLast-Modified: {}\r\nContent-Type: application/octet-stream\r\nETag: {}\r\nContent-Length: 12\r\n\r\n",
+                gitattributes_last_modified(),
+                gitattributes_etag()
+            )),
+        )
+    }
+
+    #[test]
+    fn head_missing_file_404() -> Result<()> {
+        mock(
+            b"HEAD /files/absent HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 404 Not Found\r\n\r\n",
+        )
+    }
+
+    #[test]
+    fn get_file_range_206() -> Result<()> {
+        mock(
+            b"GET /files/.gitattributes HTTP/1.1\r\nHost: localhost\r\nRange: bytes=2-5\r\n\r\n",
+            leak(format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Type: application/octet-stream\r\nETag: {}\r\nContent-Range: bytes 2-5/12\r\nContent-Length: 4\r\n\r\ntext",
+                gitattributes_etag()
+            )),
+        )
+    }
+
+    #[test]
+    fn get_file_if_none_match_304() -> Result<()> {
+        let etag = gitattributes_etag();
+        mock(
+            leak(format!(
+                "GET /files/.gitattributes HTTP/1.1\r\nHost: localhost\r\nIf-None-Match: {etag}\r\n\r\n"
+            )),
+            leak(format!("HTTP/1.1 304 Not Modified\r\nETag: {etag}\r\n\r\n")),
+        )
+    }
+
+    #[test]
+    fn get_file_unsatisfiable_range_416() -> Result<()> {
+        mock(
+            b"GET /files/.gitattributes HTTP/1.1\r\nHost: localhost\r\nRange: bytes=12-\r\n\r\n",
+            b"HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */12\r\n\r\n",
+        )
+    }
+
+    #[test]
+    fn static_root_serves_index_html() -> Result<()> {
+        let root = test_directory("static_root_serves_index_html");
+        fs::write(format!("{root}/index.html"), "<h1>Hi</h1>")?;
+        let etag = files::etag(&fs::metadata(format!("{root}/index.html"))?);
+
+        mock_with_config(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            leak(format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nETag: {etag}\r\nContent-Length: 11\r\nVary: Accept-Encoding\r\n\r\n<h1>Hi</h1>"
+            )),
+            Config {
+                static_root: Some(root),
+                ..Config::default()
+            },
+        )
+    }
+
+    #[test]
+    fn virtual_hosts_get_their_own_site() -> Result<()> {
+        let root = test_directory("virtual_hosts_get_their_own_site");
+        fs::write(format!("{root}/index.html"), "<h1>Example</h1>")?;
+        let config = Arc::new(Config {
+            vhosts: Swap::new(VirtualHosts::default().add("example.local", root)),
+            ..Config::default()
+        });
+        let get = |target: &str, host: &str| {
+            let request = format!("GET {target} HTTP/1.1\r\nHost: {host}\r\n\r\n");
+            String::from_utf8(exchange_shared(request.as_bytes(), &config)).unwrap()
+        };
+
+        assert!(get("/", "Example.local:4221").ends_with("\r\n\r\n<h1>Example</h1>"));
+        assert!(get("/echo/hi", "example.local").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        // Every other host gets the usual routes
+        assert!(get("/echo/hi", "localhost").ends_with("\r\n\r\nhi"));
+        assert!(get("/", "localhost").starts_with("HTTP/1.1 200 OK\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn static_root_serves_precompressed_files() -> Result<()> {
+        let root = test_directory("static_root_serves_precompressed_files");
+        let css = "p { color: red }\n".repeat(20);
+        fs::write(format!("{root}/site.css"), &css)?;
+        let config = || -> Result<Config> {
+            Ok(Config {
+                static_root: Some(root.clone()),
+                precompressed: Some(crate::compression::Precompressed::build(root.as_ref())?),
+                ..Config::default()
+            })
+        };
+
+        let gzipped = exchange(
+            b"GET /site.css HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n",
+            config()?,
+        );
+        let (head, body) =
+            gzipped.split_at(gzipped.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4);
+        let head = String::from_utf8_lossy(head);
+        assert!(head.contains("Content-Encoding: gzip\r\n"), "{head}");
+        assert!(head.contains("-gzip\"\r\n"), "{head}");
+        assert!(head.contains("Vary: Accept-Encoding\r\n"), "{head}");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(body).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, css);
+
+        let plain = String::from_utf8(exchange(
+            b"GET /site.css HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            config()?,
+        ))?;
+        assert!(!plain.contains("Content-Encoding"));
+        assert!(plain.ends_with(&css));
+        Ok(())
+    }
+
+    #[test]
+    fn large_files_are_sent_over_tcp_whole() -> Result<()> {
+        let directory = test_directory("large_files_are_sent_over_tcp_whole");
+        let contents: Vec<u8> = (0..=255).cycle().take(1_500_000).collect();
+        fs::write(format!("{directory}/large.bin"), &contents)?;
+        let config = Arc::new(Config {
+            directory: Some(directory),
+            ..Config::default()
+        });
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+        let server = std::thread::spawn(move || Connection::new(stream, config).process());
+
+        client.write_all(
+            b"GET /files/large.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )?;
+        let mut response = vec![];
+        client.read_to_end(&mut response)?;
+        server.join().unwrap()?;
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&contents));
+        assert_eq!(
+            response.windows(4).position(|window| window == b"\r\n\r\n"),
+            Some(response.len() - contents.len() - 4)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn files_over_mmap_min_size_are_sent_from_a_map() -> Result<()> {
+        let directory = test_directory("files_over_mmap_min_size_are_sent_from_a_map");
+        fs::write(format!("{directory}/small.txt"), "small")?;
+        fs::write(format!("{directory}/large.txt"), "large enough")?;
+        let config = Arc::new(Config {
+            directory: Some(directory),
+            mmap_min_size: Some(8),
+            ..Config::default()
+        });
+
+        for (name, body) in [("small", "small"), ("large", "large enough")] {
+            let request = format!("GET /files/{name}.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            let response = String::from_utf8(exchange_shared(request.as_bytes(), &config))?;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+            assert!(
+                response.contains(&format!("Content-Length: {}\r\n", body.len())),
+                "{response}"
+            );
+            assert!(response.ends_with(&format!("\r\n\r\n{body}")), "{response}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn files_are_downloaded_when_asked() -> Result<()> {
+        let directory = test_directory("files_are_downloaded_when_asked");
+        fs::write(format!("{directory}/report.csv"), "a,b")?;
+        let get = |target: &str, force_download: bool| {
+            let config = Arc::new(Config {
+                directory: Some(directory.clone()),
+                force_download,
+                ..Config::default()
+            });
+            let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            String::from_utf8(exchange_shared(request.as_bytes(), &config)).unwrap()
+        };
+
+        let response = get("/files/report.csv?download=1", false);
+        assert!(
+            response.contains("\r\nContent-Disposition: attachment; filename=\"report.csv\"\r\n"),
+            "{response}"
+        );
+        assert!(!get("/files/report.csv", false).contains("Content-Disposition"));
+        assert!(get("/files/report.csv", true).contains("Content-Disposition: attachment"));
+        assert!(!get("/files/absent.csv", true).contains("Content-Disposition"));
+        Ok(())
+    }
+
+    #[test]
+    fn nothing_is_written_when_read_only() -> Result<()> {
+        let directory = test_directory("nothing_is_written_when_read_only");
+        fs::write(format!("{directory}/kept.txt"), "kept")?;
+        let config = Arc::new(Config {
+            directory: Some(directory.clone()),
+            read_only: true,
+            ..Config::default()
+        });
+
+        for request in [
+            "POST /files/new.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nnew",
+            "PUT /files/kept.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nnew",
+            "DELETE /files/kept.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "DELETE /api/files?glob=kept.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        ] {
+            let response = String::from_utf8(exchange_shared(request.as_bytes(), &config))?;
+            assert!(
+                response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+                "{response}"
+            );
+        }
+        let response = String::from_utf8(exchange_shared(
+            b"GET /files/kept.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            &config,
+        ))?;
+        assert!(response.ends_with("\r\n\r\nkept"), "{response}");
+        assert!(!Path::new(&directory).join("new.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_posts_are_server_errors() -> Result<()> {
+        let directory = test_directory("failed_posts_are_server_errors");
+        fs::create_dir(format!("{directory}/taken"))?;
+
+        let response = String::from_utf8(exchange(
+            b"POST /files/taken HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nnew",
+            Config {
+                directory: Some(directory),
+                ..Config::default()
+            },
+        ))?;
+        assert!(
+            response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{response}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn heads_cut_off_by_the_client_closing_are_not_served() -> Result<()> {
+        let directory = test_directory("heads_cut_off_by_the_client_closing_are_not_served");
+
+        // The precondition would fail, had it arrived whole
+        let response = String::from_utf8(exchange(
+            b"PUT /files/t1 HTTP/1.1\r\nHost: x\r\nIf-Match: \"nomatch",
+            Config {
+                directory: Some(directory.clone()),
+                ..Config::default()
+            },
+        ))?;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{response}"
+        );
+        assert!(!Path::new(&directory).join("t1").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn form_uploads_are_saved_to_the_directory() -> Result<()> {
+        let directory = test_directory("form_uploads_are_saved_to_the_directory");
+        let body = "--xyz\r\n\
+            Content-Disposition: form-data; name=\"note\"\r\n\r\nignored\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"C:\\\\Users\\\\me\\\\a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\nuploaded\r\n\
+            --xyz--\r\n";
+        let request = format!(
+            "POST /files HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=xyz\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+
+        let response = String::from_utf8(exchange(
+            request.as_bytes(),
+            Config {
+                directory: Some(directory.clone()),
+                ..Config::default()
+            },
+        ))?;
+        assert!(
+            response.starts_with("HTTP/1.1 201 Created\r\n"),
+            "{response}"
+        );
+        assert!(response.ends_with("[\"a.txt\"]"), "{response}");
+        assert_eq!(
+            fs::read_to_string(format!("{directory}/a.txt"))?,
+            "uploaded"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn directories_are_redirected_to_a_trailing_slash() -> Result<()> {
+        let root = test_directory("directories_are_redirected_to_a_trailing_slash");
+        fs::create_dir(format!("{root}/my docs"))?;
+        fs::write(format!("{root}/my docs/index.html"), "<h1>Docs</h1>")?;
+        let config = Arc::new(Config {
+            directory: Some(root.clone()),
+            static_root: Some(root),
+            ..Config::default()
+        });
+        let get = |target: &str| {
+            let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            String::from_utf8(exchange_shared(request.as_bytes(), &config)).unwrap()
+        };
+
+        assert_eq!(
+            get("/my%20docs?v=1"),
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: /my%20docs/?v=1\r\n\r\n"
+        );
+        assert_eq!(
+            get("/files/my%20docs"),
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: /files/my%20docs/\r\n\r\n"
+        );
+        // Never to another host
+        for target in ["//my%20docs", "/%2Fmy%20docs"] {
+            assert_eq!(
+                get(target),
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: /my%20docs/\r\n\r\n"
+            );
+        }
+        assert!(get("/my%20docs/").ends_with("\r\n\r\n<h1>Docs</h1>"));
+        assert!(get("/files/my%20docs/").ends_with("\r\n\r\n<h1>Docs</h1>"));
+        Ok(())
+    }
+
+    #[test]
+    fn moved_paths_are_redirected() -> Result<()> {
+        mock_with_config(
+            b"GET /old?page=2 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 301 Moved Permanently\r\nLocation: /echo/new?page=2\r\n\r\n",
+            Config {
+                middleware: Stack::default()
+                    .wrap(Redirects::default().add("/old", "/echo/new".to_string())),
+                ..Config::default()
+            },
+        )
+    }
+
+    #[test]
+    fn cached_files_are_served_until_changed() -> Result<()> {
+        let directory = test_directory("cached_files_are_served_until_changed");
+        fs::write(format!("{directory}/a.txt"), "first")?;
+        let config = Arc::new(Config {
+            directory: Some(directory.clone()),
+            file_cache: Some(Arc::new(crate::file_cache::FileCache::new(1024))),
+            ..Config::default()
+        });
+
+        let first = exchange_shared(
+            b"GET /files/a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            &config,
+        );
+        assert!(first.ends_with(b"\r\n\r\nfirst"));
+        assert_eq!(
+            exchange_shared(
+                b"GET /files/a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                &config
+            ),
+            first
+        );
+
+        exchange_shared(
+            b"PUT /files/a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 6\r\n\r\nsecond",
+            &config,
+        );
+        let second = exchange_shared(
+            b"GET /files/a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            &config,
+        );
+        assert!(second.ends_with(b"\r\n\r\nsecond"));
+        Ok(())
+    }
+
+    #[test]
+    fn static_root_serves_gzip_sidecars() -> Result<()> {
+        let root = test_directory("static_root_serves_gzip_sidecars");
+        fs::write(format!("{root}/app.js"), "let a = 1;")?;
+        // Not really gzip, but nothing looks inside it
+        fs::write(format!("{root}/app.js.gz"), "gzipped!")?;
+        let config = || Config {
+            static_root: Some(root.clone()),
+            ..Config::default()
+        };
+
+        let gzipped = String::from_utf8(exchange(
+            b"GET /app.js HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n",
+            config(),
+        ))?;
+        for header in [
+            "Content-Type: text/javascript; charset=utf-8\r\n",
+            "Content-Encoding: gzip\r\n",
+            "Content-Length: 8\r\n",
+            "Vary: Accept-Encoding\r\n",
+        ] {
+            assert!(gzipped.contains(header), "{gzipped}");
+        }
+        assert!(gzipped.contains("-gzip\"\r\n"), "{gzipped}");
+        assert!(gzipped.ends_with("\r\n\r\ngzipped!"), "{gzipped}");
+
+        let plain = String::from_utf8(exchange(
+            b"GET /app.js HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            config(),
+        ))?;
+        assert!(!plain.contains("Content-Encoding"), "{plain}");
+        assert!(plain.contains("Vary: Accept-Encoding\r\n"), "{plain}");
+        assert!(plain.ends_with("\r\n\r\nlet a = 1;"), "{plain}");
+        Ok(())
+    }
+
+    #[test]
+    fn static_root_missing_file_404() -> Result<()> {
+        mock_with_config(
+            b"GET /missing.css HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 404 Not Found\r\n\r\n",
+            Config {
+                static_root: Some(test_directory("static_root_missing_file_404")),
+                ..Config::default()
+            },
+        )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn static_root_symlinks_out_of_it_404() -> Result<()> {
+        let root = test_directory("static_root_symlinks_out_of_it_404");
+        let outside = test_directory("static_root_symlinks_out_of_it_404-outside");
+        fs::write(format!("{outside}/secret.txt"), "secret")?;
+        std::os::unix::fs::symlink(&outside, format!("{root}/linked"))?;
+        let get = |follow_symlinks| {
+            let request = b"GET /linked/secret.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+            String::from_utf8(exchange(
+                request,
+                Config {
+                    static_root: Some(root.clone()),
+                    follow_symlinks,
+                    ..Config::default()
+                },
+            ))
+        };
+
+        let response = get(files::Symlinks::SameRoot)?;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+        let response = get(files::Symlinks::Always)?;
+        assert!(response.ends_with("\r\n\r\nsecret"), "{response}");
+        Ok(())
+    }
+
+    #[test]
+    fn files_and_sites_carry_cache_control() -> Result<()> {
+        let directory = test_directory("files_and_sites_carry_cache_control");
+        fs::write(format!("{directory}/site.css"), "body {}")?;
+        fs::write(format!("{directory}/index.html"), "<h1>Hi</h1>")?;
+        let config = Arc::new(Config {
+            directory: Some(directory.clone()),
+            static_root: Some(directory),
+            cache_control: CacheControl::default()
+                .add("*.css=max-age=31536000, immutable")?
+                .add("*=no-store")?,
+            ..Config::default()
+        });
+        let get = |target: &str| {
+            let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            String::from_utf8(exchange_shared(request.as_bytes(), &config))
+        };
+
+        for target in ["/site.css", "/files/site.css"] {
+            let response = get(target)?;
+            assert!(
+                response.contains("\r\nCache-Control: max-age=31536000, immutable\r\n"),
+                "{response}"
+            );
+        }
+        let response = get("/")?;
+        assert!(
+            response.contains("\r\nCache-Control: no-store\r\n"),
+            "{response}"
+        );
+        // Nothing to keep from a 404
+        let response = get("/files/missing.css")?;
+        assert!(!response.contains("Cache-Control"), "{response}");
+        Ok(())
+    }
+
+    #[test]
+    fn get_file_outside_directory_403() -> Result<()> {
+        mock_with_directory(
+            b"GET /files/../Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            error_page("403 Forbidden"),
+            Some("src".to_string()),
+        )
+    }
+
+    #[test]
+    fn post_file_outside_directory_403() -> Result<()> {
+        let directory = test_directory("post_file_outside_directory_403");
+        mock_with_directory(
+            b"POST /files/../escaped HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nRust",
+            error_page("403 Forbidden"),
+            Some(directory.clone()),
+        )?;
+
+        assert!(!Path::new(&directory).with_file_name("escaped").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn post_file_201() -> Result<()> {
+        mock_with_directory(
+            b"POST /files/junk HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/octet-stream\r\nContent-Length: 4\r\n\r\nRust",
+            b"HTTP/1.1 201 Created\r\n\r\n",
+            Some(test_directory("post_file_201")),
+        )
+    }
+
+    #[test]
+    fn put_new_file_201() -> Result<()> {
+        let directory = test_directory("put_new_file_201");
+        mock_with_directory(
+            b"PUT /files/junk HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nRust",
+            b"HTTP/1.1 201 Created\r\n\r\n",
+            Some(directory.clone()),
+        )?;
+
+        assert_eq!(fs::read(PathBuf::from(directory).join("junk"))?, b"Rust");
+        Ok(())
+    }
+
+    #[test]
+    fn put_nested_file_needs_create_parents() -> Result<()> {
+        let directory = test_directory("put_nested_file_needs_create_parents");
+        mock_with_directory(
+            b"PUT /files/nested/dir/name.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nRust",
+            error_page("404 Not Found"),
+            Some(directory.clone()),
+        )?;
+
+        mock_with_config(
+            b"PUT /files/nested/dir/name.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nRust",
+            b"HTTP/1.1 201 Created\r\n\r\n",
+            Config {
+                directory: Some(directory.clone()),
+                create_parents: true,
+                ..Config::default()
+            },
+        )?;
+
+        assert_eq!(
+            fs::read(PathBuf::from(directory).join("nested/dir/name.txt"))?,
+            b"Rust"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn put_nested_file_with_invalid_segment_400() -> Result<()> {
+        mock_with_config(
+            b"PUT /files/nested/../../name.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nRust",
+            b"HTTP/1.1 400 Bad Request\r\n\r\n",
+            Config {
+                directory: Some(test_directory("put_nested_file_with_invalid_segment_400")),
+                create_parents: true,
+                ..Config::default()
+            },
+        )
+    }
+
+    #[test]
+    fn put_existing_file_204() -> Result<()> {
+        let directory = test_directory("put_existing_file_204");
+        fs::write(PathBuf::from(&directory).join("junk"), b"Old")?;
+        mock_with_directory(
+            b"PUT /files/junk HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nRust",
+            b"HTTP/1.1 204 No Content\r\n\r\n",
+            Some(directory.clone()),
+        )?;
+
+        assert_eq!(fs::read(PathBuf::from(directory).join("junk"))?, b"Rust");
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_writes_412_once_the_file_changes() -> Result<()> {
+        let directory = test_directory("conditional_writes_412_once_the_file_changes");
+        let path = PathBuf::from(&directory).join("notes");
+        fs::write(&path, b"Old")?;
+        let etag = files::etag(&fs::metadata(&path)?);
+        let config = Arc::new(Config {
+            directory: Some(directory),
+            ..Config::default()
+        });
+        let failed = "HTTP/1.1 412 Precondition Failed";
+        let write = |method: &str, condition: &str, body: &str| {
+            let request = format!(
+                "{method} /files/notes HTTP/1.1\r\nHost: localhost\r\n{condition}\r\n\
+                Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            let response = String::from_utf8(exchange_shared(request.as_bytes(), &config))?;
+            Ok::<_, anyhow::Error>(response.lines().next().unwrap_or_default().to_string())
+        };
+
+        assert_eq!(write("PUT", "If-Match: \"stale\"", "Lost")?, failed);
+        assert_eq!(write("POST", "If-None-Match: *", "Lost")?, failed);
+        let since = "If-Unmodified-Since: Thu, 01 Jan 1970 00:00:00 GMT";
+        assert_eq!(write("PUT", since, "Lost")?, failed);
+        assert_eq!(fs::read(&path)?, b"Old");
+
+        let if_match = format!("If-Match: {etag}");
+        assert_eq!(write("PUT", &if_match, "Newer")?, "HTTP/1.1 204 No Content");
+        assert_eq!(fs::read(&path)?, b"Newer");
+        // The file has changed since that ETag
+        assert_eq!(write("PUT", &if_match, "Lost")?, failed);
+
+        fs::remove_file(&path)?;
+        assert_eq!(write("PUT", "If-Match: *", "Lost")?, failed);
+        assert_eq!(
+            write("POST", "If-None-Match: *", "Created")?,
+            "HTTP/1.1 201 Created"
+        );
+        assert_eq!(fs::read(&path)?, b"Created");
+        Ok(())
+    }
+
+    #[test]
+    fn delete_file_204() -> Result<()> {
+        let directory = test_directory("delete_file_204");
+        fs::write(PathBuf::from(&directory).join("junk"), b"Rust")?;
+        mock_with_directory(
+            b"DELETE /files/junk HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"HTTP/1.1 204 No Content\r\n\r\n",
+            Some(directory.clone()),
+        )?;
+
+        assert!(!PathBuf::from(directory).join("junk").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn delete_missing_file_404() -> Result<()> {
+        mock_with_directory(
+            b"DELETE /files/junk HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            error_page("404 Not Found"),
+            Some(test_directory("delete_missing_file_404")),
+        )
+    }
+
+    #[ignore] // Needs updating to reflect the gzip'd body
+    #[test]
+    fn echo_with_gzip() -> Result<()> {
+        mock(
+            b"GET /echo/rust HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nrust",
+        )
+    }
+
+    #[test]
+    fn echo_with_unsupported_encoding() -> Result<()> {
+        mock(
+            b"GET /echo/rust HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: compress\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Type: text/plain\r\nContent-Length: 4\r\nVary: Accept-Encoding\r\n\r\nrust",
+        )
+    }
+
+    #[test]
+    fn echo_as_json() -> Result<()> {
+        mock(
+            b"GET /echo/rust HTTP/1.1\r\nHost: localhost\r\nAccept: text/html;q=0.9, application/json\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Type: application/json\r\nContent-Length: 15\r\nVary: Accept-Encoding\r\n\r\n{\"echo\":\"rust\"}",
+        )?;
+        mock(
+            b"GET /echo/rust HTTP/1.1\r\nHost: localhost\r\nAccept: image/*\r\n\r\n",
+            b"HTTP/1.1 406 Not Acceptable\r\n\r\n",
+        )
+    }
+
+    #[test]
+    fn echo_refusing_every_encoding_406() -> Result<()> {
+        mock(
+            b"GET /echo/rust HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: compress, identity;q=0\r\n\r\n",
+            b"HTTP/1.1 406 Not Acceptable\r\n\r\n",
+        )
+    }
+
+    #[test]
+    fn websocket_upgrade_hands_over_the_connection() -> Result<()> {
+        let (mut client, server) = duplex::pair();
+        let server = thread::spawn(move || Connection::new(server, Arc::default()).process());
+
+        client.write_all(
+            b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )?;
+        let expected: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+            Upgrade: websocket\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        let mut head = vec![0; expected.len()];
+        client.read_exact(&mut head)?;
+        assert_eq!(head, expected);
+
+        // Only now the handshake is done, a close frame masked with zeros
+        client.write_all(b"\x88\x82\0\0\0\0\x03\xe8")?;
+        assert_eq!(client.read_all()?, b"\x88\x02\x03\xe8");
+
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn responses_are_recorded_in_the_access_log() {
+        #[derive(Clone, Default)]
+        struct Lines(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl Write for Lines {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let lines = Lines::default();
+        let config = Config {
+            access_log: AccessLog::new(lines.clone(), LogFormat::Combined),
+            ..Config::default()
+        };
+        exchange(
+            b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\nUser-Agent: t\r\n\r\n",
+            config,
+        );
+
+        let lines = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        assert!(lines.starts_with("- - - ["));
+        assert!(lines.contains("] \"GET /echo/hi HTTP/1.1\" 200 2 \"-\" \"t\" "));
+        assert!(lines.ends_with('\n'));
+    }
+
+    #[test]
+    fn forwarded_requests_are_filtered_by_client() {
+        let config = Arc::new(Config {
+            ip_filter: Swap::new(IpFilter::default().deny("192.0.2.1".parse().unwrap())),
+            trusted_proxies: TrustedProxies::default().trust("10.0.0.0/8".parse().unwrap()),
+            ..Config::default()
+        });
+        let status = |peer: &str, forwarded_for: &str| {
+            let (mut client, server) = duplex::pair();
+            client
+                .write_all(
+                    format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: {forwarded_for}\r\n\r\n")
+                        .as_bytes(),
+                )
+                .unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            Connection::new(server, Arc::clone(&config))
+                .peer(peer.to_string())
+                .process()
+                .unwrap();
+            let response = String::from_utf8(client.read_all().unwrap()).unwrap();
+            response[9..12].to_string()
+        };
+
+        assert_eq!(status("10.0.0.1", "192.0.2.1"), "403");
+        assert_eq!(status("10.0.0.1", "192.0.2.2"), "200");
+        // Anyone else could be lying
+        assert_eq!(status("203.0.113.9", "192.0.2.1"), "200");
+    }
+
+    #[test]
+    fn proxy_protocol_gives_the_client() {
+        let config = || Config {
+            proxy_protocol: true,
+            ip_filter: Swap::new(IpFilter::default().deny("192.0.2.1".parse().unwrap())),
+            ..Config::default()
+        };
+        let response = |client: &str| {
+            let input = format!(
+                "PROXY TCP4 {client} 198.51.100.1 56324 80\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n"
+            );
+            String::from_utf8(exchange(input.as_bytes(), config())).unwrap()
+        };
+
+        assert!(response("192.0.2.2").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response("192.0.2.1").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        // Whoever connected without a load balancer gets nothing
+        assert!(exchange(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", config()).is_empty());
+    }
+
+    #[test]
+    fn metrics_count_earlier_requests() {
+        let metrics = Arc::<crate::metrics::Metrics>::default();
+        let config = || Config {
+            metrics: Arc::clone(&metrics),
+            ..Config::default()
+        };
+        exchange(
+            b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            config(),
+        );
+
+        let response = exchange(
+            b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            config(),
+        );
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nhttp_requests_total{method=\"GET\",status=\"200\"} 1\n"));
+        assert!(response.contains("\nhttp_response_body_bytes_total 2\n"));
+        // The connection asking
+        assert!(response.contains("\nhttp_connections_active 1\n"));
+    }
+
+    #[test]
+    fn request_ids_are_echoed() {
+        let config = || Config {
+            echo_request_id: true,
+            ..Config::default()
+        };
+
+        let response = exchange(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: r1\r\n\r\n",
+            config(),
+        );
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\nX-Request-Id: r1\r\n\r\n");
+
+        let response = String::from_utf8(exchange(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            config(),
+        ))
+        .unwrap();
+        assert!(response.contains("\r\nX-Request-Id: "));
+    }
+
+    #[test]
+    fn files_require_their_own_users() -> Result<()> {
+        let config = || -> Result<Config> {
+            let mut credentials = crate::auth::Credentials::default();
+            credentials.add_user("api", "bob:hunter2")?;
+            credentials.add_user("files", "alice:secret")?;
+            Ok(Config {
+                credentials,
+                ..Config::default()
+            })
+        };
+
+        let response = exchange(
+            b"GET /files/a HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            config()?,
+        );
+        assert_eq!(
+            response,
+            b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"files\"\r\n\r\n"
+        );
+        // bob:hunter2
+        let response = exchange(
+            b"GET /files/a HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic Ym9iOmh1bnRlcjI=\r\n\r\n",
+            config()?,
+        );
+        assert!(response.starts_with(b"HTTP/1.1 401 "));
+        // alice:secret
+        let response = exchange(
+            b"GET /files/a HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n",
+            config()?,
+        );
+        assert!(!response.starts_with(b"HTTP/1.1 401 "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn alternative_services_are_advertised() {
+        let config = Config {
+            middleware: Stack::default().wrap(ResponseHeader {
+                name: "Alt-Svc".to_string(),
+                value: "h2=\"alt.example:443\"; ma=3600".to_string(),
+            }),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            exchange(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", config),
+            b"HTTP/1.1 200 OK\r\nAlt-Svc: h2=\"alt.example:443\"; ma=3600\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn failing_handlers_are_500s() -> Result<()> {
+        let config = |debug_errors| Config {
+            keep_alive: Some(KeepAlive {
+                timeout: Duration::from_secs(5),
+                max_requests: 10,
+            }),
+            debug_errors,
+            middleware: Stack::default().wrap(
+                |request: &Request, context: &RequestContext, next: Next| match request
+                    .path
+                    .as_str()
+                {
+                    "/fail" => Err(anyhow::anyhow!("disk on fire")),
+                    "/panic" => panic!("handler bug"),
+                    _ => next.run(request, context),
+                },
+            ),
+            ..Config::default()
+        };
+        let requests = b"GET /fail HTTP/1.1\r\nHost: localhost\r\n\r\n\
+            GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\n\
+            GET /echo/after HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        let quiet = String::from_utf8(exchange(requests, config(false)))?;
+        assert_eq!(
+            quiet
+                .matches("HTTP/1.1 500 Internal Server Error\r\n")
+                .count(),
+            2,
+            "{quiet}"
+        );
+        assert!(!quiet.contains("disk on fire"), "{quiet}");
+        // The connection carries on after both
+        assert!(quiet.ends_with("\r\n\r\nafter"), "{quiet}");
+
+        let debugged = String::from_utf8(exchange(requests, config(true)))?;
+        assert!(debugged.contains("\r\n\r\ndisk on fire"), "{debugged}");
+        assert!(
+            debugged.contains("\r\n\r\npanicked: handler bug"),
+            "{debugged}"
+        );
+        Ok(())
+    }
+
+    /// Serves a connection in the background, with `send` writing a request and reading what
+    /// comes back for it
+    fn keep_alive_client(
+        timeout: Duration,
+        max_requests: usize,
+    ) -> (duplex::DuplexStream, thread::JoinHandle<()>) {
+        let config = Config {
+            keep_alive: Some(KeepAlive {
+                timeout,
+                max_requests,
+            }),
+            ..Config::default()
+        };
+        let (client, server) = duplex::pair();
+        let connection =
+            thread::spawn(move || Connection::new(server, Arc::new(config)).process().unwrap());
+
+        (client, connection)
+    }
+
+    fn send(client: &mut duplex::DuplexStream, request: &[u8]) -> String {
+        client.write_all(request).unwrap();
+        let mut buf = [0; 1024];
+        let read = client.read(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..read]).into_owned()
+    }
+
+    #[test]
+    fn connections_are_kept_alive_up_to_the_limit() {
+        let (mut client, connection) = keep_alive_client(Duration::from_secs(5), 3);
+
+        assert_eq!(
+            send(&mut client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            send(
+                &mut client,
+                b"GET /absent HTTP/1.1\r\nHost: localhost\r\n\r\n"
+            ),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            send(
+                &mut client,
+                b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n"
+            ),
+            "HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Type: text/plain\r\n\
+            Content-Length: 2\r\nVary: Accept-Encoding\r\nConnection: close\r\n\r\nhi"
+        );
+        connection.join().unwrap();
+        assert!(client.read_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let config = Config {
+            keep_alive: Some(KeepAlive {
+                timeout: Duration::from_secs(5),
+                max_requests: 100,
+            }),
+            ..Config::default()
+        };
+        let response = exchange(
+            b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\nGET /absent HTTP/1.1\r\nHost: localhost\r\n\r\n\
+            GET /echo/three HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\nGET /echo/ignored HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            config,
+        );
+        let response = String::from_utf8(response).unwrap();
+
+        let bodies = response
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| response.rsplit("\r\n\r\n").next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, ["one", "", "three"], "{response}");
+        assert!(response.contains("404 Not Found\r\nContent-Length: 0\r\n\r\n"));
+    }
+
+    #[test]
+    fn connections_close_when_idle_or_asked_to() {
+        let (mut client, connection) = keep_alive_client(Duration::from_millis(10), 100);
+        assert!(
+            send(&mut client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .starts_with("HTTP/1.1 200 OK\r\n")
+        );
+        connection.join().unwrap();
+        assert!(client.read_all().unwrap().is_empty());
+
+        let (mut client, connection) = keep_alive_client(Duration::from_secs(5), 100);
+        assert_eq!(
+            send(
+                &mut client,
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+            ),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n"
+        );
+        connection.join().unwrap();
+    }
+
+    #[test]
+    fn every_response_is_dated_and_signed() {
+        let config = || Config {
+            date: true,
+            server: Some("test/1.0".to_string()),
+            ..Config::default()
+        };
+
+        for input in [
+            &b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n"[..],
+            b"GET / HTTP/9.9\r\n\r\n",
+        ] {
+            let response = String::from_utf8(exchange(input, config())).unwrap();
+            let date = response
+                .split("\r\n")
+                .find_map(|line| line.strip_prefix("Date: "))
+                .expect(&response);
+            assert!(date.ends_with(" GMT") && date.len() == 29, "{date}");
+            assert!(response.contains("\r\nServer: test/1.0\r\n"), "{response}");
+        }
+    }
+
+    #[test]
+    fn sessions_follow_the_cookie() {
+        let config = Arc::new(Config::default());
+        let exchange = |input: &[u8]| String::from_utf8(exchange_shared(input, &config)).unwrap();
+
+        let response = exchange(
+            b"PUT /session/user HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nalice",
+        );
+        let cookie = response
+            .split("\r\n")
+            .find_map(|line| line.strip_prefix("Set-Cookie: "))
+            .and_then(|set_cookie| set_cookie.split(';').next())
+            .expect("session cookie");
+        assert!(response.starts_with("HTTP/1.1 204 "), "{response}");
+
+        let response = exchange(
+            format!("GET /session HTTP/1.1\r\nHost: localhost\r\nCookie: {cookie}\r\n\r\n")
+                .as_bytes(),
+        );
+        assert!(
+            response.ends_with("\r\n\r\n{\"user\":\"alice\"}"),
+            "{response}"
+        );
+        assert!(!response.contains("Set-Cookie"));
+        let response = exchange(b"GET /session HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n{}"), "{response}");
+    }
+
+    #[test]
+    fn request_data_cannot_inject_headers() {
+        let response = exchange(
+            b"GET /echo/a%0D%0ASet-Cookie:%20x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            Config::default(),
+        );
+        assert_eq!(
+            response,
+            b"HTTP/1.1 200 OK\r\nVary: Accept\r\nContent-Type: text/plain\r\nContent-Length: 18\r\nVary: Accept-Encoding\r\n\r\n\
+            a\r\nSet-Cookie: x=1"
+        );
+
+        let response = exchange(
+            b"GET /files/a%0D%0ASet-Cookie:%20x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            Config::default(),
+        );
+        assert_eq!(response, b"HTTP/1.1 404 Not Found\r\n\r\n");
+    }
 }
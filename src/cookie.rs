@@ -0,0 +1,188 @@
+//! Reading the cookies a client sends, and setting new ones (RFC 6265)
+// Sessions only use some attributes, but handlers should be able to set any
+#![allow(dead_code)]
+
+use crate::http::Header;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers insist on `Secure` as well
+    None,
+}
+
+/// A cookie for the client to store, sent in a `Set-Cookie` header of its own
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    /// In seconds, 0 (or less) deleting the cookie
+    max_age: Option<i64>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    /// Tells the client to forget the cookie called `name`
+    pub fn delete(name: &str) -> Self {
+        Self::new(name, "").max_age(0)
+    }
+
+    #[must_use]
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    #[must_use]
+    pub const fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Keeps the cookie from scripts on the page
+    #[must_use]
+    pub const fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    #[must_use]
+    pub const fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    #[must_use]
+    pub const fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Whether the name is a token and the value only has characters a cookie value can, so
+    /// neither can add attributes (eg, a `;` in a value taken from the request)
+    pub fn is_valid(&self) -> bool {
+        let is_cookie_octet =
+            |byte: u8| byte.is_ascii_graphic() && !matches!(byte, b'"' | b',' | b';' | b'\\');
+        let is_path_octet = |byte: u8| (byte.is_ascii_graphic() || byte == b' ') && byte != b';';
+
+        !self.name.is_empty()
+            && !self
+                .name
+                .bytes()
+                .any(|byte| !byte.is_ascii_graphic() || b"()<>@,;:\\\"/[]?={}".contains(&byte))
+            && self.value.bytes().all(is_cookie_octet)
+            && self
+                .path
+                .as_deref()
+                .is_none_or(|path| path.bytes().all(is_path_octet))
+    }
+
+    pub fn to_header(&self) -> Header {
+        let mut cookie = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            cookie.push_str(&format!("; Path={path}"));
+        }
+        if let Some(max_age) = self.max_age {
+            cookie.push_str(&format!("; Max-Age={max_age}"));
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if let Some(same_site) = self.same_site {
+            cookie.push_str(match same_site {
+                SameSite::Strict => "; SameSite=Strict",
+                SameSite::Lax => "; SameSite=Lax",
+                SameSite::None => "; SameSite=None",
+            });
+        }
+
+        Header::Custom("Set-Cookie".to_string(), cookie)
+    }
+}
+
+/// The cookies in a `Cookie` header by name, the first winning if a name is repeated (the
+/// client sends the one with the most specific path first). Malformed pairs are skipped.
+pub fn parse(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in header.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        cookies
+            .entry(name.to_string())
+            .or_insert_with(|| value.to_string());
+    }
+
+    cookies
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let cookies = parse("session=abc123; theme=\"dark\";; broken; =x; session=older");
+
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies["session"], "abc123");
+        assert_eq!(cookies["theme"], "dark");
+    }
+
+    #[test]
+    fn attributes() {
+        let cookie = SetCookie::new("session", "abc123")
+            .path("/")
+            .max_age(3600)
+            .http_only()
+            .secure()
+            .same_site(SameSite::Lax);
+
+        assert_eq!(
+            cookie.to_header().value(),
+            "session=abc123; Path=/; Max-Age=3600; HttpOnly; Secure; SameSite=Lax"
+        );
+        assert_eq!(
+            SetCookie::delete("session").to_header().value(),
+            "session=; Max-Age=0"
+        );
+    }
+
+    #[test]
+    fn values_cannot_add_attributes() {
+        assert!(SetCookie::new("id", "a1").is_valid());
+        assert!(!SetCookie::new("id", "a1; Domain=evil.example").is_valid());
+        assert!(!SetCookie::new("i d", "a1").is_valid());
+        assert!(!SetCookie::new("id", "a1").path("/; Secure").is_valid());
+    }
+}
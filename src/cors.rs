@@ -0,0 +1,258 @@
+//! Cross-origin resource sharing, so pages served from elsewhere can call routes that opt in,
+//! or any route once `--cors-origin` is given
+//!
+//! Browsers ask first with an `OPTIONS` preflight, and remember the answer for as long as
+//! `Access-Control-Max-Age` says. `Cache-Control` on the preflight lets shared caches in front of
+//! the server answer it too (or not, if it is private).
+
+use crate::{
+    http::Header,
+    request::{Method, Request},
+    response::Response,
+};
+
+/// Who may keep a copy of a preflight response, besides the browser's preflight cache
+// Not every route group needs both, but they should be able to choose
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Caching {
+    /// Any cache, eg a CDN in front of the server
+    Public,
+    /// Only the browser's own HTTP cache
+    Private,
+}
+
+/// Which origins may use a group of routes, and how long they can go without asking again
+#[derive(Debug, Default)]
+pub struct Cors {
+    /// `*` for any origin
+    origins: Vec<String>,
+    /// Empty for any the path handles
+    methods: Vec<Method>,
+    /// Request headers pages may send, empty for any they ask for
+    headers: Vec<String>,
+    /// In seconds
+    max_age: Option<u32>,
+    caching: Option<Caching>,
+}
+
+impl Cors {
+    pub fn new<S: AsRef<str>>(origins: &[S]) -> Self {
+        Self {
+            origins: origins
+                .iter()
+                .map(|origin| origin.as_ref().to_string())
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Only lets pages use these methods, of those the path handles
+    #[must_use]
+    pub fn methods(mut self, methods: &[Method]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// Only lets pages send these request headers, rather than whichever they ask for
+    #[must_use]
+    pub fn headers<S: AsRef<str>>(mut self, headers: &[S]) -> Self {
+        self.headers = headers
+            .iter()
+            .map(|header| header.as_ref().to_string())
+            .collect();
+        self
+    }
+
+    /// How long browsers may reuse a preflight response, rather than their own (short) default
+    #[must_use]
+    pub const fn max_age(mut self, seconds: u32) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    #[must_use]
+    pub const fn caching(mut self, caching: Caching) -> Self {
+        self.caching = Some(caching);
+        self
+    }
+
+    /// Whether `request` is a preflight, rather than a plain `OPTIONS` request
+    pub fn is_preflight(request: &Request) -> bool {
+        request.method == Method::Options
+            && request.headers.contains_key("origin")
+            && request
+                .headers
+                .contains_key("access-control-request-method")
+    }
+
+    /// Adds `Access-Control-Allow-Origin`, if the request came from an origin that is allowed
+    pub fn allow_origin(&self, request: &Request, response: &mut Response) -> bool {
+        let Some(origin) = request.headers.get("origin") else {
+            return false;
+        };
+
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            response.add_header(Header::Custom(
+                "Access-Control-Allow-Origin".to_string(),
+                "*".to_string(),
+            ));
+        } else if self.origins.iter().any(|allowed| allowed == origin) {
+            response.add_header(Header::Custom(
+                "Access-Control-Allow-Origin".to_string(),
+                origin.to_string(),
+            ));
+            // Caches mustn't hand this answer to other origins
+            response.add_header(Header::Custom("Vary".to_string(), "Origin".to_string()));
+        } else {
+            return false;
+        }
+
+        true
+    }
+
+    /// Answers a preflight for a path that handles `allowed` methods
+    ///
+    /// Origins that aren't allowed get the response without any CORS headers, which the browser
+    /// takes as a refusal.
+    pub fn preflight(&self, request: &Request, allowed: &[Method], response: &mut Response) {
+        if !self.allow_origin(request, response) {
+            return;
+        }
+
+        response.add_header(Header::Custom(
+            "Access-Control-Allow-Methods".to_string(),
+            allowed
+                .iter()
+                .filter(|method| self.methods.is_empty() || self.methods.contains(method))
+                .map(Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+        ));
+        let headers = if self.headers.is_empty() {
+            request
+                .headers
+                .get("access-control-request-headers")
+                .map(ToString::to_string)
+        } else {
+            Some(self.headers.join(", "))
+        };
+        if let Some(headers) = headers {
+            response.add_header(Header::Custom(
+                "Access-Control-Allow-Headers".to_string(),
+                headers,
+            ));
+        }
+        if let Some(max_age) = self.max_age {
+            response.add_header(Header::Custom(
+                "Access-Control-Max-Age".to_string(),
+                max_age.to_string(),
+            ));
+        }
+        if let Some(caching) = self.caching {
+            let mut cache_control = match caching {
+                Caching::Public => "public".to_string(),
+                Caching::Private => "private".to_string(),
+            };
+            if let Some(max_age) = self.max_age {
+                cache_control.push_str(&format!(", max-age={max_age}"));
+            }
+            response.add_header(Header::Custom("Cache-Control".to_string(), cache_control));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{response::StatusCode, testing::TestRequest};
+
+    fn preflight(cors: &Cors, origin: &str) -> Vec<u8> {
+        let request = TestRequest::new(Method::Options, "/api/files")
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "DELETE")
+            .header("Access-Control-Request-Headers", "authorization")
+            .build();
+        let mut response = Response::no_content();
+        assert!(Cors::is_preflight(&request));
+
+        cors.preflight(&request, &[Method::Get, Method::Delete], &mut response);
+        response.encode()
+    }
+
+    #[test]
+    fn preflights_can_be_cached() {
+        let cors = Cors::new(&["https://example.com"])
+            .max_age(600)
+            .caching(Caching::Private);
+
+        assert_eq!(
+            preflight(&cors, "https://example.com"),
+            b"HTTP/1.1 204 No Content\r\n\
+            Access-Control-Allow-Origin: https://example.com\r\n\
+            Vary: Origin\r\n\
+            Access-Control-Allow-Methods: GET, DELETE\r\n\
+            Access-Control-Allow-Headers: authorization\r\n\
+            Access-Control-Max-Age: 600\r\n\
+            Cache-Control: private, max-age=600\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn methods_and_headers_can_be_limited() {
+        let cors = Cors::new(&["*"])
+            .methods(&[Method::Get])
+            .headers(&["content-type", "x-api-key"]);
+
+        assert_eq!(
+            preflight(&cors, "https://example.com"),
+            b"HTTP/1.1 204 No Content\r\n\
+            Access-Control-Allow-Origin: *\r\n\
+            Access-Control-Allow-Methods: GET\r\n\
+            Access-Control-Allow-Headers: content-type, x-api-key\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn other_origins_are_refused() {
+        let cors = Cors::new(&["https://example.com"]).max_age(600);
+
+        assert_eq!(
+            preflight(&cors, "https://evil.example"),
+            b"HTTP/1.1 204 No Content\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn any_origin() {
+        let request = TestRequest::get("/api/files")
+            .header("Origin", "https://example.com")
+            .build();
+        let mut response = Response::new(StatusCode::Ok);
+
+        assert!(!Cors::is_preflight(&request));
+        assert!(Cors::new(&["*"]).allow_origin(&request, &mut response));
+        assert_eq!(
+            response.encode(),
+            b"HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn requested_headers_cannot_inject_others() {
+        // HTTP/1.1 frames values by line, but h2 is only asked not to send CR or LF
+        let request = TestRequest::new(Method::Options, "/api/files")
+            .header("Origin", "https://example.com")
+            .header("Access-Control-Request-Method", "DELETE")
+            .header("Access-Control-Request-Headers", "a\r\nSet-Cookie: x=1")
+            .build();
+        let mut response = Response::no_content();
+
+        Cors::new(&["*"]).preflight(&request, &[Method::Delete], &mut response);
+        assert_eq!(
+            response.encode(),
+            b"HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Origin: *\r\n\
+            Access-Control-Allow-Methods: DELETE\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! `--dump-dir`: every byte each connection reads and writes, as it went over the wire (after TLS,
+//! before anything is decoded), for finding out what a misbehaving client really sent
+//!
+//! Each connection gets a `.received` and a `.sent` file, named after when it was accepted, so
+//! what a client sent can be replayed (eg, `nc localhost 4221 < 1700000000.123456-7.received`).
+
+use crate::{
+    connection::{ReadTimeout, Shutdownable},
+    redact,
+    sendfile::SendFile,
+};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{debug, warn};
+
+/// Numbers the dumps, so connections accepted in the same microsecond don't share files
+static DUMPED: AtomicU64 = AtomicU64::new(0);
+
+/// Lines are held back until they end, to check for a masked header, up to this long (headers
+/// that are any longer are refused anyway)
+const MAX_LINE: usize = 16 * 1024;
+
+/// Where a connection's bytes are copied, one file for each direction
+#[derive(Debug)]
+pub struct Dump {
+    received: Side,
+    sent: Side,
+    /// Headers whose values are masked, in lowercase, or `None` to copy everything as it is
+    masked: Option<Vec<String>>,
+}
+
+impl Dump {
+    /// Starts the files for a connection accepted now, in `directory`, masking the values of the
+    /// `redact::ALWAYS` headers and `redacted` when `redact` is set
+    pub fn create(directory: &Path, redact: bool, redacted: &[String]) -> io::Result<Self> {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        let name = format!(
+            "{}.{:06}-{}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros(),
+            DUMPED.fetch_add(1, Ordering::Relaxed)
+        );
+        let side = |extension: &str| {
+            File::create_new(directory.join(format!("{name}.{extension}"))).map(Side::new)
+        };
+        debug!(directory = %directory.display(), name, "Dumping connection");
+
+        Ok(Self {
+            received: side("received")?,
+            sent: side("sent")?,
+            masked: redact.then(|| {
+                redact::ALWAYS
+                    .iter()
+                    .map(ToString::to_string)
+                    .chain(redacted.iter().cloned())
+                    .collect()
+            }),
+        })
+    }
+}
+
+/// A direction's file, and the start of a line not yet written, while headers are being masked
+#[derive(Debug)]
+struct Side {
+    file: File,
+    line: Vec<u8>,
+}
+
+impl Side {
+    const fn new(file: File) -> Self {
+        Self { file, line: vec![] }
+    }
+
+    fn write(&mut self, bytes: &[u8], masked: Option<&[String]>) -> io::Result<()> {
+        let Some(masked) = masked else {
+            return self.file.write_all(bytes);
+        };
+
+        self.line.extend_from_slice(bytes);
+        let mut start = 0;
+        while let Some(end) = self.line[start..].iter().position(|&byte| byte == b'\n') {
+            let line = &self.line[start..=start + end];
+            self.file.write_all(&mask(line, masked))?;
+            start += end + 1;
+        }
+        self.line.drain(..start);
+        // Not a header, so there's nothing to mask, and no reason to hold it all in memory
+        if self.line.len() > MAX_LINE {
+            self.file.write_all(&self.line)?;
+            self.line.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Writes whatever was held back, as the connection has ended without finishing the line
+    fn finish(&mut self) -> io::Result<()> {
+        let line = std::mem::take(&mut self.line);
+        self.file.write_all(&line)
+    }
+}
+
+/// `line` with the value replaced, if it is one of the `masked` headers
+fn mask<'a>(line: &'a [u8], masked: &[String]) -> Cow<'a, [u8]> {
+    let Some(colon) = line.iter().position(|&byte| byte == b':') else {
+        return line.into();
+    };
+    let name = String::from_utf8_lossy(&line[..colon]).to_ascii_lowercase();
+    if !masked.contains(&name) {
+        return line.into();
+    }
+
+    let ending: &[u8] = if line.ends_with(b"\r\n") {
+        b"\r\n"
+    } else {
+        b"\n"
+    };
+    [&line[..=colon], b" ", redact::MASK.as_bytes(), ending]
+        .concat()
+        .into()
+}
+
+/// A stream that copies what is read from and written to it into a `Dump`, if it has one
+#[derive(Debug)]
+pub struct Dumped<T> {
+    inner: T,
+    dump: Option<Dump>,
+}
+
+impl<T> Dumped<T> {
+    pub const fn new(inner: T, dump: Option<Dump>) -> Self {
+        Self { inner, dump }
+    }
+
+    pub const fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub const fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// The stream and the dump, eg to carry on dumping a stream made from this one
+    #[cfg(feature = "async")]
+    pub fn into_parts(self) -> (T, Option<Dump>) {
+        (self.inner, self.dump)
+    }
+
+    /// Copies `bytes` into the dump, giving up on it (rather than the connection) if that fails
+    fn record(&mut self, received: bool, bytes: &[u8]) {
+        let Some(dump) = &mut self.dump else {
+            return;
+        };
+        let side = if received {
+            &mut dump.received
+        } else {
+            &mut dump.sent
+        };
+        if let Err(err) = side.write(bytes, dump.masked.as_deref()) {
+            warn!("Stopped dumping the connection: {err}");
+            self.dump = None;
+        }
+    }
+}
+
+impl<T: Read> Read for Dumped<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.record(true, &buf[..read]);
+
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Dumped<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.record(false, &buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Shutdownable> Shutdownable for Dumped<T> {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+impl<T: ReadTimeout> ReadTimeout for Dumped<T> {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+/// Files sent by the kernel would never pass through the dump, so they are copied while dumping
+impl<T: SendFile> SendFile for Dumped<T> {
+    fn socket(&self) -> Option<&TcpStream> {
+        if self.dump.is_some() {
+            return None;
+        }
+
+        self.inner.socket()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncRead + Unpin> AsyncRead for Dumped<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.record(true, &buf.filled()[before..]);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncWrite + Unpin> AsyncWrite for Dumped<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.record(false, &buf[..written]);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Drop for Dump {
+    fn drop(&mut self) {
+        if let Err(err) = self.received.finish().and_then(|()| self.sent.finish()) {
+            warn!("Unable to finish dumping the connection: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::test_directory;
+    use std::{fs, path::PathBuf};
+
+    /// The contents of the only file in `directory` with `extension`
+    fn dumped(directory: &Path, extension: &str) -> String {
+        let paths: Vec<_> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|found| found == extension))
+            .collect();
+        assert_eq!(paths.len(), 1, "{paths:?}");
+
+        fs::read_to_string(&paths[0]).unwrap()
+    }
+
+    #[test]
+    fn both_directions_are_copied_as_they_are() -> io::Result<()> {
+        let directory = PathBuf::from(test_directory("both_directions_are_copied_as_they_are"));
+        let request = b"GET / HTTP/1.1\r\nAuthorization: Basic c2VjcmV0\r\n\r\n";
+        let mut stream = Dumped::new(
+            io::Cursor::new(request.to_vec()),
+            Some(Dump::create(&directory, false, &[])?),
+        );
+
+        let mut received = vec![0; 10];
+        stream.read_exact(&mut received)?;
+        stream.read_to_end(&mut received)?;
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n")?;
+        drop(stream);
+
+        assert_eq!(dumped(&directory, "received").as_bytes(), request);
+        assert_eq!(dumped(&directory, "sent"), "HTTP/1.1 200 OK\r\n\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn secrets_can_be_masked() -> io::Result<()> {
+        let directory = PathBuf::from(test_directory("secrets_can_be_masked"));
+        let dump = Dump::create(&directory, true, &["x-api-key".to_string()])?;
+        let mut stream = Dumped::new(io::empty(), Some(dump));
+
+        // A header split across writes is still found
+        for part in [
+            "GET / HTTP/1.1\r\nAuthori",
+            "zation: Basic c2VjcmV0\r\nX-API-Key: secret\r\n",
+            "User-Agent: curl\r\n\r\nunfinished",
+        ] {
+            stream.record(true, part.as_bytes());
+        }
+        drop(stream);
+
+        assert_eq!(
+            dumped(&directory, "received"),
+            "GET / HTTP/1.1\r\nAuthorization: [redacted]\r\nX-API-Key: [redacted]\r\n\
+            User-Agent: curl\r\n\r\nunfinished"
+        );
+        Ok(())
+    }
+}
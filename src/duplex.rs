@@ -0,0 +1,209 @@
+//! An in-memory connection, so tests can talk to the server like a client would without
+//! scripting every read and write with mockall
+
+use crate::{
+    connection::{ReadTimeout, Shutdownable},
+    listener::Stream,
+    request::ReadPolicy,
+    sendfile::SendFile,
+};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    net::Shutdown,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// Bytes travelling in one direction
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    /// The writing end has shut down, so readers see EOF once the bytes run out
+    closed: bool,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap()
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+/// One end of an in-memory connection, reading what the other end writes
+///
+/// Clones share the connection, so dropping an end doesn't close it, shutting it down does.
+pub struct DuplexStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Cell<Option<Duration>>,
+}
+
+/// Both ends of a new connection
+pub fn pair() -> (DuplexStream, DuplexStream) {
+    let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>| DuplexStream {
+        incoming: Arc::clone(incoming),
+        outgoing: Arc::clone(outgoing),
+        read_timeout: Cell::new(None),
+    };
+
+    (end(&a, &b), end(&b, &a))
+}
+
+impl DuplexStream {
+    /// Reads until the other end shuts down writing
+    pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+}
+
+impl fmt::Debug for DuplexStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DuplexStream")
+    }
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.incoming.lock();
+        while state.bytes.is_empty() && !state.closed {
+            state = match self.read_timeout.get() {
+                Some(timeout) => {
+                    let (state, result) =
+                        self.incoming.readable.wait_timeout(state, timeout).unwrap();
+                    // Like a socket with `SO_RCVTIMEO` on Linux
+                    if result.timed_out() && state.bytes.is_empty() && !state.closed {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    state
+                }
+                None => self.incoming.readable.wait(state).unwrap(),
+            };
+        }
+
+        let length = buf.len().min(state.bytes.len());
+        for (byte, value) in buf.iter_mut().zip(state.bytes.drain(..length)) {
+            *byte = value;
+        }
+
+        Ok(length)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.lock();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.bytes.extend(buf);
+        self.outgoing.readable.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shutdownable for DuplexStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.incoming.close();
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.outgoing.close();
+        }
+
+        Ok(())
+    }
+}
+
+impl SendFile for DuplexStream {}
+
+impl ReadTimeout for DuplexStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.read_timeout.set(Some(timeout));
+
+        Ok(())
+    }
+}
+
+impl Stream for DuplexStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            incoming: Arc::clone(&self.incoming),
+            outgoing: Arc::clone(&self.outgoing),
+            read_timeout: self.read_timeout.clone(),
+        })
+    }
+
+    fn set_timeouts(&self, read: Duration, _write: Duration) -> io::Result<()> {
+        self.read_timeout.set(Some(read));
+
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        "memory".to_string()
+    }
+
+    /// Reads block until the other end writes or shuts down, like a socket
+    fn read_policy(&self) -> ReadPolicy {
+        ReadPolicy::BLOCKING
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bytes_flow_both_ways() -> io::Result<()> {
+        let (mut client, mut server) = pair();
+
+        client.write_all(b"ping")?;
+        client.shutdown(Shutdown::Write)?;
+        assert_eq!(server.read_all()?, b"ping");
+
+        server.write_all(b"pong")?;
+        server.shutdown(Shutdown::Both)?;
+        assert_eq!(client.read_all()?, b"pong");
+        assert_eq!(
+            client.write(b"again").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_time_out() {
+        let (_client, mut server) = pair();
+        server
+            .set_timeouts(Duration::from_millis(10), Duration::ZERO)
+            .unwrap();
+
+        assert_eq!(
+            server.read(&mut [0; 8]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+}
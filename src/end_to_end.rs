@@ -0,0 +1,183 @@
+//! The server as clients see it, over real TCP connections to a `TestServer`
+
+use crate::{
+    config::Config,
+    connection::KeepAlive,
+    request::Method,
+    response::StatusCode,
+    testing::{TestRequest, TestServer, test_directory},
+};
+use flate2::read::GzDecoder;
+use std::{fs, io::Read, path::Path, time::Duration};
+
+fn keep_alive(max_requests: usize) -> Config {
+    Config {
+        keep_alive: Some(KeepAlive {
+            timeout: Duration::from_secs(5),
+            max_requests,
+        }),
+        ..Config::default()
+    }
+}
+
+#[test]
+fn requests_share_a_kept_alive_connection() {
+    let server = TestServer::start(keep_alive(100));
+    let mut client = server.client();
+
+    client.get("/echo/one").assert_body("one");
+    client.get("/echo/two").assert_body("two");
+    client
+        .send(TestRequest::get("/echo/three").header("Connection", "close"))
+        .assert_header("Connection", "close")
+        .assert_body("three");
+    assert!(client.is_closed());
+}
+
+#[test]
+fn connections_close_after_max_requests() {
+    let server = TestServer::start(keep_alive(2));
+    let mut client = server.client();
+
+    client.get("/").assert_no_header("Connection");
+    client
+        .get("/")
+        .assert_status(StatusCode::Ok)
+        .assert_header("Connection", "close");
+    assert!(client.is_closed());
+}
+
+#[test]
+fn connections_close_after_every_response_without_keep_alive() {
+    let server = TestServer::start(Config::default());
+    let mut client = server.client();
+
+    client.get("/echo/once").assert_body("once");
+    assert!(client.is_closed());
+}
+
+#[test]
+fn responses_are_compressed_for_clients_that_accept_it() {
+    let server = TestServer::start(keep_alive(100));
+    let mut client = server.client();
+    let text = "compress me ".repeat(100);
+
+    let response = client.send(
+        TestRequest::get(&format!("/echo/{}", text.replace(' ', "%20")))
+            .header("Accept-Encoding", "gzip"),
+    );
+    response.assert_header("Content-Encoding", "gzip");
+    // `/echo` varies on `Accept` as well, in a `Vary` of its own
+    assert!(
+        response
+            .headers
+            .get_all("Vary")
+            .any(|vary| vary == "Accept-Encoding")
+    );
+    assert!(response.body.len() < text.len());
+    let mut decoded = String::new();
+    GzDecoder::new(&response.body[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, text);
+
+    client
+        .get("/echo/plain")
+        .assert_no_header("Content-Encoding")
+        .assert_body("plain");
+}
+
+#[test]
+fn files_are_written_read_and_deleted() {
+    let directory = test_directory("files_are_written_read_and_deleted");
+    let server = TestServer::start(Config {
+        directory: Some(directory.clone()),
+        ..keep_alive(100)
+    });
+    let mut client = server.client();
+
+    client
+        .send(TestRequest::put("/files/notes.txt").body("first draft"))
+        .assert_status(StatusCode::Created);
+    client
+        .get("/files/notes.txt")
+        .assert_status(StatusCode::Ok)
+        .assert_body("first draft");
+    client
+        .send(TestRequest::get("/files/notes.txt").header("Range", "bytes=6-10"))
+        .assert_status(StatusCode::PartialContent)
+        .assert_body("draft");
+    let head = client.send(TestRequest::new(Method::Head, "/files/notes.txt"));
+    head.assert_header("Content-Length", "11");
+    assert!(head.body.is_empty());
+
+    client
+        .send(TestRequest::delete("/files/notes.txt"))
+        .assert_status(StatusCode::NoContent);
+    client
+        .get("/files/notes.txt")
+        .assert_status(StatusCode::NotFound)
+        .assert_body_contains("<h1>404 Not Found</h1>");
+    assert!(!Path::new(&directory).join("notes.txt").exists());
+}
+
+#[test]
+fn large_files_arrive_whole() {
+    let directory = test_directory("large_files_arrive_whole");
+    let contents: Vec<u8> = (0..=255).cycle().take(1_000_000).collect();
+    fs::write(format!("{directory}/large.bin"), &contents).unwrap();
+    let server = TestServer::start(Config {
+        directory: Some(directory),
+        ..keep_alive(100)
+    });
+    let mut client = server.client();
+
+    let response = client.get("/files/large.bin");
+    response.assert_header("Content-Length", "1000000");
+    assert!(response.body == contents, "body differs");
+    // The connection is still in step for the next request
+    client.get("/echo/after").assert_body("after");
+}
+
+#[test]
+fn streamed_responses_are_chunked() {
+    let server = TestServer::start(keep_alive(100));
+    let mut client = server.client();
+
+    client
+        .get("/progress/1")
+        .assert_header("Transfer-Encoding", "chunked")
+        .assert_body_contains("step 1 of 1");
+    client.get("/echo/after").assert_body("after");
+}
+
+#[test]
+fn connections_can_be_dumped() {
+    let directory = test_directory("connections_can_be_dumped");
+    let server = TestServer::start(Config {
+        dump_dir: Some(directory.clone()),
+        ..Config::default()
+    });
+    server.client().get("/echo/dumped").assert_body("dumped");
+    drop(server);
+
+    let mut dumped: Vec<_> = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    dumped.sort();
+    assert_eq!(dumped.len(), 2, "{dumped:?}");
+    let received = fs::read_to_string(&dumped[0]).unwrap();
+    let sent = fs::read_to_string(&dumped[1]).unwrap();
+    assert!(
+        dumped[0]
+            .extension()
+            .is_some_and(|extension| extension == "received")
+    );
+    assert!(
+        received.starts_with("GET /echo/dumped HTTP/1.1\r\n"),
+        "{received}"
+    );
+    assert!(sent.starts_with("HTTP/1.1 200 OK\r\n"), "{sent}");
+    assert!(sent.ends_with("\r\n\r\ndumped"), "{sent}");
+}
@@ -0,0 +1,105 @@
+//! Pages for error responses that would otherwise have an empty body, when serving `--directory`
+//!
+//! `404.html` in the directory is used for 404s, and `error.html` for the rest (and 404s when
+//! there is no `404.html`). Without either, a minimal page saying what the status is stands in.
+
+use crate::{
+    files,
+    request::{Method, Request},
+    response::Response,
+};
+use std::{fs, path::Path};
+
+/// Gives a 403, 404 or 500 response without a body a page from `directory`, or a built-in one
+pub fn fill(directory: &str, request: &Request, response: Response) -> Response {
+    let code = response.status_code().code();
+    if !matches!(code, 403 | 404 | 500) || response.has_body() {
+        return response;
+    }
+
+    let pages: &[&str] = if code == 404 {
+        &["404.html", "error.html"]
+    } else {
+        &["error.html"]
+    };
+    let (content_type, page) = pages
+        .iter()
+        .map(|page| Path::new(directory).join(page))
+        .find_map(|path| Some((files::content_type(&path), fs::read(&path).ok()?)))
+        .unwrap_or_else(|| {
+            let status = response.status_code().to_string();
+            (
+                "text/html; charset=utf-8",
+                format!(
+                    "<!DOCTYPE html>\n<html><head><title>{status}</title></head><body><h1>{status}</h1></body></html>\n"
+                )
+                .into_bytes(),
+            )
+        });
+
+    let response = response.content_type(content_type).body_bytes(page);
+    // The Content-Length still says how big the page would have been
+    if matches!(request.method, Method::Head) {
+        response.for_head()
+    } else {
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        response::StatusCode,
+        testing::{TestRequest, TestResponse, test_directory},
+    };
+
+    #[test]
+    fn pages_come_from_the_directory() {
+        let directory = test_directory("pages_come_from_the_directory");
+        fs::write(format!("{directory}/404.html"), "<p>Lost</p>").unwrap();
+        fs::write(format!("{directory}/error.html"), "<p>Oops</p>").unwrap();
+        let request = TestRequest::get("/files/missing").build();
+
+        TestResponse::new(fill(&directory, &request, Response::not_found()))
+            .assert_header("Content-Type", "text/html; charset=utf-8")
+            .assert_body("<p>Lost</p>");
+        TestResponse::new(fill(
+            &directory,
+            &request,
+            Response::new(StatusCode::Forbidden),
+        ))
+        .assert_body("<p>Oops</p>");
+    }
+
+    #[test]
+    fn built_in_pages_stand_in_for_missing_ones() {
+        let directory = test_directory("built_in_pages_stand_in_for_missing_ones");
+        let request = TestRequest::get("/").build();
+
+        TestResponse::new(fill(
+            &directory,
+            &request,
+            Response::new(StatusCode::InternalServerError),
+        ))
+        .assert_body_contains("<h1>500 Internal Server Error</h1>");
+    }
+
+    #[test]
+    fn other_responses_are_left_alone() {
+        let directory = test_directory("other_responses_are_left_alone");
+        let request = TestRequest::get("/").build();
+
+        assert!(!fill(&directory, &request, Response::bad_request()).has_body());
+        TestResponse::new(fill(
+            &directory,
+            &request,
+            Response::not_found().body_str("custom"),
+        ))
+        .assert_body("custom");
+        let head = TestRequest::new(Method::Head, "/").build();
+        let response = fill(&directory, &head, Response::not_found());
+        assert!(!response.has_body());
+        assert!(response.headers().contains("content-length"));
+    }
+}
@@ -0,0 +1,824 @@
+//! An event loop (`--backend evented`) that waits on every connection with epoll (or kqueue),
+//! so idle and slow clients don't each hold on to a worker
+//!
+//! A connection only goes to the pool once its next request has arrived in full, and comes back
+//! to the loop once it has been answered. Bodies that can't be buffered up front (chunked, or
+//! waiting for `100 Continue`), upgraded connections and HTTP/2 are still read by the worker.
+
+use crate::{
+    config::Config,
+    connection::{self, Connection, ReadTimeout, Shutdownable},
+    h2,
+    limit::Permit,
+    listener::{self, Stream},
+    parser::Parser,
+    request::Limits,
+    response::{Response, StatusCode},
+    sendfile::SendFile,
+    server::{self, QueueFullPolicy, RECEIVE_TIMEOUT},
+    threadpool::{QueueFull, ThreadPool},
+};
+use mio::{Events, Interest, Poll, Registry, Token, Waker, unix::SourceFd};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    sync::{
+        Arc,
+        mpsc::{self, Sender},
+    },
+    task,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, warn};
+
+/// Wakes the loop when a worker hands a connection back
+const WAKER: Token = Token(usize::MAX);
+
+/// How often connections are checked for having waited too long
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connection whose reads start with what the loop has already read from it
+#[derive(Debug)]
+pub struct Evented {
+    inner: TcpStream,
+    pending: Vec<u8>,
+}
+
+impl Read for Evented {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            return self.inner.read(buf);
+        }
+        let read = buf.len().min(self.pending.len());
+        buf[..read].copy_from_slice(&self.pending[..read]);
+        self.pending.drain(..read);
+
+        Ok(read)
+    }
+}
+
+impl Write for Evented {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Shutdownable for Evented {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+impl SendFile for Evented {
+    fn socket(&self) -> Option<&TcpStream> {
+        Some(&self.inner)
+    }
+}
+
+impl ReadTimeout for Evented {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_read_timeout(Some(timeout))
+    }
+}
+
+impl Evented {
+    /// Bytes read from `inner` while waiting for a request, for the worker to read first
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+    }
+
+    pub const fn inner(&self) -> &TcpStream {
+        &self.inner
+    }
+}
+
+/// When the client on a connection last sent what, for how long it has left to send the rest
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    /// When the client last sent anything, or was last answered
+    since: Instant,
+    /// When the client started sending the next request, and when its headers were all in, for
+    /// `Config::deadlines`
+    started: Option<Instant>,
+    head_received: Option<Instant>,
+}
+
+impl Timing {
+    /// Starts timing a new connection
+    pub fn now() -> Self {
+        Self {
+            since: Instant::now(),
+            started: None,
+            head_received: None,
+        }
+    }
+
+    /// Starts waiting for the next request, once one has been answered, which may have been
+    /// pipelined after it already
+    pub fn answered(&mut self, pipelined: bool) {
+        self.since = Instant::now();
+        self.started = pipelined.then_some(self.since);
+        self.head_received = None;
+    }
+
+    /// Notes the client sending more, and whether that completed the headers of its request
+    pub fn received(&mut self, head_arrived: bool) {
+        let now = Instant::now();
+        self.since = now;
+        self.started.get_or_insert(now);
+        if self.head_received.is_none() && head_arrived {
+            self.head_received = Some(now);
+        }
+    }
+
+    /// How much longer the client has to send something, or the rest of its headers or body if
+    /// that is sooner: the keep-alive timeout between requests, or the usual receive timeout
+    /// during one
+    pub fn time_left(&self, config: &Config, between_requests: bool) -> Duration {
+        let patience = match config.keep_alive {
+            Some(keep_alive) if between_requests => keep_alive.timeout,
+            _ => Duration::from_secs(RECEIVE_TIMEOUT),
+        };
+        let now = Instant::now();
+        let idle = (self.since + patience).saturating_duration_since(now);
+        let deadline = match self.head_received {
+            Some(received) => config.deadlines.body.map(|body| received + body),
+            None => self
+                .started
+                .zip(config.deadlines.head)
+                .map(|(started, head)| started + head),
+        };
+
+        deadline.map_or(idle, |deadline| {
+            idle.min(deadline.saturating_duration_since(now))
+        })
+    }
+}
+
+/// How much of the next request on a connection has arrived, worked out from each piece as it
+/// does, so nothing received is parsed twice
+pub struct Arrival {
+    parser: Parser,
+    limits: Limits,
+    progress: Progress,
+    /// The first bytes of the connection while they could still be the HTTP/2 preface
+    preface: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy)]
+enum Progress {
+    Head,
+    /// The head is in, but this much of the body is still to come
+    Body(usize),
+    /// It can be answered without waiting on the client
+    Ready,
+}
+
+impl Arrival {
+    /// Waits for a new connection's first request, or the HTTP/2 preface if that is spoken
+    pub fn new(config: &Config) -> Self {
+        Self {
+            parser: Parser::new(config.limits),
+            limits: config.limits,
+            progress: Progress::Head,
+            preface: config.http2.then(Vec::new),
+        }
+    }
+
+    /// Starts on the next request, once one has been answered, given what is left of everything
+    /// received
+    pub fn next(&mut self, received: &[u8]) {
+        self.parser = Parser::new(self.limits);
+        self.progress = Progress::Head;
+        self.preface = None;
+        self.feed(received);
+    }
+
+    /// Takes the next piece of what the client has sent
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if let Some(preface) = &mut self.preface {
+            let wanted = h2::PREFACE.len() - preface.len();
+            preface.extend_from_slice(&bytes[..wanted.min(bytes.len())]);
+            if !h2::PREFACE.starts_with(preface) {
+                self.preface = None;
+            }
+        }
+
+        self.progress = match self.progress {
+            Progress::Head => match self.parser.feed(bytes) {
+                Ok(task::Poll::Ready(request)) => {
+                    let body = bytes.len() - self.parser.used();
+                    match request.body_to_wait_for(self.limits).saturating_sub(body) {
+                        0 => Progress::Ready,
+                        rest => Progress::Body(rest),
+                    }
+                }
+                Ok(task::Poll::Pending) => Progress::Head,
+                // Whoever reads it answers it straight away
+                Err(_) => Progress::Ready,
+            },
+            Progress::Body(rest) if rest > bytes.len() => Progress::Body(rest - bytes.len()),
+            Progress::Body(_) | Progress::Ready => Progress::Ready,
+        };
+    }
+
+    /// Whether the request can be answered from what has arrived, without waiting on the client
+    pub fn is_ready(&self) -> bool {
+        match &self.preface {
+            Some(preface) if preface.len() == h2::PREFACE.len() => true,
+            Some(_) => false,
+            None => matches!(self.progress, Progress::Ready),
+        }
+    }
+
+    /// Whether the request's headers are all in
+    pub const fn head_arrived(&self) -> bool {
+        !matches!(self.progress, Progress::Head)
+    }
+}
+
+/// A connection waiting for its next request to arrive
+pub struct Waiting {
+    pub connection: Connection<Evented>,
+    /// How many requests have been answered on it
+    pub served: usize,
+    timing: Timing,
+    arrival: Arrival,
+}
+
+impl Waiting {
+    pub fn new(stream: TcpStream, peer: String, permit: Permit, config: &Arc<Config>) -> Self {
+        let stream = Evented {
+            inner: stream,
+            pending: vec![],
+        };
+
+        Self {
+            connection: Connection::new(stream, Arc::clone(config))
+                .peer(peer)
+                .permit(permit),
+            served: 0,
+            timing: Timing::now(),
+            arrival: Arrival::new(config),
+        }
+    }
+
+    /// The connection, once a request on it has been answered and the client may send another
+    #[must_use]
+    pub fn answered(mut self) -> Self {
+        self.served += 1;
+        let received = self.received();
+        self.arrival.next(&received);
+        self.timing.answered(!received.is_empty());
+        self
+    }
+
+    /// Keeps what the client has sent for the worker to read
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.connection.stream_mut().receive(bytes);
+        self.arrival.feed(bytes);
+        self.timing.received(self.arrival.head_arrived());
+    }
+
+    /// Reads what the client has sent, as readiness is only reported once for it. False if the
+    /// client has gone.
+    fn fill(&mut self) -> bool {
+        let mut buffer = [0; 8192];
+        loop {
+            match self.connection.stream_mut().inner.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(read) => self.receive(&buffer[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Everything received but not yet answered, including pipelined requests
+    fn received(&self) -> Vec<u8> {
+        let mut received = self.connection.buffered().to_vec();
+        received.extend_from_slice(&self.connection.stream().pending);
+        received
+    }
+
+    /// Whether anything has been received that is yet to be answered
+    fn has_received(&self) -> bool {
+        !self.connection.buffered().is_empty() || !self.connection.stream().pending.is_empty()
+    }
+
+    /// Whether a worker can answer the next request without waiting on the client
+    pub fn is_ready(&self) -> bool {
+        self.arrival.is_ready()
+    }
+
+    /// How much longer the client has to send something
+    pub fn time_left(&self, config: &Config) -> Duration {
+        let between_requests = self.served > 0 && !self.has_received();
+        self.timing.time_left(config, between_requests)
+    }
+
+    /// Gives up on the client, telling it why if it was part way through a request
+    pub fn time_out(mut self) {
+        if !self.has_received() {
+            debug!("Closing idle connection");
+            return;
+        }
+        debug!("Timed out waiting for request");
+        let response = Response::new(StatusCode::RequestTimeout)
+            .header(connection::close())
+            .encode();
+        if let Err(err) = self.connection.stream_mut().write_all(&response) {
+            debug!("Error timing out connection: {err}");
+        }
+    }
+
+    fn fd(&self) -> RawFd {
+        self.connection.stream().inner().as_raw_fd()
+    }
+}
+
+/// Accepts connections from the `listeners` and waits for their requests, handing each one that
+/// has arrived to the pool, until a listener fails or the server is stopping
+pub fn serve(
+    listeners: Vec<TcpListener>,
+    config: &Arc<Config>,
+    pool: &ThreadPool,
+    on_queue_full: QueueFullPolicy,
+) -> io::Result<()> {
+    let result = EventLoop::new(listeners, config, pool, on_queue_full)
+        .and_then(|mut event_loop| event_loop.run());
+    config.health.set_accepting(false);
+    if let Err(err) = &result {
+        error!("Stopped accepting: {err}");
+    }
+
+    result
+}
+
+struct EventLoop<'a> {
+    poll: Poll,
+    waker: Arc<Waker>,
+    /// Registered with the tokens before any connection's
+    listeners: Vec<TcpListener>,
+    waiting: HashMap<Token, Waiting>,
+    /// Connections coming back from the workers
+    returned: mpsc::Receiver<(Token, Waiting)>,
+    returner: Sender<(Token, Waiting)>,
+    next_token: usize,
+    /// Whether connections were left in the listen backlog, as there were too many open
+    held_back: bool,
+    /// When to try accepting again, after running short of something (eg, file descriptors)
+    accept_after: Option<Instant>,
+    config: &'a Arc<Config>,
+    pool: &'a ThreadPool,
+    on_queue_full: QueueFullPolicy,
+}
+
+impl<'a> EventLoop<'a> {
+    fn new(
+        listeners: Vec<TcpListener>,
+        config: &'a Arc<Config>,
+        pool: &'a ThreadPool,
+        on_queue_full: QueueFullPolicy,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        for (index, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            poll.registry().register(
+                &mut SourceFd(&listener.as_raw_fd()),
+                Token(index),
+                Interest::READABLE,
+            )?;
+        }
+        let (returner, returned) = mpsc::channel();
+
+        Ok(Self {
+            poll,
+            waker,
+            next_token: listeners.len(),
+            held_back: false,
+            accept_after: None,
+            listeners,
+            waiting: HashMap::new(),
+            returned,
+            returner,
+            config,
+            pool,
+            on_queue_full,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        self.config.health.set_accepting(true);
+        loop {
+            let timeout = self.accept_after.map_or(SWEEP_INTERVAL, |after| {
+                after
+                    .saturating_duration_since(Instant::now())
+                    .min(SWEEP_INTERVAL)
+            });
+            match self.poll.poll(&mut events, Some(timeout)) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
+            for event in &events {
+                match event.token() {
+                    WAKER => {}
+                    Token(index) if index < self.listeners.len() => self.accept(index)?,
+                    token => self.readable(token),
+                }
+            }
+            // Connections waiting for a request are closed, those being answered are the pool's
+            if self.config.health.is_stopping() {
+                return Ok(());
+            }
+            while let Ok((token, waiting)) = self.returned.try_recv() {
+                self.resume(token, waiting);
+            }
+            self.sweep();
+            // Readiness was reported while there was no room, so won't be again
+            if self.held_back
+                && !self.config.connections.is_full()
+                && self
+                    .accept_after
+                    .is_none_or(|after| Instant::now() >= after)
+            {
+                self.held_back = false;
+                self.accept_after = None;
+                for index in 0..self.listeners.len() {
+                    self.accept(index)?;
+                }
+            }
+        }
+    }
+
+    /// Takes every connection waiting on the listener
+    fn accept(&mut self, index: usize) -> io::Result<()> {
+        let connections = &self.config.connections;
+        loop {
+            if connections.when_full() == QueueFullPolicy::Block && connections.is_full() {
+                self.held_back = true;
+                return Ok(());
+            }
+            let stream = match self.listeners[index].accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted
+                    ) =>
+                {
+                    continue;
+                }
+                // Tried again once connections closing may have freed up what ran short
+                Err(err) if listener::is_transient(&err) => {
+                    warn!("Error accepting a connection: {err}");
+                    self.held_back = true;
+                    self.accept_after = Some(Instant::now() + listener::ACCEPT_BACKOFF);
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            let peer = stream.peer();
+            // Only this connection is given up on, the listener carries on
+            if let Err(err) = stream.set_timeouts(
+                Duration::from_secs(RECEIVE_TIMEOUT),
+                Duration::from_secs(server::SEND_TIMEOUT),
+            ) {
+                warn!(peer, "Dropping connection without timeouts: {err}");
+                continue;
+            }
+            if !self.config.trusted_proxies.trusts(&peer)
+                && !self.config.ip_filter.load().permits(&peer)
+            {
+                debug!(peer, "Refusing connection");
+                server::refuse(stream, StatusCode::Forbidden);
+                continue;
+            }
+            let Some(permit) = connections.try_acquire() else {
+                warn!(peer, "Too many connections, shedding connection");
+                server::shed(stream);
+                continue;
+            };
+            if let Err(err) = stream.set_nonblocking(true) {
+                warn!(peer, "Dropping connection left blocking: {err}");
+                continue;
+            }
+
+            let waiting = Waiting::new(stream, peer, permit, self.config);
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            self.wait(token, waiting);
+        }
+    }
+
+    /// Reads what has arrived on a connection, handing it to a worker if that completes a request
+    fn readable(&mut self, token: Token) {
+        let Some(waiting) = self.waiting.get_mut(&token) else {
+            return;
+        };
+        if !waiting.fill() {
+            debug!("Client closed connection");
+            self.close(token);
+            return;
+        }
+        if waiting.is_ready()
+            && let Some(waiting) = self.waiting.remove(&token)
+        {
+            self.dispatch(token, waiting);
+        }
+    }
+
+    /// Watches a new or returned connection for its next request, which may already be here
+    fn wait(&mut self, token: Token, waiting: Waiting) {
+        let registered =
+            self.poll
+                .registry()
+                .register(&mut SourceFd(&waiting.fd()), token, Interest::READABLE);
+        if let Err(err) = registered {
+            warn!("Error watching connection: {err}");
+            return;
+        }
+        self.waiting.insert(token, waiting);
+        self.readable(token);
+    }
+
+    fn resume(&mut self, token: Token, waiting: Waiting) {
+        if let Err(err) = waiting.connection.stream().inner().set_nonblocking(true) {
+            warn!("Error resuming connection: {err}");
+            return;
+        }
+        // Pipelined requests are answered straight away
+        if waiting.is_ready() {
+            self.dispatch(token, waiting);
+        } else {
+            self.wait(token, waiting);
+        }
+    }
+
+    /// Hands a connection whose request has arrived to a worker, which hands it back if the
+    /// client may send another
+    fn dispatch(&mut self, token: Token, waiting: Waiting) {
+        // Only registered once it has been waited on
+        let _ = deregister(self.poll.registry(), &waiting);
+        let stream = waiting.connection.stream().inner();
+        if let Err(err) = stream.set_nonblocking(false) {
+            warn!("Error handing over connection: {err}");
+            return;
+        }
+        let overflow = match self.on_queue_full {
+            QueueFullPolicy::Block => None,
+            QueueFullPolicy::Shed => stream.try_clone().ok(),
+        };
+
+        let (returner, waker) = (self.returner.clone(), Arc::clone(&self.waker));
+        let metrics = Arc::clone(&self.config.metrics);
+        let job = move || {
+            let mut waiting = waiting;
+            match waiting.connection.exchange(waiting.served) {
+                Ok(true) => {
+                    if returner.send((token, waiting.answered())).is_ok()
+                        && let Err(err) = waker.wake()
+                    {
+                        warn!("Error waking event loop: {err}");
+                    }
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    error!("Connection error: {err}");
+                    metrics.connection_aborted();
+                }
+            }
+        };
+
+        match overflow {
+            None => self.pool.execute(job),
+            Some(overflow) => {
+                if let Err(QueueFull(job)) = self.pool.try_execute(job) {
+                    warn!("Queue full, shedding connection");
+                    server::shed(overflow);
+                    // Dropping the job shuts the connection down, so only once 503 is sent
+                    drop(job);
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(waiting) = self.waiting.remove(&token) {
+            let _ = deregister(self.poll.registry(), &waiting);
+        }
+    }
+
+    /// Closes the connections whose clients have kept them waiting too long, telling those that
+    /// were part way through a request why
+    fn sweep(&mut self) {
+        let expired = self
+            .waiting
+            .iter()
+            .filter(|(_, waiting)| waiting.time_left(self.config).is_zero())
+            .map(|(&token, _)| token)
+            .collect::<Vec<_>>();
+
+        for token in expired {
+            if let Some(waiting) = self.waiting.remove(&token) {
+                let _ = deregister(self.poll.registry(), &waiting);
+                waiting.time_out();
+            }
+        }
+    }
+}
+
+fn deregister(registry: &Registry, waiting: &Waiting) -> io::Result<()> {
+    registry.deregister(&mut SourceFd(&waiting.fd()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::KeepAlive;
+    use std::thread;
+
+    /// Serves on a port of its own, for as long as the tests run
+    fn start(config: Config) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let pool = ThreadPool::builder(1).build().unwrap();
+            serve(
+                vec![listener],
+                &Arc::new(config),
+                &pool,
+                QueueFullPolicy::Block,
+            )
+        });
+
+        address
+    }
+
+    fn keep_alive() -> Config {
+        Config {
+            keep_alive: Some(KeepAlive {
+                timeout: Duration::from_secs(5),
+                max_requests: 100,
+            }),
+            date: false,
+            ..Config::default()
+        }
+    }
+
+    /// Reads one response, leaving chunked bodies encoded
+    fn response(client: &mut TcpStream) -> String {
+        let read_until = |client: &mut TcpStream, response: &mut Vec<u8>, end: &[u8]| {
+            let mut byte = [0];
+            while !response.ends_with(end) {
+                client.read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+            }
+        };
+        let mut response = vec![];
+        read_until(client, &mut response, b"\r\n\r\n");
+        let head = String::from_utf8(response.clone()).unwrap();
+        if head.contains("Transfer-Encoding: chunked") {
+            read_until(client, &mut response, b"0\r\n\r\n");
+            return String::from_utf8(response).unwrap();
+        }
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        client.read_exact(&mut body).unwrap();
+
+        head + std::str::from_utf8(&body).unwrap()
+    }
+
+    /// Whether `bytes` would be ready to answer, given all at once and a byte at a time
+    fn ready(bytes: &[u8], config: &Config) -> bool {
+        let mut whole = Arrival::new(config);
+        whole.feed(bytes);
+        let mut pieces = Arrival::new(config);
+        for byte in bytes {
+            pieces.feed(&[*byte]);
+        }
+        assert_eq!(whole.is_ready(), pieces.is_ready());
+
+        whole.is_ready()
+    }
+
+    #[test]
+    fn requests_are_ready_once_they_need_nothing_more_from_the_client() {
+        let config = Config {
+            limits: Limits {
+                max_head: Some(64),
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+        let ready = |bytes: &[u8]| ready(bytes, &config);
+
+        assert!(!ready(b""));
+        assert!(!ready(b"GET / HTTP/1.1\r\nHost: x\r\n"));
+        assert!(ready(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert!(!ready(
+            b"PUT / HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nh"
+        ));
+        assert!(ready(
+            b"PUT / HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nhi"
+        ));
+        assert!(ready(
+            b"PUT / HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n"
+        ));
+        assert!(ready(
+            b"PUT / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n"
+        ));
+        // Whoever reads these answers them straight away
+        assert!(ready(b"GET / HTTP/9\r\n\r\n"));
+        assert!(ready(&[b'a'; 65]));
+    }
+
+    #[test]
+    fn the_http2_preface_is_only_waited_for_first() {
+        let config = Config {
+            http2: true,
+            ..Config::default()
+        };
+        assert!(!ready(&h2::PREFACE[..10], &config));
+        assert!(ready(h2::PREFACE, &config));
+        assert!(ready(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n", &config));
+
+        let mut arrival = Arrival::new(&config);
+        arrival.feed(b"GET / HTTP/1.1\r\nHost: x\r\n\r\nPRI * HTTP/2.0\r\n\r\n");
+        // Later on it is only an HTTP/1.1 request, to be rejected
+        arrival.next(b"PRI * HTTP/2.0\r\n\r\n");
+        assert!(arrival.is_ready());
+    }
+
+    #[test]
+    fn idle_clients_dont_hold_the_only_worker() {
+        let address = start(keep_alive());
+        // Neither of these has sent a whole request yet
+        let mut idle = TcpStream::connect(&address).unwrap();
+        let mut slow = TcpStream::connect(&address).unwrap();
+        slow.write_all(b"GET /echo/slow HTTP/1.1\r\nHost: loc")
+            .unwrap();
+
+        let mut client = TcpStream::connect(&address).unwrap();
+        for path in ["one", "two"] {
+            write!(
+                client,
+                "GET /echo/{path} HTTP/1.1\r\nHost: localhost\r\n\r\n"
+            )
+            .unwrap();
+            assert!(response(&mut client).ends_with(&format!("\r\n\r\n{path}")));
+        }
+
+        slow.write_all(b"alhost\r\n\r\n").unwrap();
+        assert!(response(&mut slow).ends_with("\r\n\r\nslow"));
+        idle.write_all(b"GET /echo/idle HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        assert!(response(&mut idle).ends_with("\r\n\r\nidle"));
+    }
+
+    #[test]
+    fn pipelined_requests_and_bodies() {
+        let address = start(keep_alive());
+        let mut client = TcpStream::connect(&address).unwrap();
+        client
+            .write_all(
+                b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                GET /echo/two HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .unwrap();
+        assert!(response(&mut client).ends_with("\r\n\r\none"));
+        assert!(response(&mut client).ends_with("\r\n\r\ntwo"));
+
+        client
+            .write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhel")
+            .unwrap();
+        client.write_all(b"lo").unwrap();
+        assert!(response(&mut client).ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn closes_after_the_last_request() {
+        let address = start(Config::default());
+        let mut client = TcpStream::connect(&address).unwrap();
+        client
+            .write_all(b"GET /echo/bye HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nbye"));
+    }
+}
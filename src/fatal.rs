@@ -0,0 +1,102 @@
+//! Errors that stop the server, each with its own process exit code so scripts and supervisors
+//! can tell a bad deployment from a crash
+//!
+//! The codes follow `sysexits.h`, and clap already exits with 2 for invalid arguments. TLS only
+//! uses a certificate made at startup, so failing to set it up is a runtime error.
+
+use std::{io, process::ExitCode};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Fatal {
+    /// The options parsed, but don't make sense together (or point at something unusable)
+    #[error("Invalid configuration: {0:#}")]
+    Config(anyhow::Error),
+    #[error("Unable to listen on {address}: {source}")]
+    Bind { address: String, source: io::Error },
+    /// Something went wrong once the server was up and running
+    #[error("{0:#}")]
+    Runtime(anyhow::Error),
+}
+
+impl Fatal {
+    pub const fn exit_code(&self) -> u8 {
+        match self {
+            // EX_CONFIG
+            Self::Config(_) => 78,
+            // EX_UNAVAILABLE
+            Self::Bind { .. } => 69,
+            // EX_SOFTWARE
+            Self::Runtime(_) => 70,
+        }
+    }
+
+    /// What the operator can do about it, where that isn't obvious from the error itself
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Bind { source, .. } => match source.kind() {
+                io::ErrorKind::AddrInUse => {
+                    Some("Is the server already running? Use --address to listen elsewhere")
+                }
+                io::ErrorKind::PermissionDenied => {
+                    Some("Ports below 1024 usually need elevated privileges")
+                }
+                io::ErrorKind::AddrNotAvailable => {
+                    Some("The address must belong to one of this machine's interfaces")
+                }
+                _ => None,
+            },
+            Self::Config(_) | Self::Runtime(_) => None,
+        }
+    }
+
+    /// Prints the error (and hint) for the operator, giving the code to exit with
+    pub fn report(&self) -> ExitCode {
+        eprintln!("Error: {self}");
+        if let Some(hint) = self.hint() {
+            eprintln!("Hint: {hint}");
+        }
+
+        ExitCode::from(self.exit_code())
+    }
+}
+
+impl From<io::Error> for Fatal {
+    fn from(error: io::Error) -> Self {
+        Self::Runtime(error.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn each_kind_of_error_has_its_own_code() {
+        let bind = Fatal::Bind {
+            address: "127.0.0.1:80".to_string(),
+            source: io::ErrorKind::PermissionDenied.into(),
+        };
+        let config = Fatal::Config(anyhow!("Empty CPU list"));
+        let runtime = Fatal::from(io::Error::other("oops"));
+
+        assert_eq!(bind.exit_code(), 69);
+        assert_eq!(config.exit_code(), 78);
+        assert_eq!(runtime.exit_code(), 70);
+    }
+
+    #[test]
+    fn bind_errors_suggest_a_fix() {
+        let error = Fatal::Bind {
+            address: "127.0.0.1:4221".to_string(),
+            source: io::ErrorKind::AddrInUse.into(),
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "Unable to listen on 127.0.0.1:4221: address in use"
+        );
+        assert!(error.hint().unwrap().contains("--address"));
+    }
+}
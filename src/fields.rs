@@ -0,0 +1,169 @@
+//! A request's header (or trailer) fields, held as slices of the buffer its head arrived in, so
+//! parsing a request doesn't copy each name and value into a `String` of its own
+//!
+//! Only fields given more than once, or whose value isn't UTF-8, are copied, into a value made
+//! for them.
+
+use crate::request::Error;
+use bytes::Bytes;
+use std::{borrow::Cow, iter, ops::Index, slice, str};
+
+/// Fields by lowercase name, in the order they arrived, each name only once as repeats are
+/// combined into the first
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fields(Vec<(Bytes, Bytes)>);
+
+/// Names and values, in the order they arrived
+pub type Iter<'a> =
+    iter::Map<slice::Iter<'a, (Bytes, Bytes)>, fn(&'a (Bytes, Bytes)) -> (&'a str, &'a str)>;
+
+impl Fields {
+    pub const fn new() -> Self {
+        Self(vec![])
+    }
+
+    /// Room for `capacity` fields, eg as many as a head has lines
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self(Vec::with_capacity(capacity))
+    }
+
+    /// The value of the field called `name`, ignoring case
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, value)| as_str(value))
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets the field called `name` to `value`, replacing any it had
+    pub fn insert(&mut self, mut name: String, value: String) {
+        name.make_ascii_lowercase();
+        match self.0.iter_mut().find(|(field, _)| *field == name) {
+            Some((_, existing)) => *existing = Bytes::from(value),
+            None => self.0.push((Bytes::from(name), Bytes::from(value))),
+        }
+    }
+
+    /// Adds a field as it arrived, combining it with any of the same name as RFC 9110 section
+    /// 5.3 allows, so none are lost: cookies are joined with `; ` (as RFC 9113 section 8.2.3 has
+    /// h2 do), and other values with `, `. `Host` and `Content-Length` can't be given twice, as
+    /// which of them a proxy in front went by can't be told.
+    ///
+    /// Both are kept as they are when they can be, the name once it is lowercase and the value
+    /// when it is UTF-8, which anything else is made into.
+    pub fn add(&mut self, name: Bytes, value: Bytes) -> Result<(), Error> {
+        let name = if name.iter().any(u8::is_ascii_uppercase) {
+            Bytes::from(name.to_ascii_lowercase())
+        } else {
+            name
+        };
+        let value = match String::from_utf8_lossy(&value) {
+            Cow::Borrowed(_) => value,
+            Cow::Owned(value) => Bytes::from(value),
+        };
+
+        let Some((_, existing)) = self.0.iter_mut().find(|(field, _)| *field == name) else {
+            self.0.push((name, value));
+            return Ok(());
+        };
+        let separator = match &name[..] {
+            b"host" => return Err(Error::RepeatedHeader("Host")),
+            b"content-length" => return Err(Error::RepeatedHeader("Content-Length")),
+            b"cookie" => "; ",
+            _ => ", ",
+        };
+        // Empty list elements are ignored (RFC 9110 section 5.6.1)
+        if existing.is_empty() {
+            *existing = value;
+        } else if !value.is_empty() {
+            *existing = Bytes::from([as_str(existing), separator, as_str(&value)].concat());
+        }
+
+        Ok(())
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        self.0
+            .iter()
+            .map(|(name, value)| (as_str(name), as_str(value)))
+    }
+
+    pub const fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a Fields {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// The value of the field called `name`, which there has to be
+impl Index<&str> for Fields {
+    type Output = str;
+
+    fn index(&self, name: &str) -> &str {
+        self.get(name).unwrap_or_else(|| panic!("no {name} field"))
+    }
+}
+
+/// A name or value, which only ever go in as UTF-8
+fn as_str(bytes: &Bytes) -> &str {
+    // Safety: `insert` takes `String`s, and `add` makes anything that isn't UTF-8 into a `String`
+    unsafe { str::from_utf8_unchecked(bytes) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fields_are_shared_until_combined() -> Result<(), Error> {
+        let head = Bytes::from_static(b"accept-encodinggzipx-notea\xffb");
+        let mut fields = Fields::new();
+        fields.add(head.slice(..15), head.slice(15..19))?;
+        fields.add(head.slice(19..25), head.slice(25..))?;
+        fields.add(
+            Bytes::from_static(b"Accept-Encoding"),
+            Bytes::from_static(b"br"),
+        )?;
+        fields.add(Bytes::from_static(b"cookie"), Bytes::from_static(b"a=1"))?;
+        fields.add(Bytes::from_static(b"cookie"), Bytes::from_static(b"b=2"))?;
+
+        assert_eq!(fields.get("Accept-Encoding"), Some("gzip, br"));
+        assert_eq!(fields.get("x-note"), Some("a\u{fffd}b"));
+        assert_eq!(fields.get("cookie"), Some("a=1; b=2"));
+        assert_eq!(
+            fields.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            ["accept-encoding", "x-note", "cookie"]
+        );
+        assert_eq!(
+            fields.add(Bytes::from_static(b"cookie"), Bytes::new()),
+            Ok(())
+        );
+        assert_eq!(fields["cookie"], *"a=1; b=2");
+        Ok(())
+    }
+
+    #[test]
+    fn inserting_replaces() {
+        let mut fields = Fields::new();
+        fields.insert("Host".to_string(), "a".to_string());
+        fields.insert("host".to_string(), "b".to_string());
+
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields.get("HOST"), Some("b"));
+    }
+}
@@ -0,0 +1,397 @@
+//! Keeps the contents of recently served `/files` in memory (`--file-cache-size`), so serving
+//! one again only costs a `stat` to check it hasn't changed, rather than opening and reading it
+//!
+//! Once the cache is full the least recently used files are dropped to make room. Files too big
+//! to take more than a small share of it are never cached, as they would push out many others.
+//!
+//! A copy is never served once its file has changed, as every hit checks the ETag. On top of that
+//! a thread re-checks every cached file every so often, so memory isn't held for files that were
+//! changed or deleted and haven't been asked for since. (Polling works the same everywhere, and
+//! the cache is small enough for it to cost next to nothing.)
+
+use crate::{files, metrics::Metrics};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+use tracing::debug;
+
+/// The most of the cache one file may take, as a fraction
+const MAX_SHARE: usize = 8;
+
+/// How often cached files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A cached file, with what is needed to answer for it without touching the file system
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cached {
+    pub contents: Arc<[u8]>,
+    pub content_type: &'static str,
+    pub etag: String,
+}
+
+struct Entry {
+    cached: Cached,
+    /// When it was last used, to find the least recently used
+    used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<PathBuf, Entry>,
+    /// Paths by when they were last used
+    order: BTreeMap<u64, PathBuf>,
+    size: usize,
+    clock: u64,
+}
+
+impl Lru {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, path: &Path) -> Option<Entry> {
+        let entry = self.entries.remove(path)?;
+        self.order.remove(&entry.used);
+        self.size -= entry.cached.contents.len();
+        Some(entry)
+    }
+}
+
+pub struct FileCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    /// Where hits, misses and invalidations are counted
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lru = self.lru();
+        f.debug_struct("FileCache")
+            .field("capacity", &self.capacity)
+            .field("size", &lru.size)
+            .field("files", &lru.entries.len())
+            .finish()
+    }
+}
+
+impl FileCache {
+    /// A cache holding up to `capacity` bytes of file contents
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::default(),
+            metrics: None,
+        }
+    }
+
+    /// Count hits, misses and invalidations in `metrics`
+    #[must_use]
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Re-checks the cached files every `WATCH_INTERVAL` until the cache is dropped
+    pub fn watch(self: &Arc<Self>) -> io::Result<()> {
+        let cache = Arc::downgrade(self);
+        thread::Builder::new()
+            .name("file-cache".to_string())
+            .spawn(move || {
+                loop {
+                    thread::sleep(WATCH_INTERVAL);
+                    let Some(cache) = cache.upgrade() else {
+                        return;
+                    };
+                    cache.sweep();
+                }
+            })?;
+
+        Ok(())
+    }
+
+    /// Drops the copies of files that have changed or gone since they were cached
+    fn sweep(&self) {
+        let cached = self
+            .lru()
+            .entries
+            .iter()
+            .map(|(path, entry)| (path.clone(), entry.cached.etag.clone()))
+            .collect::<Vec<_>>();
+        // Checked without holding the lock, so requests aren't held up by the file system
+        let stale = cached
+            .into_iter()
+            .filter(|(path, etag)| {
+                fs::metadata(path).map_or(true, |metadata| files::etag(&metadata) != *etag)
+            })
+            .collect::<Vec<_>>();
+
+        let mut lru = self.lru();
+        for (path, etag) in stale {
+            // Unless it has been replaced with the new version in the meantime
+            if lru
+                .entries
+                .get(&path)
+                .is_some_and(|entry| entry.cached.etag == etag)
+            {
+                debug!(path = %path.display(), "Evicting changed file");
+                lru.remove(&path);
+                self.count(Metrics::file_cache_invalidated);
+            }
+        }
+    }
+
+    fn count(&self, event: fn(&Metrics)) {
+        if let Some(metrics) = &self.metrics {
+            event(metrics);
+        }
+    }
+
+    fn lru(&self) -> MutexGuard<'_, Lru> {
+        self.lru
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Whether a file this big would be cached, so it is worth reading into memory
+    pub const fn fits(&self, size: u64) -> bool {
+        size <= (self.capacity / MAX_SHARE) as u64
+    }
+
+    /// The cached copy of the file at `path`, if it is still the version with `etag`
+    pub fn get(&self, path: &Path, etag: &str) -> Option<Cached> {
+        let mut lru = self.lru();
+        let Some(entry) = lru.entries.get(path) else {
+            self.count(Metrics::file_cache_missed);
+            return None;
+        };
+        if entry.cached.etag != etag {
+            lru.remove(path);
+            self.count(Metrics::file_cache_invalidated);
+            self.count(Metrics::file_cache_missed);
+            return None;
+        }
+
+        let (previous, cached) = (entry.used, entry.cached.clone());
+        let used = lru.tick();
+        lru.order.remove(&previous);
+        lru.order.insert(used, path.to_path_buf());
+        if let Some(entry) = lru.entries.get_mut(path) {
+            entry.used = used;
+        }
+        self.count(Metrics::file_cache_hit);
+        Some(cached)
+    }
+
+    /// Keeps a copy of the file at `path`, unless it is too big, dropping the least recently used
+    /// files until it fits
+    pub fn insert(&self, path: &Path, cached: Cached) {
+        let size = cached.contents.len();
+        if !self.fits(size as u64) {
+            return;
+        }
+
+        let mut lru = self.lru();
+        lru.remove(path);
+        while lru.size + size > self.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = lru.entries.remove(&oldest) {
+                lru.size -= entry.cached.contents.len();
+            }
+        }
+        let used = lru.tick();
+        lru.order.insert(used, path.to_path_buf());
+        lru.entries
+            .insert(path.to_path_buf(), Entry { cached, used });
+        lru.size += size;
+    }
+
+    /// Drops the copy of a file that has been written to or deleted
+    pub fn invalidate(&self, path: &Path) {
+        if self.lru().remove(path).is_some() {
+            self.count(Metrics::file_cache_invalidated);
+        }
+    }
+
+    /// Drops every copy, so the files are read afresh (eg, after they were changed behind the
+    /// server's back in a way the watcher can't see), returning how many there were
+    pub fn clear(&self) -> usize {
+        let mut lru = self.lru();
+        let cleared = lru.entries.len();
+        lru.entries.clear();
+        lru.order.clear();
+        lru.size = 0;
+
+        cleared
+    }
+
+    /// How full the cache is
+    pub fn usage(&self) -> Usage {
+        let lru = self.lru();
+        Usage {
+            files: lru.entries.len(),
+            size: lru.size,
+            capacity: self.capacity,
+        }
+    }
+}
+
+/// How many files the cache holds, and how much of its capacity they take, in bytes
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub files: usize,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+/// Parses a size like `64MB`, `512K` or `1048576` (bytes), counting in powers of 1024
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number = number
+        .parse::<usize>()
+        .map_err(|_| format!("{size:?} should start with a number of bytes"))?;
+    let multiplier: usize = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "unknown unit in {size:?}, expected B, KB, MB or GB"
+            ));
+        }
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{size:?} is too big"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::test_directory;
+
+    fn cached(contents: &str, etag: &str) -> Cached {
+        Cached {
+            contents: contents.as_bytes().into(),
+            content_type: "text/plain",
+            etag: etag.to_string(),
+        }
+    }
+
+    #[test]
+    fn least_recently_used_files_are_dropped() {
+        let cache = FileCache::new(8 * MAX_SHARE);
+        for name in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+            cache.insert(Path::new(name), cached("12345678", "1"));
+        }
+        assert!(cache.get(Path::new("a"), "1").is_some());
+
+        cache.insert(Path::new("i"), cached("12345678", "1"));
+        assert!(cache.get(Path::new("a"), "1").is_some());
+        assert!(cache.get(Path::new("b"), "1").is_none());
+        assert!(cache.get(Path::new("i"), "1").is_some());
+        assert_eq!(cache.lru().size, 64);
+    }
+
+    #[test]
+    fn changed_and_big_files_are_not_served() {
+        let cache = FileCache::new(8 * MAX_SHARE);
+        cache.insert(Path::new("a"), cached("hello", "1"));
+        assert_eq!(
+            cache.get(Path::new("a"), "1").unwrap().contents.as_ref(),
+            b"hello"
+        );
+        assert!(cache.get(Path::new("a"), "2").is_none());
+        assert!(cache.get(Path::new("a"), "1").is_none());
+
+        cache.insert(Path::new("big"), cached("123456789", "1"));
+        assert!(cache.get(Path::new("big"), "1").is_none());
+
+        cache.insert(Path::new("b"), cached("hello", "1"));
+        cache.invalidate(Path::new("b"));
+        assert!(cache.get(Path::new("b"), "1").is_none());
+        assert_eq!(cache.lru().size, 0);
+    }
+
+    #[test]
+    fn clearing_drops_everything() {
+        let cache = FileCache::new(8 * MAX_SHARE);
+        cache.insert(Path::new("a"), cached("hello", "1"));
+        cache.insert(Path::new("b"), cached("world", "1"));
+        assert_eq!(
+            cache.usage(),
+            Usage {
+                files: 2,
+                size: 10,
+                capacity: 64
+            }
+        );
+
+        assert_eq!(cache.clear(), 2);
+        assert!(cache.get(Path::new("a"), "1").is_none());
+        assert_eq!(cache.usage().size, 0);
+        // The cache carries on as before
+        cache.insert(Path::new("a"), cached("hello", "1"));
+        assert!(cache.get(Path::new("a"), "1").is_some());
+    }
+
+    #[test]
+    fn changed_and_deleted_files_are_evicted() -> io::Result<()> {
+        let directory = PathBuf::from(test_directory("changed_and_deleted_files_are_evicted"));
+        let (changed, deleted, kept) = (
+            directory.join("changed"),
+            directory.join("deleted"),
+            directory.join("kept"),
+        );
+        let metrics = Arc::new(Metrics::default());
+        let cache = FileCache::new(1024).metrics(Arc::clone(&metrics));
+        for path in [&changed, &deleted, &kept] {
+            fs::write(path, "first")?;
+            let etag = files::etag(&fs::metadata(path)?);
+            cache.insert(path, cached("first", &etag));
+        }
+
+        fs::write(&changed, "second!")?;
+        fs::remove_file(&deleted)?;
+        cache.sweep();
+        let kept_etag = files::etag(&fs::metadata(&kept)?);
+        fs::remove_dir_all(&directory)?;
+
+        assert!(cache.get(&kept, &kept_etag).is_some());
+        assert_eq!(cache.lru().entries.len(), 1);
+        let rendered = metrics.render();
+        for line in [
+            "file_cache_hits_total 1",
+            "file_cache_misses_total 0",
+            "file_cache_invalidations_total 2",
+        ] {
+            assert!(rendered.contains(line), "{rendered}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("64MB"), Ok(64 << 20));
+        assert_eq!(parse_size("512k"), Ok(512 << 10));
+        assert_eq!(parse_size("1 GiB"), Ok(1 << 30));
+        assert_eq!(parse_size("100"), Ok(100));
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("64XB").is_err());
+    }
+}
@@ -0,0 +1,529 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::UNIX_EPOCH,
+};
+
+/// What a directory request serves in static site mode
+pub const INDEX: &str = "index.html";
+
+/// Numbers the temporary files writes go through, so concurrent writes never share one
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// Which symlinks files are served through (`--follow-symlinks`), those that aren't being taken
+/// for missing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Symlinks {
+    /// None at all
+    Never,
+    /// Those that lead somewhere inside the directory being served
+    #[default]
+    SameRoot,
+    /// Any, wherever they lead
+    Always,
+}
+
+/// A strong validator built from the size and modification time, so it changes whenever the
+/// file does without having to hash the contents
+pub fn etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    format!(
+        "\"{:x}-{:x}{:08x}\"",
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    )
+}
+
+/// The gzipped copy of a file that a build step left next to it (`foo.js.gz` for `foo.js`), unless
+/// it is older than the file and so likely out of date
+pub fn gzip_sidecar(path: &Path, metadata: &Metadata) -> Option<(PathBuf, Metadata)> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".gz");
+    let sidecar = PathBuf::from(sidecar);
+    let sidecar_metadata = fs::metadata(&sidecar).ok().filter(Metadata::is_file)?;
+    let is_stale = matches!(
+        (sidecar_metadata.modified(), metadata.modified()),
+        (Ok(gzipped), Ok(original)) if gzipped < original
+    );
+
+    (!is_stale).then_some((sidecar, sidecar_metadata))
+}
+
+/// Maps a request target onto a file under `root`, falling back to the index document for
+/// directories. Targets that would step outside `root` map onto nothing.
+pub fn static_path(root: &str, target: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::from(root);
+    for component in Path::new(target.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    if path.is_dir() {
+        path.push(INDEX);
+    }
+
+    Some(path)
+}
+
+/// Whether `target` is a directory under `root` asked for without a trailing slash, which relative
+/// links from its index document need to resolve inside it
+pub fn needs_trailing_slash(root: &str, target: &str) -> bool {
+    !target.ends_with('/')
+        && static_path(root, target).is_some_and(|path| {
+            path.ends_with(INDEX)
+                && !target.ends_with(INDEX)
+                && path.parent().is_some_and(Path::is_dir)
+        })
+}
+
+/// Whether every symlink on the way from `root` to `path` (which is under it) may be followed,
+/// checking each part of the path in turn, as any of them could be a link. Parts that don't exist
+/// have nothing to follow, and `path`s that go above `root` are left for `confined` to refuse.
+pub fn follows(root: &Path, path: &Path, symlinks: Symlinks) -> bool {
+    if symlinks == Symlinks::Always {
+        return true;
+    }
+    let Ok(canonical_root) = root.canonicalize() else {
+        return false;
+    };
+    let Ok(relative) = path
+        .strip_prefix(root)
+        .or_else(|_| path.strip_prefix(&canonical_root))
+    else {
+        return true;
+    };
+
+    let mut current = canonical_root.clone();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => current.push(name),
+            Component::CurDir => continue,
+            Component::ParentDir if current != canonical_root => {
+                current.pop();
+                continue;
+            }
+            _ => return true,
+        }
+        let Ok(metadata) = fs::symlink_metadata(&current) else {
+            return true;
+        };
+        if !metadata.file_type().is_symlink() {
+            continue;
+        }
+        let inside = symlinks == Symlinks::SameRoot
+            && current
+                .canonicalize()
+                .is_ok_and(|target| target.starts_with(&canonical_root));
+        if !inside {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Whether `name` stays under the directory it is relative to, going by the name alone
+fn stays_inside(name: &str) -> bool {
+    let mut depth = 0_usize;
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+
+    depth > 0
+}
+
+/// Resolves `name` under `root`, following any `..` and the symlinks `symlinks` allows, and
+/// refuses with `PermissionDenied` if where it ends up is outside `root` (as it may with
+/// `Symlinks::Always`, so long as the name itself stays inside), or `NotFound` if it goes
+/// through a symlink it shouldn't. The file itself need not exist yet (so it can be created),
+/// but the directory it would be in must.
+pub fn confined(root: &Path, name: &str, symlinks: Symlinks) -> io::Result<PathBuf> {
+    let root = root.canonicalize()?;
+    let path = root.join(name);
+    if !follows(&root, &path, symlinks) {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("{name} goes through a symlink that isn't followed"),
+        ));
+    }
+
+    let resolved = match path.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+                return Err(err);
+            };
+            parent.canonicalize()?.join(file_name)
+        }
+        Err(err) => return Err(err),
+    };
+
+    if (resolved.starts_with(&root) && resolved != root)
+        || (symlinks == Symlinks::Always && stays_inside(name))
+    {
+        Ok(resolved)
+    } else {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{name} is outside {}", root.display()),
+        ))
+    }
+}
+
+/// Whether a single path segment is an acceptable file or directory name: not empty, not a
+/// reference to the current or parent directory, and without separators or control characters
+pub fn valid_segment(segment: &str) -> bool {
+    !matches!(segment, "" | "." | "..")
+        && !segment
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control())
+}
+
+/// Creates the directories `name` would be in under `root`, checking each is still inside
+/// `root` as it goes so a symlink can't lead the creation elsewhere (unless `symlinks` lets it)
+pub fn create_parents(root: &Path, name: &str, symlinks: Symlinks) -> io::Result<()> {
+    let segments: Vec<&str> = name.split('/').collect();
+    if !segments.iter().all(|segment| valid_segment(segment)) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{name} has an invalid segment"),
+        ));
+    }
+
+    let mut parent = String::new();
+    for segment in &segments[..segments.len() - 1] {
+        if !parent.is_empty() {
+            parent.push('/');
+        }
+        parent.push_str(segment);
+
+        let directory = confined(root, &parent, symlinks)?;
+        if !directory.is_dir() {
+            fs::create_dir(&directory)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a new version of the file at `path` with `write`, by way of a temporary file beside it
+/// that then takes its place. The old file is unlinked rather than truncated, so anything still
+/// sending it (eg, from a memory map) carries on with the old contents. With `create_only`, fails
+/// with `AlreadyExists` instead of replacing a file that is already there.
+pub fn write_replacing<F>(path: &Path, create_only: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no file name to write to"))?;
+    let temporary = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name.to_string_lossy(),
+        process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let mut file = File::create_new(&temporary)?;
+    let written = write(&mut file).and_then(|()| {
+        drop(file);
+        if create_only {
+            // Unlike renaming, linking never replaces what is there
+            fs::hard_link(&temporary, path)
+        } else {
+            fs::rename(&temporary, path)
+        }
+    });
+    if create_only || written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+
+    written
+}
+
+/// Matches a file name against a glob of `*` (any run of characters) and `?` (any one)
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Where to resume after the last `*`, should what followed it stop matching
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => {
+                let Some((star, consumed)) = backtrack else {
+                    return false;
+                };
+                p = star + 1;
+                n = consumed + 1;
+                backtrack = Some((star, consumed + 1));
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Guesses the media type from the extension, for the handful a static site usually has
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt" | "md") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::test_directory;
+
+    #[test]
+    fn writes_replace_files_rather_than_truncate_them() -> io::Result<()> {
+        let directory = PathBuf::from(test_directory(
+            "writes_replace_files_rather_than_truncate_them",
+        ));
+        let path = directory.join("file");
+        fs::write(&path, b"old contents")?;
+        // Stands in for a memory map, which would see the file shrink if it were truncated
+        let mut sending = File::open(&path)?;
+
+        write_replacing(&path, false, |file| io::Write::write_all(file, b"new"))?;
+        let mut sent = String::new();
+        io::Read::read_to_string(&mut sending, &mut sent)?;
+        assert_eq!(sent, "old contents");
+        assert_eq!(fs::read(&path)?, b"new");
+
+        let created = write_replacing(&path, true, |file| io::Write::write_all(file, b"lost"));
+        assert_eq!(created.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path)?, b"new");
+        // Nothing is left behind, whether the write went ahead or not
+        assert_eq!(fs::read_dir(&directory)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn etag_changes_with_the_file() -> std::io::Result<()> {
+        let path = Path::new(&test_directory("etag_changes_with_the_file")).join("file");
+        fs::write(&path, b"one")?;
+        let before = etag(&fs::metadata(&path)?);
+        fs::write(&path, b"three")?;
+        let after = etag(&fs::metadata(&path)?);
+
+        assert!(before.starts_with("\"3-"));
+        assert!(after.starts_with("\"5-"));
+        assert_ne!(before, after);
+        Ok(())
+    }
+
+    #[test]
+    fn static_path_serves_the_index_for_directories() -> std::io::Result<()> {
+        let root = PathBuf::from(test_directory(
+            "static_path_serves_the_index_for_directories",
+        ));
+        fs::create_dir_all(root.join("docs"))?;
+        let root_str = root.to_str().unwrap();
+
+        assert_eq!(static_path(root_str, "/"), Some(root.join(INDEX)));
+        assert_eq!(
+            static_path(root_str, "/docs/"),
+            Some(root.join("docs").join(INDEX))
+        );
+        assert_eq!(
+            static_path(root_str, "/./style.css"),
+            Some(root.join("style.css"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn static_path_stays_under_the_root() {
+        assert_eq!(static_path("/srv", "/../etc/passwd"), None);
+        assert_eq!(static_path("/srv", "/a/../../etc/passwd"), None);
+    }
+
+    #[test]
+    fn create_parents_makes_missing_directories() -> io::Result<()> {
+        let root = PathBuf::from(test_directory("create_parents_makes_missing_directories"));
+        fs::create_dir_all(root.join("a"))?;
+
+        create_parents(&root, "a/b/c/name.txt", Symlinks::SameRoot)?;
+        assert!(root.join("a/b/c").is_dir());
+        assert!(!root.join("a/b/c/name.txt").exists());
+
+        fs::write(root.join("file"), b"")?;
+        assert!(create_parents(&root, "file/name.txt", Symlinks::SameRoot).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn create_parents_checks_every_segment() {
+        let root = PathBuf::from(test_directory("create_parents_checks_every_segment"));
+
+        for name in ["a/../../b", "a//b", "./a", "a/b\\c", "a/\u{7}/b", "a/"] {
+            assert_eq!(
+                create_parents(&root, name, Symlinks::SameRoot)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidInput,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_matches("*.tmp", "a.tmp"));
+        assert!(glob_matches("*.tmp", ".tmp"));
+        assert!(glob_matches("a?c*", "abc.txt"));
+        assert!(glob_matches("*a*b", "xaxxab"));
+        assert!(glob_matches("*", "anything"));
+        assert!(!glob_matches("*.tmp", "a.tmp.bak"));
+        assert!(!glob_matches("a?c", "ac"));
+    }
+
+    #[test]
+    fn content_type_from_extension() {
+        assert_eq!(
+            content_type(Path::new("index.HTML")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("logo.svg")), "image/svg+xml");
+        assert_eq!(
+            content_type(Path::new("README")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn confined_allows_new_and_existing_files() -> io::Result<()> {
+        let root = PathBuf::from(test_directory("confined_allows_new_and_existing_files"));
+        fs::create_dir_all(root.join("sub"))?;
+        fs::write(root.join("sub/existing"), b"")?;
+        let canonical = root.canonicalize()?;
+
+        assert_eq!(
+            confined(&root, "sub/existing", Symlinks::SameRoot)?,
+            canonical.join("sub/existing")
+        );
+        assert_eq!(
+            confined(&root, "new", Symlinks::SameRoot)?,
+            canonical.join("new")
+        );
+        assert_eq!(
+            confined(&root, "sub/../new", Symlinks::SameRoot)?,
+            canonical.join("new")
+        );
+        assert_eq!(
+            confined(&root, "missing/new", Symlinks::SameRoot)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn confined_refuses_escapes() -> io::Result<()> {
+        let root = PathBuf::from(test_directory("confined_refuses_escapes"));
+
+        for name in ["../escaped", "../../etc/passwd", "/etc/passwd", ".."] {
+            assert_eq!(
+                confined(&root, name, Symlinks::SameRoot)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::PermissionDenied,
+                "{name}"
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_followed_as_allowed() -> io::Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let base = PathBuf::from(test_directory("symlinks_are_followed_as_allowed"));
+        let root = base.join("root");
+        fs::create_dir_all(root.join("docs"))?;
+        fs::create_dir_all(base.join("outside"))?;
+        fs::write(root.join("docs/page.html"), b"")?;
+        fs::write(base.join("outside/secret"), b"")?;
+        symlink(root.join("docs"), root.join("inside"))?;
+        symlink(base.join("outside"), root.join("escape"))?;
+        // Only the link in the middle of the path leads out
+        symlink(base.join("outside"), root.join("docs/up"))?;
+
+        let follows = |path: &str, symlinks| follows(&root, &root.join(path), symlinks);
+        assert!(follows("docs/page.html", Symlinks::Never));
+        assert!(!follows("inside/page.html", Symlinks::Never));
+        assert!(follows("inside/page.html", Symlinks::SameRoot));
+        assert!(!follows("escape/secret", Symlinks::SameRoot));
+        assert!(!follows("inside/up/secret", Symlinks::SameRoot));
+        assert!(follows("escape/secret", Symlinks::Always));
+        assert!(follows("docs/missing", Symlinks::Never));
+
+        assert_eq!(
+            confined(&root, "escape/secret", Symlinks::SameRoot)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            confined(&root, "escape/secret", Symlinks::Always)?,
+            base.join("outside/secret").canonicalize()?
+        );
+        // Following every link still doesn't let the name itself climb out
+        assert_eq!(
+            confined(&root, "../outside/secret", Symlinks::Always)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        Ok(())
+    }
+}
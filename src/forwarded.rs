@@ -0,0 +1,202 @@
+//! Who the client really is when the server sits behind proxies (`--trusted-proxies`), going by
+//! the `Forwarded` (RFC 7239) or `X-Forwarded-For` header they add (`--forwarded-header`)
+//!
+//! Each proxy appends the address it received the request from, so the list is read from the
+//! right, past the trusted proxies, to the first address one of them vouches for. Anything to
+//! the left of that was sent by the client and could say anything. So could the header the
+//! proxies don't set, which is never read.
+
+use crate::{ip_filter::Cidr, request::Request};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// Which header the trusted proxies add
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `Forwarded` (RFC 7239)
+    Forwarded,
+    /// `X-Forwarded-For`, which most proxies add unless told otherwise
+    #[default]
+    XForwardedFor,
+}
+
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<Cidr>,
+    header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    #[must_use]
+    pub fn trust(mut self, cidr: Cidr) -> Self {
+        self.cidrs.push(cidr);
+        self
+    }
+
+    #[must_use]
+    pub const fn header(mut self, header: ForwardedHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Whether `peer`, as `Stream::peer` gives it, is one of the proxies
+    pub fn trusts(&self, peer: &str) -> bool {
+        peer.parse::<IpAddr>()
+            .is_ok_and(|address| self.cidrs.iter().any(|cidr| cidr.contains(address)))
+    }
+
+    /// The client `request` was forwarded for, or `peer` when it didn't come through a trusted
+    /// proxy (or the proxy didn't say)
+    ///
+    /// A hop that isn't an address (eg, `unknown`, or an obfuscated `_node`) is the client as far
+    /// as anyone can tell.
+    pub fn client(&self, peer: &str, request: &Request) -> String {
+        if !self.trusts(peer) {
+            return peer.to_string();
+        }
+        let hops = match self.header {
+            ForwardedHeader::Forwarded => request.headers.get("forwarded").map(parse_forwarded),
+            ForwardedHeader::XForwardedFor => request
+                .headers
+                .get("x-forwarded-for")
+                .map(|hops| hops.split(',').map(|hop| hop.trim().to_string()).collect()),
+        }
+        .unwrap_or_default();
+
+        // Everyone in the chain being a proxy leaves the one furthest away
+        let mut client = None;
+        for hop in hops.into_iter().rev() {
+            if hop.is_empty() {
+                break;
+            }
+            let trusted = self.trusts(&hop);
+            client = Some(hop);
+            if !trusted {
+                break;
+            }
+        }
+
+        client.unwrap_or_else(|| peer.to_string())
+    }
+}
+
+/// The `for` parameter of each element of a `Forwarded` header, without any quotes, brackets or
+/// port, so addresses come out as `IpAddr` would print them
+fn parse_forwarded(forwarded: &str) -> Vec<String> {
+    forwarded
+        .split(',')
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, node)| node_address(node.trim().trim_matches('"')))
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Strips the port from a node (eg, `192.0.2.43:47011` or `[2001:db8::17]:4711`)
+fn node_address(node: &str) -> String {
+    if let Some(v6) = node.strip_prefix('[') {
+        return v6
+            .split_once(']')
+            .map_or(v6, |(address, _)| address)
+            .to_string();
+    }
+    if node.parse::<IpAddr>().is_ok() {
+        return node.to_string();
+    }
+
+    node.split_once(':')
+        .map_or(node, |(address, _)| address)
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TestRequest;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::default()
+            .trust("10.0.0.0/8".parse().unwrap())
+            .trust("2001:db8::/32".parse().unwrap())
+    }
+
+    fn client(header: &str, value: &str) -> String {
+        let request = TestRequest::get("/").header(header, value).build();
+        let proxies = match header {
+            "forwarded" => proxies().header(ForwardedHeader::Forwarded),
+            _ => proxies(),
+        };
+        proxies.client("10.0.0.1", &request)
+    }
+
+    #[test]
+    fn forwarded_for() {
+        assert_eq!(client("x-forwarded-for", "192.0.2.1"), "192.0.2.1");
+        assert_eq!(
+            client("x-forwarded-for", "6.6.6.6, 192.0.2.1, 10.0.0.2"),
+            "192.0.2.1"
+        );
+        assert_eq!(client("x-forwarded-for", "10.0.0.3, 10.0.0.2"), "10.0.0.3");
+        assert_eq!(client("x-forwarded-for", ""), "10.0.0.1");
+    }
+
+    #[test]
+    fn forwarded() {
+        assert_eq!(
+            client("forwarded", "for=192.0.2.60;proto=http;by=203.0.113.43"),
+            "192.0.2.60"
+        );
+        assert_eq!(
+            client(
+                "forwarded",
+                "for=6.6.6.6, For=\"[2001:db8:cafe::17]:4711\", for=192.0.2.43:47011"
+            ),
+            "192.0.2.43"
+        );
+        assert_eq!(
+            client(
+                "forwarded",
+                "for=192.0.2.1, for=\"[2001:db8:cafe::17]:4711\""
+            ),
+            "192.0.2.1"
+        );
+        assert_eq!(client("forwarded", "for=unknown, for=10.0.0.2"), "unknown");
+        assert_eq!(client("forwarded", "proto=https"), "10.0.0.1");
+    }
+
+    #[test]
+    fn only_the_header_the_proxies_add_is_read() {
+        let request = TestRequest::get("/")
+            .header("forwarded", "for=192.0.2.60")
+            .header("x-forwarded-for", "6.6.6.6, 192.0.2.1")
+            .build();
+
+        assert_eq!(proxies().client("10.0.0.1", &request), "192.0.2.1");
+        assert_eq!(
+            proxies()
+                .header(ForwardedHeader::Forwarded)
+                .client("10.0.0.1", &request),
+            "192.0.2.60"
+        );
+        let request = TestRequest::get("/")
+            .header("forwarded", "for=192.0.2.60")
+            .build();
+        assert_eq!(proxies().client("10.0.0.1", &request), "10.0.0.1");
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let request = TestRequest::get("/")
+            .header("x-forwarded-for", "192.0.2.1")
+            .build();
+
+        assert_eq!(proxies().client("203.0.113.9", &request), "203.0.113.9");
+        assert_eq!(proxies().client("unix", &request), "unix");
+    }
+}
@@ -0,0 +1,1209 @@
+//! HTTP/2 over cleartext with prior knowledge (h2c, RFC 9113 section 3.3)
+//!
+//! Each stream is translated into a `Request` once it has been fully received, and its
+//! `Response` sent back before moving on to the next, so streams are concurrent on the wire but
+//! handled one at a time. Response bodies are read into memory to be split into `DATA` frames.
+//!
+//! With `--tls-self-signed` the same code serves `h2` negotiated with ALPN, as the client then
+//! starts with the preface too. The HTTP/1.1 `Upgrade: h2c` dance isn't supported.
+
+use crate::{
+    fields::Fields,
+    parser,
+    request::{self, Limits, Method, Request},
+    response::{Response, StatusCode},
+};
+use anyhow::Result;
+use bytes::Bytes;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Cursor, ErrorKind, Read, Write},
+};
+use thiserror::Error;
+use tracing::{debug, error, info};
+
+/// What a client with prior knowledge sends before any frames
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_SIZE: usize = 9;
+
+/// The largest frame payload either side may send until told otherwise, which is all we accept
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+
+/// The most streams a client may have open at once, which it is told in our `SETTINGS`
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// The largest HPACK dynamic table a client may ask us to keep, which is the protocol's default
+const HEADER_TABLE_SIZE: usize = 4_096;
+
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Settings
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const STREAM_CLOSED: u32 = 0x5;
+const REFUSED_STREAM: u32 = 0x7;
+
+/// Connection errors, each of which ends the connection with a `GOAWAY`
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("The client did not send the HTTP/2 connection preface")]
+    MissingPreface,
+
+    #[error("HTTP/2 protocol error: {0}")]
+    Protocol(&'static str),
+
+    #[error("HTTP/2 flow control error")]
+    FlowControl,
+
+    #[error("HTTP/2 frame of the wrong size")]
+    FrameSize,
+
+    #[error("Unable to decompress an HTTP/2 header block")]
+    Compression,
+
+    #[error("HTTP/2 header block larger than the server accepts")]
+    HeaderBlockTooLarge,
+}
+
+impl Error {
+    const fn code(&self) -> u32 {
+        match self {
+            Self::MissingPreface | Self::Protocol(_) => 0x1,
+            Self::FlowControl => 0x3,
+            Self::FrameSize => 0x6,
+            Self::Compression => 0x9,
+            // ENHANCE_YOUR_CALM
+            Self::HeaderBlockTooLarge => 0xb,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    const fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// The payload without any padding (or priority, for `HEADERS`)
+    fn data(&self) -> Result<&[u8], Error> {
+        let mut data = &self.payload[..];
+        if self.has(PADDED) {
+            let (&padding, rest) = data.split_first().ok_or(Error::FrameSize)?;
+            let unpadded = rest.len().checked_sub(usize::from(padding));
+            data = &rest[..unpadded.ok_or(Error::Protocol("padding longer than the frame"))?];
+        }
+        if self.kind == HEADERS && self.has(PRIORITY_FLAG) {
+            data = data.get(5..).ok_or(Error::FrameSize)?;
+        }
+
+        Ok(data)
+    }
+}
+
+#[derive(Debug)]
+struct Stream {
+    headers: Vec<(Vec<u8>, Vec<u8>)>,
+    body: Vec<u8>,
+    /// Whether the client has finished sending, so the stream is waiting for its response
+    ended: bool,
+    send_window: i64,
+    /// What to answer with instead of handling the request, as it is larger than `Limits` allow
+    refused: Option<StatusCode>,
+}
+
+/// Reads what was buffered while looking for the preface before reading from the connection
+struct Prefixed<'a, T> {
+    buffered: Cursor<Vec<u8>>,
+    io: &'a mut T,
+}
+
+impl<T: Read> Read for Prefixed<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.buffered.read(buf)? {
+            0 => self.io.read(buf),
+            read => Ok(read),
+        }
+    }
+}
+
+struct Session<'a, T> {
+    io: Prefixed<'a, T>,
+    limits: Limits,
+    decoder: hpack::Decoder<'static>,
+    encoder: hpack::Encoder<'static>,
+    streams: HashMap<u32, Stream>,
+    /// Streams the client has finished sending, in the order they finished
+    ready: VecDeque<u32>,
+    /// A header block still waiting for `CONTINUATION` frames, and whether it ends its stream
+    continuing: Option<(u32, Vec<u8>, bool)>,
+    last_stream_id: u32,
+    send_window: i64,
+    initial_window: i64,
+    max_frame_size: usize,
+    going_away: bool,
+}
+
+/// Serves an HTTP/2 connection until the client closes it or sends `GOAWAY`, where `buffered`
+/// is what has already been read from `io` (including the preface). Requests are held to the
+/// same `limits` as those over HTTP/1.1.
+pub fn serve<T, F>(io: &mut T, buffered: Vec<u8>, limits: Limits, respond: F) -> Result<()>
+where
+    T: Read + Write,
+    F: FnMut(&Request) -> Result<Response>,
+{
+    let mut session = Session {
+        io: Prefixed {
+            buffered: Cursor::new(buffered),
+            io,
+        },
+        limits,
+        decoder: hpack::Decoder::new(),
+        encoder: hpack::Encoder::new(),
+        streams: HashMap::new(),
+        ready: VecDeque::new(),
+        continuing: None,
+        last_stream_id: 0,
+        send_window: DEFAULT_WINDOW_SIZE,
+        initial_window: DEFAULT_WINDOW_SIZE,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        going_away: false,
+    };
+
+    let result = session.run(respond);
+    if let Err(err) = &result
+        && let Some(error) = err.downcast_ref::<Error>()
+    {
+        // The connection is about to be dropped anyway, so this is only a courtesy
+        let _ = session.go_away(error.code());
+    }
+
+    result
+}
+
+impl<T: Read + Write> Session<'_, T> {
+    fn run<F>(&mut self, mut respond: F) -> Result<()>
+    where
+        F: FnMut(&Request) -> Result<Response>,
+    {
+        let mut preface = [0; PREFACE.len()];
+        self.io.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return Err(Error::MissingPreface.into());
+        }
+        // Otherwise our settings are the protocol's defaults
+        let mut settings = SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes().to_vec();
+        settings.extend(MAX_CONCURRENT_STREAMS.to_be_bytes());
+        if let Some(max_head) = self.limits.max_head {
+            settings.extend(SETTINGS_MAX_HEADER_LIST_SIZE.to_be_bytes());
+            settings.extend(u32::try_from(max_head).unwrap_or(u32::MAX).to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &settings)?;
+
+        loop {
+            while let Some(stream_id) = self.ready.pop_front() {
+                self.respond(stream_id, &mut respond)?;
+            }
+            if self.going_away {
+                return self.go_away(NO_ERROR);
+            }
+
+            match self.read_frame()? {
+                Some(frame) => self.handle(frame)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Option<Frame>> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        match self.io.read_exact(&mut header) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+
+        let length =
+            usize::from(header[0]) << 16 | usize::from(header[1]) << 8 | usize::from(header[2]);
+        if length > DEFAULT_MAX_FRAME_SIZE {
+            return Err(Error::FrameSize.into());
+        }
+        let mut payload = vec![0; length];
+        self.io.read_exact(&mut payload)?;
+
+        Ok(Some(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff,
+            payload,
+        }))
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Result<()> {
+        let length = u32::try_from(payload.len())?.to_be_bytes();
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&length[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        self.io.io.write_all(&frame)?;
+        Ok(())
+    }
+
+    fn go_away(&mut self, code: u32) -> Result<()> {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+
+        self.write_frame(GOAWAY, 0, 0, &payload)
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<()> {
+        if let Some((stream_id, ..)) = &self.continuing
+            && (frame.kind != CONTINUATION || frame.stream_id != *stream_id)
+        {
+            return Err(Error::Protocol("header block interrupted").into());
+        }
+
+        match frame.kind {
+            DATA => self.data(&frame),
+            HEADERS => {
+                if frame.stream_id == 0 {
+                    return Err(Error::Protocol("HEADERS on stream 0").into());
+                }
+                let block = frame.data()?.to_vec();
+                let end_stream = frame.has(END_STREAM);
+                self.header_block(frame.stream_id, block, frame.has(END_HEADERS), end_stream)
+            }
+            CONTINUATION => {
+                let Some((stream_id, mut block, end_stream)) = self.continuing.take() else {
+                    return Err(Error::Protocol("CONTINUATION without HEADERS").into());
+                };
+                block.extend_from_slice(&frame.payload);
+                self.header_block(stream_id, block, frame.has(END_HEADERS), end_stream)
+            }
+            SETTINGS => self.settings(&frame),
+            PING => {
+                if frame.payload.len() != 8 {
+                    return Err(Error::FrameSize.into());
+                }
+                if frame.has(ACK) {
+                    Ok(())
+                } else {
+                    self.write_frame(PING, ACK, 0, &frame.payload)
+                }
+            }
+            WINDOW_UPDATE => self.window_update(&frame),
+            RST_STREAM => {
+                self.streams.remove(&frame.stream_id);
+                self.ready.retain(|&stream_id| stream_id != frame.stream_id);
+                Ok(())
+            }
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+            PUSH_PROMISE => Err(Error::Protocol("clients cannot push").into()),
+            // Streams are answered in the order they finish, whatever their priority
+            PRIORITY => Ok(()),
+            // Unknown frame types must be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn data(&mut self, frame: &Frame) -> Result<()> {
+        if frame.stream_id == 0 {
+            return Err(Error::Protocol("DATA on stream 0").into());
+        }
+        let data = frame.data()?.to_vec();
+
+        // The body is buffered whole, so the client can have its window back straight away
+        let length = u32::try_from(frame.payload.len())?;
+        if length > 0 {
+            self.write_frame(WINDOW_UPDATE, 0, 0, &length.to_be_bytes())?;
+        }
+
+        let Some(stream) = self
+            .streams
+            .get_mut(&frame.stream_id)
+            .filter(|stream| !stream.ended)
+        else {
+            return self.write_frame(RST_STREAM, 0, frame.stream_id, &STREAM_CLOSED.to_be_bytes());
+        };
+        // The body of a refused stream is already known to be unwanted
+        if stream.refused.is_none() {
+            stream.body.extend_from_slice(&data);
+            if self
+                .limits
+                .max_body
+                .is_some_and(|max_body| stream.body.len() > max_body)
+            {
+                // Answered straight away, rather than holding on to the rest too
+                stream.body = vec![];
+                stream.refused = Some(StatusCode::ContentTooLarge);
+                self.ready.push_back(frame.stream_id);
+            }
+        }
+
+        if frame.has(END_STREAM) {
+            stream.ended = true;
+            if stream.refused.is_none() {
+                self.ready.push_back(frame.stream_id);
+            }
+        } else if length > 0 {
+            self.write_frame(WINDOW_UPDATE, 0, frame.stream_id, &length.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the rest of a header block unless it is `complete`. One larger than a whole
+    /// request head may be ends the connection, as skipping it would leave the decoder's table
+    /// out of step with the client's.
+    fn header_block(
+        &mut self,
+        stream_id: u32,
+        block: Vec<u8>,
+        complete: bool,
+        end_stream: bool,
+    ) -> Result<()> {
+        if self
+            .limits
+            .max_head
+            .is_some_and(|max_head| block.len() > max_head)
+        {
+            return Err(Error::HeaderBlockTooLarge.into());
+        }
+        if complete {
+            self.headers(stream_id, &block, end_stream)
+        } else {
+            self.continuing = Some((stream_id, block, end_stream));
+            Ok(())
+        }
+    }
+
+    fn headers(&mut self, stream_id: u32, block: &[u8], end_stream: bool) -> Result<()> {
+        // Every block has to be decoded, as each one can change the decoder's table
+        check_block(block)?;
+        let headers = self.decoder.decode(block).map_err(|_| Error::Compression)?;
+
+        // Trailers, which are only of interest for ending the stream
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.ended || !end_stream {
+                return Err(Error::Protocol("HEADERS on a half-closed stream").into());
+            }
+            stream.ended = true;
+            if stream.refused.is_none() {
+                self.ready.push_back(stream_id);
+            }
+            return Ok(());
+        }
+
+        if stream_id.is_multiple_of(2) || stream_id <= self.last_stream_id {
+            return Err(Error::Protocol("invalid stream identifier").into());
+        }
+        self.last_stream_id = stream_id;
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS as usize {
+            return self.write_frame(RST_STREAM, 0, stream_id, &REFUSED_STREAM.to_be_bytes());
+        }
+
+        let refused = self
+            .too_large(&headers)
+            .then_some(StatusCode::RequestHeaderFieldsTooLarge);
+        // Refused streams are answered without waiting for their bodies
+        if end_stream || refused.is_some() {
+            self.ready.push_back(stream_id);
+        }
+        self.streams.insert(
+            stream_id,
+            Stream {
+                headers,
+                body: vec![],
+                ended: end_stream,
+                send_window: self.initial_window,
+                refused,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Whether the decoded fields are more than `Limits` allow, counting each as the
+    /// `name: value` line HTTP/1.1 would have sent
+    fn too_large(&self, headers: &[(Vec<u8>, Vec<u8>)]) -> bool {
+        let lines = headers
+            .iter()
+            .map(|(name, value)| name.len() + 2 + value.len());
+        let Limits {
+            max_head, max_line, ..
+        } = self.limits;
+
+        max_line.is_some_and(|max_line| lines.clone().any(|line| line > max_line))
+            || max_head.is_some_and(|max_head| lines.sum::<usize>() > max_head)
+    }
+
+    fn settings(&mut self, frame: &Frame) -> Result<()> {
+        if frame.stream_id != 0 {
+            return Err(Error::Protocol("SETTINGS on a stream").into());
+        }
+        if frame.has(ACK) {
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Error::FrameSize.into());
+        }
+
+        for setting in frame.payload.chunks_exact(6) {
+            let identifier = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match identifier {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let window = i64::from(value);
+                    if window > MAX_WINDOW_SIZE {
+                        return Err(Error::FlowControl.into());
+                    }
+                    let delta = window - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                    }
+                    self.initial_window = window;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16_384..=16_777_215).contains(&value) {
+                        return Err(Error::Protocol("invalid SETTINGS_MAX_FRAME_SIZE").into());
+                    }
+                    self.max_frame_size = usize::try_from(value)?;
+                }
+                // Nothing else changes how we send
+                _ => {}
+            }
+        }
+
+        self.write_frame(SETTINGS, ACK, 0, &[])
+    }
+
+    fn window_update(&mut self, frame: &Frame) -> Result<()> {
+        let increment: [u8; 4] = frame.payload[..].try_into().map_err(|_| Error::FrameSize)?;
+        let increment = i64::from(u32::from_be_bytes(increment) & 0x7fff_ffff);
+        if increment == 0 {
+            return Err(Error::Protocol("WINDOW_UPDATE of 0").into());
+        }
+
+        let window = if frame.stream_id == 0 {
+            &mut self.send_window
+        } else if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+            &mut stream.send_window
+        } else {
+            return Ok(());
+        };
+        *window += increment;
+        if *window > MAX_WINDOW_SIZE {
+            return Err(Error::FlowControl.into());
+        }
+
+        Ok(())
+    }
+
+    fn respond<F>(&mut self, stream_id: u32, respond: &mut F) -> Result<()>
+    where
+        F: FnMut(&Request) -> Result<Response>,
+    {
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+        let body = std::mem::take(&mut stream.body);
+        let ended = stream.ended;
+        let mut response = if let Some(status_code) = stream.refused.take() {
+            info!(stream = stream_id, "Request too large: {status_code:?}");
+            Response::new(status_code)
+        } else {
+            match to_request(&stream.headers, body) {
+                Ok(request) => {
+                    debug!(stream = stream_id, "Request");
+                    respond(&request).unwrap_or_else(|err| {
+                        error!(stream = stream_id, "Error handling request: {err}");
+                        Response::new(StatusCode::InternalServerError)
+                    })
+                }
+                Err(err) => {
+                    info!(stream = stream_id, "Invalid request: {err}");
+                    Response::bad_request()
+                }
+            }
+        };
+        // HTTP/2 has no Upgrade mechanism (RFC 9113 section 8.6)
+        if response.take_upgrade().is_some() {
+            response = Response::new(StatusCode::NotImplemented);
+        }
+        debug!(stream = stream_id, "Sending: {response:?}");
+
+        let (status_code, headers, body) = response.into_parts()?;
+        let mut fields = vec![(
+            b":status".to_vec(),
+            status_code.code().to_string().into_bytes(),
+        )];
+        fields.extend(
+            headers
+                .iter()
+                .map(|header| (header.name().to_ascii_lowercase(), header.value()))
+                // Connection specific headers are malformed in HTTP/2
+                .filter(|(name, _)| !CONNECTION_HEADERS.contains(&&name[..]))
+                .map(|(name, value)| (name.into_bytes(), value.as_bytes().to_vec())),
+        );
+        let block = self.encoder.encode(&fields);
+        self.send_headers(stream_id, &block, body.is_empty())?;
+        self.send_data(stream_id, &body)?;
+        // The rest of the request is no longer wanted (RFC 9113 section 8.1)
+        if !ended && self.streams.contains_key(&stream_id) {
+            self.write_frame(RST_STREAM, 0, stream_id, &NO_ERROR.to_be_bytes())?;
+        }
+
+        self.streams.remove(&stream_id);
+        Ok(())
+    }
+
+    fn send_headers(&mut self, stream_id: u32, block: &[u8], end_stream: bool) -> Result<()> {
+        let mut fragments = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        // An empty block still needs its HEADERS frame
+        let mut fragment = fragments.next().unwrap_or_default();
+        loop {
+            if fragments.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, stream_id, fragment)?;
+
+            let Some(next) = fragments.next() else {
+                return Ok(());
+            };
+            fragment = next;
+            kind = CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    /// Sends as much of the body as the flow control windows allow, reading frames from the
+    /// client (which may include `WINDOW_UPDATE`s) whenever they are exhausted
+    fn send_data(&mut self, stream_id: u32, body: &[u8]) -> Result<()> {
+        let mut remaining = body;
+        while !remaining.is_empty() {
+            let Some(stream) = self.streams.get(&stream_id) else {
+                // Reset by the client while waiting for a window
+                return Ok(());
+            };
+            let window = self.send_window.min(stream.send_window);
+            if window <= 0 {
+                match self.read_frame()? {
+                    Some(frame) => self.handle(frame)?,
+                    None => return Ok(()),
+                }
+                continue;
+            }
+
+            let length = remaining
+                .len()
+                .min(self.max_frame_size)
+                .min(usize::try_from(window)?);
+            let (data, rest) = remaining.split_at(length);
+            let flags = if rest.is_empty() { END_STREAM } else { 0 };
+            self.write_frame(DATA, flags, stream_id, data)?;
+
+            let sent = i64::try_from(length)?;
+            self.send_window -= sent;
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                stream.send_window -= sent;
+            }
+            remaining = rest;
+        }
+
+        Ok(())
+    }
+}
+
+/// RFC 9113 section 8.2.2
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Walks a header block before it is decoded, as `hpack` panics on a malformed dynamic table
+/// size update rather than failing, and would let the client make the table as large as it likes
+fn check_block(block: &[u8]) -> Result<(), Error> {
+    let mut rest = block;
+    let mut fields = false;
+    while let Some(&first) = rest.first() {
+        // RFC 7541 section 6
+        let (index, literal) = match first {
+            0x80.. => (integer(&mut rest, 7)?, false),
+            0x40.. => (integer(&mut rest, 6)?, true),
+            0x20.. => {
+                // Size updates may only come before any fields (RFC 7541 section 4.2)
+                if fields || integer(&mut rest, 5)? > HEADER_TABLE_SIZE {
+                    return Err(Error::Compression);
+                }
+                continue;
+            }
+            _ => (integer(&mut rest, 4)?, true),
+        };
+        fields = true;
+        if literal {
+            if index == 0 {
+                skip_string(&mut rest)?;
+            }
+            skip_string(&mut rest)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads an integer with a `prefix` of so many bits (RFC 7541 section 5.1) from the start of
+/// `rest`, allowing no more octets than `hpack` does
+fn integer(rest: &mut &[u8], prefix: u32) -> Result<usize, Error> {
+    let mask = (1 << prefix) - 1;
+    let (&first, tail) = rest.split_first().ok_or(Error::Compression)?;
+    *rest = tail;
+    let mut value = usize::from(first & mask);
+    if value < usize::from(mask) {
+        return Ok(value);
+    }
+    for shift in [0, 7, 14, 21] {
+        let (&byte, tail) = rest.split_first().ok_or(Error::Compression)?;
+        *rest = tail;
+        value += usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::Compression)
+}
+
+/// Skips a string literal (RFC 7541 section 5.2) at the start of `rest`
+fn skip_string(rest: &mut &[u8]) -> Result<(), Error> {
+    // The Huffman flag is outside the length's prefix
+    let length = integer(rest, 7)?;
+    *rest = rest.get(length..).ok_or(Error::Compression)?;
+
+    Ok(())
+}
+
+/// Maps the pseudo-headers onto what an HTTP/1.1 request line and `Host` header would carry
+fn to_request(fields: &[(Vec<u8>, Vec<u8>)], body: Vec<u8>) -> Result<Request> {
+    let mut method = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut headers = Fields::new();
+    for (name, value) in fields {
+        // RFC 9113 section 8.2.1, which HTTP/1.1 would have to allow for too
+        parser::check_value(value)?;
+        if value.first().is_some_and(u8::is_ascii_whitespace)
+            || value.last().is_some_and(u8::is_ascii_whitespace)
+        {
+            return Err(request::Error::InvalidCharacter.into());
+        }
+        match &name[..] {
+            b":method" => method = Some(Method::decode(value)?),
+            b":path" => path = Some(String::from_utf8(value.clone())?),
+            b":authority" => authority = Some(String::from_utf8(value.clone())?),
+            b":scheme" => {}
+            name if name.starts_with(b":") => {
+                return Err(Error::Protocol("unknown pseudo-header").into());
+            }
+            name => {
+                // Uppercase names are malformed in HTTP/2, rather than the same as lowercase
+                if name.is_empty()
+                    || !name
+                        .iter()
+                        .all(|&byte| request::is_tchar(byte) && !byte.is_ascii_uppercase())
+                {
+                    return Err(request::Error::InvalidHeader.into());
+                }
+                // Cookies in particular arrive split into one field each
+                headers.add(Bytes::from(name.to_vec()), Bytes::from(value.clone()))?;
+            }
+        }
+    }
+
+    let method = method.ok_or(Error::Protocol("missing :method"))?;
+    // CONNECT requests name an authority rather than a path
+    let target = path
+        .or_else(|| {
+            (method == Method::Connect)
+                .then(|| authority.clone())
+                .flatten()
+        })
+        .ok_or(Error::Protocol("missing :path"))?;
+    if let Some(authority) = authority
+        && !headers.contains_key("host")
+    {
+        headers.insert("host".to_string(), authority);
+    }
+
+    Request::from_parts(method, &target, headers, (!body.is_empty()).then_some(body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::Header;
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = u32::try_from(payload.len()).unwrap().to_be_bytes()[1..].to_vec();
+        frame.extend([kind, flags]);
+        frame.extend(stream_id.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    fn headers(encoder: &mut hpack::Encoder, fields: &[(&str, &str)]) -> Vec<u8> {
+        encoder.encode(
+            &fields
+                .iter()
+                .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    fn get(encoder: &mut hpack::Encoder, path: &str) -> Vec<u8> {
+        headers(
+            encoder,
+            &[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", path),
+                (":authority", "localhost"),
+            ],
+        )
+    }
+
+    /// The client's side of the connection, all sent up front, with what the server sends back
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn echo(request: &Request) -> Result<Response> {
+        Ok(Response::ok()
+            .content_type("text/plain")
+            .header(Header::Custom(
+                "X-Host".to_string(),
+                request.headers["host"].to_string(),
+            ))
+            .body_bytes(
+                request
+                    .body
+                    .clone()
+                    .unwrap_or_else(|| request.path.clone().into_bytes()),
+            ))
+    }
+
+    /// Runs the server over `frames`, returning every frame it sent back
+    fn exchange(frames: &[Vec<u8>]) -> Result<Vec<Frame>> {
+        exchange_with_limits(frames, Limits::default())
+    }
+
+    fn exchange_with_limits(frames: &[Vec<u8>], limits: Limits) -> Result<Vec<Frame>> {
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, &[]));
+        input.extend(frames.concat());
+        let mut duplex = Duplex {
+            input: Cursor::new(input),
+            output: vec![],
+        };
+        serve(&mut duplex, vec![], limits, echo)?;
+
+        let mut output = Cursor::new(duplex.output);
+        let mut sent = vec![];
+        let mut header = [0; FRAME_HEADER_SIZE];
+        while output.read_exact(&mut header).is_ok() {
+            let length =
+                usize::from(header[0]) << 16 | usize::from(header[1]) << 8 | usize::from(header[2]);
+            let mut payload = vec![0; length];
+            output.read_exact(&mut payload)?;
+            sent.push(Frame {
+                kind: header[3],
+                flags: header[4],
+                stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]),
+                payload,
+            });
+        }
+
+        Ok(sent)
+    }
+
+    fn response_frames(sent: &[Frame], stream_id: u32) -> (Vec<(String, String)>, Vec<u8>) {
+        let mut decoder = hpack::Decoder::new();
+        let mut fields = vec![];
+        let mut body = vec![];
+        for frame in sent {
+            match frame.kind {
+                HEADERS | CONTINUATION => {
+                    let decoded = decoder.decode(&frame.payload).unwrap();
+                    if frame.stream_id == stream_id {
+                        fields.extend(decoded.into_iter().map(|(name, value)| {
+                            (
+                                String::from_utf8(name).unwrap(),
+                                String::from_utf8(value).unwrap(),
+                            )
+                        }));
+                    }
+                }
+                DATA if frame.stream_id == stream_id => body.extend(&frame.payload),
+                _ => {}
+            }
+        }
+
+        (fields, body)
+    }
+
+    #[test]
+    fn settings_are_exchanged() -> Result<()> {
+        let sent = exchange(&[])?;
+
+        assert_eq!(sent[0].kind, SETTINGS);
+        assert_eq!(sent[0].flags, 0);
+        assert_eq!(sent[0].payload, [0, 3, 0, 0, 0, 100]);
+        assert_eq!(sent[1].kind, SETTINGS);
+        assert_eq!(sent[1].flags, ACK);
+        Ok(())
+    }
+
+    #[test]
+    fn get_request() -> Result<()> {
+        let mut encoder = hpack::Encoder::new();
+        let sent = exchange(&[frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            1,
+            &get(&mut encoder, "/echo/hi%21?x=1"),
+        )])?;
+        let (fields, body) = response_frames(&sent, 1);
+
+        assert_eq!(
+            fields,
+            vec![
+                (":status".to_string(), "200".to_string()),
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("x-host".to_string(), "localhost".to_string()),
+                ("content-length".to_string(), "9".to_string()),
+            ]
+        );
+        assert_eq!(body, b"/echo/hi!");
+        assert!(sent.last().unwrap().has(END_STREAM));
+        Ok(())
+    }
+
+    #[test]
+    fn streams_with_bodies_and_continuations() -> Result<()> {
+        let mut encoder = hpack::Encoder::new();
+        let block = headers(
+            &mut encoder,
+            &[
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":path", "/upload"),
+                (":authority", "localhost"),
+            ],
+        );
+        let (first, second) = block.split_at(block.len() / 2);
+        let sent = exchange(&[
+            frame(HEADERS, 0, 1, first),
+            frame(CONTINUATION, END_HEADERS, 1, second),
+            frame(
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                3,
+                &get(&mut encoder, "/3"),
+            ),
+            frame(DATA, 0, 1, b"Hello, "),
+            // Padded with 3 bytes
+            frame(DATA, PADDED | END_STREAM, 1, b"\x03world!\0\0\0"),
+        ])?;
+
+        assert_eq!(response_frames(&sent, 1).1, b"Hello, world!");
+        assert_eq!(response_frames(&sent, 3).1, b"/3");
+        Ok(())
+    }
+
+    #[test]
+    fn ping_is_acknowledged() -> Result<()> {
+        let sent = exchange(&[frame(PING, 0, 0, b"12345678")])?;
+        let pong = sent.iter().find(|frame| frame.kind == PING).unwrap();
+
+        assert_eq!(pong.flags, ACK);
+        assert_eq!(pong.payload, b"12345678");
+        Ok(())
+    }
+
+    #[test]
+    fn data_waits_for_the_window() -> Result<()> {
+        let mut encoder = hpack::Encoder::new();
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend(4_u32.to_be_bytes());
+        let sent = exchange(&[
+            frame(SETTINGS, 0, 0, &settings),
+            frame(
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                1,
+                &get(&mut encoder, "/0123456789"),
+            ),
+            frame(WINDOW_UPDATE, 0, 1, &100_u32.to_be_bytes()),
+        ])?;
+
+        let data: Vec<&Frame> = sent.iter().filter(|frame| frame.kind == DATA).collect();
+        assert_eq!(data[0].payload, b"/012");
+        assert!(!data[0].has(END_STREAM));
+        assert_eq!(data[1].payload, b"3456789");
+        assert!(data[1].has(END_STREAM));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_preface_is_refused() {
+        let mut duplex = Duplex {
+            input: Cursor::new(b"GET / HTTP/1.1\r\n\r\n\r\n\r\n\r\n\r\n".to_vec()),
+            output: vec![],
+        };
+        let err = serve(&mut duplex, vec![], Limits::default(), echo).unwrap_err();
+
+        assert_eq!(err.downcast::<Error>().unwrap(), Error::MissingPreface);
+        // GOAWAY with PROTOCOL_ERROR
+        assert_eq!(duplex.output[3], GOAWAY);
+        assert_eq!(duplex.output[FRAME_HEADER_SIZE + 4..], [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn goaway_finishes_the_connection() -> Result<()> {
+        let mut encoder = hpack::Encoder::new();
+        let sent = exchange(&[
+            frame(
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                1,
+                &get(&mut encoder, "/1"),
+            ),
+            frame(GOAWAY, 0, 0, &[0; 8]),
+            frame(
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                3,
+                &get(&mut encoder, "/3"),
+            ),
+        ])?;
+
+        assert_eq!(response_frames(&sent, 1).1, b"/1");
+        assert!(response_frames(&sent, 3).1.is_empty());
+        assert_eq!(sent.last().unwrap().kind, GOAWAY);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_table_size_updates_are_compression_errors() {
+        // Too many octets, which `hpack` would panic on, and a table larger than the default
+        for block in [
+            &[0x3f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f][..],
+            &[0x3f, 0xe2, 0x1f],
+            // After a field
+            &[0x82, 0x20],
+        ] {
+            let mut input = PREFACE.to_vec();
+            input.extend(frame(HEADERS, END_HEADERS | END_STREAM, 1, block));
+            let mut duplex = Duplex {
+                input: Cursor::new(input),
+                output: vec![],
+            };
+            let err = serve(&mut duplex, vec![], Limits::default(), echo).unwrap_err();
+
+            assert_eq!(err.downcast::<Error>().unwrap(), Error::Compression);
+            assert_eq!(duplex.output[duplex.output.len() - 4..], [0, 0, 0, 9]);
+        }
+    }
+
+    #[test]
+    fn invalid_fields_are_bad_requests() -> Result<()> {
+        let mut encoder = hpack::Encoder::new();
+        let mut frames = vec![];
+        for (stream_id, field) in [
+            (1, ("x-test", "a\r\nb")),
+            (3, ("x-test", "a\0b")),
+            (5, ("x-test", " padded")),
+            (7, ("X-Test", "upper")),
+            (9, ("x test", "space")),
+        ] {
+            frames.push(frame(
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                stream_id,
+                &headers(
+                    &mut encoder,
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "localhost"),
+                        field,
+                    ],
+                ),
+            ));
+        }
+        let sent = exchange(&frames)?;
+
+        for stream_id in [1, 3, 5, 7, 9] {
+            let (fields, _) = response_frames(&sent, stream_id);
+            assert_eq!(fields[0], (":status".to_string(), "400".to_string()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn requests_are_held_to_the_limits() -> Result<()> {
+        let mut encoder = hpack::Encoder::new();
+        let long = "a".repeat(64);
+        let sent = exchange_with_limits(
+            &[
+                frame(
+                    HEADERS,
+                    END_HEADERS,
+                    1,
+                    &headers(
+                        &mut encoder,
+                        &[
+                            (":method", "POST"),
+                            (":scheme", "http"),
+                            (":path", "/upload"),
+                            (":authority", "localhost"),
+                        ],
+                    ),
+                ),
+                frame(DATA, 0, 1, b"Hello, "),
+                frame(DATA, 0, 1, b"world!"),
+                frame(
+                    HEADERS,
+                    END_HEADERS | END_STREAM,
+                    3,
+                    &headers(
+                        &mut encoder,
+                        &[
+                            (":method", "GET"),
+                            (":scheme", "http"),
+                            (":path", "/"),
+                            (":authority", "localhost"),
+                            ("x-long", &long),
+                        ],
+                    ),
+                ),
+            ],
+            Limits {
+                max_body: Some(8),
+                max_head: Some(1_024),
+                max_line: Some(32),
+            },
+        )?;
+
+        assert_eq!(sent[0].payload[6..], [0, 6, 0, 0, 4, 0]);
+        let (fields, body) = response_frames(&sent, 1);
+        assert_eq!(fields[0], (":status".to_string(), "413".to_string()));
+        assert!(body.is_empty());
+        assert!(
+            sent.iter()
+                .any(|frame| frame.kind == RST_STREAM && frame.stream_id == 1)
+        );
+        assert_eq!(
+            response_frames(&sent, 3).0[0],
+            (":status".to_string(), "431".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn header_blocks_larger_than_a_head_end_the_connection() {
+        let mut encoder = hpack::Encoder::new();
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(HEADERS, 0, 1, &get(&mut encoder, "/")));
+        input.extend(frame(CONTINUATION, 0, 1, &[0x82; 64]));
+        let mut duplex = Duplex {
+            input: Cursor::new(input),
+            output: vec![],
+        };
+        let limits = Limits {
+            max_head: Some(64),
+            ..Limits::default()
+        };
+        let err = serve(&mut duplex, vec![], limits, echo).unwrap_err();
+
+        assert_eq!(err.downcast::<Error>().unwrap(), Error::HeaderBlockTooLarge);
+    }
+
+    #[test]
+    fn streams_beyond_the_cap_are_refused() -> Result<()> {
+        let mut encoder = hpack::Encoder::new();
+        let frames: Vec<_> = (0..=MAX_CONCURRENT_STREAMS)
+            .map(|n| frame(HEADERS, END_HEADERS, n * 2 + 1, &get(&mut encoder, "/")))
+            .collect();
+        let sent = exchange(&frames)?;
+
+        let reset: Vec<&Frame> = sent
+            .iter()
+            .filter(|frame| frame.kind == RST_STREAM)
+            .collect();
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].stream_id, MAX_CONCURRENT_STREAMS * 2 + 1);
+        assert_eq!(reset[0].payload, REFUSED_STREAM.to_be_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+//! What `/healthz` and `/readyz` report, for load balancers and orchestrators deciding whether to
+//! restart the server or send it traffic
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Health {
+    accepting: AtomicBool,
+    stopping: AtomicBool,
+}
+
+impl Health {
+    /// Set while the listener is handing connections to the pool, and cleared as soon as it
+    /// stops (eg, to shut down) so traffic is sent elsewhere
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::Relaxed);
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    /// Asks the listeners to stop, and connections not to be kept open, so the server can exit
+    /// once the requests it has are answered
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+}
@@ -1,38 +1,183 @@
-use std::hash::{Hash, Hasher};
+use std::{
+    hash::{Hash, Hasher},
+    ops::RangeInclusive,
+    slice,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub const VERSION: &[u8] = b"HTTP/1.1";
 pub const CRLF: &[u8; 2] = b"\r\n";
-pub const SUPPORTED_ENCODINGS: [&str; 1] = ["gzip"];
+/// The content codings responses can be compressed with, in the order they are preferred when a
+/// client weights them the same
+pub const SUPPORTED_ENCODINGS: &[&str] = &[
+    #[cfg(feature = "brotli")]
+    "br",
+    #[cfg(feature = "zstd")]
+    "zstd",
+    "gzip",
+    #[cfg(feature = "deflate")]
+    "deflate",
+];
 
-#[derive(Debug, Ord, PartialOrd)]
+#[derive(Debug)]
 pub enum Header {
+    Allow(String),
     ContentEncoding(String),
     ContentType(String),
+    ETag(String),
     Custom(String, String),
 }
 
 impl Header {
     pub fn name(&self) -> &str {
         match self {
+            Self::Allow(_) => "Allow",
             Self::ContentEncoding(_) => "Content-Encoding",
             Self::ContentType(_) => "Content-Type",
+            Self::ETag(_) => "ETag",
             Self::Custom(name, _) => &name[..],
         }
     }
 
     pub fn value(&self) -> &str {
         match self {
-            Self::ContentEncoding(value) | Self::ContentType(value) | Self::Custom(_, value) => {
-                value
+            Self::Allow(value)
+            | Self::ContentEncoding(value)
+            | Self::ContentType(value)
+            | Self::ETag(value)
+            | Self::Custom(_, value) => value,
+        }
+    }
+
+    /// Whether this is the header called `name`, ignoring case
+    pub fn is(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name)
+    }
+
+    /// Whether the header can be sent as is: a token for a name, and only visible characters,
+    /// spaces and tabs in the value (RFC 9110 section 5). Anything else, CR and LF especially,
+    /// would let a value taken from the request end the header and start another.
+    pub fn is_valid(&self) -> bool {
+        let name = self.name();
+        let value = self.value();
+
+        !name.is_empty()
+            && name.bytes().all(is_token_char)
+            && value
+                .bytes()
+                .all(|byte| byte.is_ascii_graphic() || byte == b' ' || byte == b'\t')
+    }
+}
+
+/// A response's headers, in the order they were added and as many times as they were, so fields
+/// that can't be combined into one line (eg, `Set-Cookie`) can still be sent more than once
+#[derive(Debug, Default)]
+pub struct Headers(Vec<Header>);
+
+impl Headers {
+    pub const fn new() -> Self {
+        Self(vec![])
+    }
+
+    /// Adds `header` after the others, alongside any called the same
+    pub fn append(&mut self, header: Header) {
+        self.0.push(header);
+    }
+
+    /// Puts `header` in the place of the first one called the same, dropping any others, or
+    /// after the others when there aren't any
+    pub fn set(&mut self, header: Header) {
+        match self
+            .0
+            .iter()
+            .position(|existing| existing.is(header.name()))
+        {
+            Some(index) => {
+                let mut position = 0;
+                self.0.retain(|existing| {
+                    position += 1;
+                    position <= index + 1 || !existing.is(header.name())
+                });
+                self.0[index] = header;
             }
+            None => self.0.push(header),
         }
     }
+
+    /// The value of the first header called `name`, ignoring case
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|header| header.is(name))
+            .map(Header::value)
+    }
+
+    /// The values of every header called `name`, ignoring case, in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |header| header.is(name))
+            .map(Header::value)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|header| header.is(name))
+    }
+
+    pub fn retain<F: FnMut(&Header) -> bool>(&mut self, keep: F) {
+        self.0.retain(keep);
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Header> {
+        self.0.iter()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("Content-Type")
+    }
+
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.get("Content-Encoding")
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a Header;
+    type IntoIter = slice::Iter<'a, Header>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// RFC 9110 section 5.6.2
+const fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric()
+        || matches!(
+            byte,
+            b'!' | b'#'
+                | b'$'
+                | b'%'
+                | b'&'
+                | b'\''
+                | b'*'
+                | b'+'
+                | b'-'
+                | b'.'
+                | b'^'
+                | b'_'
+                | b'`'
+                | b'|'
+                | b'~'
+        )
 }
 
 impl Hash for Header {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
             Self::ContentEncoding(_) => 2.hash(state),
+            Self::Allow(_) => 3.hash(state),
+            Self::ETag(_) => 4.hash(state),
             Self::ContentType(_) => 0.hash(state),
             Self::Custom(name, _) => {
                 1.hash(state);
@@ -45,7 +190,10 @@ impl Hash for Header {
 impl PartialEq for Header {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Self::ContentType(_), Self::ContentType(_)) => true,
+            (Self::Allow(_), Self::Allow(_))
+            | (Self::ContentEncoding(_), Self::ContentEncoding(_))
+            | (Self::ContentType(_), Self::ContentType(_))
+            | (Self::ETag(_), Self::ETag(_)) => true,
             (Self::Custom(name1, _), Self::Custom(name2, _)) => name1 == name2,
             _ => false,
         }
@@ -54,11 +202,562 @@ impl PartialEq for Header {
 
 impl Eq for Header {}
 
+/// Whether `etag` is in an `If-None-Match` list, using the weak comparison RFC 9110 requires
+pub fn if_none_match(header: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+    header
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// Whether `etag` is in an `If-Match` list, using the strong comparison RFC 9110 requires, so
+/// weak validators never match
+pub fn if_match(header: &str, etag: &str) -> bool {
+    header.split(',').any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || (!tag.starts_with("W/") && !etag.starts_with("W/") && tag == etag)
+    })
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A point in time broken down into its UTC calendar date and time of day
+struct Civil {
+    year: u64,
+    /// From 0 for January
+    month: usize,
+    day: u64,
+    /// From 0 for Thursday, as 1 January 1970 was
+    weekday: usize,
+    /// Since midnight
+    seconds: u64,
+}
+
+impl Civil {
+    fn new(time: SystemTime) -> Self {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+        // Howard Hinnant's civil_from_days, with eras of 400 years starting on 1 March
+        let shifted = days + 719_468;
+        let era = shifted / 146_097;
+        let day_of_era = shifted % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = (month_index + 2) % 12;
+
+        Self {
+            year: era * 400 + year_of_era + u64::from(month < 2),
+            month: month as usize,
+            day,
+            weekday: days as usize % 7,
+            seconds,
+        }
+    }
+
+    fn time(&self) -> String {
+        format!(
+            "{:02}:{:02}:{:02}",
+            self.seconds / 3_600,
+            self.seconds / 60 % 60,
+            self.seconds % 60
+        )
+    }
+}
+
+/// Formats `time` as an IMF-fixdate, the HTTP-date format RFC 9110 says to generate
+pub fn date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+    let civil = Civil::new(time);
+    format!(
+        "{}, {:02} {} {} {} GMT",
+        WEEKDAYS[civil.weekday],
+        civil.day,
+        MONTHS[civil.month],
+        civil.year,
+        civil.time()
+    )
+}
+
+/// Parses an IMF-fixdate (eg, `Sun, 06 Nov 1994 08:49:37 GMT`), the only HTTP-date format
+/// clients still send, as `None` if it isn't one
+pub fn parse_date(date: &str) -> Option<SystemTime> {
+    let [weekday, day, month, year, time, "GMT"] = *date.split_whitespace().collect::<Vec<_>>()
+    else {
+        return None;
+    };
+    if weekday.len() != 4 || !weekday.ends_with(',') {
+        return None;
+    }
+    let day: u64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u64;
+    let year: u64 = year.parse().ok().filter(|&year| year >= 1970)?;
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    if hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    // Howard Hinnant's days_from_civil, the other way round from `Civil::new`
+    let year = year - u64::from(month < 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 10) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + hours * 3_600 + minutes * 60 + seconds))
+}
+
+/// Formats `time` as Common Log Format does, eg `10/Oct/2000:13:55:36 +0000`
+pub fn log_date(time: SystemTime) -> String {
+    let civil = Civil::new(time);
+    format!(
+        "{:02}/{}/{}:{} +0000",
+        civil.day,
+        MONTHS[civil.month],
+        civil.year,
+        civil.time()
+    )
+}
+
+/// Decodes `%XX` escapes, failing on malformed escapes or if the result isn't UTF-8
+pub fn percent_decode(input: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = char::from(bytes.next()?).to_digit(16)?;
+            let low = char::from(bytes.next()?).to_digit(16)?;
+            // Safety: two hex digits always fit in a byte
+            decoded.push(u8::try_from(high << 4 | low).unwrap());
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Percent-encodes a decoded path for a request target, leaving `/` and the characters RFC 3986
+/// allows in a path segment as they are
+pub fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~!$&'()*+,;=:@".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    encoded
+}
+
+/// A `Content-Disposition` that has browsers save the response as `filename` rather than show it
+///
+/// `filename` has the name quoted, with anything that isn't printable ASCII replaced, for clients
+/// that only understand that. Names that needed replacing also go in full in an RFC 5987
+/// `filename*`, which clients prefer when they can read it.
+pub fn attachment(filename: &str) -> String {
+    let mut fallback = String::with_capacity(filename.len());
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                fallback.push('\\');
+                fallback.push(c);
+            }
+            ' '..='~' => fallback.push(c),
+            _ => fallback.push('_'),
+        }
+    }
+    if filename.chars().all(|c| matches!(c, ' '..='~')) {
+        return format!("attachment; filename=\"{fallback}\"");
+    }
+
+    let mut encoded = String::with_capacity(filename.len() * 3);
+    for byte in filename.bytes() {
+        // RFC 5987 section 3.2.1's attr-char
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// The outcome of applying a `Range` request header to a representation of `length` bytes
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// Not a range we understand, so the header is ignored and the full representation sent
+    Ignored,
+    Satisfiable(RangeInclusive<u64>),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parses a single `bytes=first-last`, `bytes=first-` or `bytes=-suffix` range
+    ///
+    /// Multiple ranges would need a `multipart/byteranges` response, so are ignored (which
+    /// RFC 9110 permits).
+    pub fn parse(header: &str, length: u64) -> Self {
+        let Some((unit, spec)) = header.split_once('=') else {
+            return Self::Ignored;
+        };
+        if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+            return Self::Ignored;
+        }
+        let Some((first, last)) = spec.trim().split_once('-') else {
+            return Self::Ignored;
+        };
+
+        let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Ok(first), Ok(last)) if first <= last => first..=last.min(length.saturating_sub(1)),
+            (Ok(first), Err(_)) if last.is_empty() => first..=length.saturating_sub(1),
+            (Err(_), Ok(suffix)) if first.is_empty() => {
+                if suffix == 0 {
+                    return Self::Unsatisfiable;
+                }
+                length.saturating_sub(suffix)..=length.saturating_sub(1)
+            }
+            _ => return Self::Ignored,
+        };
+
+        if *range.start() >= length {
+            Self::Unsatisfiable
+        } else {
+            Self::Satisfiable(range)
+        }
+    }
+}
+
+/// What to encode a response in, going by its `Accept-Encoding` (RFC 9110 section 12.5.3)
+#[derive(Debug, PartialEq, Eq)]
+pub enum Negotiated {
+    /// One of those offered
+    Encoding(&'static str),
+    Identity,
+    /// The client refuses everything on offer, even the body as it is
+    NotAcceptable,
+}
+
+/// Picks the encoding in `offered` the client prefers, sending the body as it is when it prefers
+/// that (or didn't say). Ties go to the encoding, as a smaller body is what the client asked for,
+/// and then to the first offered.
+pub fn negotiate_encoding(accept_encoding: Option<&str>, offered: &[&'static str]) -> Negotiated {
+    let Some(header) = accept_encoding else {
+        return Negotiated::Identity;
+    };
+    let accepted = weighted(header);
+    let quality = |coding: &str| {
+        accepted
+            .iter()
+            .find(|(accepted, _)| accepted == coding)
+            .or_else(|| accepted.iter().find(|(accepted, _)| accepted == "*"))
+            .map(|(_, quality)| *quality)
+    };
+
+    let best = offered
+        .iter()
+        .filter_map(|&encoding| Some((encoding, quality(encoding)?)))
+        .filter(|(_, quality)| *quality > 0)
+        .fold(
+            None,
+            |best: Option<(&'static str, u16)>, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            },
+        );
+    // Always acceptable unless ruled out, by name or with `*`, but only preferred when weighted
+    let identity = quality("identity");
+
+    match best {
+        Some((encoding, quality)) if identity.is_none_or(|identity| quality >= identity) => {
+            Negotiated::Encoding(encoding)
+        }
+        _ if identity != Some(0) => Negotiated::Identity,
+        _ => Negotiated::NotAcceptable,
+    }
+}
+
+/// Picks the media type in `offered` the client weights highest going by its `Accept` (RFC 9110
+/// section 12.5.1), or the first when it didn't say. Each is weighted by the most specific range
+/// matching it, and ties go to the first offered. `None` when the client accepts none of them.
+pub fn negotiate_media_type(
+    accept: Option<&str>,
+    offered: &[&'static str],
+) -> Option<&'static str> {
+    let Some(header) = accept else {
+        return offered.first().copied();
+    };
+    let accepted = weighted(header);
+    let quality = |media_type: &str| {
+        let (kind, _) = media_type.split_once('/')?;
+        accepted
+            .iter()
+            .filter_map(|(range, quality)| {
+                let specificity = match range.split_once('/')? {
+                    ("*", "*") => 0,
+                    (given, "*") if given.eq_ignore_ascii_case(kind) => 1,
+                    _ if range.eq_ignore_ascii_case(media_type) => 2,
+                    _ => return None,
+                };
+                Some((specificity, *quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, quality)| quality)
+    };
+
+    offered
+        .iter()
+        .filter_map(|&media_type| Some((media_type, quality(media_type)?)))
+        .filter(|(_, quality)| *quality > 0)
+        .fold(
+            None,
+            |best: Option<(&'static str, u16)>, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            },
+        )
+        .map(|(media_type, _)| media_type)
+}
+
+/// The items of an `Accept`-style header in lowercase, without their parameters, and each with its
+/// weight in thousandths
+fn weighted(header: &str) -> Vec<(String, u16)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let value = parts.next()?.trim().to_ascii_lowercase();
+            let mut quality = 1000;
+            for parameter in parts {
+                if let Some((name, value)) = parameter.split_once('=')
+                    && name.trim().eq_ignore_ascii_case("q")
+                {
+                    // A malformed weight makes the whole item unusable
+                    quality = qvalue(value.trim())?;
+                }
+            }
+            (!value.is_empty()).then_some((value, quality))
+        })
+        .collect()
+}
+
+/// A weight from `0` to `1` with up to three decimals, in thousandths
+fn qvalue(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if !matches!(whole, "0" | "1")
+        || fraction.len() > 3
+        || !fraction.bytes().all(|byte| byte.is_ascii_digit())
+    {
+        return None;
+    }
+    let thousandths = format!("{fraction:0<3}").parse::<u16>().ok()?;
+    let quality = if whole == "1" { 1000 } else { 0 } + thousandths;
+
+    (quality <= 1000).then_some(quality)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use std::collections::HashSet;
 
+    #[test]
+    fn encoding_negotiation() {
+        let negotiate = |header| negotiate_encoding(Some(header), &["gzip"]);
+        let gzip = Negotiated::Encoding("gzip");
+
+        assert_eq!(negotiate_encoding(None, &["gzip"]), Negotiated::Identity);
+        assert_eq!(negotiate("gzip"), gzip);
+        assert_eq!(negotiate("GZIP, deflate"), gzip);
+        assert_eq!(negotiate("invalid-1, gzip, invalid-2"), gzip);
+        assert_eq!(negotiate("br;q=1.0, gzip;q=0.5"), gzip);
+        assert_eq!(negotiate("*"), gzip);
+        assert_eq!(negotiate("gzip;q=0.5, identity"), Negotiated::Identity);
+        assert_eq!(negotiate("gzip;q=0, *"), Negotiated::Identity);
+        assert_eq!(negotiate("gzip;q=1.5"), Negotiated::Identity);
+        assert_eq!(negotiate("deflate, br"), Negotiated::Identity);
+        assert_eq!(negotiate(""), Negotiated::Identity);
+        assert_eq!(negotiate("br, identity;q=0"), Negotiated::NotAcceptable);
+        assert_eq!(negotiate("*;q=0"), Negotiated::NotAcceptable);
+        assert_eq!(negotiate("gzip;q=0.001, *;q=0"), gzip);
+
+        let offered = ["br", "zstd", "gzip"];
+        let negotiate = |header| negotiate_encoding(Some(header), &offered);
+        assert_eq!(negotiate("gzip, br"), Negotiated::Encoding("br"));
+        assert_eq!(
+            negotiate("br;q=0.5, zstd;q=0.8"),
+            Negotiated::Encoding("zstd")
+        );
+        assert_eq!(negotiate("*, br;q=0"), Negotiated::Encoding("zstd"));
+    }
+
+    #[test]
+    fn media_type_negotiation() {
+        let offered = ["text/plain", "application/json"];
+        let negotiate = |header| negotiate_media_type(Some(header), &offered);
+
+        assert_eq!(negotiate_media_type(None, &offered), Some("text/plain"));
+        assert_eq!(negotiate("*/*"), Some("text/plain"));
+        assert_eq!(negotiate("application/json"), Some("application/json"));
+        assert_eq!(negotiate("Application/JSON"), Some("application/json"));
+        assert_eq!(negotiate("application/*"), Some("application/json"));
+        assert_eq!(
+            negotiate("text/plain;q=0.5, application/json"),
+            Some("application/json")
+        );
+        assert_eq!(
+            negotiate("text/*;charset=utf-8;q=0.9, */*;q=0.1"),
+            Some("text/plain")
+        );
+        assert_eq!(negotiate("*/*, text/plain;q=0"), Some("application/json"));
+        assert_eq!(negotiate("application/json;q=2"), None);
+        assert_eq!(negotiate("image/png"), None);
+        assert_eq!(negotiate("text/html, */*;q=0"), None);
+    }
+
+    #[test]
+    fn dates() {
+        let at = |seconds| date(UNIX_EPOCH + std::time::Duration::from_secs(seconds));
+
+        assert_eq!(at(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // The example from RFC 9110
+        assert_eq!(at(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(at(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(at(1_735_689_599), "Tue, 31 Dec 2024 23:59:59 GMT");
+
+        for seconds in [0, 784_111_777, 951_782_400, 1_735_689_599] {
+            assert_eq!(
+                parse_date(&at(seconds)),
+                Some(UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+            );
+        }
+        assert_eq!(parse_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_date("Sun, 06 Nov 1994 24:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn log_dates() {
+        assert_eq!(
+            log_date(UNIX_EPOCH + std::time::Duration::from_secs(971_182_536)),
+            "10/Oct/2000:12:55:36 +0000"
+        );
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(
+            percent_decode("/echo/hello%20world").unwrap(),
+            "/echo/hello world"
+        );
+        assert_eq!(percent_decode("/caf%C3%a9").unwrap(), "/café");
+        assert_eq!(percent_decode("/plain").unwrap(), "/plain");
+        assert_eq!(percent_decode("/bad%2"), None);
+        assert_eq!(percent_decode("/bad%zz"), None);
+        assert_eq!(percent_decode("/bad%FF"), None);
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        for path in ["/echo/hello world", "/café", "/a%b?c#d", "/keep/-._~:@"] {
+            let encoded = percent_encode_path(path);
+            assert_eq!(percent_decode(&encoded).unwrap(), path);
+        }
+        assert_eq!(percent_encode_path("/a b"), "/a%20b");
+    }
+
+    #[test]
+    fn attachments_are_named_safely() {
+        assert_eq!(
+            attachment("notes.txt"),
+            "attachment; filename=\"notes.txt\""
+        );
+        assert_eq!(
+            attachment("say \"hi\"\\.txt"),
+            "attachment; filename=\"say \\\"hi\\\"\\\\.txt\""
+        );
+        assert_eq!(
+            attachment("café menu.pdf"),
+            "attachment; filename=\"caf_ menu.pdf\"; filename*=UTF-8''caf%C3%A9%20menu.pdf"
+        );
+        assert!(Header::Custom("Content-Disposition".to_string(), attachment("a\r\nb")).is_valid());
+    }
+
+    #[test]
+    fn if_none_match_lists() {
+        assert!(if_none_match("\"abc\"", "\"abc\""));
+        assert!(if_none_match("\"xyz\", W/\"abc\"", "\"abc\""));
+        assert!(if_none_match("*", "\"abc\""));
+        assert!(!if_none_match("\"xyz\"", "\"abc\""));
+    }
+
+    #[test]
+    fn if_match_lists() {
+        assert!(if_match("\"xyz\", \"abc\"", "\"abc\""));
+        assert!(if_match("*", "\"abc\""));
+        assert!(!if_match("W/\"abc\"", "\"abc\""));
+        assert!(!if_match("\"abc\"", "W/\"abc\""));
+        assert!(!if_match("\"xyz\"", "\"abc\""));
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-4", 10),
+            ByteRange::Satisfiable(0..=4)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=5-", 10),
+            ByteRange::Satisfiable(5..=9)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-3", 10),
+            ByteRange::Satisfiable(7..=9)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-30", 10),
+            ByteRange::Satisfiable(0..=9)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=8-100", 10),
+            ByteRange::Satisfiable(8..=9)
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_byte_ranges() {
+        assert_eq!(ByteRange::parse("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=-0", 10), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=0-0", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignored_byte_ranges() {
+        assert_eq!(ByteRange::parse("items=0-4", 10), ByteRange::Ignored);
+        assert_eq!(ByteRange::parse("bytes=0-1,3-4", 10), ByteRange::Ignored);
+        assert_eq!(ByteRange::parse("bytes=4-1", 10), ByteRange::Ignored);
+        assert_eq!(ByteRange::parse("bytes=abc", 10), ByteRange::Ignored);
+        assert_eq!(ByteRange::parse("bytes=-", 10), ByteRange::Ignored);
+    }
+
     #[test]
     fn duplicate_headers_are_not_allowed() {
         let mut headers = HashSet::new();
@@ -74,6 +773,68 @@ mod test {
         assert!(headers.contains(&Header::Custom("x-server".to_string(), "rust".to_string())));
     }
 
+    #[test]
+    fn headers_keep_their_order_and_repeats() {
+        let custom = |name: &str, value: &str| Header::Custom(name.to_string(), value.to_string());
+        let mut headers = Headers::new();
+        headers.append(custom("Vary", "Accept"));
+        headers.append(Header::ContentType("text/plain".to_string()));
+        headers.append(custom("vary", "Accept-Encoding"));
+
+        assert_eq!(
+            headers.iter().map(Header::name).collect::<Vec<_>>(),
+            ["Vary", "Content-Type", "vary"]
+        );
+        assert_eq!(headers.get("VARY"), Some("Accept"));
+        assert_eq!(
+            headers.get_all("Vary").collect::<Vec<_>>(),
+            ["Accept", "Accept-Encoding"]
+        );
+        assert_eq!(headers.content_type(), Some("text/plain"));
+        assert!(!headers.contains("Content-Encoding"));
+    }
+
+    #[test]
+    fn set_replaces_headers_in_place() {
+        let custom = |name: &str, value: &str| Header::Custom(name.to_string(), value.to_string());
+        let mut headers = Headers::new();
+        headers.append(custom("X-One", "1"));
+        headers.append(custom("X-Two", "a"));
+        headers.append(custom("X-Three", "3"));
+        headers.append(custom("x-two", "b"));
+
+        headers.set(custom("X-Two", "c"));
+        headers.set(Header::ContentType("text/html".to_string()));
+        headers.set(custom("content-type", "text/plain"));
+
+        assert_eq!(
+            headers
+                .iter()
+                .map(|header| (header.name(), header.value()))
+                .collect::<Vec<_>>(),
+            [
+                ("X-One", "1"),
+                ("X-Two", "c"),
+                ("X-Three", "3"),
+                ("content-type", "text/plain")
+            ]
+        );
+    }
+
+    #[test]
+    fn headers_must_not_break_framing() {
+        let custom = |name: &str, value: &str| Header::Custom(name.to_string(), value.to_string());
+
+        assert!(custom("X-Name", "a value\twith \"quotes\"").is_valid());
+        assert!(!custom("X-Name", "a\r\nSet-Cookie: x=1").is_valid());
+        assert!(!custom("X-Name", "a\nb").is_valid());
+        assert!(!custom("X-Name", "caf\u{e9}").is_valid());
+        assert!(!custom("X-Name", "bell\x07").is_valid());
+        assert!(!custom("X Name", "value").is_valid());
+        assert!(!custom("", "value").is_valid());
+        assert!(!Header::ContentType("text/plain\r\n".to_string()).is_valid());
+    }
+
     #[test]
     fn should_not_be_equal() {
         let header1 = Header::ContentType("type".to_string());
@@ -0,0 +1,139 @@
+//! Which clients may connect at all (`--allow-ip`, `--deny-ip`), checked as soon as a connection
+//! is accepted so unwanted clients never reach the router
+//!
+//! Connections from `--trusted-proxies` are let in regardless, and each request they forward is
+//! checked against the client it was forwarded for instead. Clients without an address (eg, on a
+//! Unix socket) are let in.
+
+use anyhow::{Context, Result, bail};
+use std::{net::IpAddr, str::FromStr};
+
+/// An address range in CIDR notation (eg, `10.0.0.0/8`), or a single address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener show up as IPv4-mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            IpAddr::V4(_) => address,
+        };
+
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(cidr: &str) -> Result<Self> {
+        let (network, prefix) = cidr.split_once('/').unwrap_or((cidr, ""));
+        let network = network
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid address in {cidr}"))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => prefix
+                .parse::<u8>()
+                .with_context(|| format!("Invalid prefix length in {cidr}"))?,
+        };
+        if prefix > bits {
+            bail!("Prefix length in {cidr} is longer than {bits} bits");
+        }
+
+        Ok(Self { network, prefix })
+    }
+}
+
+/// Denied ranges win over allowed ones, and an empty allow list lets everyone else in
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    #[must_use]
+    pub fn allow(mut self, cidr: Cidr) -> Self {
+        self.allow.push(cidr);
+        self
+    }
+
+    #[must_use]
+    pub fn deny(mut self, cidr: Cidr) -> Self {
+        self.deny.push(cidr);
+        self
+    }
+
+    /// Whether the client at `peer`, as `Stream::peer` gives it, may connect
+    pub fn permits(&self, peer: &str) -> bool {
+        let Ok(address) = peer.parse::<IpAddr>() else {
+            return true;
+        };
+
+        !self.deny.iter().any(|cidr| cidr.contains(address))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(address)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cidr(cidr: &str) -> Cidr {
+        cidr.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges() {
+        let private = cidr("10.0.0.0/8");
+        assert!(private.contains("10.1.2.3".parse().unwrap()));
+        assert!(!private.contains("11.0.0.1".parse().unwrap()));
+        assert!(private.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        assert!(cidr("0.0.0.0/0").contains("203.0.113.9".parse().unwrap()));
+        assert!(cidr("192.0.2.1").contains("192.0.2.1".parse().unwrap()));
+        assert!(!cidr("192.0.2.1").contains("192.0.2.2".parse().unwrap()));
+        assert!(cidr("2001:db8::/32").contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr("2001:db8::/32").contains("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("::/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = IpFilter::default()
+            .allow(cidr("10.0.0.0/8"))
+            .deny(cidr("10.0.0.13"));
+
+        assert!(filter.permits("10.0.0.1"));
+        assert!(!filter.permits("10.0.0.13"));
+        assert!(!filter.permits("192.0.2.1"));
+        assert!(filter.permits("unix"));
+        assert!(IpFilter::default().permits("192.0.2.1"));
+    }
+}
@@ -0,0 +1,1163 @@
+#![cfg_attr(coverage_nightly, feature(coverage_attribute))]
+
+use access_log::{AccessLog, LogFormat};
+use admin::Admin;
+use anyhow::{Context, Result, bail};
+use audit::Strictness;
+use auth::Credentials;
+use cache_control::CacheControl;
+use clap::{Parser, ValueEnum};
+use compression::{Policy, Precompressed};
+use config::Config;
+use connection::{Deadlines, KeepAlive};
+use cors::Cors;
+use fatal::Fatal;
+use file_cache::FileCache;
+use files::Symlinks;
+use forwarded::{ForwardedHeader, TrustedProxies};
+use health::Health;
+use limit::ConnectionLimit;
+use listener::Listener;
+use metrics::{Metrics, Report};
+use middleware::{ResponseHeader, Stack};
+use mirror::Mirror;
+use reload::Swap;
+use request::{Limits, Method};
+use serde::Serialize;
+use server::{Backend, QueueFullPolicy};
+use session::Sessions;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::{
+    fs,
+    net::TcpListener,
+    num::NonZeroUsize,
+    path::Path,
+    process::ExitCode,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use systemd::Inherited;
+use threadpool::ThreadPool;
+use tls::TlsListener;
+use tracing::{info, warn};
+
+mod access_log;
+mod admin;
+mod affinity;
+#[cfg(feature = "alloc-tracking")]
+mod alloc_tracking;
+#[cfg(all(feature = "async", unix))]
+mod async_server;
+mod audit;
+mod auth;
+mod buffers;
+mod bulk;
+mod cache_control;
+mod compression;
+mod config;
+mod connection;
+mod cookie;
+mod cors;
+mod dump;
+#[cfg(test)]
+mod duplex;
+#[cfg(test)]
+mod end_to_end;
+mod error_pages;
+#[cfg(unix)]
+mod evented;
+mod fatal;
+mod fields;
+mod file_cache;
+mod files;
+mod forwarded;
+mod h2;
+mod health;
+mod http;
+mod ip_filter;
+mod limit;
+mod listener;
+mod logging;
+mod metrics;
+mod middleware;
+mod mirror;
+mod multipart;
+pub mod parser;
+mod profiling;
+mod proxy_protocol;
+mod redact;
+mod redirects;
+mod reload;
+pub mod request;
+mod request_id;
+mod response;
+mod router;
+mod routes;
+mod sendfile;
+mod server;
+mod session;
+#[cfg(unix)]
+mod shutdown;
+mod sse;
+#[cfg(unix)]
+mod systemd;
+#[cfg(test)]
+mod testing;
+mod threadpool;
+mod tls;
+mod vhost;
+mod websocket;
+
+/// Every option can also be set with an `HTTP_SERVER_` environment variable (eg,
+/// `HTTP_SERVER_DIRECTORY`), which the command line takes precedence over
+#[derive(Parser, Debug, Serialize)]
+struct Args {
+    /// Address to listen on (can be repeated, eg for IPv4 and IPv6)
+    #[arg(
+        long,
+        env = "HTTP_SERVER_ADDRESS",
+        default_value = "127.0.0.1:4221",
+        value_delimiter = ','
+    )]
+    address: Vec<String>,
+
+    /// Bind with SO_REUSEPORT, so other processes (or `--acceptors`) can listen on the same
+    /// address, with the kernel spreading connections between them
+    #[arg(long, env = "HTTP_SERVER_REUSE_PORT")]
+    reuse_port: bool,
+
+    /// How many listeners, each with its own accept loop, to bind every `--address` with
+    #[arg(
+        long,
+        env = "HTTP_SERVER_ACCEPTORS",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        requires = "reuse_port"
+    )]
+    acceptors: u16,
+
+    /// Listen on this port instead of the ones in `--address`
+    #[arg(long, env = "HTTP_SERVER_PORT")]
+    #[serde(skip)]
+    port: Option<u16>,
+
+    /// Listen on this Unix domain socket instead of `--address`
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", env = "HTTP_SERVER_UNIX")]
+    unix: Option<String>,
+
+    #[arg(long, env = "HTTP_SERVER_DIRECTORY")]
+    directory: Option<String>,
+
+    /// Let `PUT /files` create any directories missing from the path
+    #[arg(long, env = "HTTP_SERVER_CREATE_PARENTS")]
+    create_parents: bool,
+
+    /// Have browsers save `/files` rather than show them, as `GET /files/NAME?download=1` does
+    /// for one
+    #[arg(long, env = "HTTP_SERVER_FORCE_DOWNLOAD")]
+    force_download: bool,
+
+    /// Refuse every request that would write to, move or delete anything in `--directory`, with
+    /// 403 Forbidden
+    #[arg(long, env = "HTTP_SERVER_READ_ONLY", conflicts_with = "create_parents")]
+    read_only: bool,
+
+    /// Which symlinks under `--directory`, `--static-root` and `--vhost`s to serve files through,
+    /// answering 404 for those through any other
+    #[arg(
+        long,
+        value_enum,
+        env = "HTTP_SERVER_FOLLOW_SYMLINKS",
+        default_value_t = Symlinks::SameRoot
+    )]
+    follow_symlinks: Symlinks,
+
+    /// Send `Cache-Control: VALUE` with the files under `--directory`, `--static-root` and
+    /// `--vhost`s whose name (or path, for patterns with a `/`) matches one of the comma-separated
+    /// globs in PATTERNS, eg `*.css,*.js=max-age=31536000, immutable` (can be repeated, the first
+    /// match deciding; separate them with `;` in the environment variable)
+    #[arg(
+        long = "cache-control",
+        value_name = "PATTERNS=VALUE",
+        env = "HTTP_SERVER_CACHE_CONTROL",
+        value_delimiter = ';'
+    )]
+    cache_control: Vec<String>,
+
+    /// Serve files from here for any GET the API routes don't handle, using index.html for
+    /// directories
+    #[arg(long, env = "HTTP_SERVER_STATIC_ROOT")]
+    static_root: Option<String>,
+
+    /// Serve only the static site in DIR to requests for HOST, instead of the usual routes (can
+    /// be repeated)
+    #[arg(
+        long = "vhost",
+        value_name = "HOST=DIR",
+        env = "HTTP_SERVER_VHOSTS",
+        value_delimiter = ','
+    )]
+    vhosts: Vec<String>,
+
+    /// Send requests for the path FROM to TO (a path or URL) with 301 Moved Permanently (can be
+    /// repeated)
+    #[arg(
+        long = "redirect",
+        value_name = "FROM=TO",
+        env = "HTTP_SERVER_REDIRECTS",
+        value_delimiter = ','
+    )]
+    redirects: Vec<String>,
+
+    /// Gzip the text files under `--static-root` as much as possible at startup, for clients
+    /// that accept it (files changed since are sent as they are)
+    #[arg(long, env = "HTTP_SERVER_PRECOMPRESS", requires = "static_root")]
+    precompress: bool,
+
+    /// Send smaller response bodies uncompressed (eg, `1KB`)
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "HTTP_SERVER_COMPRESS_MIN_SIZE",
+        default_value = "0",
+        value_parser = file_cache::parse_size
+    )]
+    compress_min_size: usize,
+
+    /// Only compress responses of this media type (eg, `text/*`, can be repeated, defaults to
+    /// text, JSON, SVG and WebAssembly)
+    #[arg(
+        long = "compress-type",
+        value_name = "TYPE",
+        env = "HTTP_SERVER_COMPRESS_TYPES",
+        value_delimiter = ','
+    )]
+    compress_types: Vec<String>,
+
+    /// Keep up to this much of the most used `/files` in memory (eg, `64MB`), rather than
+    /// reading them for every request
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "HTTP_SERVER_FILE_CACHE_SIZE",
+        value_parser = file_cache::parse_size
+    )]
+    file_cache_size: Option<usize>,
+
+    /// Send files at least this big (eg, `8MB`) from a memory map backed by the page cache,
+    /// instead of copying them through a buffer or with `sendfile(2)`. A file truncated while
+    /// it is being sent can crash the server.
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "HTTP_SERVER_MMAP_MIN_SIZE",
+        value_parser = file_cache::parse_size
+    )]
+    mmap_min_size: Option<usize>,
+
+    /// Serve HTTPS with a certificate for localhost made at startup, for trying out features
+    /// that need a secure context during development (browsers will warn about it)
+    #[arg(long, env = "HTTP_SERVER_TLS_SELF_SIGNED", conflicts_with = "unix")]
+    tls_self_signed: bool,
+
+    /// Serve HTTP/2 to clients that start with its preface (h2c with prior knowledge)
+    #[arg(long, env = "HTTP_SERVER_HTTP2")]
+    http2: bool,
+
+    /// The largest request body accepted (eg, `10MB`), or 0 for no limit. Bigger ones are
+    /// answered with 413 without being read.
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "HTTP_SERVER_MAX_BODY_SIZE",
+        default_value = "10MB",
+        value_parser = file_cache::parse_size
+    )]
+    max_body_size: usize,
+
+    /// The most the request line and headers may take together, answered with 431 once passed
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "HTTP_SERVER_MAX_HEADER_SIZE",
+        default_value = "16KB",
+        value_parser = file_cache::parse_size
+    )]
+    max_header_size: usize,
+
+    /// The longest the request line or any header may be, answered with 414 or 431 once passed
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "HTTP_SERVER_MAX_HEADER_LINE",
+        default_value = "8KB",
+        value_parser = file_cache::parse_size
+    )]
+    max_header_line: usize,
+
+    /// Seconds to keep a connection open waiting for the client's next request, or 0 to close it
+    /// after every response
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "HTTP_SERVER_KEEP_ALIVE_TIMEOUT",
+        default_value_t = 5
+    )]
+    keep_alive_timeout: u64,
+
+    /// How many requests a connection may make before it is closed, so no client holds on to a
+    /// worker forever
+    #[arg(
+        long,
+        value_name = "N",
+        env = "HTTP_SERVER_MAX_REQUESTS_PER_CONNECTION",
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_requests_per_connection: u64,
+
+    /// Seconds a client has to send a request's headers, however slowly it sends them, or 0 for
+    /// no limit
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "HTTP_SERVER_HEADER_TIMEOUT",
+        default_value_t = 10
+    )]
+    header_timeout: u64,
+
+    /// Seconds a client has to send a request's body once the headers are in, or 0 for no limit
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "HTTP_SERVER_BODY_TIMEOUT",
+        default_value_t = 60
+    )]
+    body_timeout: u64,
+
+    /// Seconds a request has from arriving to its response being sent (including the handler),
+    /// after which the connection is closed, or 0 for no limit
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "HTTP_SERVER_REQUEST_TIMEOUT",
+        default_value_t = 0
+    )]
+    request_timeout: u64,
+
+    /// Mask this header's value in the logs, along with Authorization, Cookie and the like (can
+    /// be repeated)
+    #[arg(
+        long = "redact-header",
+        value_name = "NAME",
+        env = "HTTP_SERVER_REDACT_HEADERS",
+        value_delimiter = ','
+    )]
+    redact_headers: Vec<String>,
+
+    /// Copy the bytes each connection receives and sends, exactly as they are, to a `.received`
+    /// and a `.sent` file in this directory, for debugging clients
+    #[arg(long, value_name = "DIR", env = "HTTP_SERVER_DUMP_DIR")]
+    dump_dir: Option<String>,
+
+    /// Mask Authorization, Cookie and any `--redact-header` values in the `--dump-dir` files
+    #[arg(long, env = "HTTP_SERVER_DUMP_REDACT", requires = "dump_dir")]
+    dump_redact: bool,
+
+    /// Append the access log to this file rather than printing it (`-` for stdout)
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "HTTP_SERVER_ACCESS_LOG",
+        default_value = "-"
+    )]
+    access_log: String,
+
+    /// How much the access log records about each request
+    #[arg(
+        long,
+        value_enum,
+        env = "HTTP_SERVER_ACCESS_LOG_FORMAT",
+        default_value_t = LogFormat::Combined
+    )]
+    access_log_format: LogFormat,
+
+    /// Check requests and responses against RFC 9110 semantics
+    #[arg(long, value_enum, env = "HTTP_SERVER_STRICT", default_value_t = Strictness::Off)]
+    strict: Strictness,
+
+    /// Let this user in to routes that require authentication, with Basic auth (can be
+    /// repeated)
+    #[arg(
+        long = "user",
+        value_name = "NAME:PASSWORD",
+        env = "HTTP_SERVER_USERS",
+        value_delimiter = ','
+    )]
+    #[serde(skip)]
+    users: Vec<String>,
+
+    /// Let a client presenting this token in as user NAME, with Bearer auth (can be repeated)
+    #[arg(
+        long = "token",
+        value_name = "NAME:TOKEN",
+        env = "HTTP_SERVER_TOKENS",
+        value_delimiter = ','
+    )]
+    #[serde(skip)]
+    tokens: Vec<String>,
+
+    /// Read more `--token`s from this file, one NAME:TOKEN per line (can be repeated)
+    #[arg(
+        long = "token-file",
+        value_name = "PATH",
+        env = "HTTP_SERVER_TOKEN_FILES",
+        value_delimiter = ','
+    )]
+    token_files: Vec<String>,
+
+    /// Only accept connections from this address or CIDR range (can be repeated)
+    #[arg(
+        long = "allow-ip",
+        value_name = "CIDR",
+        env = "HTTP_SERVER_ALLOW_IPS",
+        value_delimiter = ','
+    )]
+    allow_ips: Vec<String>,
+
+    /// Refuse connections from this address or CIDR range, even if allowed (can be repeated)
+    #[arg(
+        long = "deny-ip",
+        value_name = "CIDR",
+        env = "HTTP_SERVER_DENY_IPS",
+        value_delimiter = ','
+    )]
+    deny_ips: Vec<String>,
+
+    /// Believe `--forwarded-header` from proxies at this address or CIDR range, for logging and
+    /// `--allow-ip` / `--deny-ip` (can be repeated)
+    #[arg(
+        long = "trusted-proxies",
+        value_name = "CIDR",
+        env = "HTTP_SERVER_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    trusted_proxies: Vec<String>,
+
+    /// Which header the `--trusted-proxies` add, the only one read
+    #[arg(
+        long,
+        value_enum,
+        env = "HTTP_SERVER_FORWARDED_HEADER",
+        default_value_t = ForwardedHeader::XForwardedFor
+    )]
+    forwarded_header: ForwardedHeader,
+
+    /// Expect every connection to start with a PROXY protocol (v1 or v2) header from a load
+    /// balancer, saying who the client is. The TLS handshake would have to come after it, so
+    /// this can't be used with `--tls-self-signed`.
+    #[arg(
+        long,
+        env = "HTTP_SERVER_PROXY_PROTOCOL",
+        conflicts_with = "tls_self_signed"
+    )]
+    proxy_protocol: bool,
+
+    /// Require this user, with Basic auth, for `/files` (can be repeated)
+    #[arg(
+        long = "files-auth",
+        value_name = "NAME:PASSWORD",
+        env = "HTTP_SERVER_FILES_AUTH",
+        value_delimiter = ','
+    )]
+    #[serde(skip)]
+    files_users: Vec<String>,
+
+    /// Let anyone with this Bearer token use `/__admin`, which only local clients may until one
+    /// is given (can be repeated)
+    #[arg(
+        long = "admin-token",
+        value_name = "NAME:TOKEN",
+        env = "HTTP_SERVER_ADMIN_TOKENS",
+        value_delimiter = ','
+    )]
+    #[serde(skip)]
+    admin_tokens: Vec<String>,
+
+    /// Workers started up front, which the pool never shrinks below (defaults to the number of
+    /// CPUs)
+    #[arg(
+        long,
+        env = "HTTP_SERVER_THREADS",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    threads: Option<u16>,
+
+    /// Allow the thread pool to grow up to this many workers when connections back up
+    #[arg(long, env = "HTTP_SERVER_MAX_THREADS")]
+    max_threads: Option<usize>,
+
+    /// Seconds an extra worker can be idle before it is retired
+    #[arg(long, env = "HTTP_SERVER_IDLE_TIMEOUT", default_value_t = 30)]
+    idle_timeout: u64,
+
+    /// Stack size in bytes for each worker thread (defaults to the platform default)
+    #[arg(long, env = "HTTP_SERVER_STACK_SIZE")]
+    stack_size: Option<usize>,
+
+    /// Fault in worker stacks and start every worker before accepting connections
+    #[arg(long, env = "HTTP_SERVER_WARM_UP")]
+    warm_up: bool,
+
+    /// Pin worker N to the Nth CPU in this list (eg, `0-3,8`)
+    #[arg(long, env = "HTTP_SERVER_CPUS")]
+    cpus: Option<String>,
+
+    /// Pin workers to the CPUs of this NUMA node (Linux only)
+    #[arg(long, env = "HTTP_SERVER_NUMA_NODE", conflicts_with = "cpus")]
+    numa_node: Option<usize>,
+
+    /// Maximum connections waiting for a worker (0 for unbounded)
+    #[arg(long, env = "HTTP_SERVER_QUEUE_CAPACITY", default_value_t = 1024)]
+    queue_capacity: usize,
+
+    /// How connections are waited on
+    #[arg(
+        long,
+        value_enum,
+        env = "HTTP_SERVER_BACKEND",
+        default_value_t = Backend::Threaded,
+        conflicts_with_all = ["tls_self_signed", "proxy_protocol", "unix"]
+    )]
+    backend: Backend,
+
+    /// What to do with new connections when the queue is full
+    #[arg(
+        long,
+        value_enum,
+        env = "HTTP_SERVER_ON_QUEUE_FULL",
+        default_value_t = QueueFullPolicy::Shed
+    )]
+    on_queue_full: QueueFullPolicy,
+
+    /// Maximum connections open at once, across every listener (defaults to no limit)
+    #[arg(long, env = "HTTP_SERVER_MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+
+    /// What to do with new connections once there are `--max-connections`
+    #[arg(
+        long,
+        value_enum,
+        env = "HTTP_SERVER_ON_MAX_CONNECTIONS",
+        default_value_t = QueueFullPolicy::Shed
+    )]
+    on_max_connections: QueueFullPolicy,
+
+    /// Say what went wrong in the body of a 500 from a handler that failed or panicked, rather
+    /// than only logging it (not for servers open to the public)
+    #[arg(long, env = "HTTP_SERVER_DEBUG_ERRORS")]
+    debug_errors: bool,
+
+    /// Don't send each request's ID (the client's own X-Request-Id, or a generated one) back in
+    /// an X-Request-Id response header
+    #[arg(long, env = "HTTP_SERVER_NO_REQUEST_ID_HEADER")]
+    no_request_id_header: bool,
+
+    /// Let pages from this origin (eg, `https://example.com`, or `*` for any) use every route
+    /// without CORS settings of its own (can be repeated)
+    #[arg(
+        long = "cors-origin",
+        value_name = "ORIGIN",
+        env = "HTTP_SERVER_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    cors_origins: Vec<String>,
+
+    /// Only let those pages use this method (can be repeated, defaults to any a route handles)
+    #[arg(
+        long = "cors-method",
+        value_name = "METHOD",
+        env = "HTTP_SERVER_CORS_METHODS",
+        value_delimiter = ','
+    )]
+    cors_methods: Vec<String>,
+
+    /// Only let those pages send this request header (can be repeated, defaults to any)
+    #[arg(
+        long = "cors-header",
+        value_name = "NAME",
+        env = "HTTP_SERVER_CORS_HEADERS",
+        value_delimiter = ','
+    )]
+    cors_headers: Vec<String>,
+
+    /// Also send copies of requests to this server (eg, `10.0.0.2:4221`), throwing away its
+    /// responses, to try it out with real traffic
+    #[arg(long, value_name = "HOST:PORT", env = "HTTP_SERVER_MIRROR")]
+    mirror: Option<String>,
+
+    /// The percentage of requests `--mirror` copies
+    #[arg(
+        long,
+        env = "HTTP_SERVER_MIRROR_PERCENT",
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    mirror_percent: u8,
+
+    /// Advertise an alternative service in an Alt-Svc header on every response (eg,
+    /// `h2="alt.example:443"; ma=86400`), so capable clients can switch to it (can be repeated)
+    #[arg(
+        long = "alt-svc",
+        value_name = "ALTERNATIVE",
+        env = "HTTP_SERVER_ALT_SVC",
+        value_delimiter = ','
+    )]
+    alt_svc: Vec<String>,
+
+    /// What responses say in the Server header, or nothing to leave it out
+    #[arg(
+        long,
+        value_name = "PRODUCT",
+        env = "HTTP_SERVER_SERVER_HEADER",
+        default_value = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))
+    )]
+    server_header: String,
+
+    /// Seconds a session is kept after the client last used it
+    #[arg(long, env = "HTTP_SERVER_SESSION_TTL", default_value_t = 3600)]
+    session_ttl: u64,
+
+    /// Also write the summary logged when the server stops to this file, as JSON
+    #[arg(long, value_name = "PATH", env = "HTTP_SERVER_SHUTDOWN_REPORT")]
+    shutdown_report: Option<String>,
+
+    /// What to log to stderr: a level (eg `debug`) or `RUST_LOG` style directives, which it
+    /// replaces (defaults to `RUST_LOG`, or `info`)
+    #[arg(long, value_name = "FILTER", env = "HTTP_SERVER_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// Read the log level, IP lists, virtual hosts, redirects and connection limit from this
+    /// JSON file too, where the command line doesn't give them, and again whenever the server
+    /// gets SIGHUP
+    #[arg(long, value_name = "PATH", env = "HTTP_SERVER_CONFIG")]
+    config: Option<String>,
+
+    /// Print the effective configuration as JSON and exit
+    #[arg(long)]
+    #[serde(skip)]
+    print_config: bool,
+}
+
+impl Args {
+    /// One line describing how the server is set up, for the logs
+    fn summary(&self, address: &str) -> String {
+        let or_none = |value: &Option<String>| value.clone().unwrap_or_else(|| "none".to_string());
+
+        format!(
+            "Listening on {address} with {}..{} workers, directory: {}, static root: {}, \
+            http2: {}, strict: {}, queue: {}",
+            self.threads(),
+            self.max_threads.unwrap_or_else(|| self.threads()),
+            or_none(&self.directory),
+            or_none(&self.static_root),
+            self.http2,
+            self.strict
+                .to_possible_value()
+                .map_or_else(String::new, |value| value.get_name().to_string()),
+            self.queue_capacity()
+                .map_or_else(|| "unbounded".to_string(), |capacity| capacity.to_string()),
+        )
+    }
+
+    /// The settings a `--config` file can give, as the command line has them, with `None` for
+    /// those it leaves to the defaults
+    fn settings(&self) -> reload::Settings {
+        let given = |list: &Vec<String>| (!list.is_empty()).then(|| list.clone());
+
+        reload::Settings {
+            log_level: self.log_level.clone(),
+            allow_ips: given(&self.allow_ips),
+            deny_ips: given(&self.deny_ips),
+            vhosts: given(&self.vhosts),
+            redirects: given(&self.redirects),
+            max_connections: self.max_connections,
+        }
+    }
+
+    /// These arguments with `settings` in place of the command line's, as the server runs with
+    /// them
+    fn with_settings(mut self, settings: &reload::Settings) -> Self {
+        self.log_level.clone_from(&settings.log_level);
+        self.allow_ips = settings.allow_ips.clone().unwrap_or_default();
+        self.deny_ips = settings.deny_ips.clone().unwrap_or_default();
+        self.vhosts = settings.vhosts.clone().unwrap_or_default();
+        self.redirects = settings.redirects.clone().unwrap_or_default();
+        self.max_connections = settings.max_connections;
+        self
+    }
+
+    /// `--queue-capacity`, `None` when unbounded
+    fn queue_capacity(&self) -> Option<usize> {
+        (self.queue_capacity > 0).then_some(self.queue_capacity)
+    }
+
+    /// `--threads`, or one worker per CPU
+    fn threads(&self) -> usize {
+        self.threads.map_or_else(
+            || thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            usize::from,
+        )
+    }
+}
+
+/// Serves as the command line asks, until stopped, which is all the binary does
+#[cfg_attr(coverage_nightly, coverage(off))]
+pub fn main() -> ExitCode {
+    let mut args = Args::parse();
+    if let Some(port) = args.port.take() {
+        for address in &mut args.address {
+            *address = with_port(address, port);
+        }
+    }
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(fatal) => fatal.report(),
+    }
+}
+
+/// Swaps the port in `address`, which may be an IPv6 address like `[::1]:4221`
+fn with_port(address: &str, port: u16) -> String {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+
+    format!("{host}:{port}")
+}
+
+fn cors_methods(methods: &[String]) -> Result<Vec<Method>> {
+    methods
+        .iter()
+        .map(|method| {
+            Method::decode(method.trim().to_ascii_uppercase().as_bytes())
+                .with_context(|| format!("--cors-method {method}"))
+        })
+        .collect()
+}
+
+/// Checks the options that clap can't, before anything is started
+#[cfg_attr(coverage_nightly, coverage(off))]
+fn validate(args: &Args) -> Result<Vec<usize>> {
+    if let Some(max_threads) = args.max_threads
+        && max_threads < args.threads()
+    {
+        bail!(
+            "--max-threads must be at least {}, the number of workers started up front (--threads)",
+            args.threads()
+        );
+    }
+    for (option, directory) in [
+        ("--directory", &args.directory),
+        ("--static-root", &args.static_root),
+    ] {
+        if let Some(directory) = directory
+            && !Path::new(directory).is_dir()
+        {
+            bail!("{option} {directory} is not a directory");
+        }
+    }
+
+    match (&args.cpus, args.numa_node) {
+        (Some(cpus), _) => affinity::parse_cpu_list(cpus).context("--cpus"),
+        (None, Some(node)) => affinity::numa_node_cpus(node),
+        (None, None) => Ok(vec![]),
+    }
+}
+
+#[cfg_attr(coverage_nightly, coverage(off))]
+fn run(args: Args) -> Result<(), Fatal> {
+    let base = args.settings();
+    let settings = match &args.config {
+        Some(path) => base.filled_in(
+            reload::Settings::read(Path::new(path))
+                .with_context(|| format!("--config {path}"))
+                .map_err(Fatal::Config)?,
+        ),
+        None => base.clone(),
+    };
+    if args.print_config {
+        let config = serde_json::to_string_pretty(&args.with_settings(&settings))
+            .map_err(|err| Fatal::Runtime(err.into()))?;
+        println!("{config}");
+        return Ok(());
+    }
+
+    let log_level = logging::init(settings.log_level.as_deref()).map_err(Fatal::Config)?;
+    let cpus = validate(&args).map_err(Fatal::Config)?;
+    let reloadable = settings.build().map_err(Fatal::Config)?;
+    let access_log = match args.access_log.as_str() {
+        "-" => AccessLog::stdout(args.access_log_format),
+        path => AccessLog::file(path, args.access_log_format)
+            .with_context(|| format!("--access-log {path}"))
+            .map_err(Fatal::Config)?,
+    };
+    if let Some(directory) = &args.dump_dir {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("--dump-dir {directory}"))
+            .map_err(Fatal::Config)?;
+        warn!(
+            directory,
+            redact = args.dump_redact,
+            "Dumping every connection, for debugging"
+        );
+    }
+    let mut credentials = Credentials::default();
+    for user in &args.users {
+        credentials
+            .add_user("api", user)
+            .context("--user")
+            .map_err(Fatal::Config)?;
+    }
+    for token in &args.tokens {
+        credentials
+            .add_token("api", token)
+            .context("--token")
+            .map_err(Fatal::Config)?;
+    }
+    for path in &args.token_files {
+        credentials
+            .add_token_file("api", Path::new(path))
+            .with_context(|| format!("--token-file {path}"))
+            .map_err(Fatal::Config)?;
+    }
+    for user in &args.files_users {
+        credentials
+            .add_user("files", user)
+            .context("--files-auth")
+            .map_err(Fatal::Config)?;
+    }
+    for token in &args.admin_tokens {
+        credentials
+            .add_token(admin::REALM, token)
+            .context("--admin-token")
+            .map_err(Fatal::Config)?;
+    }
+    let mut trusted_proxies = TrustedProxies::default().header(args.forwarded_header);
+    for cidr in &args.trusted_proxies {
+        trusted_proxies = trusted_proxies.trust(
+            cidr.parse()
+                .context("--trusted-proxies")
+                .map_err(Fatal::Config)?,
+        );
+    }
+    let cors = match args.cors_origins.as_slice() {
+        [] => None,
+        origins => Some(
+            Cors::new(origins)
+                .methods(&cors_methods(&args.cors_methods).map_err(Fatal::Config)?)
+                .headers(&args.cors_headers),
+        ),
+    };
+    let mirror = args
+        .mirror
+        .clone()
+        .map(|upstream| Mirror::spawn(upstream, args.mirror_percent))
+        .transpose()
+        .map_err(|err| Fatal::Runtime(err.into()))?;
+    let precompressed = match (&args.static_root, args.precompress) {
+        (Some(root), true) => Some(
+            Precompressed::build(Path::new(root))
+                .with_context(|| format!("--precompress {root}"))
+                .map_err(Fatal::Runtime)?,
+        ),
+        _ => None,
+    };
+    let metrics = Arc::<Metrics>::default();
+    let file_cache = args
+        .file_cache_size
+        .map(|size| -> Result<_, Fatal> {
+            let cache = Arc::new(FileCache::new(size).metrics(Arc::clone(&metrics)));
+            cache.watch()?;
+            Ok(cache)
+        })
+        .transpose()?;
+    let cache_control = args
+        .cache_control
+        .iter()
+        .try_fold(CacheControl::default(), |cache_control, rule| {
+            cache_control.add(rule)
+        })
+        .map_err(Fatal::Config)?;
+    let redirects = Swap::new(reloadable.redirects);
+    // Always there, as a reload may add redirects where there were none
+    let mut middleware = Stack::default().wrap(redirects.clone());
+    if !args.alt_svc.is_empty() {
+        middleware = middleware.wrap(ResponseHeader {
+            name: "Alt-Svc".to_string(),
+            value: args
+                .alt_svc
+                .iter()
+                .map(|alternative| alternative.trim())
+                .collect::<Vec<_>>()
+                .join(", "),
+        });
+    }
+    let config = Arc::new(Config {
+        directory: args.directory.clone(),
+        static_root: args.static_root.clone(),
+        vhosts: Swap::new(reloadable.vhosts),
+        create_parents: args.create_parents,
+        force_download: args.force_download,
+        read_only: args.read_only,
+        follow_symlinks: args.follow_symlinks,
+        cache_control,
+        limits: Limits {
+            max_body: Some(args.max_body_size).filter(|&size| size > 0),
+            max_head: Some(args.max_header_size),
+            max_line: Some(args.max_header_line),
+        },
+        http2: args.http2,
+        keep_alive: (args.keep_alive_timeout > 0).then(|| KeepAlive {
+            timeout: Duration::from_secs(args.keep_alive_timeout),
+            max_requests: usize::try_from(args.max_requests_per_connection).unwrap_or(usize::MAX),
+        }),
+        deadlines: Deadlines {
+            head: (args.header_timeout > 0).then(|| Duration::from_secs(args.header_timeout)),
+            body: (args.body_timeout > 0).then(|| Duration::from_secs(args.body_timeout)),
+        },
+        request_timeout: (args.request_timeout > 0)
+            .then(|| Duration::from_secs(args.request_timeout)),
+        redacted_headers: args
+            .redact_headers
+            .iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect(),
+        dump_dir: args.dump_dir.clone(),
+        dump_redact: args.dump_redact,
+        strictness: args.strict,
+        access_log,
+        credentials,
+        metrics,
+        health: Health::default(),
+        admin: Admin::new(log_level),
+        ip_filter: Swap::new(reloadable.ip_filter),
+        connections: Arc::new(ConnectionLimit::new(
+            reloadable.max_connections,
+            args.on_max_connections,
+        )),
+        proxy_protocol: args.proxy_protocol,
+        trusted_proxies,
+        debug_errors: args.debug_errors,
+        echo_request_id: !args.no_request_id_header,
+        compression: Policy {
+            min_size: args.compress_min_size,
+            types: args.compress_types.clone(),
+        },
+        precompressed,
+        file_cache,
+        mmap_min_size: args.mmap_min_size.map(|size| size as u64),
+        cors,
+        mirror,
+        redirects,
+        middleware,
+        state: None,
+        sessions: Sessions::new(Duration::from_secs(args.session_ttl))
+            .context("Unable to make a key for session cookies")
+            .map_err(Fatal::Runtime)?,
+        date: true,
+        server: Some(args.server_header.trim().to_string()).filter(|server| !server.is_empty()),
+    });
+
+    #[cfg(unix)]
+    if let Some(path) = &args.config {
+        reload::on_signal(Arc::clone(&config), base, path.clone())?;
+    }
+
+    // Sockets systemd is holding on to for this process take the place of any to bind
+    #[cfg(unix)]
+    let inherited = match systemd::listeners().map_err(Fatal::Config)? {
+        Some(Inherited::Unix(listeners)) => return start(&args, listeners, &config, cpus),
+        Some(Inherited::Tcp(listeners)) => Some(listeners),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let inherited = None;
+
+    #[cfg(unix)]
+    if inherited.is_none()
+        && let Some(path) = &args.unix
+    {
+        let listener = UnixListener::bind(path).map_err(|source| Fatal::Bind {
+            address: path.clone(),
+            source,
+        })?;
+        return start(&args, vec![listener], &config, cpus);
+    }
+
+    let listeners = match inherited {
+        Some(listeners) => listeners,
+        None => args
+            .address
+            .iter()
+            .flat_map(|address| std::iter::repeat_n(address, usize::from(args.acceptors)))
+            .map(|address| {
+                let listener = if args.reuse_port {
+                    listener::bind_shared(address)
+                } else {
+                    TcpListener::bind(address)
+                };
+                listener.map_err(|source| Fatal::Bind {
+                    address: address.clone(),
+                    source,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    if args.tls_self_signed {
+        let tls = tls::self_signed(args.http2)
+            .context("--tls-self-signed")
+            .map_err(Fatal::Runtime)?;
+        let listeners = listeners
+            .into_iter()
+            .map(|listener| TlsListener::new(listener, Arc::clone(&tls)))
+            .collect();
+        return start(&args, listeners, &config, cpus);
+    }
+    #[cfg(feature = "async")]
+    if args.backend == Backend::Async {
+        #[cfg(unix)]
+        return start_async(&args, listeners, &config);
+        #[cfg(not(unix))]
+        return Err(Fatal::Config(anyhow::anyhow!(
+            "--backend async is only available on Unix"
+        )));
+    }
+    if args.backend == Backend::Evented {
+        #[cfg(unix)]
+        return start_with(&args, listeners, &config, cpus, |listeners, pool| {
+            evented::serve(listeners, &config, pool, args.on_queue_full)
+        });
+        #[cfg(not(unix))]
+        return Err(Fatal::Config(anyhow::anyhow!(
+            "--backend evented is only available on Unix"
+        )));
+    }
+    start(&args, listeners, &config, cpus)
+}
+
+#[cfg_attr(coverage_nightly, coverage(off))]
+fn start<L: Listener + Send>(
+    args: &Args,
+    listeners: Vec<L>,
+    config: &Arc<Config>,
+    cpus: Vec<usize>,
+) -> Result<(), Fatal> {
+    start_with(args, listeners, config, cpus, |listeners, pool| {
+        server::serve(listeners, config, pool, args.on_queue_full)
+    })
+}
+
+/// Starts the pool, then has `serve` hand it connections from the `listeners` until it stops
+#[cfg_attr(coverage_nightly, coverage(off))]
+fn start_with<L: Listener>(
+    args: &Args,
+    listeners: Vec<L>,
+    config: &Arc<Config>,
+    cpus: Vec<usize>,
+    serve: impl FnOnce(Vec<L>, &ThreadPool) -> std::io::Result<()>,
+) -> Result<(), Fatal> {
+    let mut addresses = listeners
+        .iter()
+        .map(Listener::local_addr)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    #[cfg(unix)]
+    shutdown::on_signal(Arc::clone(config), addresses.clone())?;
+    // Each of the `--acceptors` shares its address
+    addresses.dedup();
+    info!("{}", args.summary(&addresses.join(", ")));
+
+    let pool = Arc::new(
+        ThreadPool::builder(args.threads())
+            .max_size(args.max_threads)
+            .idle_timeout(Duration::from_secs(args.idle_timeout))
+            .stack_size(args.stack_size)
+            .warm_up(args.warm_up)
+            .queue_capacity(args.queue_capacity())
+            .cpus(cpus)
+            .metrics(Arc::clone(&config.metrics))
+            .build()?,
+    );
+    config.admin.serving(Some(&pool));
+
+    let started = Instant::now();
+    let served = serve(listeners, &pool);
+    // Only once the requests already given to the workers are answered
+    pool.shutdown();
+    report(args, &config.metrics.report(started.elapsed()))?;
+    served?;
+
+    Ok(())
+}
+
+/// Serves on tokio rather than the pool, with `--max-threads` answering requests at once
+#[cfg(all(feature = "async", unix))]
+#[cfg_attr(coverage_nightly, coverage(off))]
+fn start_async(
+    args: &Args,
+    listeners: Vec<TcpListener>,
+    config: &Arc<Config>,
+) -> Result<(), Fatal> {
+    let addresses = listeners
+        .iter()
+        .map(Listener::local_addr)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    info!("{}", args.summary(&addresses.join(", ")));
+    shutdown::on_signal(Arc::clone(config), addresses)?;
+
+    config.admin.serving(None);
+    let started = Instant::now();
+    let served = async_server::serve(listeners, config, args.max_threads);
+    report(args, &config.metrics.report(started.elapsed()))?;
+    served?;
+
+    Ok(())
+}
+
+/// Logs how the run went, and saves it to `--shutdown-report` if asked
+#[cfg_attr(coverage_nightly, coverage(off))]
+fn report(args: &Args, report: &Report) -> Result<(), Fatal> {
+    info!(
+        uptime_seconds = report.uptime_seconds,
+        requests = report.requests,
+        client_errors = report.client_errors,
+        server_errors = report.server_errors,
+        connections_drained = report.connections_drained,
+        connections_aborted = report.connections_aborted,
+        "Stopped serving"
+    );
+
+    if let Some(path) = &args.shutdown_report {
+        let json =
+            serde_json::to_string_pretty(report).map_err(|err| Fatal::Runtime(err.into()))?;
+        fs::write(path, json)
+            .with_context(|| format!("--shutdown-report {path}"))
+            .map_err(Fatal::Runtime)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn port_replaces_the_one_in_the_address() {
+        assert_eq!(with_port("127.0.0.1:4221", 8080), "127.0.0.1:8080");
+        assert_eq!(with_port("[::1]:4221", 8080), "[::1]:8080");
+        assert_eq!(with_port("localhost", 8080), "localhost:8080");
+    }
+}
@@ -0,0 +1,143 @@
+//! Caps how many connections are open at once, across every listener
+//!
+//! Each connection holds a `Permit` for as long as it is open, so the count goes down however it
+//! ends, including a handler panicking.
+
+use crate::server::QueueFullPolicy;
+use std::sync::{
+    Arc, Condvar, Mutex,
+    atomic::{AtomicUsize, Ordering},
+};
+
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    /// `usize::MAX` for no limit
+    max: AtomicUsize,
+    /// What to do with connections beyond `max`
+    when_full: QueueFullPolicy,
+    open: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl Default for ConnectionLimit {
+    fn default() -> Self {
+        Self::new(None, QueueFullPolicy::Block)
+    }
+}
+
+impl ConnectionLimit {
+    pub const fn new(max: Option<usize>, when_full: QueueFullPolicy) -> Self {
+        Self {
+            max: AtomicUsize::new(match max {
+                Some(max) => max,
+                None => usize::MAX,
+            }),
+            when_full,
+            open: Mutex::new(0),
+            closed: Condvar::new(),
+        }
+    }
+
+    pub const fn when_full(&self) -> QueueFullPolicy {
+        self.when_full
+    }
+
+    pub fn is_full(&self) -> bool {
+        *self.open.lock().unwrap() >= self.max()
+    }
+
+    /// Changes how many connections may be open from now on, letting in any that are waiting if
+    /// there is now room (those already open beyond a lowered limit are left to finish)
+    pub fn set_max(&self, max: Option<usize>) {
+        let _open = self.open.lock().unwrap();
+        self.max.store(max.unwrap_or(usize::MAX), Ordering::Relaxed);
+        self.closed.notify_all();
+    }
+
+    fn max(&self) -> usize {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Counts a connection as open, unless there are already as many as allowed
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let mut open = self.open.lock().unwrap();
+        if *open >= self.max() {
+            return None;
+        }
+        *open += 1;
+
+        Some(Permit(Arc::clone(self)))
+    }
+
+    /// Waits for one of the connections to close if need be, then counts a connection as open
+    pub fn acquire(self: &Arc<Self>) -> Permit {
+        let mut open = self.open.lock().unwrap();
+        while *open >= self.max() {
+            open = self.closed.wait(open).unwrap();
+        }
+        *open += 1;
+
+        Permit(Arc::clone(self))
+    }
+}
+
+/// A connection counted as open, until it is dropped
+#[derive(Debug)]
+pub struct Permit(Arc<ConnectionLimit>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.open.lock().unwrap() -= 1;
+        self.0.closed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn only_lets_max_connections_open() {
+        let limit = Arc::new(ConnectionLimit::new(Some(2), QueueFullPolicy::Shed));
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.is_full());
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+        assert!(!limit.is_full());
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn acquire_waits_for_a_connection_to_close() {
+        let limit = Arc::new(ConnectionLimit::new(Some(1), QueueFullPolicy::Block));
+        let open = limit.try_acquire().unwrap();
+        let waiting = thread::spawn({
+            let limit = Arc::clone(&limit);
+            move || drop(limit.acquire())
+        });
+
+        thread::sleep(Duration::from_millis(16));
+        assert!(!waiting.is_finished());
+        drop(open);
+        waiting.join().unwrap();
+    }
+
+    #[test]
+    fn raising_the_limit_lets_waiting_connections_in() {
+        let limit = Arc::new(ConnectionLimit::new(Some(1), QueueFullPolicy::Block));
+        let _open = limit.try_acquire().unwrap();
+        let waiting = thread::spawn({
+            let limit = Arc::clone(&limit);
+            move || drop(limit.acquire())
+        });
+
+        thread::sleep(Duration::from_millis(16));
+        assert!(!waiting.is_finished());
+        limit.set_max(None);
+        waiting.join().unwrap();
+        assert!(!limit.is_full());
+    }
+}
@@ -0,0 +1,237 @@
+//! Where connections come from, so the server loop doesn't care whether it is TCP, a Unix
+//! socket or something in-process for the tests
+
+use crate::{
+    connection::{ReadTimeout, Shutdownable},
+    request::ReadPolicy,
+    sendfile::SendFile,
+};
+use socket2::{Domain, Socket, Type};
+use std::{
+    fmt::Debug,
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// How many connections the kernel queues for each listener before `accept`
+const BACKLOG: i32 = 1024;
+
+/// How long to wait before accepting again after `accept` failed for a reason that passes, so
+/// running out of file descriptors doesn't spin
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether `accept` failing with `err` says nothing about the listener itself, being about the
+/// one connection (eg, it was reset while queued) or the process running short of something
+/// (eg, file descriptors, under a low `ulimit -n`) that connections closing will free up
+pub fn is_transient(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    ) {
+        return true;
+    }
+
+    matches!(
+        err.kind(),
+        ErrorKind::Interrupted | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+/// A connection accepted by a `Listener`
+pub trait Stream:
+    Read + Write + Shutdownable + ReadTimeout + SendFile + Debug + Send + Sized + 'static
+{
+    /// Another handle on the same connection, to answer on if the worker pool is too busy
+    fn try_clone(&self) -> io::Result<Self>;
+
+    fn set_timeouts(&self, read: Duration, write: Duration) -> io::Result<()>;
+
+    /// The client's address, as the access log shows it
+    fn peer(&self) -> String;
+
+    /// What an empty read means, so the end of the connection isn't mistaken for a slow client
+    /// or the other way round
+    fn read_policy(&self) -> ReadPolicy;
+}
+
+pub trait Listener {
+    type Stream: Stream;
+
+    /// Waits for the next connection, or `None` if there will never be another
+    fn accept(&self) -> io::Result<Option<Self::Stream>>;
+
+    /// Where clients connect to, for the logs
+    fn local_addr(&self) -> io::Result<String>;
+}
+
+#[cfg_attr(coverage_nightly, coverage(off))]
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Self::try_clone(self)
+    }
+
+    fn set_timeouts(&self, read: Duration, write: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(read))?;
+        self.set_write_timeout(Some(write))
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .map_or_else(|_| "-".to_string(), |address| address.ip().to_string())
+    }
+
+    fn read_policy(&self) -> ReadPolicy {
+        ReadPolicy::BLOCKING
+    }
+}
+
+/// Binds `address` with `SO_REUSEPORT`, so several listeners can share it and the kernel spreads
+/// the connections between them, rather than one accept loop taking them all
+pub fn bind_shared(address: &str) -> io::Result<TcpListener> {
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"))?;
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+
+    Ok(socket.into())
+}
+
+#[cfg_attr(coverage_nightly, coverage(off))]
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<Option<TcpStream>> {
+        Self::accept(self).map(|(stream, _)| Some(stream))
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        Self::local_addr(self).map(|address| address.to_string())
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::{
+        net::Shutdown,
+        os::unix::net::{UnixListener, UnixStream},
+    };
+
+    impl Shutdownable for UnixStream {
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            self.shutdown(how)
+        }
+    }
+
+    impl SendFile for UnixStream {}
+
+    impl ReadTimeout for UnixStream {
+        fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+            self.set_read_timeout(Some(timeout))
+        }
+    }
+
+    impl Stream for UnixStream {
+        fn try_clone(&self) -> io::Result<Self> {
+            Self::try_clone(self)
+        }
+
+        fn set_timeouts(&self, read: Duration, write: Duration) -> io::Result<()> {
+            self.set_read_timeout(Some(read))?;
+            self.set_write_timeout(Some(write))
+        }
+
+        /// Unix socket clients are (almost always) unnamed
+        fn peer(&self) -> String {
+            "unix".to_string()
+        }
+
+        fn read_policy(&self) -> ReadPolicy {
+            ReadPolicy::BLOCKING
+        }
+    }
+
+    #[cfg_attr(coverage_nightly, coverage(off))]
+    impl Listener for UnixListener {
+        type Stream = UnixStream;
+
+        fn accept(&self) -> io::Result<Option<UnixStream>> {
+            Self::accept(self).map(|(stream, _)| Some(stream))
+        }
+
+        fn local_addr(&self) -> io::Result<String> {
+            let address = Self::local_addr(self)?;
+            Ok(address.as_pathname().map_or_else(
+                || "unnamed Unix socket".to_string(),
+                |path| format!("unix:{}", path.display()),
+            ))
+        }
+    }
+}
+
+/// Connections made from within the process, which the tests use to drive the whole server
+#[cfg(test)]
+pub mod memory {
+    use super::*;
+    use crate::duplex::{self, DuplexStream};
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    pub struct MemoryListener(Receiver<DuplexStream>);
+
+    /// Connects to a `MemoryListener`, which stops accepting once every `Connector` has gone
+    #[derive(Clone)]
+    pub struct Connector(Sender<DuplexStream>);
+
+    pub fn listener() -> (MemoryListener, Connector) {
+        let (sender, receiver) = mpsc::channel();
+
+        (MemoryListener(receiver), Connector(sender))
+    }
+
+    impl Connector {
+        pub fn connect(&self) -> io::Result<DuplexStream> {
+            let (client, server) = duplex::pair();
+            self.0
+                .send(server)
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+
+            Ok(client)
+        }
+    }
+
+    impl Listener for MemoryListener {
+        type Stream = DuplexStream;
+
+        fn accept(&self) -> io::Result<Option<DuplexStream>> {
+            Ok(self.0.recv().ok())
+        }
+
+        fn local_addr(&self) -> io::Result<String> {
+            Ok("memory".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_addresses_can_be_bound_again() -> io::Result<()> {
+        let first = bind_shared("127.0.0.1:0")?;
+        let address = first.local_addr()?.to_string();
+
+        let second = bind_shared(&address)?;
+        assert_eq!(second.local_addr()?, first.local_addr()?);
+        assert!(TcpListener::bind(&address).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+//! Diagnostics, as opposed to the access log: written to stderr through `tracing`, so they can be
+//! filtered by level and module
+
+use anyhow::{Result, anyhow};
+use std::{
+    env, fmt,
+    io::{self, IsTerminal},
+};
+use tracing_subscriber::{
+    EnvFilter,
+    fmt::{
+        Formatter,
+        format::{DefaultFields, Format},
+    },
+    reload,
+};
+
+/// What swaps the filter of the subscriber `init` installed
+type Handle = reload::Handle<EnvFilter, Formatter<DefaultFields, Format, fn() -> io::Stderr>>;
+
+/// What is logged when neither `--log-level` nor `RUST_LOG` say otherwise
+const DEFAULT_LEVEL: &str = "info";
+
+/// Installs the subscriber, filtering with `directives` if given, `RUST_LOG` if not
+pub fn init(directives: Option<&str>) -> Result<LogLevel> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter(directives)?)
+        .with_writer(io::stderr as fn() -> io::Stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_filter_reloading();
+    let handle = builder.reload_handle();
+    builder.try_init().map_err(|err| anyhow!("{err}"))?;
+
+    Ok(LogLevel(handle))
+}
+
+/// Changes what is logged while the server runs, for `POST /__admin/log-level`
+pub struct LogLevel(Handle);
+
+impl LogLevel {
+    /// The directives in use, as `filter` would write them
+    pub fn current(&self) -> Option<String> {
+        self.0.with_current(ToString::to_string).ok()
+    }
+
+    /// Filters with `filter` from now on
+    pub fn set(&self, filter: EnvFilter) -> Result<()> {
+        self.0.reload(filter).map_err(|err| anyhow!("{err}"))
+    }
+}
+
+impl fmt::Debug for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LogLevel").field(&self.current()).finish()
+    }
+}
+
+/// A level (eg `debug`) or `RUST_LOG` style directives (eg
+/// `info,codecrafters_http_server::h2=trace`)
+pub fn filter(directives: Option<&str>) -> Result<EnvFilter> {
+    match directives {
+        Some(directives) => {
+            EnvFilter::try_new(directives).map_err(|err| anyhow!("--log-level: {err}"))
+        }
+        None => match env::var("RUST_LOG") {
+            Ok(directives) => {
+                EnvFilter::try_new(directives).map_err(|err| anyhow!("RUST_LOG: {err}"))
+            }
+            Err(_) => Ok(EnvFilter::new(DEFAULT_LEVEL)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels_and_directives() {
+        assert_eq!(filter(Some("debug")).unwrap().to_string(), "debug");
+        assert_eq!(
+            filter(Some("warn,codecrafters_http_server::h2=trace"))
+                .unwrap()
+                .to_string(),
+            "codecrafters_http_server::h2=trace,warn"
+        );
+        assert!(filter(Some("h2=loud")).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+//! Counters and gauges for `/metrics`, in the Prometheus text exposition format
+//!
+//! One registry is shared by every connection and the thread pool, so everything is an atomic or
+//! behind a short-lived lock.
+
+use crate::buffers;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the request duration histogram buckets, in seconds
+const BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// By method and status code
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    /// By the method and path the route was registered with (eg, `GET /echo/*`)
+    routes: Mutex<BTreeMap<String, u64>>,
+    /// Requests that took no longer than the matching bound in `BUCKETS`, but longer than the
+    /// one before
+    durations: [AtomicU64; BUCKETS.len()],
+    duration_micros: AtomicU64,
+    active_connections: AtomicUsize,
+    connections: AtomicU64,
+    /// Connections that ended with an error, or the client going away mid-response
+    aborted_connections: AtomicU64,
+    bytes_served: AtomicU64,
+    queue_depth: AtomicUsize,
+    /// Zero for unbounded
+    queue_capacity: AtomicUsize,
+    /// Jobs that panicked on a worker
+    panics: AtomicU64,
+    file_cache_hits: AtomicU64,
+    file_cache_misses: AtomicU64,
+    /// Cached files dropped because they changed or were deleted
+    file_cache_invalidations: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a response, once it has gone out
+    pub fn record(&self, method: &str, status: u16, duration: Duration, bytes: u64) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), status))
+            .or_default() += 1;
+
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.durations[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.duration_micros.fetch_add(
+            u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a request the router found a route for, before it is answered
+    pub fn route_requested(&self, method: &str, path: &str) {
+        *self
+            .routes
+            .lock()
+            .unwrap()
+            .entry(format!("{method} {path}"))
+            .or_default() += 1;
+    }
+
+    /// How many requests each route has had, for `/__admin/status`
+    pub fn route_requests(&self) -> BTreeMap<String, u64> {
+        self.routes.lock().unwrap().clone()
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_aborted(&self) {
+        self.aborted_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn file_cache_hit(&self) {
+        self.file_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn file_cache_missed(&self) {
+        self.file_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn file_cache_invalidated(&self) {
+        self.file_cache_invalidations
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn file_cache_counts(&self) -> FileCacheCounts {
+        FileCacheCounts {
+            hits: self.file_cache_hits.load(Ordering::Relaxed),
+            misses: self.file_cache_misses.load(Ordering::Relaxed),
+            invalidations: self.file_cache_invalidations.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Kept up to date by the thread pool as jobs come and go
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// Set by the thread pool when it is built, if its queue is bounded
+    pub fn set_queue_capacity(&self, capacity: usize) {
+        self.queue_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    /// Whether new connections would have to wait for (or be shed by) the thread pool
+    pub fn is_queue_full(&self) -> bool {
+        let capacity = self.queue_capacity.load(Ordering::Relaxed);
+        capacity > 0 && self.queue_depth.load(Ordering::Relaxed) >= capacity
+    }
+
+    /// Counted by the thread pool, whose worker carries on with the next job
+    pub fn worker_panicked(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How the run went, for when the server stops
+    pub fn report(&self, uptime: Duration) -> Report {
+        let requests = self.requests.lock().unwrap();
+        let count = |statuses: std::ops::Range<u16>| {
+            requests
+                .iter()
+                .filter(|((_, status), _)| statuses.contains(status))
+                .map(|(_, count)| count)
+                .sum()
+        };
+        let active = self.active_connections.load(Ordering::Relaxed) as u64;
+        let aborted = self.aborted_connections.load(Ordering::Relaxed);
+
+        Report {
+            uptime_seconds: uptime.as_secs_f64(),
+            requests: requests.values().sum(),
+            client_errors: count(400..500),
+            server_errors: count(500..600),
+            connections_drained: self
+                .connections
+                .load(Ordering::Relaxed)
+                .saturating_sub(active + aborted),
+            connections_aborted: aborted,
+        }
+    }
+
+    /// Everything, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let requests = self.requests.lock().unwrap().clone();
+        let pool = buffers::stats();
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Requests answered",
+        );
+        for ((method, status), count) in &requests {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{method}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time from accepting a request to finishing its response",
+        );
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&self.durations) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let total: u64 = requests.values().sum();
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {total}\n\
+            http_request_duration_seconds_sum {}\n\
+            http_request_duration_seconds_count {total}",
+            Duration::from_micros(self.duration_micros.load(Ordering::Relaxed)).as_secs_f64()
+        );
+
+        for (name, kind, help, value) in [
+            (
+                "http_response_body_bytes_total",
+                "counter",
+                "Bytes of response bodies sent",
+                self.bytes_served.load(Ordering::Relaxed),
+            ),
+            (
+                "http_connections_active",
+                "gauge",
+                "Connections currently being served",
+                self.active_connections.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "http_connections_aborted_total",
+                "counter",
+                "Connections ended by an error, or the client going away mid-response",
+                self.aborted_connections.load(Ordering::Relaxed),
+            ),
+            (
+                "threadpool_queue_depth",
+                "gauge",
+                "Connections waiting for a worker",
+                self.queue_depth.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "threadpool_panics_total",
+                "counter",
+                "Jobs that panicked on a worker",
+                self.panics.load(Ordering::Relaxed),
+            ),
+            (
+                "file_cache_hits_total",
+                "counter",
+                "Files served from the file cache",
+                self.file_cache_hits.load(Ordering::Relaxed),
+            ),
+            (
+                "file_cache_misses_total",
+                "counter",
+                "Files read because the file cache didn't have them",
+                self.file_cache_misses.load(Ordering::Relaxed),
+            ),
+            (
+                "file_cache_invalidations_total",
+                "counter",
+                "Cached files dropped because they changed or were deleted",
+                self.file_cache_invalidations.load(Ordering::Relaxed),
+            ),
+            (
+                "buffer_pool_hits_total",
+                "counter",
+                "Buffers handed out from the pool",
+                pool.hits,
+            ),
+            (
+                "buffer_pool_misses_total",
+                "counter",
+                "Buffers allocated because the pool had none big enough",
+                pool.misses,
+            ),
+            (
+                "buffer_pool_pooled",
+                "gauge",
+                "Buffers sitting in the pool",
+                pool.pooled as u64,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        out
+    }
+}
+
+/// A summary of a run, logged (and optionally saved as JSON) when the server stops
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub uptime_seconds: f64,
+    pub requests: u64,
+    /// 4xx responses
+    pub client_errors: u64,
+    /// 5xx responses
+    pub server_errors: u64,
+    /// Connections that finished normally
+    pub connections_drained: u64,
+    pub connections_aborted: u64,
+}
+
+/// How the file cache has fared since startup
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FileCacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.record("GET", 200, Duration::from_millis(3), 10);
+        metrics.record("GET", 200, Duration::from_millis(30), 5);
+        metrics.record("POST", 201, Duration::from_secs(10), 0);
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.set_queue_depth(4);
+
+        let rendered = metrics.render();
+
+        for line in [
+            "# TYPE http_requests_total counter",
+            "http_requests_total{method=\"GET\",status=\"200\"} 2",
+            "http_requests_total{method=\"POST\",status=\"201\"} 1",
+            "http_request_duration_seconds_bucket{le=\"0.001\"} 0",
+            "http_request_duration_seconds_bucket{le=\"0.005\"} 1",
+            "http_request_duration_seconds_bucket{le=\"0.05\"} 2",
+            "http_request_duration_seconds_bucket{le=\"5\"} 2",
+            "http_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "http_request_duration_seconds_sum 10.033",
+            "http_request_duration_seconds_count 3",
+            "http_response_body_bytes_total 15",
+            "http_connections_active 1",
+            "threadpool_queue_depth 4",
+            "# TYPE buffer_pool_hits_total counter",
+            "# TYPE buffer_pool_misses_total counter",
+            "# TYPE buffer_pool_pooled gauge",
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "{line:?} missing from\n{rendered}"
+            );
+        }
+    }
+
+    #[test]
+    fn reports_the_run() {
+        let metrics = Metrics::default();
+        metrics.record("GET", 200, Duration::ZERO, 0);
+        metrics.record("GET", 404, Duration::ZERO, 0);
+        metrics.record("POST", 503, Duration::ZERO, 0);
+        for _ in 0..4 {
+            metrics.connection_opened();
+        }
+        metrics.connection_aborted();
+        metrics.connection_closed();
+        metrics.connection_closed();
+        metrics.connection_closed();
+
+        assert_eq!(
+            metrics.report(Duration::from_millis(1500)),
+            Report {
+                uptime_seconds: 1.5,
+                requests: 3,
+                client_errors: 1,
+                server_errors: 1,
+                connections_drained: 2,
+                connections_aborted: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn queue_is_full_at_capacity() {
+        let metrics = Metrics::default();
+        metrics.set_queue_depth(2);
+        assert!(!metrics.is_queue_full());
+
+        metrics.set_queue_capacity(2);
+        assert!(metrics.is_queue_full());
+        metrics.set_queue_depth(1);
+        assert!(!metrics.is_queue_full());
+    }
+}
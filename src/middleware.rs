@@ -0,0 +1,161 @@
+//! Layers around the router, each seeing every request on its way in and the response on its
+//! way out, so cross-cutting behaviour (eg, redirects, extra headers, rate limiting) can be added
+//! without threading it through `Connection`
+//!
+//! A layer either answers the request itself, or passes it on with `next.run` and does what it
+//! likes with the response that comes back.
+
+use crate::{
+    http::Header,
+    request::Request,
+    response::Response,
+    router::{RequestContext, Router},
+};
+use anyhow::Result;
+use std::fmt;
+
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &Request, context: &RequestContext, next: Next) -> Result<Response>;
+}
+
+/// Closures make middleware too, for layers with nothing to configure
+impl<F> Middleware for F
+where
+    F: Fn(&Request, &RequestContext, Next) -> Result<Response> + Send + Sync,
+{
+    fn handle(&self, request: &Request, context: &RequestContext, next: Next) -> Result<Response> {
+        self(request, context, next)
+    }
+}
+
+/// The layers after the one handling a request, and the router at the bottom
+pub struct Next<'a> {
+    layers: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl Next<'_> {
+    /// Passes the request on to the next layer, or the router after the last one
+    pub fn run(self, request: &Request, context: &RequestContext) -> Result<Response> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.handle(
+                request,
+                context,
+                Next {
+                    layers,
+                    router: self.router,
+                },
+            ),
+            None => self.router.dispatch(request, context),
+        }
+    }
+}
+
+/// The layers every request goes through, outermost first
+#[derive(Default)]
+pub struct Stack(Vec<Box<dyn Middleware>>);
+
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Stack").field(&self.0.len()).finish()
+    }
+}
+
+impl Stack {
+    /// Adds `layer` inside those already added, so it sees requests after them, and responses
+    /// before them
+    #[must_use]
+    pub fn wrap<M: Middleware + 'static>(mut self, layer: M) -> Self {
+        self.0.push(Box::new(layer));
+        self
+    }
+
+    /// Sends `request` through the layers to `router`
+    pub fn run(
+        &self,
+        request: &Request,
+        context: &RequestContext,
+        router: &Router,
+    ) -> Result<Response> {
+        Next {
+            layers: &self.0,
+            router,
+        }
+        .run(request, context)
+    }
+}
+
+/// Adds a header to every response (eg, `Alt-Svc`)
+#[derive(Debug)]
+pub struct ResponseHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl Middleware for ResponseHeader {
+    fn handle(&self, request: &Request, context: &RequestContext, next: Next) -> Result<Response> {
+        let mut response = next.run(request, context)?;
+        response.add_header(Header::Custom(self.name.clone(), self.value.clone()));
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        request::Method,
+        response::StatusCode,
+        testing::{TestRequest, TestResponse},
+    };
+
+    fn router() -> Router {
+        Router::new().route(Method::Get, "/", |_: &Request, _: &RequestContext| {
+            Ok(Response::ok().body_str("routed"))
+        })
+    }
+
+    /// Appends its name to the body on the way out
+    fn sign(name: &'static str) -> impl Middleware {
+        move |request: &Request, context: &RequestContext, next: Next| {
+            let response = next.run(request, context)?;
+            let body = TestResponse::new(response).text().to_string();
+            Ok(Response::ok().body_str(&format!("{body} {name}")))
+        }
+    }
+
+    #[test]
+    fn layers_wrap_the_router_in_order() -> Result<()> {
+        let stack = Stack::default().wrap(sign("outer")).wrap(sign("inner"));
+
+        TestResponse::new(stack.run(
+            &TestRequest::get("/").build(),
+            &RequestContext::default(),
+            &router(),
+        )?)
+        .assert_body("routed inner outer");
+        Ok(())
+    }
+
+    #[test]
+    fn layers_can_answer_without_the_router() -> Result<()> {
+        let stack = Stack::default()
+            .wrap(ResponseHeader {
+                name: "X-Layer".to_string(),
+                value: "1".to_string(),
+            })
+            .wrap(|_: &Request, _: &RequestContext, _: Next| {
+                Ok(Response::new(StatusCode::TooManyRequests))
+            });
+
+        TestResponse::new(stack.run(
+            &TestRequest::get("/").build(),
+            &RequestContext::default(),
+            &router(),
+        )?)
+        .assert_status(StatusCode::TooManyRequests)
+        .assert_header("X-Layer", "1");
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Copies a share of requests to another server (`--mirror`), to try out a new backend with real
+//! traffic without it affecting what clients get back
+//!
+//! Copies are queued for a thread of their own to send, so a slow or broken upstream never holds
+//! up a worker. When the queue is full copies are dropped, and the upstream's responses are read
+//! and thrown away.
+
+use crate::{
+    http::{self, Header},
+    request::Request,
+};
+use std::{
+    fmt,
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+    },
+    thread,
+    time::Duration,
+};
+use tracing::debug;
+
+/// Copies waiting to be sent, beyond which more are dropped
+const QUEUE_CAPACITY: usize = 256;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Mirror {
+    sender: SyncSender<Vec<u8>>,
+    percent: u8,
+    seen: AtomicU64,
+}
+
+impl fmt::Debug for Mirror {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mirror")
+            .field("percent", &self.percent)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mirror {
+    /// Starts sending copies of `percent` of requests to `upstream` (eg, `10.0.0.2:4221`)
+    pub fn spawn(upstream: String, percent: u8) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUE_CAPACITY);
+        thread::Builder::new()
+            .name("mirror".to_string())
+            .spawn(move || {
+                for copy in receiver {
+                    if let Err(err) = send(&upstream, &copy) {
+                        debug!(upstream, "Error mirroring request: {err}");
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            sender,
+            percent: percent.min(100),
+            seen: AtomicU64::new(0),
+        })
+    }
+
+    /// Queues a copy of `request`, if it is one of the share being mirrored
+    pub fn offer(&self, request: &Request) {
+        if !self.samples() {
+            return;
+        }
+
+        match self.sender.try_send(encode(request)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("Mirror queue full, dropping copy"),
+            Err(TrySendError::Disconnected(_)) => debug!("Mirror stopped, dropping copy"),
+        }
+    }
+
+    /// Spreads the share evenly, rather than at random: `percent` of every 100 requests
+    fn samples(&self) -> bool {
+        self.seen.fetch_add(1, Ordering::Relaxed) % 100 < u64::from(self.percent)
+    }
+}
+
+/// The request as HTTP/1.1, whichever version it arrived in, closing the connection after it.
+/// Fields that couldn't be sent as they are (eg, a value with a CR or LF an HTTP/2 client got
+/// past) are left out, rather than letting them add lines of their own.
+fn encode(request: &Request) -> Vec<u8> {
+    let mut copy = format!(
+        "{} {}",
+        request.method.as_str(),
+        http::percent_encode_path(&request.path)
+    );
+    if let Some(query) = &request.query {
+        copy.push('?');
+        copy.push_str(query);
+    }
+    copy.push_str(" HTTP/1.1\r\n");
+    for (name, value) in &request.headers {
+        if matches!(
+            name,
+            "connection" | "content-length" | "transfer-encoding" | "x-request-id"
+        ) {
+            continue;
+        }
+        if !Header::Custom(name.to_string(), value.to_string()).is_valid() {
+            debug!(name, "Leaving an invalid field out of the mirrored copy");
+            continue;
+        }
+        copy.push_str(&format!("{name}: {value}\r\n"));
+    }
+    // So the copy can be matched up with the original in both servers' logs
+    copy.push_str(&format!("x-request-id: {}\r\n", request.id));
+    copy.push_str("connection: close\r\n");
+    let body = request.body.as_deref().unwrap_or_default();
+    if !body.is_empty() {
+        copy.push_str(&format!("content-length: {}\r\n", body.len()));
+    }
+    copy.push_str("\r\n");
+
+    let mut copy = copy.into_bytes();
+    copy.extend_from_slice(body);
+    copy
+}
+
+fn send(upstream: &str, copy: &[u8]) -> io::Result<()> {
+    let address = upstream
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for upstream"))?;
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    stream.write_all(copy)?;
+    io::copy(&mut stream, &mut io::sink())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TestRequest;
+    use std::{io::Read, net::TcpListener};
+
+    #[test]
+    fn copies_are_sent_upstream() -> io::Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0")?;
+        let mirror = Mirror::spawn(upstream.local_addr()?.to_string(), 100)?;
+        let mut request = TestRequest::post("/files/a%20b?x=1")
+            .header("Content-Type", "text/plain")
+            .body("hi")
+            .build();
+        request.id = "r1".to_string();
+
+        mirror.offer(&request);
+        let (mut stream, _) = upstream.accept()?;
+        let mut copy = String::new();
+        while !copy.ends_with("hi") {
+            let mut buf = [0; 1024];
+            let read = stream.read(&mut buf)?;
+            copy.push_str(std::str::from_utf8(&buf[..read]).unwrap());
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n")?;
+
+        assert!(
+            copy.starts_with("POST /files/a%20b?x=1 HTTP/1.1\r\n"),
+            "{copy}"
+        );
+        for line in [
+            "content-type: text/plain\r\n",
+            "x-request-id: r1\r\n",
+            "connection: close\r\n",
+            "content-length: 2\r\n",
+        ] {
+            assert!(copy.contains(line), "{copy}");
+        }
+        assert!(copy.ends_with("\r\n\r\nhi"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_fields_are_left_out() {
+        let mut request = TestRequest::get("/").header("X-Ok", "fine").build();
+        request
+            .headers
+            .insert("x-smuggled".to_string(), "a\r\nhost: evil".to_string());
+        request
+            .headers
+            .insert("bad name".to_string(), "b".to_string());
+        let copy = String::from_utf8(encode(&request)).unwrap();
+
+        assert!(copy.contains("x-ok: fine\r\n"), "{copy}");
+        assert!(!copy.contains("evil"), "{copy}");
+        assert!(!copy.contains("bad name"), "{copy}");
+    }
+
+    #[test]
+    fn only_a_share_is_mirrored() -> io::Result<()> {
+        let mirror = Mirror::spawn("127.0.0.1:0".to_string(), 25)?;
+
+        let sampled = (0..200).filter(|_| mirror.samples()).count();
+        assert_eq!(sampled, 50);
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{
+    http::{self, Header},
+    response::{BodyWriter, Response, StatusCode},
+};
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// Pushes successive parts of a `multipart/x-mixed-replace` body, each of which replaces the
+/// previous one on the client (eg, MJPEG frames or progress updates)
+pub struct MultipartWriter<'a, 'b> {
+    writer: &'a mut BodyWriter<'b>,
+    boundary: &'a str,
+}
+
+impl MultipartWriter<'_, '_> {
+    /// Sends a complete part to the client straight away
+    pub fn part(&mut self, content_type: &str, data: &[u8]) -> io::Result<()> {
+        write!(
+            self.writer,
+            "--{}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+            self.boundary,
+            data.len()
+        )?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(http::CRLF)?;
+        self.writer.flush()
+    }
+}
+
+/// A long-lived response where `producer` decides what each part is and when it is sent
+pub fn mixed_replace<F>(producer: F) -> Response
+where
+    F: FnOnce(&mut MultipartWriter) -> io::Result<()> + Send + 'static,
+{
+    let boundary = boundary();
+
+    let mut response = Response::new(StatusCode::Ok);
+    response.add_header(Header::ContentType(format!(
+        "multipart/x-mixed-replace; boundary={boundary}"
+    )));
+    response.stream(move |writer| {
+        producer(&mut MultipartWriter {
+            writer,
+            boundary: &boundary,
+        })?;
+        write!(writer, "--{boundary}--\r\n")
+    });
+
+    response
+}
+
+/// Not cryptographically random, but unlikely to turn up inside a part by accident
+fn boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos());
+
+    format!("boundary-{nanos:x}")
+}
+
+/// One part of a `multipart/form-data` body: a form field, or a file when it has a filename
+#[derive(Debug, PartialEq, Eq)]
+pub struct Part {
+    /// The name of the form field it is for
+    pub name: String,
+    /// What the file was called on the client, for file inputs (empty when none was chosen)
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("The body is not multipart/form-data")]
+    NotFormData,
+    #[error("The Content-Type has no boundary")]
+    MissingBoundary,
+    #[error("The body is not split up by the boundary")]
+    MissingDelimiter,
+    #[error("A part is missing its headers, or the blank line after them")]
+    MalformedHeaders,
+    #[error("A part has no Content-Disposition naming its form field")]
+    MissingName,
+    #[error("The body ends before the closing boundary")]
+    Truncated,
+}
+
+/// Splits a `multipart/form-data` body (RFC 7578) into its parts, given the request's
+/// `Content-Type`, which says what separates them
+pub fn form_data(content_type: &str, body: &[u8]) -> Result<Vec<Part>, Error> {
+    let (media_type, parameters) = content_type.split_once(';').unwrap_or((content_type, ""));
+    if !media_type
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return Err(Error::NotFormData);
+    }
+    let boundary = parameter(parameters, "boundary")
+        .filter(|boundary| !boundary.is_empty())
+        .ok_or(Error::MissingBoundary)?;
+
+    // Each part is preceded by the boundary at the start of a line, and the last one followed by
+    // the boundary and `--`. Anything before the first (the preamble) is ignored.
+    let delimiter = format!("\r\n--{boundary}");
+    let mut rest = if body.starts_with(&delimiter.as_bytes()[2..]) {
+        &body[delimiter.len() - 2..]
+    } else {
+        let start = find(body, delimiter.as_bytes()).ok_or(Error::MissingDelimiter)?;
+        &body[start + delimiter.len()..]
+    };
+
+    let mut parts = vec![];
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        // Whitespace may pad the line the delimiter is on
+        let line_end = find(rest, http::CRLF).ok_or(Error::Truncated)?;
+        if rest[..line_end]
+            .iter()
+            .any(|byte| !matches!(byte, b' ' | b'\t'))
+        {
+            return Err(Error::MissingDelimiter);
+        }
+        rest = &rest[line_end + 2..];
+
+        let head_end = if rest.starts_with(http::CRLF) {
+            0
+        } else {
+            find(rest, b"\r\n\r\n").ok_or(Error::MalformedHeaders)? + 2
+        };
+        let head = str::from_utf8(&rest[..head_end]).map_err(|_| Error::MalformedHeaders)?;
+        rest = &rest[head_end + 2..];
+        let end = find(rest, delimiter.as_bytes()).ok_or(Error::Truncated)?;
+        parts.push(part(head, rest[..end].to_vec())?);
+        rest = &rest[end + delimiter.len()..];
+    }
+}
+
+/// A part from its headers (each ending in CRLF) and the data after them
+fn part(head: &str, data: Vec<u8>) -> Result<Part, Error> {
+    let mut disposition = None;
+    let mut content_type = None;
+    for line in head.split_terminator("\r\n") {
+        let (name, value) = line.split_once(':').ok_or(Error::MalformedHeaders)?;
+        if name.trim().eq_ignore_ascii_case("content-disposition") {
+            disposition = Some(value.trim());
+        } else if name.trim().eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+
+    let (kind, parameters) = disposition
+        .map(|disposition| disposition.split_once(';').unwrap_or((disposition, "")))
+        .ok_or(Error::MissingName)?;
+    if !kind.trim().eq_ignore_ascii_case("form-data") {
+        return Err(Error::MissingName);
+    }
+
+    Ok(Part {
+        name: parameter(parameters, "name").ok_or(Error::MissingName)?,
+        filename: parameter(parameters, "filename"),
+        content_type,
+        data,
+    })
+}
+
+/// The value of the `name` parameter in `parameters` (eg, `; name="a"; filename="b.txt"`), which
+/// may be a quoted string with `;` and escaped quotes inside
+fn parameter(parameters: &str, name: &str) -> Option<String> {
+    let mut rest = parameters;
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        let (key, after) = rest.split_once('=')?;
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (_, '\\') => value.push(chars.next()?.1),
+                        (index, '"') => break index + 1,
+                        (_, char) => value.push(char),
+                    }
+                };
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = after;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parts_are_separated_by_the_boundary() {
+        let response = mixed_replace(|writer| {
+            writer.part("text/plain", b"one")?;
+            writer.part("text/plain", b"two")
+        })
+        .encode();
+        let response = String::from_utf8(response).unwrap();
+
+        let boundary = response
+            .split("boundary=")
+            .nth(1)
+            .and_then(|rest| rest.split("\r\n").next())
+            .unwrap();
+        assert!(response.contains(&format!(
+            "--{boundary}\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\none\r\n"
+        )));
+        assert!(response.contains(&format!(
+            "--{boundary}\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\ntwo\r\n"
+        )));
+        assert!(response.contains(&format!("--{boundary}--\r\n")));
+    }
+
+    const FORM: &str = "multipart/form-data; boundary=----WebKitFormBoundary7MA4YWxk";
+
+    #[test]
+    fn form_data_is_split_into_fields_and_files() {
+        let body = b"preamble\r\n------WebKitFormBoundary7MA4YWxk\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            Holiday\r\n\
+            ------WebKitFormBoundary7MA4YWxk\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"a;b \\\"c\\\".txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            line one\r\nline two\r\n\
+            ------WebKitFormBoundary7MA4YWxk--\r\n";
+
+        assert_eq!(
+            form_data(FORM, body),
+            Ok(vec![
+                Part {
+                    name: "title".to_string(),
+                    filename: None,
+                    content_type: None,
+                    data: b"Holiday".to_vec(),
+                },
+                Part {
+                    name: "photo".to_string(),
+                    filename: Some("a;b \"c\".txt".to_string()),
+                    content_type: Some("text/plain".to_string()),
+                    data: b"line one\r\nline two".to_vec(),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed_form_data_is_refused() {
+        let part = b"--b\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue";
+
+        assert_eq!(form_data("text/plain", b""), Err(Error::NotFormData));
+        assert_eq!(
+            form_data("multipart/form-data", b""),
+            Err(Error::MissingBoundary)
+        );
+        assert_eq!(
+            form_data("multipart/form-data; boundary=b", b"no parts"),
+            Err(Error::MissingDelimiter)
+        );
+        assert_eq!(
+            form_data("multipart/form-data; boundary=b", part),
+            Err(Error::Truncated)
+        );
+        assert_eq!(
+            form_data(
+                "multipart/form-data; boundary=b",
+                b"--b\r\nContent-Type: text/plain\r\n\r\nvalue\r\n--b--"
+            ),
+            Err(Error::MissingName)
+        );
+    }
+}
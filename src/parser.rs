@@ -0,0 +1,468 @@
+//! Parses a request's line and headers as they arrive, in whatever pieces the transport reads
+//! them in, keeping its place between pieces so nothing is scanned twice
+//!
+//! The body is left to `Request::read_body`, as what happens before it is read (eg, sending
+//! `100 Continue`) depends on the headers.
+//!
+//! ```ignore
+//! let mut parser = Parser::new(limits);
+//! while let Poll::Pending = parser.feed(&read_some()?)? {}
+//! ```
+
+use crate::{
+    buffers,
+    fields::Fields,
+    http,
+    request::{self, Error, Limits, Method, Request},
+};
+use anyhow::Result;
+use bytes::Bytes;
+use std::{mem, ops::Range, str, task::Poll};
+
+pub struct Parser {
+    limits: Limits,
+    state: State,
+    /// The lines of the head that have arrived, without their endings, which the request's fields
+    /// are sliced from once the head is complete
+    head: Vec<u8>,
+    /// Where the name and value of each header are in `head`, kept for the next request's
+    fields: Vec<(Range<usize>, Range<usize>)>,
+    /// Where the current line starts in `head`
+    line_start: usize,
+    /// How many bytes of the head have arrived, line endings included
+    received: usize,
+    /// How many bytes the last `feed` took
+    used: usize,
+}
+
+enum State {
+    RequestLine,
+    Headers {
+        method: Method,
+        /// The undecoded target, which stays in `head` until the request is built from it
+        target: Range<usize>,
+    },
+}
+
+impl Parser {
+    /// Most request lines and headers fit in this, so it is what is taken from the buffer pool
+    const HEAD_CAPACITY: usize = 1024;
+
+    /// Room for the headers a browser sends, so `fields` seldom grows
+    const FIELDS_CAPACITY: usize = 16;
+
+    /// A parser for the head of a request, rejecting it as soon as it is bigger than `limits`
+    /// allow
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            state: State::RequestLine,
+            head: buffers::take(Self::HEAD_CAPACITY),
+            fields: Vec::with_capacity(Self::FIELDS_CAPACITY),
+            line_start: 0,
+            received: 0,
+            used: 0,
+        }
+    }
+
+    /// Takes the bytes of `bytes` that belong to the head, returning the request once the blank
+    /// line ending it arrives. The parser is then ready for the next request.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Poll<Request>> {
+        self.used = 0;
+        while self.used < bytes.len() {
+            let rest = &bytes[self.used..];
+            let Some(end) = rest.iter().position(|&byte| byte == b'\n') else {
+                self.head.extend_from_slice(rest);
+                self.take(rest.len())?;
+                break;
+            };
+
+            self.head.extend_from_slice(&rest[..end]);
+            // A bare LF could be read as a line ending by one parser and not another, so is
+            // rejected rather than let a request be smuggled past something in front
+            if self.head.len() == self.line_start || self.head.pop() != Some(b'\r') {
+                return Err(Error::BareLineFeed.into());
+            }
+            self.take(end + 1)?;
+            if let Some(request) = self.end_line()? {
+                return Ok(Poll::Ready(request));
+            }
+        }
+
+        Ok(Poll::Pending)
+    }
+
+    /// How many of the bytes given to the last `feed` were part of the head, the rest being the
+    /// body or the next request
+    pub const fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Why the connection ending before the blank line leaves no request. What did arrive isn't
+    /// served, as a header that was cut off (eg, an `If-Match`) could have changed its meaning.
+    pub fn finish(self) -> Error {
+        match self.state {
+            State::RequestLine => Error::MissingRequestLine,
+            State::Headers { .. } => Error::IncompleteHead,
+        }
+    }
+
+    /// The request whose head has been parsed, leaving the parser to start on the next one
+    fn request(&mut self) -> Result<Request> {
+        let request = match mem::replace(&mut self.state, State::RequestLine) {
+            State::RequestLine => Err(Error::MissingRequestLine.into()),
+            State::Headers { method, target } => self.build(method, target),
+        };
+        self.head.clear();
+        self.fields.clear();
+        self.line_start = 0;
+        self.received = 0;
+
+        request
+    }
+
+    /// The request made from `head`, whose fields share one copy of it, so `head` is left to be
+    /// reused for the next
+    fn build(&mut self, method: Method, target: Range<usize>) -> Result<Request> {
+        let head = Bytes::copy_from_slice(&self.head);
+        let mut headers = Fields::with_capacity(self.fields.len());
+        for (name, value) in self.fields.drain(..) {
+            headers.add(head.slice(name), head.slice(value))?;
+        }
+        // RFC 9112 section 3.2
+        if !headers.contains_key("host") {
+            return Err(Error::MissingHost.into());
+        }
+
+        Request::from_parts(method, str::from_utf8(&head[target])?, headers, None)
+    }
+
+    /// Counts `length` more bytes of the head, checking they keep it within the limits
+    fn take(&mut self, length: usize) -> Result<(), Error> {
+        self.used += length;
+        self.received += length;
+        if self
+            .limits
+            .max_head
+            .is_some_and(|max_head| self.received > max_head)
+        {
+            return Err(Error::HeadersTooLarge);
+        }
+        if self
+            .limits
+            .max_line
+            .is_some_and(|max_line| self.head.len() - self.line_start > max_line)
+        {
+            return Err(match self.state {
+                State::RequestLine => Error::RequestLineTooLong,
+                State::Headers { .. } => Error::HeadersTooLarge,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Parses the line that has just ended, returning the request if it was the blank one
+    fn end_line(&mut self) -> Result<Option<Request>> {
+        let (start, line) = (self.line_start, &mut self.head[self.line_start..]);
+        match self.state {
+            State::RequestLine => {
+                let (method, target) = request_line(line)?;
+                self.state = State::Headers { method, target };
+            }
+            State::Headers { .. } if line.is_empty() => return self.request().map(Some),
+            State::Headers { .. } => {
+                let (name, value) = field(line)?;
+                line[name.clone()].make_ascii_lowercase();
+                let name = start + name.start..start + name.end;
+                // Caught here rather than when the fields are made, so the rest isn't waited for
+                let repeated = |field: &[u8]| {
+                    self.head[name.clone()] == *field
+                        && self
+                            .fields
+                            .iter()
+                            .any(|(seen, _)| self.head[seen.clone()] == *field)
+                };
+                if repeated(b"host") {
+                    return Err(Error::RepeatedHeader("Host").into());
+                }
+                if repeated(b"content-length") {
+                    return Err(Error::RepeatedHeader("Content-Length").into());
+                }
+                self.fields
+                    .push((name, start + value.start..start + value.end));
+            }
+        }
+        // The line is kept for the request to be made from
+        self.line_start = self.head.len();
+
+        Ok(None)
+    }
+}
+
+impl Drop for Parser {
+    fn drop(&mut self) {
+        buffers::give(mem::take(&mut self.head));
+    }
+}
+
+/// The method of `line`, which has to be for HTTP/1.1, and where its target is
+fn request_line(line: &[u8]) -> Result<(Method, Range<usize>)> {
+    let mut spaces = line
+        .iter()
+        .enumerate()
+        .filter_map(|(index, &byte)| (byte == b' ').then_some(index));
+
+    let method_end = spaces.next().unwrap_or(line.len());
+    if method_end == 0 {
+        return Err(Error::MissingHTTPMethod.into());
+    }
+    let method = Method::decode(&line[..method_end])?;
+    if method_end == line.len() {
+        return Err(Error::MissingRequestTarget.into());
+    }
+    let target_end = spaces.next().ok_or(Error::MissingHTTPVersion)?;
+    let target = method_end + 1..target_end;
+    // Only single spaces separate the parts, and the target is visible ASCII (RFC 9112 section
+    // 3), which percent-encoding keeps it to
+    if target.is_empty() || !line[target.clone()].iter().all(u8::is_ascii_graphic) {
+        return Err(Error::InvalidCharacter.into());
+    }
+    if line[target_end + 1..] != *http::VERSION {
+        return Err(Error::UnsupportedHTTPVersion.into());
+    }
+
+    Ok((method, target))
+}
+
+/// Splits a header (or trailer) line into its name and its value (RFC 9110 section 5), saying
+/// where each is in `line`. The name has to be a token right up to the colon, as whitespace there
+/// has been used to smuggle headers past proxies that read it differently (RFC 9112 section 5.1).
+pub fn field(line: &[u8]) -> Result<(Range<usize>, Range<usize>), Error> {
+    let colon = line
+        .iter()
+        .position(|&byte| byte == b':')
+        .ok_or(Error::InvalidHeader)?;
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+    if name.is_empty() || !name.iter().all(|&byte| request::is_tchar(byte)) {
+        return Err(Error::InvalidHeader);
+    }
+    check_value(value)?;
+    let trimmed = value.trim_ascii_start();
+    let start = colon + 1 + value.len() - trimmed.len();
+
+    Ok((0..colon, start..start + trimmed.trim_ascii_end().len()))
+}
+
+/// Checks a field value has no controls but tabs, however it arrived. RFC 9110 section 5.5 only
+/// allows the rest to be passed on as they are, which nothing here needs.
+pub fn check_value(value: &[u8]) -> Result<(), Error> {
+    if value
+        .iter()
+        .any(|&byte| byte.is_ascii_control() && byte != b'\t')
+    {
+        return Err(Error::InvalidCharacter);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REQUEST: &[u8] =
+        b"PUT /files/a%20b?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\nhi";
+
+    fn ready(poll: Poll<Request>) -> Request {
+        match poll {
+            Poll::Ready(request) => request,
+            Poll::Pending => panic!("request is incomplete"),
+        }
+    }
+
+    #[test]
+    fn heads_split_anywhere_parse_the_same() -> Result<()> {
+        let head = REQUEST.len() - 2;
+        for split in 0..head {
+            let mut parser = Parser::new(Limits::default());
+            assert!(
+                parser.feed(&REQUEST[..split])?.is_pending(),
+                "split at {split}"
+            );
+            assert_eq!(parser.used(), split);
+
+            let request = ready(parser.feed(&REQUEST[split..])?);
+            assert_eq!(parser.used(), head - split);
+            assert_eq!(request.method, Method::Put);
+            assert_eq!(request.path, "/files/a b");
+            assert_eq!(&request.headers["content-length"], "2");
+            assert_eq!(request.body, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn one_byte_at_a_time() -> Result<()> {
+        let mut parser = Parser::new(Limits::default());
+        let mut request = None;
+        for byte in REQUEST.chunks(1) {
+            if let Poll::Ready(parsed) = parser.feed(byte)? {
+                request = Some(parsed);
+                break;
+            }
+        }
+
+        assert_eq!(&request.unwrap().headers["host"], "localhost");
+        Ok(())
+    }
+
+    #[test]
+    fn parsers_carry_on_with_the_next_request() -> Result<()> {
+        let pipelined = b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: y\r\n\r\n";
+        let mut parser = Parser::new(Limits::default());
+
+        assert_eq!(ready(parser.feed(pipelined)?).path, "/a");
+        let second = ready(parser.feed(&pipelined[parser.used()..])?);
+        assert_eq!((second.path.as_str(), &second.headers["host"]), ("/b", "y"));
+        Ok(())
+    }
+
+    fn rejected(head: &[u8]) -> Error {
+        Parser::new(Limits::default())
+            .feed(head)
+            .unwrap_err()
+            .downcast::<Error>()
+            .unwrap()
+    }
+
+    #[test]
+    fn heads_are_held_to_rfc_9112() {
+        for (head, error) in [
+            (&b"GET / HTTP/1.1\nHost: x\r\n\r\n"[..], Error::BareLineFeed),
+            (b"GET / HTTP/1.1\r\nHost: x\n\r\n", Error::BareLineFeed),
+            (b"GET / HTTP/1.1\r\nHost: x\r\n\n", Error::BareLineFeed),
+            (b"GET / HTTP/1.1\r\n\r\n", Error::MissingHost),
+            (b"GET / HTTP/1.1\r\nHost : x\r\n\r\n", Error::InvalidHeader),
+            (
+                b"GET / HTTP/1.1\r\nHost: x\r\n Folded: y\r\n\r\n",
+                Error::InvalidHeader,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: x\r\nX(y): z\r\n\r\n",
+                Error::InvalidHeader,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: x\r\n: z\r\n\r\n",
+                Error::InvalidHeader,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: x\0y\r\n\r\n",
+                Error::InvalidCharacter,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: x\ry\r\n\r\n",
+                Error::InvalidCharacter,
+            ),
+            (
+                b"GET /\0 HTTP/1.1\r\nHost: x\r\n\r\n",
+                Error::InvalidCharacter,
+            ),
+            (
+                b"GET  / HTTP/1.1\r\nHost: x\r\n\r\n",
+                Error::InvalidCharacter,
+            ),
+            (
+                b"GET / HTTP/1.1 \r\nHost: x\r\n\r\n",
+                Error::UnsupportedHTTPVersion,
+            ),
+        ] {
+            assert_eq!(rejected(head), error, "{}", head.escape_ascii());
+        }
+    }
+
+    #[test]
+    fn values_may_have_tabs_and_other_text() -> Result<()> {
+        let request = ready(
+            Parser::new(Limits::default())
+                .feed(b"GET / HTTP/1.1\r\nHost:x\r\nX-Note: \ta \xe9\tb \r\n\r\n")?,
+        );
+
+        assert_eq!(&request.headers["host"], "x");
+        assert_eq!(&request.headers["x-note"], "a \u{fffd}\tb");
+        Ok(())
+    }
+
+    #[test]
+    fn limits_apply_before_lines_end() {
+        let limits = Limits {
+            max_line: Some(16),
+            ..Limits::default()
+        };
+
+        let mut parser = Parser::new(limits);
+        assert_eq!(
+            parser
+                .feed(b"GET /a-very-long-")
+                .and_then(|_| parser.feed(b"t"))
+                .unwrap_err()
+                .downcast::<Error>()
+                .unwrap(),
+            Error::RequestLineTooLong
+        );
+        let mut parser = Parser::new(limits);
+        assert_eq!(
+            parser
+                .feed(b"GET / HTTP/1.1\r\nX-Long: 0123456789")
+                .unwrap_err()
+                .downcast::<Error>()
+                .unwrap(),
+            Error::HeadersTooLarge
+        );
+    }
+
+    #[test]
+    fn repeated_headers_are_combined() -> Result<()> {
+        let request = ready(Parser::new(Limits::default()).feed(
+            b"GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\nCookie: a=1\r\n\
+            accept-encoding: br\r\nAccept-Encoding:\r\nCookie: b=2\r\n\r\n",
+        )?);
+
+        assert_eq!(&request.headers["accept-encoding"], "gzip, br");
+        assert_eq!(&request.headers["cookie"], "a=1; b=2");
+        assert_eq!(request.cookies()["b"], "2");
+        Ok(())
+    }
+
+    #[test]
+    fn framing_and_host_can_only_be_given_once() {
+        assert_eq!(
+            rejected(b"PUT / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nContent-Length: 1\r\n"),
+            Error::RepeatedHeader("Content-Length")
+        );
+        assert_eq!(
+            rejected(b"GET / HTTP/1.1\r\nHost: x\r\nHost: y\r\n"),
+            Error::RepeatedHeader("Host")
+        );
+    }
+
+    #[test]
+    fn requests_cut_short_are_not_served() -> Result<()> {
+        let mut parser = Parser::new(Limits::default());
+        assert!(
+            parser
+                .feed(b"GET / HTTP/1.1\r\nHost: x\r\nUser-")?
+                .is_pending()
+        );
+        assert_eq!(parser.finish(), Error::IncompleteHead);
+
+        let mut parser = Parser::new(Limits::default());
+        assert!(parser.feed(b"GET / HTTP/1.1\r\nHost: x\r\n")?.is_pending());
+        assert_eq!(parser.finish(), Error::IncompleteHead);
+
+        let mut parser = Parser::new(Limits::default());
+        assert!(parser.feed(b"GET / HTT")?.is_pending());
+        assert_eq!(parser.finish(), Error::MissingRequestLine);
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Timings of the phases of handling a request (collected with the `profiling` feature), so
+//! performance work can target where the time actually goes
+//!
+//! Each request's timings are reported in a `Server-Timing` header when the request has an
+//! `X-Debug-Timing` header, and the totals since startup at `/debug/profile` as folded stacks,
+//! which `flamegraph.pl` and inferno take as is. Without the feature `time` only calls through.
+
+#[cfg(feature = "profiling")]
+use crate::{http::Header, request::Request, response::Response, router::RequestContext};
+#[cfg(feature = "profiling")]
+use std::{
+    cell::Cell,
+    fmt::Write as _,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading and decoding the request
+    Parse,
+    /// Dispatching it, including the handler
+    Route,
+    Handler,
+    /// Sending the response, including any body that is streamed
+    Write,
+}
+
+#[cfg(feature = "profiling")]
+impl Phase {
+    const ALL: [Self; 4] = [Self::Parse, Self::Route, Self::Handler, Self::Write];
+
+    /// Where the phase sits in a flame graph, as handlers run within routing
+    const fn stack(self) -> &'static str {
+        match self {
+            Self::Parse => "request;parse",
+            Self::Route => "request;route",
+            Self::Handler => "request;route;handler",
+            Self::Write => "request;write",
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Parse => "parse",
+            Self::Route => "route",
+            Self::Handler => "handler",
+            Self::Write => "write",
+        }
+    }
+}
+
+#[cfg(feature = "profiling")]
+thread_local! {
+    // Each worker handles one request at a time, so per thread timings are per request timings
+    static CURRENT: Cell<[u64; 4]> = const { Cell::new([0; 4]) };
+}
+
+/// Nanoseconds spent in each phase since startup
+#[cfg(feature = "profiling")]
+static TOTALS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+/// Runs `f`, counting the time it takes towards `phase`
+#[inline]
+pub fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "profiling")]
+    {
+        let started = Instant::now();
+        let result = f();
+        let nanos = u64::try_from(started.elapsed().as_nanos()).unwrap_or(u64::MAX);
+
+        let _ = CURRENT.try_with(|current| {
+            let mut timings = current.get();
+            timings[phase as usize] += nanos;
+            current.set(timings);
+        });
+        TOTALS[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+
+        result
+    }
+    #[cfg(not(feature = "profiling"))]
+    {
+        let _ = phase;
+        f()
+    }
+}
+
+/// Starts timing a new request on this thread
+#[inline]
+pub fn start_request() {
+    #[cfg(feature = "profiling")]
+    CURRENT.with(|current| current.set([0; 4]));
+}
+
+/// Time spent in `phase` excluding any phases nested within it, in nanoseconds
+#[cfg(feature = "profiling")]
+fn exclusive(timings: &[u64; 4], phase: Phase) -> u64 {
+    match phase {
+        Phase::Route => {
+            timings[Phase::Route as usize].saturating_sub(timings[Phase::Handler as usize])
+        }
+        phase => timings[phase as usize],
+    }
+}
+
+/// Reports the request's timings so far in `Server-Timing`, if the client asked for them with an
+/// `X-Debug-Timing` request header (the response hasn't been written yet, so that isn't included)
+#[cfg(feature = "profiling")]
+pub fn debug_headers(request: &Request, response: Response) -> Response {
+    if !request.headers.contains_key("x-debug-timing") {
+        return response;
+    }
+    let timings = CURRENT.with(Cell::get);
+
+    let entries = Phase::ALL[..3]
+        .iter()
+        .map(|&phase| {
+            let millis = exclusive(&timings, phase) as f64 / 1_000_000.0;
+            format!("{};dur={millis:.3}", phase.name())
+        })
+        .collect::<Vec<_>>();
+
+    response.header(Header::Custom(
+        "Server-Timing".to_string(),
+        entries.join(", "),
+    ))
+}
+
+/// `GET /debug/profile`, the time spent in each phase since startup in microseconds
+#[cfg(feature = "profiling")]
+pub fn dump(_: &Request, _: &RequestContext) -> anyhow::Result<Response> {
+    let totals = TOTALS.each_ref().map(|total| total.load(Ordering::Relaxed));
+
+    let mut folded = String::new();
+    for phase in Phase::ALL {
+        let _ = writeln!(
+            folded,
+            "{} {}",
+            phase.stack(),
+            exclusive(&totals, phase) / 1_000
+        );
+    }
+
+    Ok(Response::ok().content_type("text/plain").body_str(&folded))
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod test {
+    use super::*;
+    use crate::testing::TestRequest;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn nested_phases_are_exclusive() {
+        start_request();
+        time(Phase::Route, || {
+            thread::sleep(Duration::from_millis(2));
+            time(Phase::Handler, || thread::sleep(Duration::from_millis(50)));
+        });
+
+        let timings = CURRENT.with(Cell::get);
+        assert!(exclusive(&timings, Phase::Handler) >= 50_000_000);
+        // Sleeps can overrun, but not by as long as the handler took
+        let route = exclusive(&timings, Phase::Route);
+        assert!((2_000_000..50_000_000).contains(&route), "{route}");
+    }
+
+    #[test]
+    fn debug_headers_are_opt_in() {
+        start_request();
+        time(Phase::Parse, || ());
+
+        let request = TestRequest::get("/").header("X-Debug-Timing", "1").build();
+        let header = crate::testing::TestResponse::new(debug_headers(&request, Response::ok()))
+            .header("Server-Timing")
+            .map(str::to_string)
+            .unwrap();
+        assert!(header.starts_with("parse;dur="), "{header}");
+        assert!(
+            header.contains(", route;dur=0.000, handler;dur=0.000"),
+            "{header}"
+        );
+
+        let request = TestRequest::get("/").build();
+        assert_eq!(
+            debug_headers(&request, Response::ok()).encode(),
+            b"HTTP/1.1 200 OK\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn dump_is_folded_stacks() {
+        let response = TestRequest::get("/debug/profile").call(dump, &RequestContext::default());
+
+        let stacks = response
+            .text()
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stacks,
+            [
+                "request;parse",
+                "request;route",
+                "request;route;handler",
+                "request;write"
+            ]
+        );
+    }
+}
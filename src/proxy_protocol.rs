@@ -0,0 +1,179 @@
+//! HAProxy's PROXY protocol (`--proxy-protocol`), which load balancers working at the TCP level
+//! use to pass on who the client is, in a preamble before anything the client sent
+//!
+//! Both the text (v1) and binary (v2) forms are understood. Once enabled every connection has to
+//! start with one, as anyone able to connect without a load balancer could otherwise claim to be
+//! anyone (see <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>).
+
+use std::{
+    io::{self, BufRead, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use thiserror::Error;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest a v1 header can be, including its CRLF
+const V1_MAX_LENGTH: u64 = 107;
+
+/// Reads the preamble from the start of a connection, leaving what follows it for HTTP
+///
+/// Gives the address of the client the load balancer is proxying for, or `None` when it doesn't
+/// say (eg, its own health checks, or a client on a Unix socket).
+pub fn read_header<R: BufRead>(reader: &mut R) -> Result<Option<SocketAddr>, Error> {
+    // Shorter than either form can be, so it's safe to read before knowing which it is
+    let mut start = [0; V2_SIGNATURE.len()];
+    reader.read_exact(&mut start)?;
+
+    if start == V2_SIGNATURE {
+        read_v2(reader)
+    } else if start.starts_with(V1_PREFIX) {
+        let mut line = start.to_vec();
+        reader
+            .take(V1_MAX_LENGTH - start.len() as u64)
+            .read_until(b'\n', &mut line)?;
+        parse_v1(&line)
+    } else {
+        Err(Error::Missing)
+    }
+}
+
+/// eg, `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`, or `PROXY UNKNOWN\r\n`
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or(Error::Invalid)?;
+    let mut fields = line.split(' ').skip(1);
+
+    let parse_address = |address: Option<&str>| -> Result<IpAddr, Error> {
+        address
+            .and_then(|address| address.parse().ok())
+            .ok_or(Error::Invalid)
+    };
+    let (source, is_v4) = match fields.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4") => (parse_address(fields.next())?, true),
+        Some("TCP6") => (parse_address(fields.next())?, false),
+        _ => return Err(Error::Invalid),
+    };
+    let destination = parse_address(fields.next())?;
+    let port = fields
+        .next()
+        .and_then(|port| port.parse::<u16>().ok())
+        .ok_or(Error::Invalid)?;
+    if source.is_ipv4() != is_v4 || destination.is_ipv4() != is_v4 {
+        return Err(Error::Invalid);
+    }
+
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+/// The signature is followed by the version and command, the address family and protocol, the
+/// length of the rest, then the addresses and any TLVs (which are skipped)
+fn read_v2<R: Read>(reader: &mut R) -> Result<Option<SocketAddr>, Error> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    let [version_command, family, length @ ..] = header;
+    let mut rest = vec![0; usize::from(u16::from_be_bytes(length))];
+    reader.read_exact(&mut rest)?;
+
+    match version_command {
+        // LOCAL, eg, the load balancer's health checks
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(Error::Invalid),
+    }
+    let address = |bytes: &[u8], port: &[u8]| {
+        let port = u16::from_be_bytes([port[0], port[1]]);
+        match bytes.len() {
+            4 => SocketAddr::new(
+                Ipv4Addr::from(<[u8; 4]>::try_from(bytes).unwrap()).into(),
+                port,
+            ),
+            _ => SocketAddr::new(
+                Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()).into(),
+                port,
+            ),
+        }
+    };
+    // Source address, destination address, source port, destination port
+    match family >> 4 {
+        0x1 if rest.len() >= 12 => Ok(Some(address(&rest[..4], &rest[8..10]))),
+        0x2 if rest.len() >= 36 => Ok(Some(address(&rest[..16], &rest[32..34]))),
+        0x1 | 0x2 => Err(Error::Invalid),
+        // Unspecified, or a Unix socket
+        _ => Ok(None),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Connection didn't start with a PROXY protocol header")]
+    Missing,
+
+    #[error("Invalid PROXY protocol header")]
+    Invalid,
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(mut input: &[u8]) -> (Result<Option<SocketAddr>, Error>, &[u8]) {
+        let result = read_header(&mut input);
+        (result, input)
+    }
+
+    #[test]
+    fn v1() {
+        let (result, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /");
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n");
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:4711".parse().unwrap()));
+        let (result, rest) = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nGET /");
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[test]
+    fn v2() {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend([0x21, 0x11, 0, 15]);
+        input.extend([192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        // A TLV, which is skipped
+        input.extend([0x04, 0, 0]);
+        input.extend(b"GET /");
+        let (result, rest) = read(&input);
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read(&local).0.unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_headers() {
+        assert!(matches!(
+            read(b"GET / HTTP/1.1\r\n\r\n").0,
+            Err(Error::Missing)
+        ));
+        assert!(matches!(
+            read(b"PROXY TCP4 192.0.2.1 2001:db8::2 1 2\r\n").0,
+            Err(Error::Invalid)
+        ));
+        assert!(matches!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443").0,
+            Err(Error::Invalid)
+        ));
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend([0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert!(matches!(read(&short).0, Err(Error::Invalid)));
+    }
+}
@@ -0,0 +1,78 @@
+//! Keeps secrets out of the logs, by masking the values of headers that carry credentials
+
+use crate::request::Request;
+use std::{collections::BTreeMap, fmt};
+
+/// Masked whatever the configuration says
+pub const ALWAYS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+pub const MASK: &str = "[redacted]";
+
+/// Debug formats a request as its derived `Debug` would, except for the masked header values
+pub struct Redacted<'a> {
+    request: &'a Request,
+    /// Extra header names to mask, in lowercase
+    headers: &'a [String],
+}
+
+impl<'a> Redacted<'a> {
+    pub const fn new(request: &'a Request, headers: &'a [String]) -> Self {
+        Self { request, headers }
+    }
+
+    fn masks(&self, name: &str) -> bool {
+        ALWAYS.contains(&name) || self.headers.iter().any(|header| header == name)
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Sorted, so the logs are easier to scan than with the fields in the order they arrived
+        let headers: BTreeMap<&str, &str> = self
+            .request
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.masks(name) { MASK } else { value };
+                (name, value)
+            })
+            .collect();
+
+        f.debug_struct("Request")
+            .field("method", &self.request.method)
+            .field("path", &self.request.path)
+            .field("query", &self.request.query)
+            .field("headers", &headers)
+            .field("body", &self.request.body)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn credentials_are_masked() {
+        let request = Request::decode(
+            &b"GET / HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer secret\r\nCookie: id=secret\r\nX-Api-Key: secret\r\nUser-Agent: curl\r\n\r\n"[..],
+        )
+        .unwrap();
+        let logged = format!("{:?}", Redacted::new(&request, &["x-api-key".to_string()]));
+
+        assert!(!logged.contains("secret"));
+        assert!(logged.contains(r#""authorization": "[redacted]""#));
+        assert!(logged.contains(r#""x-api-key": "[redacted]""#));
+        assert!(logged.contains(r#""user-agent": "curl""#));
+    }
+
+    #[test]
+    fn only_configured_headers_are_masked() {
+        let request =
+            Request::decode(&b"GET / HTTP/1.1\r\nHost: x\r\nX-Api-Key: visible\r\n\r\n"[..])
+                .unwrap();
+        let logged = format!("{:?}", Redacted::new(&request, &[]));
+
+        assert!(logged.contains("visible"));
+    }
+}
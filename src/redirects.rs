@@ -0,0 +1,91 @@
+//! Paths that have moved (`--redirect /old=/new`), which requests are sent on from with
+//! `301 Moved Permanently` by a layer of middleware, before any route sees them
+
+use crate::{
+    http,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::{Response, StatusCode},
+    router::RequestContext,
+};
+use anyhow::Result;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Redirects(HashMap<String, String>);
+
+impl Redirects {
+    /// Sends requests for exactly `from` to `to`, which may be a path or a whole URL
+    #[must_use]
+    pub fn add(mut self, from: &str, to: String) -> Self {
+        self.0.insert(from.to_string(), to);
+        self
+    }
+
+    /// Where a request for `path` should go instead, if anywhere. The query string is kept,
+    /// unless the new location has one of its own.
+    pub fn location(&self, path: &str, query: Option<&str>) -> Option<String> {
+        self.0.get(path).map(|to| with_query(to.clone(), query))
+    }
+}
+
+impl Middleware for Redirects {
+    fn handle(&self, request: &Request, context: &RequestContext, next: Next) -> Result<Response> {
+        match self.location(&request.path, request.query.as_deref()) {
+            Some(location) => Ok(Response::redirect(StatusCode::MovedPermanently, &location)),
+            None => next.run(request, context),
+        }
+    }
+}
+
+/// `path` (as the request had it, decoded) with a `/` on the end, to send a request for a
+/// directory to, so relative links from its index document resolve inside it
+///
+/// Leading slashes are collapsed into one, as `//evil.com/` (from `GET //evil.com`, or
+/// `/%2Fevil.com` decoded) would send the client to another host.
+pub fn trailing_slash(path: &str, query: Option<&str>) -> String {
+    let path = path.trim_start_matches('/');
+    with_query(format!("/{}/", http::percent_encode_path(path)), query)
+}
+
+fn with_query(mut location: String, query: Option<&str>) -> String {
+    if let Some(query) = query
+        && !location.contains('?')
+    {
+        location.push('?');
+        location.push_str(query);
+    }
+
+    location
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_exact_paths_are_redirected() {
+        let redirects = Redirects::default()
+            .add("/old", "/new".to_string())
+            .add("/away", "https://example.com/?from=here".to_string());
+
+        assert_eq!(redirects.location("/old", None), Some("/new".to_string()));
+        assert_eq!(
+            redirects.location("/old", Some("page=2")),
+            Some("/new?page=2".to_string())
+        );
+        assert_eq!(
+            redirects.location("/away", Some("page=2")),
+            Some("https://example.com/?from=here".to_string())
+        );
+        assert_eq!(redirects.location("/old/page", None), None);
+    }
+
+    #[test]
+    fn trailing_slashes_are_added_to_the_encoded_path() {
+        assert_eq!(trailing_slash("/files/my dir", None), "/files/my%20dir/");
+        assert_eq!(trailing_slash("/docs", Some("v=1")), "/docs/?v=1");
+        assert_eq!(trailing_slash("//evil.com", None), "/evil.com/");
+        assert_eq!(trailing_slash("///evil.com/x", None), "/evil.com/x/");
+    }
+}
@@ -0,0 +1,280 @@
+//! `--config FILE`: the settings that can be changed without restarting, by editing the file and
+//! sending the server SIGHUP
+//!
+//! The file is JSON, with the names and shapes `--print-config` uses, for `log_level`,
+//! `allow_ips`, `deny_ips`, `vhosts`, `redirects` and `max_connections` (0 for no limit). What
+//! it sets takes the place of the defaults, but not of anything the command line (or the
+//! environment) gives, and anything taken out of it goes back to the default on the next
+//! reload. A file that doesn't parse, or has a setting that doesn't check out, is ignored as a
+//! whole so the server carries on as it was.
+//!
+//! Each request is served with the settings in place when it arrives, so connections that are
+//! already open aren't dropped, and pick up the new settings from their next request.
+
+use crate::{
+    config::Config,
+    ip_filter::IpFilter,
+    logging,
+    middleware::{Middleware, Next},
+    redirects::Redirects,
+    request::Request,
+    response::Response,
+    router::RequestContext,
+    vhost::VirtualHosts,
+};
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::{
+    fmt, fs,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+/// The reloadable settings, as the command line or the file gives them
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub log_level: Option<String>,
+    pub allow_ips: Option<Vec<String>>,
+    pub deny_ips: Option<Vec<String>>,
+    /// `HOST=DIR`s
+    pub vhosts: Option<Vec<String>>,
+    /// `FROM=TO`s
+    pub redirects: Option<Vec<String>>,
+    pub max_connections: Option<usize>,
+}
+
+impl Settings {
+    pub fn read(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// These settings, with those `file` has for any these leave to the defaults
+    #[must_use]
+    pub fn filled_in(&self, file: Self) -> Self {
+        Self {
+            log_level: self.log_level.clone().or(file.log_level),
+            allow_ips: self.allow_ips.clone().or(file.allow_ips),
+            deny_ips: self.deny_ips.clone().or(file.deny_ips),
+            vhosts: self.vhosts.clone().or(file.vhosts),
+            redirects: self.redirects.clone().or(file.redirects),
+            max_connections: self.max_connections.or(file.max_connections),
+        }
+    }
+
+    /// Checks everything but the log level, building what requests are served with
+    pub fn build(&self) -> Result<Reloadable> {
+        let mut ip_filter = IpFilter::default();
+        for cidr in self.allow_ips.iter().flatten() {
+            ip_filter = ip_filter.allow(cidr.parse().context("--allow-ip")?);
+        }
+        for cidr in self.deny_ips.iter().flatten() {
+            ip_filter = ip_filter.deny(cidr.parse().context("--deny-ip")?);
+        }
+
+        let mut vhosts = VirtualHosts::default();
+        for vhost in self.vhosts.iter().flatten() {
+            let Some((host, directory)) = vhost.split_once('=') else {
+                bail!("--vhost {vhost} should be HOST=DIR");
+            };
+            if !Path::new(directory).is_dir() {
+                bail!("--vhost {directory} is not a directory");
+            }
+            vhosts = vhosts.add(host, directory.to_string());
+        }
+
+        let mut redirects = Redirects::default();
+        for redirect in self.redirects.iter().flatten() {
+            match redirect.split_once('=') {
+                Some((from, to)) if from.starts_with('/') && !to.is_empty() => {
+                    redirects = redirects.add(from, to.to_string());
+                }
+                _ => bail!("--redirect {redirect} should be FROM=TO, with FROM a path"),
+            }
+        }
+
+        Ok(Reloadable {
+            ip_filter,
+            vhosts,
+            redirects,
+            max_connections: self.max_connections.filter(|&max| max > 0),
+        })
+    }
+}
+
+/// The settings, checked and ready to be swapped in
+#[derive(Debug)]
+pub struct Reloadable {
+    pub ip_filter: IpFilter,
+    pub vhosts: VirtualHosts,
+    pub redirects: Redirects,
+    /// `None` for no limit
+    pub max_connections: Option<usize>,
+}
+
+impl Reloadable {
+    fn apply(self, config: &Config) {
+        config.ip_filter.store(self.ip_filter);
+        config.vhosts.store(self.vhosts);
+        config.redirects.store(self.redirects);
+        config.connections.set_max(self.max_connections);
+    }
+}
+
+/// Re-reads `path` under `base` (the command line's settings), and swaps in the result
+pub fn reload(config: &Config, base: &Settings, path: &Path) -> Result<()> {
+    let settings = base.filled_in(Settings::read(path)?);
+    // Without a level of its own, logging goes back to how it started
+    let filter = logging::filter(settings.log_level.as_deref())?;
+    let reloadable = settings.build()?;
+
+    if let Some(log_level) = config.admin.log_level() {
+        log_level.set(filter)?;
+    }
+    reloadable.apply(config);
+
+    Ok(())
+}
+
+/// Reloads `path` whenever the server is sent SIGHUP, until it exits
+#[cfg(unix)]
+pub fn on_signal(config: Arc<Config>, base: Settings, path: String) -> std::io::Result<()> {
+    use signal_hook::{consts::SIGHUP, iterator::Signals};
+    use tracing::{info, warn};
+
+    let mut signals = Signals::new([SIGHUP])?;
+    std::thread::Builder::new()
+        .name("reload".to_string())
+        .spawn(move || {
+            for _ in signals.forever() {
+                match reload(&config, &base, Path::new(&path)) {
+                    Ok(()) => info!(path, "Reloaded the configuration"),
+                    Err(err) => warn!(path, "Keeping the configuration as it was: {err:#}"),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// A value requests are served with, which a reload replaces whole while the requests that
+/// already have the old one finish with it. Clones share the value.
+pub struct Swap<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Swap<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    /// The value in place now
+    pub fn load(&self) -> Arc<T> {
+        Arc::clone(
+            &self
+                .0
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        )
+    }
+
+    pub fn store(&self, value: T) {
+        *self
+            .0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(value);
+    }
+}
+
+impl<T> Clone for Swap<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T: Default> Default for Swap<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Swap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Swap").field(&self.load()).finish()
+    }
+}
+
+/// A layer that can be reloaded, as whichever version is in place when the request arrives
+impl<M: Middleware> Middleware for Swap<M> {
+    fn handle(&self, request: &Request, context: &RequestContext, next: Next) -> Result<Response> {
+        self.load().handle(request, context, next)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::test_directory;
+
+    #[test]
+    fn the_file_fills_in_what_the_command_line_leaves_out() -> Result<()> {
+        let directory = test_directory("the_file_fills_in_what_the_command_line_leaves_out");
+        let path = Path::new(&directory).join("config.json");
+        let config = Config::default();
+        let base = Settings {
+            deny_ips: Some(vec!["192.0.2.1".to_string()]),
+            redirects: Some(vec!["/old=/new".to_string()]),
+            ..Settings::default()
+        };
+        base.build()?.apply(&config);
+        assert!(!config.ip_filter.load().permits("192.0.2.1"));
+
+        fs::write(
+            &path,
+            format!(
+                r#"{{"deny_ips": ["198.51.100.0/24"], "vhosts": ["example.local={directory}"],
+                "max_connections": 1}}"#
+            ),
+        )?;
+        reload(&config, &base, &path)?;
+        // The command line's come first
+        assert!(!config.ip_filter.load().permits("192.0.2.1"));
+        assert!(config.ip_filter.load().permits("198.51.100.7"));
+        assert!(config.vhosts.load().root("example.local").is_some());
+        assert_eq!(
+            config.redirects.load().location("/old", None).as_deref(),
+            Some("/new")
+        );
+        assert!(config.connections.try_acquire().is_some());
+        let _open = config.connections.try_acquire();
+        assert!(config.connections.is_full());
+
+        // Taken out of the file again
+        fs::write(&path, "{}")?;
+        reload(&config, &base, &path)?;
+        assert!(!config.ip_filter.load().permits("192.0.2.1"));
+        assert!(config.ip_filter.load().permits("198.51.100.7"));
+        assert!(config.vhosts.load().root("example.local").is_none());
+        assert!(!config.connections.is_full());
+        Ok(())
+    }
+
+    #[test]
+    fn a_bad_file_changes_nothing() -> Result<()> {
+        let directory = test_directory("a_bad_file_changes_nothing");
+        let path = Path::new(&directory).join("config.json");
+        let config = Config::default();
+        let base = Settings::default();
+
+        for bad in [
+            r#"{"deny_ips": ["192.0.2.1"], "redirects": ["nowhere"]}"#,
+            r#"{"deny_ips": ["192.0.2.1"], "log_level": "h2=loud"}"#,
+            r#"{"deny_ips": ["192.0.2.1"], "port": 80}"#,
+            r#"{"deny_ips": ["192.0.2.1"]"#,
+        ] {
+            fs::write(&path, bad)?;
+            assert!(reload(&config, &base, &path).is_err(), "{bad}");
+            assert!(config.ip_filter.load().permits("192.0.2.1"), "{bad}");
+        }
+        Ok(())
+    }
+}
@@ -1,126 +1,472 @@
-use crate::http;
+use crate::{
+    cookie,
+    fields::Fields,
+    http,
+    multipart::{self, Part},
+    parser::{self, Parser},
+    request_id,
+    response::StatusCode,
+    session::Session,
+};
 use anyhow::Result;
+use bytes::Bytes;
 use std::{
     collections::HashMap,
-    io::{BufRead, ErrorKind},
+    io::{self, BufRead, ErrorKind, Read},
+    sync::OnceLock,
+    task::Poll,
+    thread,
+    time::Duration,
 };
 use thiserror::Error;
 
+/// What a transport's reads mean when they come back empty, so decoding can tell a client that
+/// has gone from one that hasn't sent anything yet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadPolicy {
+    /// Whether `Ok(0)` means the client has closed, as it does for sockets. Otherwise it is
+    /// retried like a read that would block.
+    pub zero_is_eof: bool,
+    /// How many times to retry a read that would block before giving up with 408. Blocking
+    /// streams have already waited out their read timeout by then, so don't retry.
+    pub retries: u32,
+    /// How long to wait before the first retry, doubling after each one
+    pub backoff: Duration,
+}
+
+impl ReadPolicy {
+    /// Sockets, pipes and the like, with a read timeout
+    pub const BLOCKING: Self = Self {
+        zero_is_eof: true,
+        retries: 0,
+        backoff: Duration::ZERO,
+    };
+
+    /// Calls `attempt` until it reads something, the connection ends or the retries run out.
+    /// It returns how much it read, or the error it got.
+    fn retry(self, mut attempt: impl FnMut() -> io::Result<usize>) -> io::Result<usize> {
+        let mut backoff = self.backoff;
+        let mut retries = 0;
+        loop {
+            let empty = match attempt() {
+                Ok(0) if self.zero_is_eof => return Ok(0),
+                Ok(0) => ErrorKind::UnexpectedEof,
+                Ok(read) => return Ok(read),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                    err.kind()
+                }
+                Err(err) => return Err(err),
+            };
+            if retries == self.retries {
+                return match empty {
+                    ErrorKind::UnexpectedEof => Ok(0),
+                    kind => Err(kind.into()),
+                };
+            }
+
+            retries += 1;
+            thread::sleep(backoff);
+            backoff = backoff.saturating_mul(2);
+        }
+    }
+}
+
+/// How the end of a request's body is found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    None,
+    Length(usize),
+    Chunked,
+}
+
+/// Fields a client mustn't send after the body, as the request has been framed, routed and
+/// authorised by the time they arrive (RFC 9110 section 6.5.1)
+const FORBIDDEN_TRAILERS: [&str; 10] = [
+    "authorization",
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "cookie",
+    "expect",
+    "host",
+    "range",
+    "trailer",
+    "transfer-encoding",
+];
+
+/// How big a request may be, so a client can't make a worker hold as much as it likes in memory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The largest body, in bytes, or `None` for no limit
+    pub max_body: Option<usize>,
+    /// The most bytes the request line and headers may take together
+    pub max_head: Option<usize>,
+    /// The longest any one line of them may be
+    pub max_line: Option<usize>,
+}
+
+/// Reads from `inner` as `policy` says, retrying what can be and reporting an empty read only
+/// at the end of the connection
+struct Retrying<R> {
+    inner: R,
+    policy: ReadPolicy,
+}
+
+impl<R: Read> Read for Retrying<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.read(buf))
+    }
+}
+
+impl<R: BufRead> BufRead for Retrying<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let inner = &mut self.inner;
+        if self.policy.retry(|| inner.fill_buf().map(<[u8]>::len))? == 0 {
+            return Ok(&[]);
+        }
+
+        // Already buffered, so this doesn't read again
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount);
+    }
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub method: Method,
-    pub target: String,
-    pub headers: HashMap<String, String>,
+    /// The percent-decoded path of the request target, which is what routes match against
+    pub path: String,
+    /// The undecoded query string, without the `?`
+    pub query: Option<String>,
+    pub headers: Fields,
     pub body: Option<Vec<u8>>,
+    /// Fields sent after a chunked body, by lowercase name. They are kept apart from `headers`,
+    /// which the request was routed by before they arrived.
+    pub trailers: Fields,
+    /// The client's `X-Request-Id`, or one made up for it, to tie together what is logged
+    pub id: String,
+    session: OnceLock<Session>,
 }
 
 impl Request {
-    const BUFFER_SIZE: usize = 32;
+    /// The largest body a `GET`, `HEAD` or `DELETE` may have, as it is only read to find where
+    /// the next request would start and then thrown away
+    const UNEXPECTED_BODY_LIMIT: usize = 64 * 1024;
 
-    pub fn decode<T: BufRead>(mut reader: T) -> Result<Self> {
-        let mut bytes_received = Vec::<u8>::new();
+    /// The most of a body that is made room for before it arrives, as `Content-Length` is only
+    /// what the client claims (and may be unlimited)
+    const BODY_RESERVATION: usize = 64 * 1024;
 
-        loop {
-            println!(
-                "attempting to read {} bytes from `reader`",
-                Self::BUFFER_SIZE
-            );
-            let mut buffer = [0; Self::BUFFER_SIZE];
+    /// Decodes a request from a blocking stream, as the tests mostly do
+    #[cfg(test)]
+    pub fn decode<T: BufRead>(reader: T) -> Result<Self> {
+        Self::decode_with(reader, ReadPolicy::BLOCKING, Limits::default())
+    }
 
-            match reader.read(&mut buffer) {
-                Ok(0) => {
-                    println!("read 0 bytes (end of connection?)");
-                    break;
-                }
-                Ok(read) => {
-                    println!("read {read} bytes");
-                    bytes_received.extend_from_slice(&buffer[..read]);
+    /// Decodes a whole request at once, for when nothing needs to happen between the headers
+    /// and the body
+    #[cfg(test)]
+    pub fn decode_with<T: BufRead>(
+        mut reader: T,
+        policy: ReadPolicy,
+        limits: Limits,
+    ) -> Result<Self> {
+        let mut request = Self::decode_head(&mut reader, policy, limits)?;
+        request.read_body(&mut reader, policy, limits)?;
 
-                    if read < buffer.len() {
-                        println!("did not fill buffer last loop, exiting...");
-                        break;
-                    }
-                }
-                Err(err)
-                    if err.kind() == ErrorKind::TimedOut || err.kind() == ErrorKind::WouldBlock =>
-                {
+        Ok(request)
+    }
+
+    /// Decodes the request line and headers from a transport whose reads behave as `policy`
+    /// describes, rejecting the request if it (or the body it promises) is bigger than `limits`
+    /// allow. The body is left for `read_body`.
+    pub fn decode_head<T: BufRead>(reader: T, policy: ReadPolicy, limits: Limits) -> Result<Self> {
+        let mut reader = Retrying {
+            inner: reader,
+            policy,
+        };
+        let mut parser = Parser::new(limits);
+        let request = loop {
+            let available = match reader.fill_buf() {
+                Ok(available) => available,
+                Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
                     return Err(Error::RequestTimeout.into());
                 }
-                Err(err) => {
-                    return Err(err.into());
-                }
+                Err(err) => return Err(err.into()),
+            };
+            if available.is_empty() {
+                return Err(parser.finish().into());
             }
+            // Whatever follows the head is left in `reader` for the body or the next request
+            let parsed = parser.feed(available)?;
+            reader.consume(parser.used());
+            if let Poll::Ready(request) = parsed {
+                break request;
+            }
+        };
+
+        request.check_length(limits)?;
+        Ok(request)
+    }
+
+    /// Rejects a request whose `Content-Length` promises more than `limits` allow. Chunked bodies
+    /// are only checked as they arrive.
+    fn check_length(&self, limits: Limits) -> Result<(), Error> {
+        let Framing::Length(length) = self.framing()? else {
+            return Ok(());
+        };
+        if self.has_meaningless_body() && length > Self::UNEXPECTED_BODY_LIMIT {
+            return Err(Error::UnexpectedBody);
+        }
+        if limits.max_body.is_some_and(|max_body| length > max_body) {
+            return Err(Error::BodyTooLarge);
         }
 
-        let mut bytes_received = bytes_received.as_slice();
-        let request_line =
-            if let Some(cr_index) = bytes_received.windows(2).position(|x| x == b"\r\n") {
-                let result = &bytes_received[..cr_index];
-                bytes_received = &bytes_received[cr_index + 2..];
-                result
-            } else {
-                return Err(Error::MissingRequestLine.into());
-            };
+        Ok(())
+    }
 
-        let mut parts = request_line.split(|x| x == &b' ');
+    /// How much of the body has to arrive before reading the request won't wait on the client,
+    /// which is none if it is invalid, chunked or waits for `100 Continue`
+    pub fn body_to_wait_for(&self, limits: Limits) -> usize {
+        // Too big a body is answered without waiting for it
+        if self.check_length(limits).is_err() {
+            return 0;
+        }
 
-        let method = match parts.next() {
-            Some(method) if !method.is_empty() => Method::decode(method)?,
-            _ => return Err(Error::MissingHTTPMethod.into()),
+        match (self.expects_continue(), self.framing()) {
+            (Ok(false), Ok(Framing::Length(length))) => length,
+            _ => 0,
+        }
+    }
+
+    /// Whether the client is waiting for `100 Continue` before sending the body, which it only
+    /// needs if there is one. Expectations other than that can't be met.
+    pub fn expects_continue(&self) -> Result<bool, Error> {
+        let Some(expect) = self.headers.get("expect") else {
+            return Ok(false);
         };
+        if !expect.eq_ignore_ascii_case("100-continue") {
+            return Err(Error::UnsupportedExpectation);
+        }
 
-        let request_target = parts.next().ok_or(Error::MissingRequestTarget)?;
+        Ok(!matches!(self.framing()?, Framing::None | Framing::Length(0)) && self.body.is_none())
+    }
 
-        if let Some(version) = parts.next() {
-            if version != http::VERSION {
-                return Err(Error::UnsupportedHTTPVersion.into());
+    /// Only `chunked` is supported as a transfer coding. A request with both it and a
+    /// `Content-Length` is rejected, as the two could be read differently by a proxy in front
+    /// (RFC 9112 section 6.3).
+    fn framing(&self) -> Result<Framing, Error> {
+        match (
+            self.headers.get("transfer-encoding"),
+            self.headers.get("content-length"),
+        ) {
+            (Some(_), Some(_)) => Err(Error::AmbiguousFraming),
+            (Some(coding), None) if coding.trim().eq_ignore_ascii_case("chunked") => {
+                Ok(Framing::Chunked)
             }
-        } else {
-            return Err(Error::MissingHTTPVersion.into());
+            (Some(_), None) => Err(Error::UnsupportedTransferEncoding),
+            // Only digits, as `parse` would take a sign too (RFC 9110 section 8.6)
+            (None, Some(length)) if !length.bytes().all(|byte| byte.is_ascii_digit()) => {
+                Err(Error::InvalidContentLength)
+            }
+            (None, Some(length)) => length
+                .parse()
+                .map(Framing::Length)
+                .map_err(|_| Error::InvalidContentLength),
+            (None, None) => Ok(Framing::None),
         }
+    }
 
-        let headers_buf: &[u8] = bytes_received
-            .windows(4)
-            .position(|x| x == b"\r\n\r\n")
-            .map_or(&[], |crcr_index| {
-                let result = &bytes_received[..crcr_index];
-                bytes_received = &bytes_received[crcr_index + 4..];
-                result
-            });
-
-        let mut headers = HashMap::new();
-        let mut lines = headers_buf.lines();
-        while let Some(Ok(header)) = lines.next() {
-            let mut split = header.splitn(2, ':');
-            match (split.next(), split.next()) {
-                // Technically I think we should return 400 to client if key has any whitespace
-                (Some(k), Some(v)) => headers.insert(k.trim().to_lowercase(), v.trim().to_string()),
-                _ => return Err(Error::InvalidHeader.into()),
-            };
+    /// RFC 9110 gives bodies on `GET`, `HEAD` and `DELETE` no meaning
+    const fn has_meaningless_body(&self) -> bool {
+        matches!(self.method, Method::Get | Method::Head | Method::Delete)
+    }
+
+    /// The decoded query string parameters, with every value given for each name in the order
+    /// they appear. A parameter without a value (eg, `?dry_run`) has an empty one.
+    pub fn query_params(&self) -> HashMap<String, Vec<String>> {
+        let decode = |component: &str| http::percent_decode(&component.replace('+', " "));
+
+        let mut params: HashMap<String, Vec<String>> = HashMap::new();
+        for pair in self.query.as_deref().unwrap_or_default().split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if let (Some(key), Some(value)) = (decode(key), decode(value))
+                && !key.is_empty()
+            {
+                params.entry(key).or_default().push(value);
+            }
         }
 
-        let body = if bytes_received.is_empty() {
-            None
+        params
+    }
+
+    /// The cookies the client sent, by name
+    pub fn cookies(&self) -> HashMap<String, String> {
+        self.headers
+            .get("cookie")
+            .map(cookie::parse)
+            .unwrap_or_default()
+    }
+
+    /// The fields and files of a `multipart/form-data` body, as a browser's upload form sends
+    pub fn form_parts(&self) -> Result<Vec<Part>, multipart::Error> {
+        multipart::form_data(
+            self.headers.get("content-type").unwrap_or_default(),
+            self.body.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Whether the `name` header lists `token` (eg, `Connection: close`), ignoring case
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers.get(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    }
+
+    /// The first value of the `name` query string parameter
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_params()
+            .remove(name)
+            .and_then(|values| values.into_iter().next())
+    }
+
+    /// The body may not have arrived with the headers (eg, the client is waiting to see if the
+    /// server will send `100 Continue`), so read the rest of what `Content-Length` promised, or
+    /// the chunks and any trailers after them
+    ///
+    /// Bodies without meaning are read and discarded, so handlers never see them (`decode_head`
+    /// has already rejected large ones).
+    pub fn read_body<T: BufRead>(
+        &mut self,
+        reader: T,
+        policy: ReadPolicy,
+        limits: Limits,
+    ) -> Result<()> {
+        let mut reader = Retrying {
+            inner: reader,
+            policy,
+        };
+        let unexpected = self.has_meaningless_body();
+        let length = match self.framing()? {
+            Framing::None => return Ok(()),
+            Framing::Length(length) => length,
+            Framing::Chunked => {
+                let (limit, too_large) = if unexpected {
+                    (Some(Self::UNEXPECTED_BODY_LIMIT), Error::UnexpectedBody)
+                } else {
+                    (limits.max_body, Error::BodyTooLarge)
+                };
+                let body = read_chunks(&mut reader, limits, limit.map(|limit| (limit, too_large)))?;
+                self.trailers = read_trailers(&mut reader, limits)?;
+                self.body = (!unexpected).then_some(body);
+                return Ok(());
+            }
+        };
+
+        let remaining = length.saturating_sub(self.body.as_ref().map_or(0, Vec::len));
+        let mut rest = (&mut reader).take(remaining as u64);
+        let read = if unexpected {
+            self.body = None;
+            io::copy(&mut rest, &mut io::sink()).map_err(body_error)?
+        } else if remaining > 0 {
+            let body = self.body.get_or_insert_with(Vec::new);
+            body.reserve_exact(remaining.min(Self::BODY_RESERVATION));
+            // Straight from the reader's buffer into the body, without zeroing it first
+            rest.read_to_end(body).map_err(body_error)? as u64
         } else {
-            Some(bytes_received.to_vec())
+            0
         };
+        if read < remaining as u64 {
+            return Err(Error::IncompleteBody.into());
+        }
+
+        Ok(())
+    }
+
+    /// Builds a request from its already framed parts, splitting and decoding the target
+    ///
+    /// Besides the usual origin-form (`/path?query`), the target can be in absolute-form
+    /// (`http://host/path`, as sent to proxies), whose host has to agree with any `Host` header,
+    /// or be `*` for an `OPTIONS` request about the server as a whole (RFC 9112 section 3.2).
+    pub fn from_parts(
+        method: Method,
+        target: &str,
+        mut headers: Fields,
+        body: Option<Vec<u8>>,
+    ) -> Result<Self> {
+        if target == "*" && method != Method::Options {
+            return Err(Error::InvalidRequestTarget.into());
+        }
+        let absolute = absolute_form(target);
+        let target = match &absolute {
+            Some((authority, target)) => {
+                match headers.get("host") {
+                    Some(host) if !host.eq_ignore_ascii_case(authority) => {
+                        return Err(Error::HostMismatch.into());
+                    }
+                    Some(_) => {}
+                    None => {
+                        headers.insert("host".to_string(), (*authority).to_string());
+                    }
+                }
+                target.as_str()
+            }
+            None => target,
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+        let path = http::percent_decode(path).ok_or(Error::InvalidRequestTarget)?;
 
         Ok(Self {
             method,
-            target: String::from_utf8(request_target.to_vec())?,
+            path,
+            query,
+            id: request_id::for_request(&headers),
             headers,
             body,
+            trailers: Fields::new(),
+            session: OnceLock::new(),
         })
     }
+
+    /// The client's session, once it has been looked up from its cookie before routing
+    pub fn session(&self) -> Option<&Session> {
+        self.session.get()
+    }
+
+    pub fn set_session(&self, session: Session) {
+        let _ = self.session.set(session);
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// The methods RFC 9110 defines, and any other token a client sends as an extension method
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Method {
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Extension(String),
 }
 
-#[derive(Debug, Error, PartialEq, Eq)]
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
 pub enum Error {
     #[error("The HTTP request is missing the request line (method, request target and version")]
     MissingRequestLine,
@@ -131,48 +477,343 @@ pub enum Error {
     #[error("Unable to extract request target")]
     MissingRequestTarget,
 
+    #[error("Invalid percent-encoding in the request target")]
+    InvalidRequestTarget,
+
     #[error("Unable to ascertain HTTP version")]
     MissingHTTPVersion,
 
     #[error("Unsupported HTTP version")]
     UnsupportedHTTPVersion,
 
-    #[error("Unsupported HTTP Method")]
-    UnsupportedMethod,
+    #[error("Invalid HTTP method")]
+    InvalidMethod,
 
     #[error("Invalid HTTP header")]
     InvalidHeader,
 
+    #[error("Lines must end with CRLF, not a bare LF")]
+    BareLineFeed,
+
+    #[error("The request has a control character (eg, NUL) or space where it can't")]
+    InvalidCharacter,
+
+    #[error("HTTP/1.1 requests must have a Host header")]
+    MissingHost,
+
+    #[error("Only one {0} header may be given")]
+    RepeatedHeader(&'static str),
+
     #[error("Request timeout: did not send data in timely fashion")]
     RequestTimeout,
+
+    #[error("Invalid Content-Length header")]
+    InvalidContentLength,
+
+    #[error("The connection closed before the whole head was received")]
+    IncompleteHead,
+
+    #[error("The connection closed before the whole body was received")]
+    IncompleteBody,
+
+    #[error("GET, HEAD and DELETE requests may only have a small body, which is ignored")]
+    UnexpectedBody,
+
+    #[error("The body is larger than the server accepts")]
+    BodyTooLarge,
+
+    #[error("The request line is longer than the server accepts")]
+    RequestLineTooLong,
+
+    #[error("The headers are larger than the server accepts")]
+    HeadersTooLarge,
+
+    #[error("The request target's host doesn't match the Host header")]
+    HostMismatch,
+
+    #[error("Only 100-continue can be expected")]
+    UnsupportedExpectation,
+
+    #[error("Only the chunked transfer coding is supported")]
+    UnsupportedTransferEncoding,
+
+    #[error("Transfer-Encoding and Content-Length can't both be given")]
+    AmbiguousFraming,
+
+    #[error("Invalid chunk in the body")]
+    InvalidChunk,
+}
+
+impl Error {
+    /// What to tell the client, before closing the connection as where the next request would
+    /// start can't be told. Methods that are well formed but unknown aren't errors here, the
+    /// router answers them with 501.
+    pub const fn status_code(&self) -> StatusCode {
+        match self {
+            Self::MissingRequestLine
+            | Self::MissingHTTPMethod
+            | Self::MissingRequestTarget
+            | Self::InvalidRequestTarget
+            | Self::HostMismatch
+            | Self::InvalidMethod
+            | Self::InvalidHeader
+            | Self::BareLineFeed
+            | Self::InvalidCharacter
+            | Self::MissingHost
+            | Self::RepeatedHeader(_)
+            | Self::InvalidContentLength
+            | Self::AmbiguousFraming
+            | Self::InvalidChunk
+            | Self::IncompleteHead
+            | Self::IncompleteBody => StatusCode::BadRequest,
+            // Without a version it is an HTTP/0.9 simple request
+            Self::MissingHTTPVersion | Self::UnsupportedHTTPVersion => {
+                StatusCode::HttpVersionNotSupported
+            }
+            Self::RequestTimeout => StatusCode::RequestTimeout,
+            Self::UnexpectedBody | Self::BodyTooLarge => StatusCode::ContentTooLarge,
+            Self::RequestLineTooLong => StatusCode::UriTooLong,
+            Self::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            Self::UnsupportedExpectation => StatusCode::ExpectationFailed,
+            Self::UnsupportedTransferEncoding => StatusCode::NotImplemented,
+        }
+    }
+}
+
+/// Splits an absolute-form target into its authority and the origin-form target the rest is
+/// equivalent to, which has a path of at least `/`
+fn absolute_form(target: &str) -> Option<(&str, String)> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    let (authority, target) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let target = if target.starts_with('/') {
+        target.to_string()
+    } else {
+        format!("/{target}")
+    };
+
+    Some((authority, target))
+}
+
+/// What went wrong reading the body, as the client should hear it
+fn body_error(err: io::Error) -> anyhow::Error {
+    match err.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => Error::RequestTimeout.into(),
+        ErrorKind::UnexpectedEof => Error::IncompleteBody.into(),
+        _ => anyhow::Error::from(err),
+    }
+}
+
+/// Reads one CRLF terminated line of a chunked body, without its ending, giving up on lines
+/// longer than `limits` allow
+fn read_line<T: BufRead>(reader: &mut T, limits: Limits) -> Result<Vec<u8>> {
+    let most = limits
+        .max_line
+        .map_or(u64::MAX, |max_line| max_line as u64 + 2);
+    let mut line = vec![];
+    reader
+        .take(most)
+        .read_until(b'\n', &mut line)
+        .map_err(body_error)?;
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() as u64 + 1 >= most {
+            Error::HeadersTooLarge
+        } else {
+            Error::IncompleteBody
+        }
+        .into());
+    }
+    if line.pop() != Some(b'\r') {
+        return Err(Error::BareLineFeed.into());
+    }
+
+    Ok(line)
+}
+
+/// Reads chunks up to and including the last, empty one (RFC 9112 section 7.1), ignoring any
+/// extensions. Gives up with the error paired with `limit` once the body would be bigger.
+fn read_chunks<T: BufRead>(
+    reader: &mut T,
+    limits: Limits,
+    limit: Option<(usize, Error)>,
+) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = read_line(reader, limits)?;
+        let size = line.split(|&byte| byte == b';').next().unwrap_or_default();
+        // Only hex digits, as `from_str_radix` would take a sign too
+        let size = size.trim_ascii();
+        if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+            return Err(Error::InvalidChunk.into());
+        }
+        let size = std::str::from_utf8(size)
+            .ok()
+            .and_then(|size| usize::from_str_radix(size, 16).ok())
+            .ok_or(Error::InvalidChunk)?;
+        if size == 0 {
+            return Ok(body);
+        }
+        if let Some((limit, too_large)) = &limit
+            && body.len().saturating_add(size) > *limit
+        {
+            return Err(too_large.clone().into());
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).map_err(body_error)?;
+        if !read_line(reader, limits)?.is_empty() {
+            return Err(Error::InvalidChunk.into());
+        }
+    }
+}
+
+/// Reads the fields after the last chunk, up to the blank line ending the body, dropping any a
+/// client mustn't send there
+fn read_trailers<T: BufRead>(reader: &mut T, limits: Limits) -> Result<Fields> {
+    let mut trailers = Fields::new();
+    let mut size = 0;
+    loop {
+        let mut line = read_line(reader, limits)?;
+        if line.is_empty() {
+            return Ok(trailers);
+        }
+        size += line.len() + 2;
+        if limits.max_head.is_some_and(|max_head| size > max_head) {
+            return Err(Error::HeadersTooLarge.into());
+        }
+
+        let (name, value) = parser::field(&line)?;
+        line[name.clone()].make_ascii_lowercase();
+        let line = Bytes::from(line);
+        if !FORBIDDEN_TRAILERS
+            .iter()
+            .any(|forbidden| line[name.clone()] == *forbidden.as_bytes())
+        {
+            trailers.add(line.slice(name), line.slice(value))?;
+        }
+    }
 }
 
 impl Method {
     pub fn decode(data: &[u8]) -> Result<Self> {
-        match data {
-            b"GET" => Ok(Self::Get),
-            b"POST" => Ok(Self::Post),
-            _ => Err(Error::UnsupportedMethod.into()),
+        Ok(match data {
+            b"GET" => Self::Get,
+            b"HEAD" => Self::Head,
+            b"POST" => Self::Post,
+            b"PUT" => Self::Put,
+            b"DELETE" => Self::Delete,
+            b"CONNECT" => Self::Connect,
+            b"OPTIONS" => Self::Options,
+            b"TRACE" => Self::Trace,
+            b"PATCH" => Self::Patch,
+            // Whether the method is implemented is the router's call, only its syntax is ours
+            _ if data.iter().all(|&byte| is_tchar(byte)) => {
+                // tchars are all ASCII, so nothing is lost
+                Self::Extension(String::from_utf8_lossy(data).into_owned())
+            }
+            _ => return Err(Error::InvalidMethod.into()),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Connect => "CONNECT",
+            Self::Options => "OPTIONS",
+            Self::Trace => "TRACE",
+            Self::Patch => "PATCH",
+            Self::Extension(method) => method,
         }
     }
 }
 
+/// RFC 9110 section 5.6.2
+pub fn is_tchar(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
     fn it_works() -> Result<()> {
-        let input = b"GET / HTTP/1.1\r\nUser-Agent: Rust\r\n\r\n";
+        let input = b"GET / HTTP/1.1\r\nHost: x\r\nUser-Agent: Rust\r\n\r\n";
         let result = Request::decode(&input[..]).unwrap();
 
         assert_eq!(result.method, Method::Get);
-        assert_eq!(result.target, String::from("/"));
-        assert_eq!(result.headers.get("user-agent"), Some(&"Rust".to_string()));
+        assert_eq!(result.path, String::from("/"));
+        assert_eq!(result.query, None);
+        assert_eq!(result.headers.get("user-agent"), Some("Rust"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn target_is_split_and_decoded() -> Result<()> {
+        let input = b"GET /echo/hello%20world?x=1&y=%20 HTTP/1.1\r\nHost: x\r\n\r\n";
+        let result = Request::decode(&input[..])?;
 
+        assert_eq!(result.path, "/echo/hello world");
+        assert_eq!(result.query.as_deref(), Some("x=1&y=%20"));
         Ok(())
     }
 
+    #[test]
+    fn cookies() -> Result<()> {
+        let result =
+            Request::decode(&b"GET / HTTP/1.1\r\nHost: x\r\nCookie: a=1; b=2\r\n\r\n"[..])?;
+
+        assert_eq!(result.cookies()["b"], "2");
+        assert!(
+            Request::decode(&b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"[..])?
+                .cookies()
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn query_params() -> Result<()> {
+        let input =
+            b"DELETE /api/files?glob=*.tmp&dry_run&name=a+b%2Fc HTTP/1.1\r\nHost: x\r\n\r\n";
+        let result = Request::decode(&input[..])?;
+
+        assert_eq!(result.query_param("glob").as_deref(), Some("*.tmp"));
+        assert_eq!(result.query_param("dry_run").as_deref(), Some(""));
+        assert_eq!(result.query_param("name").as_deref(), Some("a b/c"));
+        assert_eq!(result.query_param("missing"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_query_params() -> Result<()> {
+        let input = b"GET /echo/hi?tag=a&&tag=b&%zz=bad&tag HTTP/1.1\r\nHost: x\r\n\r\n";
+        let params = Request::decode(&input[..])?.query_params();
+
+        assert_eq!(params.len(), 1);
+        assert_eq!(params["tag"], vec!["a", "b", ""]);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_percent_encoding() {
+        let input = b"GET /echo/%zz HTTP/1.1\r\nHost: x\r\n\r\n";
+        let result = Request::decode(&input[..]);
+
+        assert_eq!(
+            result.unwrap_err().downcast::<Error>().unwrap(),
+            Error::InvalidRequestTarget
+        );
+    }
+
     #[test]
     fn empty_request() {
         let input = b"";
@@ -233,21 +874,31 @@ mod test {
         );
     }
 
+    #[test]
+    fn extension_method() -> Result<()> {
+        let input = b"DANCE / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let result = Request::decode(&input[..])?;
+
+        assert_eq!(result.method, Method::Extension("DANCE".to_string()));
+        assert_eq!(result.method.as_str(), "DANCE");
+        Ok(())
+    }
+
     #[test]
     fn invalid_method() {
-        let input = b"DANCE / HTTP/1.1\r\n";
+        let input = b"DA(NCE / HTTP/1.1\r\nHost: x\r\n";
         let result = Request::decode(&input[..]);
 
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().downcast::<Error>().unwrap(),
-            Error::UnsupportedMethod
+            Error::InvalidMethod
         );
     }
 
     #[test]
     fn invalid_header() {
-        let input = b"GET / HTTP/1.1\r\nBad Header\r\n\r\n";
+        let input = b"GET / HTTP/1.1\r\nHost: x\r\nBad Header\r\n\r\n";
         let result = Request::decode(&input[..]);
 
         assert!(result.is_err());
@@ -257,15 +908,337 @@ mod test {
         );
     }
 
+    #[test]
+    fn no_headers_has_no_body() -> Result<()> {
+        let result = Request::decode(&b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"[..])?;
+
+        assert_eq!(result.headers.len(), 1);
+        assert_eq!(result.body, None);
+        Ok(())
+    }
+
+    #[test]
+    fn body_arriving_after_headers() -> Result<()> {
+        // Chaining means the first read returns only the headers, as if the body was in a
+        // later TCP segment
+        let headers = &b"PUT /files/x HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\n"[..];
+        let body = &b"Rust"[..];
+        let result = Request::decode(std::io::BufReader::new(headers.chain(body)))?;
+
+        assert_eq!(result.body, Some(b"Rust".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn pipelined_requests_are_read_one_at_a_time() -> Result<()> {
+        let mut reader = &b"PUT /a HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nhiGET /b HTTP/1.1\r\nHost: x\r\n\r\n\
+            GET /c HTTP/1.1\r\nHost: x\r\n\r\n"[..];
+
+        let first = Request::decode(&mut reader)?;
+        assert_eq!(
+            (first.path.as_str(), first.body),
+            ("/a", Some(b"hi".to_vec()))
+        );
+        let second = Request::decode(&mut reader)?;
+        assert_eq!((second.path.as_str(), second.body), ("/b", None));
+        let third = Request::decode(&mut reader)?;
+        assert_eq!(third.path, "/c");
+        assert!(reader.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn incomplete_body() {
+        let input = b"PUT /files/x HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nRust";
+        let result = Request::decode(&input[..]);
+
+        assert_eq!(
+            result.unwrap_err().downcast::<Error>().unwrap(),
+            Error::IncompleteBody
+        );
+    }
+
+    #[test]
+    fn bodies_without_meaning_are_discarded() -> Result<()> {
+        let headers = &b"DELETE /files/x HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\n"[..];
+        let body = &b"Rust"[..];
+        let mut reader = std::io::BufReader::new(headers.chain(body));
+        let result = Request::decode(&mut reader)?;
+
+        assert_eq!(result.body, None);
+        // All of it was read, so the connection is ready for the next request
+        assert!(reader.fill_buf()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn large_bodies_without_meaning_are_rejected() {
+        let input = b"GET / HTTP/1.1\r\nHost: x\r\nContent-Length: 65537\r\n\r\n";
+        let result = Request::decode(&input[..]);
+
+        assert_eq!(
+            result.unwrap_err().downcast::<Error>().unwrap(),
+            Error::UnexpectedBody
+        );
+    }
+
+    /// Returns each of its reads in turn, as a non-blocking transport might
+    struct Scripted(std::collections::VecDeque<io::Result<&'static [u8]>>);
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let bytes = self.0.pop_front().unwrap_or(Ok(b""))?;
+            buf[..bytes.len()].copy_from_slice(bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    fn scripted(reads: Vec<io::Result<&'static [u8]>>) -> std::io::BufReader<Scripted> {
+        std::io::BufReader::new(Scripted(reads.into()))
+    }
+
+    #[test]
+    fn empty_reads_are_retried_when_they_are_not_eof() -> Result<()> {
+        let reads = || {
+            vec![
+                Err(ErrorKind::WouldBlock.into()),
+                Ok(&b""[..]),
+                Err(ErrorKind::Interrupted.into()),
+                Ok(&b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"[..]),
+            ]
+        };
+        // As for a stream that returns `WouldBlock` (or `Ok(0)`) as soon as there is nothing to read
+        let policy = ReadPolicy {
+            zero_is_eof: false,
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+
+        let result = Request::decode_with(scripted(reads()), policy, Limits::default())?;
+        assert_eq!(result.path, "/");
+
+        let result = Request::decode(scripted(reads()));
+        assert_eq!(
+            result.unwrap_err().downcast::<Error>().unwrap(),
+            Error::RequestTimeout
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let reads = vec![
+            Err(ErrorKind::WouldBlock.into()),
+            Err(ErrorKind::WouldBlock.into()),
+            Ok(&b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"[..]),
+        ];
+        let policy = ReadPolicy {
+            zero_is_eof: false,
+            retries: 1,
+            backoff: Duration::ZERO,
+        };
+        let result = Request::decode_with(scripted(reads), policy, Limits::default());
+
+        assert_eq!(
+            result.unwrap_err().downcast::<Error>().unwrap(),
+            Error::RequestTimeout
+        );
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_rejected() -> Result<()> {
+        let input = b"PUT /files/x HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello";
+        let limits = |max_body| Limits {
+            max_body: Some(max_body),
+            ..Limits::default()
+        };
+
+        let result = Request::decode_with(&input[..], ReadPolicy::BLOCKING, limits(4));
+        assert_eq!(
+            result.unwrap_err().downcast::<Error>().unwrap(),
+            Error::BodyTooLarge
+        );
+        let result = Request::decode_with(&input[..], ReadPolicy::BLOCKING, limits(5))?;
+        assert_eq!(result.body, Some(b"hello".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn unlimited_bodies_are_only_made_room_for_as_they_arrive() {
+        let unlimited = Limits {
+            max_body: None,
+            ..Limits::default()
+        };
+        for length in [u64::MAX, 100_000_000_000_000] {
+            let input =
+                format!("PUT /files/x HTTP/1.1\r\nHost: x\r\nContent-Length: {length}\r\n\r\nhi");
+            let result = Request::decode_with(input.as_bytes(), ReadPolicy::BLOCKING, unlimited);
+            assert_eq!(
+                result.unwrap_err().downcast::<Error>().unwrap(),
+                Error::IncompleteBody
+            );
+        }
+    }
+
+    #[test]
+    fn absolute_and_asterisk_form_targets() -> Result<()> {
+        let decode = |input: &'static [u8]| Request::decode(input);
+
+        let request = decode(
+            b"GET http://localhost:4221/echo/hi?x=1 HTTP/1.1\r\nHost: localhost:4221\r\n\r\n",
+        )?;
+        assert_eq!(
+            (request.path.as_str(), request.query.as_deref()),
+            ("/echo/hi", Some("x=1"))
+        );
+        assert_eq!(&request.headers["host"], "localhost:4221");
+        let request = decode(b"GET HTTP://Example.com?x HTTP/1.1\r\nHost: example.com\r\n\r\n")?;
+        assert_eq!(
+            (request.path.as_str(), request.query.as_deref()),
+            ("/", Some("x"))
+        );
+        assert_eq!(
+            decode(b"GET http://a.com/ HTTP/1.1\r\nHost: b.com\r\n\r\n")
+                .unwrap_err()
+                .downcast::<Error>()?,
+            Error::HostMismatch
+        );
+
+        assert_eq!(decode(b"OPTIONS * HTTP/1.1\r\nHost: x\r\n\r\n")?.path, "*");
+        assert_eq!(
+            decode(b"GET * HTTP/1.1\r\nHost: x\r\n\r\n")
+                .unwrap_err()
+                .downcast::<Error>()?,
+            Error::InvalidRequestTarget
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_bodies_and_their_trailers() -> Result<()> {
+        let mut reader = &b"PUT /files/x HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\
+            Trailer: Checksum\r\n\r\n5;name=value\r\nhello\r\n1\r\n!\r\n0\r\n\
+            Checksum: abc\r\nHost: evil\r\n\r\nGET / HTTP/1.1\r\nHost: x\r\n\r\n"[..];
+
+        let request = Request::decode(&mut reader)?;
+        assert_eq!(request.body, Some(b"hello!".to_vec()));
+        assert_eq!(
+            request.trailers.iter().collect::<Vec<_>>(),
+            [("checksum", "abc")]
+        );
+        assert_eq!(&request.headers["host"], "x");
+        assert_eq!(Request::decode(&mut reader)?.path, "/");
+        Ok(())
+    }
+
+    #[test]
+    fn bad_chunked_bodies_are_rejected() {
+        let decode = |input: &'static [u8], max_body| {
+            let limits = Limits {
+                max_body,
+                ..Limits::default()
+            };
+            Request::decode_with(input, ReadPolicy::BLOCKING, limits)
+                .unwrap_err()
+                .downcast::<Error>()
+                .unwrap()
+        };
+        let chunked = |chunks: &str| {
+            format!(
+                "PUT /files/x HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n{chunks}"
+            )
+            .into_bytes()
+            .leak()
+        };
+
+        assert_eq!(decode(chunked("x\r\n"), None), Error::InvalidChunk);
+        assert_eq!(decode(chunked("2\r\nhello\r\n"), None), Error::InvalidChunk);
+        assert_eq!(decode(chunked("5\r\nhel"), None), Error::IncompleteBody);
+        // Either could be read differently by a proxy in front
+        assert_eq!(
+            decode(chunked("+5\r\nhello\r\n"), None),
+            Error::InvalidChunk
+        );
+        assert_eq!(decode(chunked("5\nhello\r\n"), None), Error::BareLineFeed);
+        assert_eq!(
+            decode(chunked("3\r\nhel\r\n3\r\nlo!\r\n0\r\n\r\n"), Some(5)),
+            Error::BodyTooLarge
+        );
+        assert_eq!(
+            decode(
+                b"PUT / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                None
+            ),
+            Error::UnsupportedTransferEncoding
+        );
+        assert_eq!(
+            decode(
+                b"PUT / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n",
+                None
+            ),
+            Error::AmbiguousFraming
+        );
+    }
+
+    #[test]
+    fn heads_over_the_limits_are_rejected() {
+        let limits = Limits {
+            max_head: Some(48),
+            max_line: Some(24),
+            ..Limits::default()
+        };
+        let decode = |input: &'static [u8]| {
+            Request::decode_with(input, ReadPolicy::BLOCKING, limits)
+                .map_err(|err| err.downcast::<Error>().unwrap())
+                .map(|request| request.path)
+        };
+
+        assert_eq!(
+            decode(b"GET /fits HTTP/1.1\r\nHost: x\r\nA: 1\r\n\r\n"),
+            Ok("/fits".to_string())
+        );
+        assert_eq!(
+            decode(b"GET /far/too/long HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Err(Error::RequestLineTooLong)
+        );
+        assert_eq!(
+            decode(b"GET / HTTP/1.1\r\nHost: x\r\nX-Long: 12345678901234567890\r\n\r\n"),
+            Err(Error::HeadersTooLarge)
+        );
+        // Without ever ending the line
+        assert_eq!(
+            decode(b"GET / HTTP/1.1\r\nHost: x\r\nX-Long: 12345678901234567890"),
+            Err(Error::HeadersTooLarge)
+        );
+        assert_eq!(
+            decode(
+                b"GET / HTTP/1.1\r\nHost: x\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\nE: 5\r\nF: 6\r\nG: 7\r\n\r\n"
+            ),
+            Err(Error::HeadersTooLarge)
+        );
+    }
+
+    #[test]
+    fn invalid_content_length() {
+        for length in ["lots", "+5", "-0", " "] {
+            let input =
+                format!("PUT /files/x HTTP/1.1\r\nHost: x\r\nContent-Length: {length}\r\n\r\n");
+            let result = Request::decode(input.as_bytes());
+
+            assert_eq!(
+                result.unwrap_err().downcast::<Error>().unwrap(),
+                Error::InvalidContentLength,
+                "{length}"
+            );
+        }
+    }
+
     #[test]
     fn header_value_with_colon() -> Result<()> {
         let input = b"GET / HTTP/1.1\r\nHost: localhost:7878\r\n\r\n";
         let result = Request::decode(&input[..])?;
 
-        assert_eq!(
-            result.headers.get("host"),
-            Some(&"localhost:7878".to_string())
-        );
+        assert_eq!(result.headers.get("host"), Some("localhost:7878"));
 
         Ok(())
     }
@@ -0,0 +1,76 @@
+//! Identifies each request in the logs, and to the client, so a report of something going wrong
+//! can be matched with what the server logged about it
+
+use crate::fields::Fields;
+use std::{
+    process,
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Longer IDs from clients are replaced, as they would bloat every log line
+const MAX_LENGTH: usize = 128;
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// Tells this run of the server apart from others, so IDs don't repeat after a restart
+static PREFIX: LazyLock<u32> = LazyLock::new(|| {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.subsec_nanos());
+
+    nanos ^ process::id().rotate_left(16)
+});
+
+/// The client's own `X-Request-Id` if it is usable, otherwise a new one
+pub fn for_request(headers: &Fields) -> String {
+    headers
+        .get("x-request-id")
+        .filter(|id| is_usable(id))
+        .map(ToString::to_string)
+        .unwrap_or_else(generate)
+}
+
+pub fn generate() -> String {
+    format!(
+        "{:08x}-{:012x}",
+        *PREFIX,
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Printable ASCII without spaces, so it is a single token wherever it is logged
+fn is_usable(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generated_ids_are_unique() {
+        let (first, second) = (generate(), generate());
+
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 21);
+        assert_eq!(first[..9], second[..9]);
+    }
+
+    #[test]
+    fn clients_can_supply_their_own() {
+        let headers = |id: &str| {
+            let mut headers = Fields::new();
+            headers.insert("x-request-id".to_string(), id.to_string());
+            headers
+        };
+
+        assert_eq!(for_request(&headers("abc-123")), "abc-123");
+        assert_ne!(for_request(&headers("has spaces")), "has spaces");
+        assert_ne!(for_request(&headers(&"a".repeat(129))).len(), 129);
+        assert_ne!(for_request(&Fields::new()), "");
+    }
+}
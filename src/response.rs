@@ -1,42 +1,404 @@
-use crate::{http, http::Header};
-use std::collections::BTreeSet;
+use crate::{
+    buffers,
+    cookie::SetCookie,
+    http,
+    http::{Header, Headers},
+};
+use memmap2::Mmap;
+use serde::Serialize;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+use tracing::warn;
+
+/// Produces a body incrementally, deciding itself when bytes should hit the wire
+pub type Producer = Box<dyn FnOnce(&mut BodyWriter) -> io::Result<()> + Send>;
+
+/// Takes over the connection once a `101 Switching Protocols` response has been sent
+pub type Upgrade = Box<dyn FnOnce(&mut dyn Duplex) -> io::Result<()> + Send>;
+
+/// Both directions of a connection, for protocols that take it over
+pub trait Duplex: Read + Write {}
+
+impl<T: Read + Write> Duplex for T {}
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Copied to the client in fixed size chunks, so it never has to be in memory all at once
+    Reader(Box<dyn Read + Send>, u64),
+    /// A regular file, which large enough ones are handed to the connection to send itself (eg,
+    /// with `sendfile(2)`), falling back to copying it like a `Reader`
+    File(File, u64),
+    /// A file mapped into memory, so the page cache backs it rather than a buffer of ours
+    Mapped(Mmap),
+    Stream(Producer),
+    /// Not a body as such, but whatever the protocol being switched to sends
+    Upgrade(Upgrade),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Self::Reader(_, length) => f.debug_tuple("Reader").field(length).finish(),
+            Self::File(_, length) => f.debug_tuple("File").field(length).finish(),
+            Self::Mapped(map) => f.debug_tuple("Mapped").field(&map.len()).finish(),
+            Self::Stream(_) => f.write_str("Stream"),
+            Self::Upgrade(_) => f.write_str("Upgrade"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Response {
     status_code: StatusCode,
-    // TODO: Sort the headers until it's easier to check responses
-    headers: BTreeSet<Header>,
-    body: Option<Vec<u8>>,
+    headers: Headers,
+    body: Option<Body>,
 }
 
 impl Response {
     pub const fn new(status_code: StatusCode) -> Self {
         Self {
             status_code,
-            headers: BTreeSet::new(),
+            headers: Headers::new(),
             body: None,
         }
     }
 
+    /// Switches the connection to `protocol`, which `upgrade` then speaks until it returns
+    pub fn switching_protocols<F>(protocol: &str, upgrade: F) -> Self
+    where
+        F: FnOnce(&mut dyn Duplex) -> io::Result<()> + Send + 'static,
+    {
+        let mut response = Self::new(StatusCode::SwitchingProtocols)
+            .header(Header::Custom(
+                "Connection".to_string(),
+                "Upgrade".to_string(),
+            ))
+            .header(Header::Custom("Upgrade".to_string(), protocol.to_string()));
+        response.set_body(Body::Upgrade(Box::new(upgrade)));
+
+        response
+    }
+
+    /// Removes what takes over the connection after this response, if anything
+    pub fn take_upgrade(&mut self) -> Option<Upgrade> {
+        match self.body.take() {
+            Some(Body::Upgrade(upgrade)) => Some(upgrade),
+            body => {
+                self.body = body;
+                None
+            }
+        }
+    }
+
+    pub const fn ok() -> Self {
+        Self::new(StatusCode::Ok)
+    }
+
+    /// Sends the client to `location` instead, with one of the 3xx statuses
+    pub fn redirect(status_code: StatusCode, location: &str) -> Self {
+        Self::new(status_code).header(Header::Custom("Location".to_string(), location.to_string()))
+    }
+
+    pub const fn created() -> Self {
+        Self::new(StatusCode::Created)
+    }
+
+    pub const fn no_content() -> Self {
+        Self::new(StatusCode::NoContent)
+    }
+
+    pub const fn bad_request() -> Self {
+        Self::new(StatusCode::BadRequest)
+    }
+
+    pub const fn not_found() -> Self {
+        Self::new(StatusCode::NotFound)
+    }
+
+    #[must_use]
+    pub fn header(mut self, header: Header) -> Self {
+        self.add_header(header);
+        self
+    }
+
+    /// Sets a cookie on the client, in a `Set-Cookie` header of its own. Cookies that would
+    /// smuggle in attributes are dropped, like invalid headers.
+    // No route keeps state in cookies yet, but handlers should be able to
+    #[allow(dead_code)]
+    #[must_use]
+    pub fn cookie(self, cookie: &SetCookie) -> Self {
+        if !cookie.is_valid() {
+            warn!(?cookie, "Dropping invalid cookie");
+            return self;
+        }
+
+        self.header(cookie.to_header())
+    }
+
+    /// Replaces the `Content-Type`, if there already was one
+    #[must_use]
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.set_header(Header::ContentType(content_type.to_string()));
+        self
+    }
+
+    #[must_use]
+    pub fn body_bytes(mut self, body: Vec<u8>) -> Self {
+        self.body(body);
+        self
+    }
+
+    #[must_use]
+    pub fn body_str(self, body: &str) -> Self {
+        self.body_bytes(body.into())
+    }
+
+    /// Serialises `value` as the body, setting the `Content-Type` to match
+    pub fn body_json<T: Serialize>(self, value: &T) -> serde_json::Result<Self> {
+        Ok(self
+            .content_type("application/json")
+            .body_bytes(serde_json::to_vec(value)?))
+    }
+
+    /// Streams `length` bytes from `reader` as the body
+    #[must_use]
+    pub fn body_reader<R: Read + Send + 'static>(mut self, reader: R, length: u64) -> Self {
+        self.set_body(Body::Reader(Box::new(reader), length));
+        self
+    }
+
+    /// Replaces the body with what `transform` makes of it (eg, compressing it), reading a
+    /// streamed file into memory first. Bodies produced as they are sent can't be transformed.
+    pub fn map_body<F>(mut self, transform: F) -> io::Result<Self>
+    where
+        F: FnOnce(Vec<u8>) -> io::Result<Vec<u8>>,
+    {
+        let body = match self.body.take() {
+            Some(Body::Bytes(body)) => body,
+            Some(Body::Reader(reader, length)) => {
+                let mut body = Vec::with_capacity(usize::try_from(length).unwrap_or_default());
+                reader.take(length).read_to_end(&mut body)?;
+                body
+            }
+            Some(Body::File(file, length)) => {
+                let mut body = Vec::with_capacity(usize::try_from(length).unwrap_or_default());
+                file.take(length).read_to_end(&mut body)?;
+                body
+            }
+            Some(Body::Mapped(map)) => map.to_vec(),
+            None => vec![],
+            body @ Some(Body::Stream(_) | Body::Upgrade(_)) => {
+                self.body = body;
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only bodies in memory or from a reader can be transformed",
+                ));
+            }
+        };
+        self.set_body(Body::Bytes(transform(body)?));
+
+        Ok(self)
+    }
+
+    /// Streams the file at `path` as the body, rather than reading it all into memory
+    pub fn body_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only regular files can be used as a body",
+            ));
+        }
+
+        let mut response = self;
+        response.set_body(Body::File(file, metadata.len()));
+
+        Ok(response)
+    }
+
+    /// Sends the file at `path` as the body from a memory map, so serving it takes no memory of
+    /// its own beyond the page cache
+    pub fn body_mapped<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only regular files can be used as a body",
+            ));
+        }
+        // Safety: The map is only read. This server never truncates a file in place, as every
+        // write goes through `files::write_replacing`, which leaves the mapped file as it was.
+        // Something else truncating it while it's being sent is the one risk, which
+        // `--mmap-min-size` warns about.
+        let map = unsafe { Mmap::map(&file)? };
+        self.set_body(Body::Mapped(map));
+
+        Ok(self)
+    }
+
+    pub const fn status_code(&self) -> &StatusCode {
+        &self.status_code
+    }
+
+    pub const fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The length of the body, if it is held in memory (which only it can be transformed
+    /// without reading a file or holding up a stream)
+    pub fn body_len_in_memory(&self) -> Option<usize> {
+        match &self.body {
+            Some(Body::Bytes(bytes)) => Some(bytes.len()),
+            _ => None,
+        }
+    }
+
+    /// Whether all of the response is in memory, so sending it waits on nothing but the client
+    #[cfg(feature = "async")]
+    pub const fn is_in_memory(&self) -> bool {
+        matches!(self.body, None | Some(Body::Bytes(_)))
+    }
+
+    /// The length of the body, if it is known before it is sent (which a stream's isn't)
+    pub fn body_len(&self) -> Option<u64> {
+        match &self.body {
+            Some(Body::Bytes(bytes)) => Some(bytes.len() as u64),
+            Some(Body::Reader(_, length) | Body::File(_, length)) => Some(*length),
+            Some(Body::Mapped(map)) => Some(map.len() as u64),
+            _ => None,
+        }
+    }
+
+    /// Says there is no body with `Content-Length: 0`, so a client on a connection kept open
+    /// doesn't wait for it to close to find out. Statuses that never have a body are left alone.
+    pub fn frame_empty_body(&mut self) {
+        let code = self.status_code.code();
+        if self.body.is_none()
+            && !self.headers.iter().any(is_framing)
+            && !matches!(code, 100..=199 | 204 | 304)
+        {
+            self.headers.append(Header::Custom(
+                "Content-Length".to_string(),
+                "0".to_string(),
+            ));
+        }
+    }
+
+    pub const fn has_body(&self) -> bool {
+        matches!(
+            self.body,
+            Some(
+                Body::Bytes(_)
+                    | Body::Reader(..)
+                    | Body::File(..)
+                    | Body::Mapped(_)
+                    | Body::Stream(_)
+            )
+        )
+    }
+
+    /// Drops the body but keeps the headers describing it, as a `HEAD` response should
+    #[must_use]
+    pub fn for_head(mut self) -> Self {
+        self.body = None;
+        self
+    }
+
+    /// Drops the body, along with the headers describing how it is framed
+    #[must_use]
+    pub fn without_body(mut self) -> Self {
+        self.body = None;
+        self.headers.retain(|header| !is_framing(header));
+
+        self
+    }
+
+    /// Headers that could split the response (eg, a value from the request with a CRLF in it)
+    /// are dropped, rather than sent
+    ///
+    /// So are `Content-Length` and `Transfer-Encoding` once there is a body, as they are worked
+    /// out from it. Without one they are kept, for a `HEAD` response to describe what `GET` sends.
+    ///
+    /// The header goes after those already added, even ones with the same name (eg, a second
+    /// `Set-Cookie`).
     pub fn add_header(&mut self, header: Header) {
-        self.headers.insert(header);
+        if self.accepts(&header) {
+            self.headers.append(header);
+        }
+    }
+
+    /// Like `add_header`, but replacing any headers with the same name rather than going alongside
+    /// them
+    pub fn set_header(&mut self, header: Header) {
+        if self.accepts(&header) {
+            self.headers.set(header);
+        }
+    }
+
+    fn accepts(&self, header: &Header) -> bool {
+        if !header.is_valid() {
+            warn!(name = ?header.name(), value = ?header.value(), "Dropping invalid header");
+            return false;
+        }
+        if self.has_body() && is_framing(header) {
+            warn!(name = ?header.name(), value = ?header.value(), "Dropping framing header");
+            return false;
+        }
+
+        true
     }
 
     pub fn body(&mut self, body: Vec<u8>) {
-        self.add_header(Header::Custom(
-            "Content-Length".to_string(),
-            body.len().to_string(),
-        ));
+        self.set_body(Body::Bytes(body));
+    }
+
+    /// The length isn't known up front, so the body is sent using chunked transfer encoding,
+    /// with a chunk going out every time the producer flushes the `BodyWriter`
+    pub fn stream<F>(&mut self, producer: F)
+    where
+        F: FnOnce(&mut BodyWriter) -> io::Result<()> + Send + 'static,
+    {
+        self.set_body(Body::Stream(Box::new(producer)));
+    }
+
+    /// Replaces the body, and the headers saying how it is framed with ones that match it: its
+    /// length if that is known, otherwise chunked
+    fn set_body(&mut self, body: Body) {
+        let framing = match &body {
+            Body::Bytes(bytes) => Some(("Content-Length", bytes.len().to_string())),
+            Body::Reader(_, length) | Body::File(_, length) => {
+                Some(("Content-Length", length.to_string()))
+            }
+            Body::Mapped(map) => Some(("Content-Length", map.len().to_string())),
+            Body::Stream(_) => Some(("Transfer-Encoding", "chunked".to_string())),
+            // The protocol switched to does its own framing
+            Body::Upgrade(_) => None,
+        };
+        self.headers.retain(|header| !is_framing(header));
+        if let Some((name, value)) = framing {
+            self.headers.append(Header::Custom(name.to_string(), value));
+        }
 
         self.body = Some(body);
     }
 
-    pub fn encode(self) -> Vec<u8> {
+    /// The size of the status line and headers, as `write_to` sends them
+    pub fn head_len(&self) -> usize {
         let mut buf = vec![];
+        self.encode_head(&mut buf);
+        buf.len()
+    }
 
+    fn encode_head(&self, buf: &mut Vec<u8>) {
         buf.extend(http::VERSION);
         buf.extend(b" ");
-        buf.extend(self.status_code.as_bytes());
+        buf.extend(self.status_code.to_string().as_bytes());
         buf.extend(http::CRLF);
         for header in &self.headers {
             buf.extend(header.name().as_bytes());
@@ -45,40 +407,453 @@ impl Response {
             buf.extend(http::CRLF);
         }
         buf.extend(http::CRLF);
+    }
 
-        if let Some(body) = self.body {
-            buf.extend(body);
-        }
+    pub fn encode(self) -> Vec<u8> {
+        let mut buf = vec![];
+        // Safety: Writing to a `Vec` cannot fail, only the producer of a streamed body can
+        self.write_to(&mut buf).unwrap();
 
         buf
     }
+
+    /// Room for the status line and headers of a typical response
+    const HEAD_CAPACITY: usize = 1024;
+
+    /// Size of the chunks used to copy `Body::Reader` bodies to the client
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    /// Files smaller than this are copied after the head, so they still go out in a single write
+    const SEND_FILE_MIN: u64 = Self::CHUNK_SIZE as u64;
+
+    /// Copies `length` bytes from `reader` after the head already in `buf`, so a small body
+    /// still goes out in a single write
+    fn copy_body<W: Write>(
+        reader: &mut impl Read,
+        length: u64,
+        buf: &mut Vec<u8>,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let mut chunk = buffers::take(Self::CHUNK_SIZE);
+        chunk.resize(Self::CHUNK_SIZE, 0);
+        let result = Self::copy_chunks(reader, length, &mut chunk, buf, writer);
+        buffers::give(chunk);
+
+        result
+    }
+
+    fn copy_chunks<W: Write>(
+        reader: &mut impl Read,
+        length: u64,
+        chunk: &mut [u8],
+        buf: &mut Vec<u8>,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let mut remaining = length;
+        while remaining > 0 {
+            let read = match reader.read(chunk) {
+                // The Content-Length has already been promised, so the client can't be told
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            remaining -= read as u64;
+
+            buf.extend_from_slice(&chunk[..read]);
+            if buf.len() >= Self::CHUNK_SIZE {
+                writer.write_all(buf)?;
+                buf.clear();
+            }
+        }
+
+        if !buf.is_empty() {
+            writer.write_all(buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes the response apart for protocols that frame it differently to HTTP/1.1, reading
+    /// the whole body into memory and dropping the headers that only describe HTTP/1.1 framing
+    pub fn into_parts(mut self) -> io::Result<(StatusCode, Headers, Vec<u8>)> {
+        let body = match self.body.take() {
+            None => vec![],
+            Some(Body::Bytes(body)) => body,
+            Some(Body::Reader(reader, length)) => {
+                let mut body = Vec::with_capacity(usize::try_from(length).unwrap_or_default());
+                reader.take(length).read_to_end(&mut body)?;
+                body
+            }
+            Some(Body::File(file, length)) => {
+                let mut body = Vec::with_capacity(usize::try_from(length).unwrap_or_default());
+                file.take(length).read_to_end(&mut body)?;
+                body
+            }
+            Some(Body::Mapped(map)) => map.to_vec(),
+            // Other protocols have their own ways of switching
+            Some(Body::Upgrade(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "protocol upgrades are HTTP/1.1 only",
+                ));
+            }
+            Some(Body::Stream(producer)) => {
+                let mut body = vec![];
+                let mut body_writer = BodyWriter::unchunked(&mut body);
+                producer(&mut body_writer)?;
+                body_writer.finish()?;
+                body
+            }
+        };
+        self.headers
+            .retain(|header| !header.is("Transfer-Encoding"));
+
+        Ok((self.status_code, self.headers, body))
+    }
+
+    /// Writes the response to `writer`, with fixed length responses going out in a single write
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        self.write_with(writer, |_, _, _| None)
+    }
+
+    /// Like `write_to`, but once the head has gone out, a large file body is handed to
+    /// `send_file` to send itself, unless it returns `None` to have it copied after all
+    pub fn write_with<W, F>(mut self, writer: &mut W, send_file: F) -> io::Result<()>
+    where
+        W: Write,
+        F: FnOnce(&mut W, &File, u64) -> Option<io::Result<()>>,
+    {
+        let body = self.body.take();
+        let mut buf = buffers::take(
+            Self::HEAD_CAPACITY
+                + match &body {
+                    Some(Body::Bytes(body)) => body.len(),
+                    Some(Body::Reader(..) | Body::File(..)) => Self::CHUNK_SIZE,
+                    _ => 0,
+                },
+        );
+        self.encode_head(&mut buf);
+
+        let result = match body {
+            // The upgrade itself is for whoever owns the connection
+            None | Some(Body::Upgrade(_)) => writer.write_all(&buf),
+            Some(Body::Bytes(body)) => {
+                buf.extend_from_slice(&body);
+                buffers::give(body);
+                writer.write_all(&buf)
+            }
+            Some(Body::Reader(reader, length)) => {
+                Self::copy_body(&mut reader.take(length), length, &mut buf, writer)
+            }
+            Some(Body::File(file, length)) if length >= Self::SEND_FILE_MIN => {
+                writer.write_all(&buf).and_then(|()| {
+                    buf.clear();
+                    send_file(writer, &file, length).unwrap_or_else(|| {
+                        Self::copy_body(&mut file.take(length), length, &mut buf, writer)
+                    })
+                })
+            }
+            Some(Body::File(file, length)) => {
+                Self::copy_body(&mut file.take(length), length, &mut buf, writer)
+            }
+            // Written straight from the map rather than joining the head in `buf`
+            Some(Body::Mapped(map)) => writer.write_all(&buf).and_then(|()| writer.write_all(&map)),
+            Some(Body::Stream(producer)) => writer
+                .write_all(&buf)
+                .and_then(|()| writer.flush())
+                .and_then(|()| {
+                    let mut body_writer = BodyWriter::new(writer);
+                    producer(&mut body_writer)?;
+                    body_writer.finish()
+                }),
+        };
+        buffers::give(buf);
+
+        result
+    }
 }
 
-#[derive(Debug)]
+/// Whether `header` says how the body is delimited, which only the response itself should
+fn is_framing(header: &Header) -> bool {
+    header.is("Content-Length") || header.is("Transfer-Encoding")
+}
+
+/// Whether an error writing a response means the client has gone away (or stopped reading
+/// for longer than the write timeout), rather than something going wrong on our side
+pub fn is_disconnect(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::WriteZero
+    )
+}
+
+/// Buffers everything written to it until `flush`, when it is sent as a single chunk
+///
+/// Once the client has disconnected every write fails with `BrokenPipe`, so producers using `?`
+/// stop generating bytes nobody will read.
+pub struct BodyWriter<'a> {
+    writer: &'a mut dyn Write,
+    buffer: Vec<u8>,
+    disconnected: bool,
+    chunked: bool,
+    trailers: Vec<Header>,
+}
+
+impl<'a> BodyWriter<'a> {
+    fn new(writer: &'a mut dyn Write) -> Self {
+        Self {
+            writer,
+            buffer: vec![],
+            disconnected: false,
+            chunked: true,
+            trailers: vec![],
+        }
+    }
+
+    /// Passes the body through as is, for when something else frames it
+    fn unchunked(writer: &'a mut dyn Write) -> Self {
+        Self {
+            chunked: false,
+            ..Self::new(writer)
+        }
+    }
+
+    /// Sends `header` after the last chunk, for values only known once the body has been
+    /// produced (eg, a checksum). It should be named in the response's `Trailer` header.
+    ///
+    /// Invalid headers are dropped, as are trailers on a body that isn't chunked.
+    pub fn trailer(&mut self, header: Header) {
+        if !header.is_valid() {
+            warn!(name = ?header.name(), value = ?header.value(), "Dropping invalid trailer");
+            return;
+        }
+
+        self.trailers.push(header);
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        if !self.chunked {
+            return Ok(());
+        }
+
+        let mut end = b"0\r\n".to_vec();
+        for trailer in std::mem::take(&mut self.trailers) {
+            end.extend(format!("{}: {}", trailer.name(), trailer.value()).bytes());
+            end.extend(http::CRLF);
+        }
+        end.extend(http::CRLF);
+        self.send(&end)
+    }
+
+    fn check_connected(&self) -> io::Result<()> {
+        if self.disconnected {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "client disconnected",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.check_connected()?;
+
+        let result = self
+            .writer
+            .write_all(bytes)
+            .and_then(|()| self.writer.flush());
+        if let Err(error) = &result {
+            self.disconnected = is_disconnect(error);
+        }
+
+        result
+    }
+}
+
+impl Write for BodyWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_connected()?;
+
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // A zero length chunk would signal the end of the body
+        if self.buffer.is_empty() {
+            return self.check_connected();
+        }
+
+        if !self.chunked {
+            let buffer = std::mem::take(&mut self.buffer);
+            return self.send(&buffer);
+        }
+
+        let mut chunk = format!("{:x}", self.buffer.len()).into_bytes();
+        chunk.extend(http::CRLF);
+        chunk.append(&mut self.buffer);
+        chunk.extend(http::CRLF);
+        self.send(&chunk)
+    }
+}
+
+// Not every status code is used by the routes, but handlers should be able to express them
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StatusCode {
+    Continue,
+    SwitchingProtocols,
     Ok,
     Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
     BadRequest,
+    Unauthorized,
+    Forbidden,
     NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
     RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PreconditionFailed,
+    ContentTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    UnprocessableContent,
+    UpgradeRequired,
+    PreconditionRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
     NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
     HttpVersionNotSupported,
+    /// Escape hatch for anything not covered above
+    Custom(u16, String),
 }
 
 impl StatusCode {
-    pub const fn as_bytes(&self) -> &[u8] {
+    pub const fn code(&self) -> u16 {
+        match self {
+            Self::Continue => 100,
+            Self::SwitchingProtocols => 101,
+            Self::Ok => 200,
+            Self::Created => 201,
+            Self::Accepted => 202,
+            Self::NoContent => 204,
+            Self::PartialContent => 206,
+            Self::MovedPermanently => 301,
+            Self::Found => 302,
+            Self::SeeOther => 303,
+            Self::NotModified => 304,
+            Self::TemporaryRedirect => 307,
+            Self::PermanentRedirect => 308,
+            Self::BadRequest => 400,
+            Self::Unauthorized => 401,
+            Self::Forbidden => 403,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::NotAcceptable => 406,
+            Self::RequestTimeout => 408,
+            Self::Conflict => 409,
+            Self::Gone => 410,
+            Self::LengthRequired => 411,
+            Self::PreconditionFailed => 412,
+            Self::ContentTooLarge => 413,
+            Self::UriTooLong => 414,
+            Self::UnsupportedMediaType => 415,
+            Self::RangeNotSatisfiable => 416,
+            Self::ExpectationFailed => 417,
+            Self::UnprocessableContent => 422,
+            Self::UpgradeRequired => 426,
+            Self::PreconditionRequired => 428,
+            Self::TooManyRequests => 429,
+            Self::RequestHeaderFieldsTooLarge => 431,
+            Self::InternalServerError => 500,
+            Self::NotImplemented => 501,
+            Self::BadGateway => 502,
+            Self::ServiceUnavailable => 503,
+            Self::GatewayTimeout => 504,
+            Self::HttpVersionNotSupported => 505,
+            Self::Custom(code, _) => *code,
+        }
+    }
+
+    /// The reason phrases from RFC 9110
+    pub fn reason(&self) -> &str {
         match self {
-            Self::Ok => b"200 OK",
-            Self::Created => b"201 Created",
-            Self::BadRequest => b"400 Bad Request",
-            Self::NotFound => b"404 Not Found",
-            Self::RequestTimeout => b"408 Request Timeout",
-            Self::NotImplemented => b"501 Not Implemented",
-            Self::HttpVersionNotSupported => b"505 HTTP Version Not Supported",
+            Self::Continue => "Continue",
+            Self::SwitchingProtocols => "Switching Protocols",
+            Self::Ok => "OK",
+            Self::Created => "Created",
+            Self::Accepted => "Accepted",
+            Self::NoContent => "No Content",
+            Self::PartialContent => "Partial Content",
+            Self::MovedPermanently => "Moved Permanently",
+            Self::Found => "Found",
+            Self::SeeOther => "See Other",
+            Self::NotModified => "Not Modified",
+            Self::TemporaryRedirect => "Temporary Redirect",
+            Self::PermanentRedirect => "Permanent Redirect",
+            Self::BadRequest => "Bad Request",
+            Self::Unauthorized => "Unauthorized",
+            Self::Forbidden => "Forbidden",
+            Self::NotFound => "Not Found",
+            Self::MethodNotAllowed => "Method Not Allowed",
+            Self::NotAcceptable => "Not Acceptable",
+            Self::RequestTimeout => "Request Timeout",
+            Self::Conflict => "Conflict",
+            Self::Gone => "Gone",
+            Self::LengthRequired => "Length Required",
+            Self::PreconditionFailed => "Precondition Failed",
+            Self::ContentTooLarge => "Content Too Large",
+            Self::UriTooLong => "URI Too Long",
+            Self::UnsupportedMediaType => "Unsupported Media Type",
+            Self::RangeNotSatisfiable => "Range Not Satisfiable",
+            Self::ExpectationFailed => "Expectation Failed",
+            Self::UnprocessableContent => "Unprocessable Content",
+            Self::UpgradeRequired => "Upgrade Required",
+            Self::PreconditionRequired => "Precondition Required",
+            Self::TooManyRequests => "Too Many Requests",
+            Self::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Self::InternalServerError => "Internal Server Error",
+            Self::NotImplemented => "Not Implemented",
+            Self::BadGateway => "Bad Gateway",
+            Self::ServiceUnavailable => "Service Unavailable",
+            Self::GatewayTimeout => "Gateway Timeout",
+            Self::HttpVersionNotSupported => "HTTP Version Not Supported",
+            Self::Custom(_, reason) => reason,
         }
     }
 }
 
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -107,6 +882,121 @@ mod test {
         assert_eq!(response, expected);
     }
 
+    #[test]
+    fn it_returns_431_with_reason_phrase() {
+        let response = Response::new(StatusCode::RequestHeaderFieldsTooLarge).encode();
+        let expected = b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n";
+
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn it_returns_a_custom_status_code() {
+        let status_code = StatusCode::Custom(418, "I'm a teapot".to_string());
+        assert_eq!(status_code.code(), 418);
+
+        let response = Response::new(status_code).encode();
+        let expected = b"HTTP/1.1 418 I'm a teapot\r\n\r\n";
+
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn it_builds_fluently() {
+        let response = Response::ok()
+            .content_type("text/plain")
+            .body_str("hi")
+            .encode();
+        let expected =
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi";
+
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn it_has_a_json_body() -> serde_json::Result<()> {
+        #[derive(Serialize)]
+        struct Echo<'a> {
+            echo: &'a str,
+        }
+
+        let response = Response::ok().body_json(&Echo { echo: "hi" })?.encode();
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 13\r\n\r\n{\"echo\":\"hi\"}";
+
+        assert_eq!(response, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn it_has_a_file_body() -> io::Result<()> {
+        let response = Response::ok().body_file(".gitattributes")?.encode();
+
+        assert!(response.ends_with(b"Content-Length: 12\r\n\r\n* text=auto\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn it_has_a_mapped_file_body() -> io::Result<()> {
+        let response = Response::ok().body_mapped(".gitattributes")?;
+        assert_eq!(response.headers().get("content-length"), Some("12"));
+
+        assert!(response.encode().ends_with(b"\r\n\r\n* text=auto\n"));
+        assert!(Response::ok().body_mapped("src").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn it_streams_a_large_reader_in_chunks() {
+        /// Counts the writes made, so we can tell the body wasn't sent in one go
+        #[derive(Default)]
+        struct Counting {
+            writes: usize,
+            written: Vec<u8>,
+        }
+
+        impl Write for Counting {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.writes += 1;
+                self.written.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let body: Vec<u8> = (0..200_000u32).map(|x| x as u8).collect();
+        let mut counting = Counting::default();
+        Response::ok()
+            .body_reader(io::Cursor::new(body.clone()), body.len() as u64)
+            .write_to(&mut counting)
+            .unwrap();
+
+        assert!(counting.writes > 1);
+        assert!(counting.written.ends_with(&body));
+        assert!(
+            counting
+                .written
+                .starts_with(b"HTTP/1.1 200 OK\r\nContent-Length: 200000\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn it_fails_when_reader_is_short() {
+        let response = Response::ok().body_reader(io::Cursor::new(b"abc".to_vec()), 10);
+
+        assert_eq!(
+            response.write_to(&mut vec![]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn it_refuses_a_directory_as_a_file_body() {
+        assert!(Response::ok().body_file("src").is_err());
+    }
+
     #[test]
     fn it_has_a_custom_header() {
         let mut response = Response::new(StatusCode::Ok);
@@ -136,4 +1026,181 @@ mod test {
         assert!(contains_subslice(b"Content-Type: text/plain\r\n"));
         assert!(contains_subslice(b"Content-Length: 13\r\n"));
     }
+
+    #[test]
+    fn it_sets_several_cookies() {
+        let response = Response::no_content()
+            .cookie(&SetCookie::new("session", "abc").http_only())
+            .cookie(&SetCookie::new("theme", "dark").path("/"))
+            .cookie(&SetCookie::new("bad", "a;b"));
+
+        assert_eq!(
+            response.encode(),
+            b"HTTP/1.1 204 No Content\r\nSet-Cookie: session=abc; HttpOnly\r\n\
+            Set-Cookie: theme=dark; Path=/\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn headers_are_sent_in_the_order_they_were_added() {
+        let mut response = Response::ok()
+            .header(Header::Custom("X-First".to_string(), "1".to_string()))
+            .content_type("text/html")
+            .header(Header::Custom("Vary".to_string(), "Accept".to_string()))
+            .content_type("text/plain");
+        response.set_header(Header::Custom("X-First".to_string(), "one".to_string()));
+
+        assert_eq!(
+            response.body_str("hi").encode(),
+            b"HTTP/1.1 200 OK\r\nX-First: one\r\nContent-Type: text/plain\r\nVary: Accept\r\n\
+            Content-Length: 2\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn framing_follows_the_body() -> io::Result<()> {
+        let response = Response::ok()
+            .header(Header::Custom(
+                "Content-Length".to_string(),
+                "99".to_string(),
+            ))
+            .body_str("first")
+            .body_str("second")
+            .header(Header::Custom(
+                "Transfer-Encoding".to_string(),
+                "chunked".to_string(),
+            ))
+            .map_body(|body| Ok(body.repeat(2)))?;
+
+        assert_eq!(
+            response.encode(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\nsecondsecond"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_bodies_cannot_be_transformed() {
+        let mut response = Response::ok();
+        response.stream(|writer| writer.write_all(b"hi"));
+
+        assert!(response.map_body(Ok).is_err());
+    }
+
+    #[test]
+    fn it_streams_a_chunked_body() {
+        let mut response = Response::new(StatusCode::Ok);
+        response.stream(|writer| {
+            writer.write_all(b"Hello")?;
+            writer.flush()?;
+            writer.write_all(b", ")?;
+            writer.write_all(b"world!")
+        });
+        let response = response.encode();
+        let expected =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n8\r\n, world!\r\n0\r\n\r\n";
+
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn it_sends_trailers_after_the_last_chunk() {
+        let mut response = Response::new(StatusCode::Ok);
+        response.add_header(Header::Custom("Trailer".to_string(), "X-Count".to_string()));
+        response.stream(|writer| {
+            writer.write_all(b"Hello")?;
+            writer.trailer(Header::Custom("X-Count".to_string(), "5".to_string()));
+            writer.trailer(Header::Custom("X-Bad".to_string(), "\r\n".to_string()));
+            Ok(())
+        });
+        let response = response.encode();
+        let expected = b"HTTP/1.1 200 OK\r\nTrailer: X-Count\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n0\r\nX-Count: 5\r\n\r\n";
+
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn into_parts_unchunks_the_body() -> io::Result<()> {
+        let mut response = Response::ok().content_type("text/plain");
+        response.stream(|writer| {
+            writer.write_all(b"Hello")?;
+            writer.flush()?;
+            writer.write_all(b", world!")
+        });
+        let (status_code, headers, body) = response.into_parts()?;
+
+        assert_eq!(status_code, StatusCode::Ok);
+        assert_eq!(
+            headers.iter().map(Header::name).collect::<Vec<_>>(),
+            ["Content-Type"]
+        );
+        assert_eq!(body, b"Hello, world!");
+        Ok(())
+    }
+
+    /// Accepts the response head, then behaves as if the client hung up
+    struct HangsUp {
+        writes: usize,
+    }
+
+    impl Write for HangsUp {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            if self.writes > 1 {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn producer_stops_when_client_disconnects() {
+        let produced = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = std::sync::Arc::clone(&produced);
+
+        let mut response = Response::new(StatusCode::Ok);
+        response.stream(move |writer| {
+            for _ in 0..100 {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                writer.write_all(b"tick")?;
+                writer.flush()?;
+            }
+
+            Ok(())
+        });
+
+        let error = response.write_to(&mut HangsUp { writes: 0 }).unwrap_err();
+        assert!(is_disconnect(&error));
+        assert_eq!(produced.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn writes_fail_once_disconnected() {
+        let mut sink = HangsUp { writes: 1 };
+        let mut writer = BodyWriter::new(&mut sink);
+
+        writer.write_all(b"tick").unwrap();
+        assert!(writer.flush().is_err());
+        assert_eq!(
+            writer.write(b"tock").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn empty_flushes_do_not_end_the_body() {
+        let mut response = Response::new(StatusCode::Ok);
+        response.stream(|writer| {
+            writer.flush()?;
+            writer.write_all(b"a")
+        });
+        let response = response.encode();
+
+        assert!(response.ends_with(b"\r\n\r\n1\r\na\r\n0\r\n\r\n"));
+    }
 }
@@ -0,0 +1,853 @@
+use crate::{
+    admin::Admin,
+    auth::{Credentials, Requirement},
+    cache_control::CacheControl,
+    compression::{Level, Policy, Precompressed},
+    cors::Cors,
+    file_cache::FileCache,
+    files::Symlinks,
+    health::Health,
+    http::Header,
+    metrics::Metrics,
+    profiling::{self, Phase},
+    request::{Method, Request},
+    response::{Response, StatusCode},
+};
+use anyhow::Result;
+use std::{any::Any, fmt};
+
+/// Everything a handler can see besides the request itself
+#[derive(Debug, Default)]
+pub struct RequestContext<'a> {
+    pub directory: Option<&'a str>,
+    pub static_root: Option<&'a str>,
+    pub create_parents: bool,
+    /// Whether `/files` are always sent as downloads, rather than only when asked
+    pub force_download: bool,
+    /// Whether the routes that write to `directory` refuse to
+    pub read_only: bool,
+    /// Which symlinks files are served through, others being taken for missing
+    pub follow_symlinks: Symlinks,
+    /// What `Cache-Control` says on the files served, if anything
+    pub cache_control: Option<&'a CacheControl>,
+    /// The client's address, through any trusted proxies, if known
+    pub client: Option<&'a str>,
+    /// The address the connection itself is from, which is a proxy's when the request was
+    /// forwarded
+    pub peer: Option<&'a str>,
+    /// Who routes that require authentication let in, `None` leaving them open
+    pub credentials: Option<&'a Credentials>,
+    /// What `/metrics` reports, if anything is keeping count
+    pub metrics: Option<&'a Metrics>,
+    /// What `/readyz` reports, not ready when `None`
+    pub health: Option<&'a Health>,
+    /// What `/__admin` reports on and changes, which isn't there when `None`
+    pub admin: Option<&'a Admin>,
+    /// Which origins may use routes that don't have CORS settings of their own
+    pub cors: Option<&'a Cors>,
+    /// The media type of the request body, lowercased and without parameters, once the route
+    /// has accepted it
+    // The routes that accept bodies only take one type so far, but handlers can tell them apart
+    #[allow(dead_code)]
+    pub content_type: Option<&'a str>,
+    /// How hard the route wants its response compressed
+    pub compression: Level,
+    /// When responses are compressed after the handler, `None` leaving them as they are
+    pub compression_policy: Option<&'a Policy>,
+    /// Gzipped copies of the static files, if they were made at startup
+    pub precompressed: Option<&'a Precompressed>,
+    /// Where `/files` keeps copies of the files it serves most, if anywhere
+    pub file_cache: Option<&'a FileCache>,
+    /// Whatever the application built on the server shares between its handlers (eg, counters,
+    /// connection pools), which `state` gets at
+    // The server's own routes have nothing to share beyond the config
+    #[allow(dead_code)]
+    pub state: Option<&'a (dyn Any + Send + Sync)>,
+    /// Files at least this big are sent from a memory map, if any are
+    pub mmap_min_size: Option<u64>,
+}
+
+impl RequestContext<'_> {
+    /// The application's shared state, if there is any and it is a `T`
+    #[allow(dead_code)]
+    pub fn state<T: Any>(&self) -> Option<&T> {
+        self.state?.downcast_ref()
+    }
+}
+
+/// Answers the requests for a route: a `fn` or closure taking the request and its context, or a
+/// type of its own holding what it needs
+pub trait Handler: Send + Sync {
+    fn call(&self, request: &Request, context: &RequestContext) -> Result<Response>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request, &RequestContext) -> Result<Response> + Send + Sync,
+{
+    fn call(&self, request: &Request, context: &RequestContext) -> Result<Response> {
+        self(request, context)
+    }
+}
+
+impl fmt::Debug for dyn Handler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Handler")
+    }
+}
+
+#[derive(Debug)]
+enum Path {
+    Exact(&'static str),
+    Prefix(&'static str),
+    Around(&'static str, &'static str),
+}
+
+impl Path {
+    /// Paths ending in `*` match anything starting with what comes before it, while a `*`
+    /// elsewhere matches anything non-empty between what comes before and after it
+    fn parse(path: &'static str) -> Self {
+        match path.split_once('*') {
+            None => Self::Exact(path),
+            Some((prefix, "")) => Self::Prefix(prefix),
+            Some((prefix, suffix)) => Self::Around(prefix, suffix),
+        }
+    }
+
+    fn matches(&self, target: &str) -> bool {
+        match self {
+            Self::Exact(path) => target == *path,
+            Self::Prefix(prefix) => target.starts_with(prefix),
+            Self::Around(prefix, suffix) => {
+                target.len() > prefix.len() + suffix.len()
+                    && target.starts_with(prefix)
+                    && target.ends_with(suffix)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Route {
+    method: Method,
+    /// As registered, to count the route's requests under
+    pattern: &'static str,
+    path: Path,
+    handler: Box<dyn Handler>,
+    /// Media types the body may have, anything when empty
+    accepts: &'static [&'static str],
+    /// `None` once the route has opted out of compression
+    compression: Option<Level>,
+}
+
+#[derive(Debug, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Box<dyn Handler>>,
+    requirements: Vec<(Path, Requirement)>,
+    cors: Vec<(Path, Cors)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<H: Handler + 'static>(
+        mut self,
+        method: Method,
+        path: &'static str,
+        handler: H,
+    ) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern: path,
+            path: Path::parse(path),
+            handler: Box::new(handler),
+            accepts: &[],
+            compression: Some(Level::default()),
+        });
+
+        self
+    }
+
+    /// Limits the route registered just before to bodies of these media types (eg
+    /// `application/json`, or `text/*`), answering any other with 415
+    ///
+    /// Bodies without a `Content-Type` are taken to be `application/octet-stream`, as RFC 9110
+    /// allows, while requests without a body are always let through.
+    pub fn accepts(mut self, media_types: &'static [&'static str]) -> Self {
+        self.routes
+            .last_mut()
+            .expect("accepts follows a route")
+            .accepts = media_types;
+
+        self
+    }
+
+    /// How hard the route registered just before should work at compressing what it sends
+    pub fn compression(mut self, level: Level) -> Self {
+        self.routes
+            .last_mut()
+            .expect("compression follows a route")
+            .compression = Some(level);
+
+        self
+    }
+
+    /// Leaves what the route registered just before sends uncompressed, whatever the policy
+    pub fn uncompressed(mut self) -> Self {
+        self.routes
+            .last_mut()
+            .expect("uncompressed follows a route")
+            .compression = None;
+
+        self
+    }
+
+    /// Makes routes under `path` (matched as in `route`) refuse requests that don't meet
+    /// `requirement`, before their handler is called
+    pub fn require(mut self, path: &'static str, requirement: Requirement) -> Self {
+        self.requirements.push((Path::parse(path), requirement));
+
+        self
+    }
+
+    /// Lets pages from other origins use routes under `path`, as `cors` allows
+    pub fn cors(mut self, path: &'static str, cors: Cors) -> Self {
+        self.cors.push((Path::parse(path), cors));
+
+        self
+    }
+
+    /// Handles `GET` requests for paths no route matches, instead of a 404
+    pub fn fallback<H: Handler + 'static>(mut self, handler: H) -> Self {
+        self.fallback = Some(Box::new(handler));
+
+        self
+    }
+
+    /// Whether any route handles `method`, as a method nothing handles is not implemented
+    /// rather than merely not allowed
+    pub fn implements(&self, method: &Method) -> bool {
+        *method == Method::Options
+            || self.routes.iter().any(|route| {
+                route.method == *method || (*method == Method::Head && route.method == Method::Get)
+            })
+    }
+
+    /// The methods that have a handler for `target`, in registration order
+    pub fn allowed_methods(&self, target: &str) -> Vec<Method> {
+        let mut methods = vec![];
+        for route in self
+            .routes
+            .iter()
+            .filter(|route| route.path.matches(target))
+        {
+            if !methods.contains(&route.method) {
+                methods.push(route.method.clone());
+            }
+        }
+        with_head(&mut methods);
+
+        methods
+    }
+
+    /// The route for `method` requests to `target`, with `HEAD` answered by the `GET` route
+    /// unless it has one of its own (RFC 9110 section 9.3.2)
+    fn route_for(&self, method: &Method, target: &str) -> Option<&Route> {
+        let route = |method: &Method| {
+            self.routes
+                .iter()
+                .find(|route| route.method == *method && route.path.matches(target))
+        };
+        route(method).or_else(|| {
+            (*method == Method::Head)
+                .then(|| route(&Method::Get))
+                .flatten()
+        })
+    }
+
+    pub fn dispatch(&self, request: &Request, context: &RequestContext) -> Result<Response> {
+        // `OPTIONS *` asks about the server rather than any resource
+        if request.path == "*" {
+            let mut methods = vec![];
+            for route in &self.routes {
+                if !methods.contains(&route.method) {
+                    methods.push(route.method.clone());
+                }
+            }
+            with_head(&mut methods);
+            methods.push(Method::Options);
+
+            return Ok(Response::new(StatusCode::NoContent).header(Header::Allow(
+                methods
+                    .iter()
+                    .map(Method::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )));
+        }
+
+        if let Some(route) = self.route_for(&request.method, &request.path) {
+            if let Some(metrics) = context.metrics {
+                metrics.route_requested(request.method.as_str(), route.pattern);
+            }
+            let refused = context.credentials.and_then(|credentials| {
+                self.requirements
+                    .iter()
+                    .filter(|(path, _)| path.matches(&request.path))
+                    .find_map(|(_, requirement)| requirement.check(request, credentials))
+            });
+            let content_type = request
+                .body
+                .as_ref()
+                .is_some_and(|body| !body.is_empty())
+                .then(|| media_type(request.headers.get("content-type")));
+            let accepted = content_type.as_deref().is_none_or(|content_type| {
+                route.accepts.is_empty()
+                    || route
+                        .accepts
+                        .iter()
+                        .any(|accepted| media_type_matches(accepted, content_type))
+            });
+            let mut response = match refused {
+                Some(refused) => refused,
+                None if !accepted => Response::new(StatusCode::UnsupportedMediaType),
+                None => profiling::time(Phase::Handler, || {
+                    route.handler.call(
+                        request,
+                        &RequestContext {
+                            content_type: content_type.as_deref(),
+                            compression: route.compression.unwrap_or_default(),
+                            ..*context
+                        },
+                    )
+                })?,
+            };
+            if let (Some(policy), Some(level)) = (context.compression_policy, route.compression) {
+                response = policy.apply(request, response, level)?;
+            }
+            if let Some(cors) = self.cors_for(&request.path, context) {
+                cors.allow_origin(request, &mut response);
+            }
+
+            return Ok(for_method(request, response));
+        }
+
+        if !self.implements(&request.method) {
+            return Ok(Response::new(StatusCode::NotImplemented));
+        }
+
+        let mut allowed = self.allowed_methods(&request.path);
+        if allowed.is_empty() {
+            let mut response = match &self.fallback {
+                Some(handler) if matches!(request.method, Method::Get | Method::Head) => {
+                    handler.call(request, context)?
+                }
+                _ => return Ok(Response::new(StatusCode::NotFound)),
+            };
+            if let Some(policy) = context.compression_policy {
+                response = policy.apply(request, response, context.compression)?;
+            }
+            if let Some(cors) = self.cors_for(&request.path, context) {
+                cors.allow_origin(request, &mut response);
+            }
+
+            return Ok(for_method(request, response));
+        }
+        allowed.push(Method::Options);
+
+        let status_code = if request.method == Method::Options {
+            StatusCode::NoContent
+        } else {
+            StatusCode::MethodNotAllowed
+        };
+        let mut response = Response::new(status_code);
+        response.add_header(Header::Allow(
+            allowed
+                .iter()
+                .map(Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+        ));
+        if Cors::is_preflight(request)
+            && let Some(cors) = self.cors_for(&request.path, context)
+        {
+            cors.preflight(request, &allowed, &mut response);
+        }
+
+        Ok(response)
+    }
+
+    fn cors_for<'a>(&'a self, target: &str, context: &RequestContext<'a>) -> Option<&'a Cors> {
+        self.cors
+            .iter()
+            .find(|(path, _)| path.matches(target))
+            .map(|(_, cors)| cors)
+            .or(context.cors)
+    }
+}
+
+/// Adds `HEAD` after `GET`, as whatever can be got can have its headers asked for
+fn with_head(methods: &mut Vec<Method>) {
+    if let Some(get) = methods.iter().position(|method| *method == Method::Get)
+        && !methods.contains(&Method::Head)
+    {
+        methods.insert(get + 1, Method::Head);
+    }
+}
+
+/// The response without its body if `request` is a `HEAD`, its headers still describing what a
+/// `GET` would have been sent
+fn for_method(request: &Request, response: Response) -> Response {
+    if request.method == Method::Head {
+        response.for_head()
+    } else {
+        response
+    }
+}
+
+/// The `type/subtype` of a `Content-Type`, in lowercase
+fn media_type(content_type: Option<&str>) -> String {
+    content_type.map_or_else(
+        || "application/octet-stream".to_string(),
+        |content_type| {
+            content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        },
+    )
+}
+
+/// Whether `media_type` is `pattern`, or a subtype of a `type/*` pattern
+pub fn media_type_matches(pattern: &str, media_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => media_type
+            .split_once('/')
+            .is_some_and(|(given, _)| given.eq_ignore_ascii_case(kind)),
+        None => pattern.eq_ignore_ascii_case(media_type),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fields::Fields;
+
+    fn ok(_: &Request, _: &RequestContext) -> Result<Response> {
+        Ok(Response::new(StatusCode::Ok))
+    }
+
+    fn request(method: Method, target: &str) -> Request {
+        Request::from_parts(method, target, Fields::new(), None).unwrap()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .route(Method::Get, "/", ok)
+            .route(Method::Get, "/files/*", ok)
+            .route(Method::Post, "/files/*", ok)
+            .route(Method::Delete, "/admin", ok)
+            .route(Method::Get, "/users/*/name", ok)
+    }
+
+    #[test]
+    fn requests_are_counted_by_route() -> Result<()> {
+        let metrics = Metrics::default();
+        let context = RequestContext {
+            metrics: Some(&metrics),
+            ..RequestContext::default()
+        };
+        let router = router();
+        for (method, target) in [
+            (Method::Get, "/files/a"),
+            (Method::Get, "/files/b"),
+            (Method::Post, "/files/a"),
+            (Method::Get, "/nowhere"),
+        ] {
+            router.dispatch(&request(method, target), &context)?;
+        }
+
+        assert_eq!(
+            metrics.route_requests().into_iter().collect::<Vec<_>>(),
+            [
+                ("GET /files/*".to_string(), 2),
+                ("POST /files/*".to_string(), 1)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn routes_can_opt_out_of_compression() -> Result<()> {
+        fn text(_: &Request, _: &RequestContext) -> Result<Response> {
+            Ok(Response::ok().content_type("text/plain").body_str("hello"))
+        }
+        let router = Router::new()
+            .route(Method::Get, "/compressed", text)
+            .route(Method::Get, "/plain", text)
+            .uncompressed();
+        let policy = Policy::default();
+        let context = RequestContext {
+            compression_policy: Some(&policy),
+            ..RequestContext::default()
+        };
+        let get = |target| {
+            let mut request = request(Method::Get, target);
+            request
+                .headers
+                .insert("accept-encoding".to_string(), "gzip".to_string());
+            router.dispatch(&request, &context)
+        };
+
+        assert_eq!(
+            get("/compressed")?.headers().content_encoding(),
+            Some("gzip")
+        );
+        assert_eq!(get("/plain")?.headers().content_encoding(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn exact_path_only_matches_itself() {
+        let router = router();
+
+        assert_eq!(router.allowed_methods("/"), vec![Method::Get, Method::Head]);
+        assert!(router.allowed_methods("/other").is_empty());
+    }
+
+    #[test]
+    fn prefix_path_matches_everything_below() {
+        let router = router();
+
+        assert_eq!(
+            router.allowed_methods("/files/abc"),
+            vec![Method::Get, Method::Head, Method::Post]
+        );
+    }
+
+    #[test]
+    fn wildcard_within_path() {
+        let router = router();
+
+        assert_eq!(
+            router.allowed_methods("/users/1/name"),
+            vec![Method::Get, Method::Head]
+        );
+        assert!(router.allowed_methods("/users//name").is_empty());
+        assert!(router.allowed_methods("/users/1/age").is_empty());
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow() -> Result<()> {
+        let response = router()
+            .dispatch(
+                &request(Method::Delete, "/files/abc"),
+                &RequestContext::default(),
+            )?
+            .encode();
+
+        assert_eq!(
+            response,
+            b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD, POST, OPTIONS\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unregistered_method_is_501() -> Result<()> {
+        let router = router();
+        let context = RequestContext::default();
+
+        assert!(!router.implements(&Method::Patch));
+        assert_eq!(
+            router
+                .dispatch(&request(Method::Patch, "/files/abc"), &context)?
+                .encode(),
+            b"HTTP/1.1 501 Not Implemented\r\n\r\n"
+        );
+        assert_eq!(
+            router
+                .dispatch(
+                    &request(Method::Extension("DANCE".to_string()), "/nope"),
+                    &context
+                )?
+                .encode(),
+            b"HTTP/1.1 501 Not Implemented\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn options_is_204_with_allow() -> Result<()> {
+        let response = router()
+            .dispatch(&request(Method::Options, "/"), &RequestContext::default())?
+            .encode();
+
+        assert_eq!(
+            response,
+            b"HTTP/1.1 204 No Content\r\nAllow: GET, HEAD, OPTIONS\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn options_asterisk_lists_every_method() -> Result<()> {
+        let response = router()
+            .dispatch(&request(Method::Options, "*"), &RequestContext::default())?
+            .encode();
+
+        assert_eq!(
+            response,
+            b"HTTP/1.1 204 No Content\r\nAllow: GET, HEAD, POST, DELETE, OPTIONS\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_path_is_404() -> Result<()> {
+        let response = router()
+            .dispatch(
+                &request(Method::Options, "/nope"),
+                &RequestContext::default(),
+            )?
+            .encode();
+
+        assert_eq!(response, b"HTTP/1.1 404 Not Found\r\n\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn required_authentication_is_checked_before_the_handler() -> Result<()> {
+        let mut credentials = Credentials::default();
+        credentials.add_token("admin", "admin:t0ken")?;
+        let router = router().require(
+            "/admin",
+            Requirement::new("admin", &[crate::auth::Scheme::Bearer]),
+        );
+        let context = RequestContext {
+            credentials: Some(&credentials),
+            ..RequestContext::default()
+        };
+        let mut authorized = request(Method::Delete, "/admin");
+        authorized
+            .headers
+            .insert("authorization".to_string(), "Bearer t0ken".to_string());
+
+        assert_eq!(
+            router
+                .dispatch(&request(Method::Delete, "/admin"), &context)?
+                .encode(),
+            b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer realm=\"admin\"\r\n\r\n"
+        );
+        assert_eq!(
+            router.dispatch(&authorized, &context)?.encode(),
+            b"HTTP/1.1 200 OK\r\n\r\n"
+        );
+        assert_eq!(
+            router
+                .dispatch(&request(Method::Get, "/"), &context)?
+                .encode(),
+            b"HTTP/1.1 200 OK\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cors_applies_to_its_routes() -> Result<()> {
+        let router = router().cors(
+            "/files/*",
+            Cors::new(&["*"])
+                .max_age(60)
+                .caching(crate::cors::Caching::Public),
+        );
+        let context = RequestContext::default();
+        let mut preflight = request(Method::Options, "/files/abc");
+        preflight
+            .headers
+            .insert("origin".to_string(), "https://example.com".to_string());
+        preflight.headers.insert(
+            "access-control-request-method".to_string(),
+            "POST".to_string(),
+        );
+        let mut get = request(Method::Get, "/");
+        get.headers
+            .insert("origin".to_string(), "https://example.com".to_string());
+
+        assert_eq!(
+            router.dispatch(&preflight, &context)?.encode(),
+            b"HTTP/1.1 204 No Content\r\nAllow: GET, HEAD, POST, OPTIONS\r\n\
+            Access-Control-Allow-Origin: *\r\n\
+            Access-Control-Allow-Methods: GET, HEAD, POST, OPTIONS\r\nAccess-Control-Max-Age: 60\r\n\
+            Cache-Control: public, max-age=60\r\n\r\n"
+        );
+        assert_eq!(
+            router.dispatch(&get, &context)?.encode(),
+            b"HTTP/1.1 200 OK\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn configured_cors_covers_the_other_routes() -> Result<()> {
+        let cors = Cors::new(&["https://example.com"]);
+        let context = RequestContext {
+            cors: Some(&cors),
+            ..RequestContext::default()
+        };
+        let mut get = request(Method::Get, "/");
+        get.headers
+            .insert("origin".to_string(), "https://example.com".to_string());
+
+        assert_eq!(
+            router().dispatch(&get, &context)?.encode(),
+            b"HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: https://example.com\r\n\
+            Vary: Origin\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn handlers_share_the_application_state() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Counts its own requests, as well as every handler's in the shared state
+        #[derive(Default)]
+        struct Counter(AtomicUsize);
+
+        impl Handler for Counter {
+            fn call(&self, _: &Request, context: &RequestContext) -> Result<Response> {
+                let own = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+                let shared = context
+                    .state::<AtomicUsize>()
+                    .map_or(0, |hits| hits.fetch_add(1, Ordering::Relaxed) + 1);
+                Ok(Response::ok().body_str(&format!("{own} of {shared}")))
+            }
+        }
+
+        let router = Router::new()
+            .route(Method::Get, "/a", Counter::default())
+            .route(Method::Get, "/b", Counter::default());
+        let hits = AtomicUsize::new(0);
+        let context = RequestContext {
+            state: Some(&hits),
+            ..RequestContext::default()
+        };
+        let get = |target| -> Result<String> {
+            let response = router.dispatch(&request(Method::Get, target), &context)?;
+            Ok(crate::testing::TestResponse::new(response)
+                .text()
+                .to_string())
+        };
+
+        assert_eq!(get("/a")?, "1 of 1");
+        assert_eq!(get("/b")?, "1 of 2");
+        assert_eq!(get("/a")?, "2 of 3");
+        assert!(RequestContext::default().state::<AtomicUsize>().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn bodies_must_be_an_accepted_media_type() -> Result<()> {
+        fn content_type(_: &Request, context: &RequestContext) -> Result<Response> {
+            Ok(Response::ok().body_str(context.content_type.unwrap_or("none")))
+        }
+        let router = Router::new()
+            .route(Method::Post, "/upload", content_type)
+            .accepts(&["application/json", "text/*"]);
+        let context = RequestContext::default();
+        let post = |content_type: Option<&str>, body: &str| {
+            let mut request = crate::testing::TestRequest::post("/upload").body(body);
+            if let Some(content_type) = content_type {
+                request = request.header("Content-Type", content_type);
+            }
+            router.dispatch(&request.build(), &context)
+        };
+
+        assert_eq!(
+            post(Some("Application/JSON; charset=utf-8"), "{}")?.encode(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 16\r\n\r\napplication/json"
+        );
+        assert_eq!(
+            post(Some("text/csv"), "a,b")?.encode(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\ntext/csv"
+        );
+        assert_eq!(
+            post(Some("application/xml"), "<a/>")?.encode(),
+            b"HTTP/1.1 415 Unsupported Media Type\r\n\r\n"
+        );
+        assert_eq!(
+            post(None, "{}")?.encode(),
+            b"HTTP/1.1 415 Unsupported Media Type\r\n\r\n"
+        );
+        assert_eq!(
+            post(Some("application/xml"), "")?.encode(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nnone"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fallback_only_handles_unrouted_gets() -> Result<()> {
+        fn teapot(_: &Request, _: &RequestContext) -> Result<Response> {
+            Ok(Response::new(StatusCode::Custom(
+                418,
+                "I'm a teapot".to_string(),
+            )))
+        }
+        let router = router().fallback(teapot);
+        let context = RequestContext::default();
+
+        assert_eq!(
+            router
+                .dispatch(&request(Method::Get, "/nope"), &context)?
+                .encode(),
+            b"HTTP/1.1 418 I'm a teapot\r\n\r\n"
+        );
+        assert_eq!(
+            router
+                .dispatch(&request(Method::Post, "/nope"), &context)?
+                .encode(),
+            b"HTTP/1.1 404 Not Found\r\n\r\n"
+        );
+        assert_eq!(
+            router
+                .dispatch(&request(Method::Get, "/"), &context)?
+                .encode(),
+            b"HTTP/1.1 200 OK\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn head_is_answered_as_get_without_the_body() -> Result<()> {
+        fn hello(_: &Request, _: &RequestContext) -> Result<Response> {
+            Ok(Response::ok().body_str("hello"))
+        }
+        let router = Router::new()
+            .route(Method::Get, "/hello", hello)
+            .route(Method::Post, "/form", ok)
+            .fallback(hello);
+        let context = RequestContext::default();
+        let head = |target| router.dispatch(&request(Method::Head, target), &context);
+
+        assert_eq!(
+            head("/hello")?.encode(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"
+        );
+        assert_eq!(
+            head("/elsewhere")?.encode(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"
+        );
+        assert_eq!(
+            head("/form")?.encode(),
+            b"HTTP/1.1 405 Method Not Allowed\r\nAllow: POST, OPTIONS\r\n\r\n"
+        );
+        assert!(router.implements(&Method::Head));
+        Ok(())
+    }
+}
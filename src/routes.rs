@@ -0,0 +1,747 @@
+use crate::{
+    admin,
+    auth::{Requirement, Scheme},
+    bulk,
+    compression::{self, Level},
+    cors::{Caching, Cors},
+    file_cache::Cached,
+    files,
+    health::Health,
+    http::{self, ByteRange, Header, Negotiated},
+    multipart, redirects,
+    request::{Method, Request},
+    response::{Response, StatusCode},
+    router::{Handler, RequestContext, Router},
+    sse::{self, Event},
+    websocket,
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{ErrorKind, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+use tracing::warn;
+
+/// How long `/progress` waits between updates
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The most updates `/progress` sends, so a request can't hold a thread for days
+const MAX_PROGRESS_STEPS: u32 = 100;
+
+/// How long `/events` waits between ticks
+const EVENTS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most times `/echo` will repeat what it is sent
+const MAX_ECHO_REPEAT: usize = 1024;
+
+/// The routes required by the CodeCrafters challenge
+pub fn router() -> Router {
+    let router = Router::new()
+        .route(Method::Get, "/", root)
+        .route(Method::Get, "/echo/*", echo)
+        .route(Method::Post, "/echo", echo_body)
+        .compression(Level::Fast)
+        .route(Method::Get, "/user-agent", user_agent)
+        .route(Method::Get, "/metrics", metrics)
+        // Probes only want the status, and are made too often to spend time compressing
+        .route(Method::Get, "/healthz", healthz)
+        .uncompressed()
+        .route(Method::Get, "/readyz", readyz)
+        .uncompressed()
+        .route(Method::Get, "/ws", websocket::echo)
+        .route(Method::Get, "/progress/*", progress)
+        .route(Method::Get, "/events", events)
+        .route(Method::Get, "/session", session)
+        .route(Method::Get, "/session/*", recall)
+        .route(Method::Put, "/session/*", remember)
+        .route(Method::Delete, "/session/*", forget)
+        .route(Method::Get, "/files/*", get_file)
+        .route(Method::Post, "/files", writes(upload_files))
+        .accepts(&["multipart/form-data"])
+        .route(Method::Post, "/files/*", writes(post_file))
+        .route(Method::Put, "/files/*", writes(put_file))
+        .route(Method::Delete, "/files/*", writes(delete_file))
+        .route(Method::Get, "/api/files/*/stat", bulk::stat)
+        .route(Method::Delete, "/api/files", writes(bulk::delete))
+        .route(Method::Post, "/api/files/copy", writes(bulk::copy))
+        .accepts(&["application/json"])
+        .route(Method::Post, "/api/files/move", writes(bulk::rename))
+        .accepts(&["application/json"])
+        .route(Method::Get, "/__admin/status", admin::status)
+        .route(
+            Method::Post,
+            "/__admin/file-cache/flush",
+            admin::flush_file_cache,
+        )
+        .route(Method::Post, "/__admin/log-level", admin::set_log_level)
+        .route(Method::Post, "/__admin/threads", admin::resize_pool)
+        .fallback(static_file)
+        .cors(
+            "/api/*",
+            Cors::new(&["*"]).max_age(86_400).caching(Caching::Public),
+        )
+        .require(
+            "/api/*",
+            Requirement::new("api", &[Scheme::Basic, Scheme::Bearer]),
+        )
+        .require("/files", Requirement::new("files", &[Scheme::Basic]))
+        .require("/files/*", Requirement::new("files", &[Scheme::Basic]))
+        .require(
+            "/__admin/*",
+            Requirement::new(admin::REALM, &[Scheme::Bearer]),
+        );
+    #[cfg(feature = "profiling")]
+    let router = router.route(Method::Get, "/debug/profile", crate::profiling::dump);
+
+    router
+}
+
+/// What a virtual host serves: only its static site
+pub fn site() -> Router {
+    Router::new()
+        .route(Method::Get, "/", static_file)
+        .fallback(static_file)
+}
+
+fn root(request: &Request, context: &RequestContext) -> Result<Response> {
+    if context.static_root.is_some() {
+        return static_file(request, context);
+    }
+
+    Ok(Response::ok())
+}
+
+/// Serves `--static-root`, if there is one
+fn static_file(request: &Request, context: &RequestContext) -> Result<Response> {
+    if context
+        .static_root
+        .is_some_and(|root| files::needs_trailing_slash(root, &request.path))
+    {
+        return Ok(Response::redirect(
+            StatusCode::MovedPermanently,
+            &redirects::trailing_slash(&request.path, request.query.as_deref()),
+        ));
+    }
+    let Some((root, path)) = context.static_root.and_then(|root| {
+        files::static_path(root, &request.path).map(|path| (Path::new(root), path))
+    }) else {
+        return Ok(Response::not_found());
+    };
+    if !files::follows(root, &path, context.follow_symlinks) {
+        return Ok(Response::not_found());
+    }
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(Response::not_found()),
+    };
+    let content_type = files::content_type(&path);
+    let mut etag = files::etag(&metadata);
+    let sidecar = files::gzip_sidecar(&path, &metadata)
+        .filter(|(sidecar, _)| files::follows(root, sidecar, context.follow_symlinks));
+    // Shared caches mustn't hand one client's encoding to another that can't take it
+    let vary = sidecar.is_some()
+        || (context.precompressed.is_some() && compression::is_compressible(content_type));
+    // Only gzipped copies are made or looked for
+    let accepts_gzip = match compression::negotiate(request, &["gzip"]) {
+        Negotiated::Encoding(_) => true,
+        Negotiated::Identity => false,
+        Negotiated::NotAcceptable => return Ok(Response::new(StatusCode::NotAcceptable)),
+    };
+    let gzipped = context
+        .precompressed
+        .filter(|_| accepts_gzip)
+        .zip(metadata.modified().ok())
+        .and_then(|(precompressed, modified)| precompressed.get(&path, modified));
+    // The copy made at startup is as small as gzip gets, so only fall back to one on disk
+    let sidecar = sidecar.filter(|_| accepts_gzip && gzipped.is_none());
+    if let Some((_, sidecar_metadata)) = &sidecar {
+        etag = files::etag(sidecar_metadata);
+    }
+    if gzipped.is_some() || sidecar.is_some() {
+        // Each encoding is a representation of its own, with its own validator
+        etag.insert_str(etag.len() - 1, "-gzip");
+    }
+
+    let mut response = if request
+        .headers
+        .get("if-none-match")
+        .is_some_and(|tags| http::if_none_match(tags, &etag))
+    {
+        Response::new(StatusCode::NotModified).header(Header::ETag(etag))
+    } else {
+        let response = Response::ok()
+            .content_type(content_type)
+            .header(Header::ETag(etag));
+        match (gzipped, sidecar) {
+            (Some(gzipped), _) => response
+                .header(Header::ContentEncoding("gzip".to_string()))
+                .body_bytes(gzipped.to_vec()),
+            (None, Some((sidecar, sidecar_metadata))) => file_body(
+                response.header(Header::ContentEncoding("gzip".to_string())),
+                &sidecar,
+                sidecar_metadata.len(),
+                context,
+            ),
+            (None, None) => file_body(response, &path, metadata.len(), context),
+        }
+    };
+    if vary {
+        response.add_header(Header::Custom(
+            "Vary".to_string(),
+            "Accept-Encoding".to_string(),
+        ));
+    }
+    if let Some(cache_control) = context.cache_control {
+        cache_control.apply(&request.path, &mut response);
+    }
+
+    Ok(response)
+}
+
+fn echo(request: &Request, _: &RequestContext) -> Result<Response> {
+    // Safety: Router has already checked target starts_with
+    let body = request.path.strip_prefix("/echo/").unwrap();
+    let body = match request
+        .query_param("repeat")
+        .map(|repeat| repeat.parse::<usize>())
+    {
+        None => body.to_string(),
+        Some(Ok(repeat)) if repeat <= MAX_ECHO_REPEAT => body.repeat(repeat),
+        Some(_) => return Ok(Response::bad_request()),
+    };
+    let Some(media_type) = http::negotiate_media_type(
+        request.headers.get("accept"),
+        &["text/plain", "application/json"],
+    ) else {
+        return Ok(Response::new(StatusCode::NotAcceptable));
+    };
+    // Caches mustn't hand the JSON to a client that asked for text
+    let response = Response::ok().header(Header::Custom("Vary".to_string(), "Accept".to_string()));
+
+    // Compressed on the way out, when the client accepts it
+    Ok(if media_type == "application/json" {
+        response.body_json(&Echo { echo: &body })?
+    } else {
+        response.content_type("text/plain").body_str(&body)
+    })
+}
+
+/// Streams the request's body back, followed by the trailers it came with
+fn echo_body(request: &Request, _: &RequestContext) -> Result<Response> {
+    let body = request.body.clone().unwrap_or_default();
+    let trailers: BTreeMap<_, _> = request
+        .trailers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let mut response = Response::ok().content_type("application/octet-stream");
+    if !trailers.is_empty() {
+        let names = trailers.keys().cloned().collect::<Vec<_>>().join(", ");
+        response.add_header(Header::Custom("Trailer".to_string(), names));
+    }
+    response.stream(move |writer| {
+        writer.write_all(&body)?;
+        for (name, value) in trailers {
+            writer.trailer(Header::Custom(name, value));
+        }
+
+        Ok(())
+    });
+
+    Ok(response)
+}
+
+#[derive(Serialize)]
+struct Echo<'a> {
+    echo: &'a str,
+}
+
+fn user_agent(request: &Request, _: &RequestContext) -> Result<Response> {
+    Ok(request
+        .headers
+        .get("user-agent")
+        .map_or_else(Response::bad_request, |user_agent| {
+            Response::ok()
+                .content_type("text/plain")
+                .body_str(user_agent)
+        }))
+}
+
+/// Prometheus scrapes this
+fn metrics(_: &Request, context: &RequestContext) -> Result<Response> {
+    Ok(context.metrics.map_or_else(Response::not_found, |metrics| {
+        Response::ok()
+            .content_type("text/plain; version=0.0.4")
+            .body_str(&metrics.render())
+    }))
+}
+
+/// Up for as long as the process is, so only a hung or crashed server gets restarted
+fn healthz(_: &Request, _: &RequestContext) -> Result<Response> {
+    Ok(Response::ok().content_type("text/plain").body_str("ok"))
+}
+
+/// Whether to send the server traffic: only while it is accepting connections, and the thread pool
+/// has room for them
+fn readyz(_: &Request, context: &RequestContext) -> Result<Response> {
+    let ready = context.health.is_some_and(Health::is_accepting)
+        && context
+            .metrics
+            .is_none_or(|metrics| !metrics.is_queue_full());
+
+    Ok(if ready {
+        Response::ok().content_type("text/plain").body_str("ready")
+    } else {
+        Response::new(StatusCode::ServiceUnavailable)
+            .content_type("text/plain")
+            .body_str("not ready")
+    })
+}
+
+/// What the client's session holds, as a JSON object
+fn session(request: &Request, _: &RequestContext) -> Result<Response> {
+    let values = request
+        .session()
+        .map(|session| session.to_map().into_iter().collect::<BTreeMap<_, _>>())
+        .unwrap_or_default();
+
+    Ok(Response::ok()
+        .content_type("application/json")
+        .body_str(&serde_json::to_string(&values)?))
+}
+
+/// What the client's session holds under `/session/<key>`
+fn recall(request: &Request, _: &RequestContext) -> Result<Response> {
+    // Safety: Router has already checked target starts_with
+    let key = request.path.strip_prefix("/session/").unwrap();
+
+    Ok(request
+        .session()
+        .and_then(|session| session.get(key))
+        .map_or_else(Response::not_found, |value| {
+            Response::ok().content_type("text/plain").body_str(&value)
+        }))
+}
+
+/// Puts the body in the client's session under `/session/<key>`, starting one if need be
+fn remember(request: &Request, _: &RequestContext) -> Result<Response> {
+    // Safety: Router has already checked target starts_with
+    let key = request.path.strip_prefix("/session/").unwrap();
+    let Ok(value) = std::str::from_utf8(request.body.as_deref().unwrap_or_default()) else {
+        return Ok(Response::bad_request());
+    };
+    let Some(session) = request.session() else {
+        return Ok(Response::new(StatusCode::ServiceUnavailable));
+    };
+
+    session.insert(key, value);
+    Ok(Response::no_content())
+}
+
+/// Takes `/session/<key>` out of the client's session, which ends it once nothing is left
+fn forget(request: &Request, _: &RequestContext) -> Result<Response> {
+    // Safety: Router has already checked target starts_with
+    let key = request.path.strip_prefix("/session/").unwrap();
+
+    Ok(
+        match request.session().and_then(|session| session.remove(key)) {
+            Some(_) => Response::no_content(),
+            None => Response::not_found(),
+        },
+    )
+}
+
+/// Pushes `/progress/<steps>` status updates using `multipart/x-mixed-replace`
+fn progress(request: &Request, _: &RequestContext) -> Result<Response> {
+    // Safety: Router has already checked target starts_with
+    let Ok(steps) = request
+        .path
+        .strip_prefix("/progress/")
+        .unwrap()
+        .parse::<u32>()
+    else {
+        return Ok(Response::bad_request());
+    };
+    let steps = steps.min(MAX_PROGRESS_STEPS);
+
+    Ok(multipart::mixed_replace(move |writer| {
+        for step in 1..=steps {
+            if step > 1 {
+                thread::sleep(PROGRESS_INTERVAL);
+            }
+            writer.part("text/plain", format!("step {step} of {steps}").as_bytes())?;
+        }
+
+        Ok(())
+    }))
+}
+
+/// Streams a numbered `tick` event every second as Server-Sent Events, stopping after
+/// `?count=N` if given
+fn events(request: &Request, _: &RequestContext) -> Result<Response> {
+    let count = match request
+        .query_param("count")
+        .map(|count| count.parse::<u64>())
+    {
+        None => None,
+        Some(Ok(count)) => Some(count),
+        Some(Err(_)) => return Ok(Response::bad_request()),
+    };
+    // A reconnecting client carries on from the last tick it saw
+    let first = request
+        .headers
+        .get("last-event-id")
+        .and_then(|id| id.parse::<u64>().ok())
+        .map_or(1, |id| id.saturating_add(1));
+
+    Ok(sse::stream(move |events| {
+        let ticks = (first..).take(count.map_or(usize::MAX, |count| {
+            usize::try_from(count).unwrap_or(usize::MAX)
+        }));
+        for tick in ticks {
+            if tick > first {
+                thread::sleep(EVENTS_INTERVAL);
+            }
+            let tick = tick.to_string();
+            // Fails once the client has gone
+            if events
+                .send(Event::new(&tick).name("tick").id(&tick))
+                .is_err()
+            {
+                return;
+            }
+        }
+    }))
+}
+
+/// Sends the file at `path`, `length` bytes long, as the body of `response`, from a memory map
+/// if it's big enough to be worth one
+fn file_body(response: Response, path: &Path, length: u64, context: &RequestContext) -> Response {
+    if context.mmap_min_size.is_some_and(|min| length >= min) {
+        response.body_mapped(path)
+    } else {
+        response.body_file(path)
+    }
+    .unwrap_or_else(|_| Response::not_found())
+}
+
+/// Has browsers save the file rather than show it when asked to with `?download=1`, or for every
+/// file with `--force-download`, and keep it for as long as `--cache-control` says
+fn get_file(request: &Request, context: &RequestContext) -> Result<Response> {
+    let mut response = serve_file(request, context)?;
+    let download =
+        context.force_download || request.query_param("download").as_deref() == Some("1");
+    let name = request.path.rsplit('/').next().unwrap_or_default();
+    if download && !name.is_empty() && matches!(response.status_code().code(), 200 | 206) {
+        response.add_header(Header::Custom(
+            "Content-Disposition".to_string(),
+            http::attachment(name),
+        ));
+    }
+    if let Some(cache_control) = context.cache_control {
+        cache_control.apply(&request.path, &mut response);
+    }
+
+    Ok(response)
+}
+
+fn serve_file(request: &Request, context: &RequestContext) -> Result<Response> {
+    let mut path = match file_path(request, context) {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    // Directories are served like the static site's, by their index document
+    if path.is_dir() {
+        if !request.path.ends_with('/') {
+            return Ok(Response::redirect(
+                StatusCode::MovedPermanently,
+                &redirects::trailing_slash(&request.path, request.query.as_deref()),
+            ));
+        }
+        path.push(files::INDEX);
+        // The directory has been resolved already, but not the index document in it
+        let root = Path::new(context.directory.unwrap_or("."));
+        if !files::follows(root, &path, context.follow_symlinks) {
+            return Ok(Response::not_found());
+        }
+    }
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(Response::not_found()),
+    };
+    let length = metadata.len();
+    let etag = files::etag(&metadata);
+
+    if request
+        .headers
+        .get("if-none-match")
+        .is_some_and(|tags| http::if_none_match(tags, &etag))
+    {
+        return Ok(Response::new(StatusCode::NotModified).header(Header::ETag(etag)));
+    }
+
+    let range = request
+        .headers
+        .get("range")
+        .map_or(ByteRange::Ignored, |range| ByteRange::parse(range, length));
+    match range {
+        ByteRange::Ignored => {
+            let response = Response::ok()
+                .header(Header::Custom(
+                    "Accept-Ranges".to_string(),
+                    "bytes".to_string(),
+                ))
+                .header(Header::Custom(
+                    "Last-Modified".to_string(),
+                    http::date(metadata.modified()?),
+                ));
+            let Some(cache) = context.file_cache.filter(|cache| cache.fits(length)) else {
+                let response = response
+                    .content_type("application/octet-stream")
+                    .header(Header::ETag(etag));
+                return Ok(file_body(response, &path, length, context));
+            };
+
+            let cached = match cache.get(&path, &etag) {
+                Some(cached) => cached,
+                None => {
+                    let Ok(contents) = fs::read(&path) else {
+                        return Ok(Response::not_found());
+                    };
+                    let cached = Cached {
+                        contents: contents.into(),
+                        content_type: "application/octet-stream",
+                        etag,
+                    };
+                    cache.insert(&path, cached.clone());
+                    cached
+                }
+            };
+            Ok(response
+                .content_type(cached.content_type)
+                .header(Header::ETag(cached.etag))
+                .body_bytes(cached.contents.to_vec()))
+        }
+        ByteRange::Satisfiable(range) => {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(*range.start()))?;
+
+            Ok(Response::new(StatusCode::PartialContent)
+                .content_type("application/octet-stream")
+                .header(Header::ETag(etag))
+                .header(Header::Custom(
+                    "Content-Range".to_string(),
+                    format!("bytes {}-{}/{length}", range.start(), range.end()),
+                ))
+                .body_reader(file, range.end() - range.start() + 1))
+        }
+        ByteRange::Unsatisfiable => Ok(Response::new(StatusCode::RangeNotSatisfiable).header(
+            Header::Custom("Content-Range".to_string(), format!("bytes */{length}")),
+        )),
+    }
+}
+
+/// Lets download managers see the size, validators and range support without the body
+fn post_file(request: &Request, context: &RequestContext) -> Result<Response> {
+    let path = match file_path(request, context) {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    if let Some(failed) = precondition_failed(request, &path) {
+        return Ok(failed);
+    }
+    let written = write_body(request, &path);
+    invalidate(context, &path);
+    match written {
+        Ok(()) => Ok(Response::created()),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            Ok(Response::new(StatusCode::PreconditionFailed))
+        }
+        Err(err) => {
+            warn!(path = %request.path, "Unable to write the file: {err}");
+            Ok(Response::new(StatusCode::InternalServerError))
+        }
+    }
+}
+
+/// Saves each file of a `multipart/form-data` body (eg, from a browser's upload form) to the
+/// directory, under the name it had on the client
+fn upload_files(request: &Request, context: &RequestContext) -> Result<Response> {
+    let parts = match request.form_parts() {
+        Ok(parts) => parts,
+        Err(err) => {
+            return Ok(Response::bad_request()
+                .content_type("text/plain")
+                .body_str(&err.to_string()));
+        }
+    };
+
+    let directory = Path::new(context.directory.unwrap_or("."));
+    let mut saved = vec![];
+    for part in parts {
+        // Some browsers send the whole path the file had on the client
+        let Some(filename) = part
+            .filename
+            .as_deref()
+            .and_then(|filename| filename.rsplit(['/', '\\']).next())
+            .filter(|filename| files::valid_segment(filename))
+        else {
+            continue;
+        };
+        let path = match files::confined(directory, filename, context.follow_symlinks) {
+            Ok(path) => path,
+            Err(err) => {
+                warn!(filename, "Refusing upload: {err}");
+                return Ok(Response::new(StatusCode::Forbidden));
+            }
+        };
+        files::write_replacing(&path, false, |file| file.write_all(&part.data))?;
+        invalidate(context, &path);
+        saved.push(filename.to_string());
+    }
+
+    if saved.is_empty() {
+        return Ok(Response::bad_request()
+            .content_type("text/plain")
+            .body_str("No files were uploaded"));
+    }
+    Ok(Response::created().body_json(&saved)?)
+}
+
+fn put_file(request: &Request, context: &RequestContext) -> Result<Response> {
+    if context.create_parents {
+        // Safety: Router has already checked target starts_with
+        let filename = request.path.strip_prefix("/files/").unwrap();
+        if let Err(err) = files::create_parents(
+            Path::new(context.directory.unwrap_or(".")),
+            filename,
+            context.follow_symlinks,
+        ) {
+            warn!(path = %request.path, "Unable to create the parents: {err}");
+            return Ok(Response::new(match err.kind() {
+                ErrorKind::InvalidInput => StatusCode::BadRequest,
+                ErrorKind::PermissionDenied => StatusCode::Forbidden,
+                _ => StatusCode::Conflict,
+            }));
+        }
+    }
+
+    let path = match file_path(request, context) {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    if let Some(failed) = precondition_failed(request, &path) {
+        return Ok(failed);
+    }
+    let existed = path.is_file();
+    let written = write_body(request, &path);
+    invalidate(context, &path);
+    match written {
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            return Ok(Response::new(StatusCode::PreconditionFailed));
+        }
+        written => written?,
+    }
+
+    // Creating a resource is a 201, replacing one has nothing further to say
+    Ok(if existed {
+        Response::no_content()
+    } else {
+        Response::created()
+    })
+}
+
+fn delete_file(request: &Request, context: &RequestContext) -> Result<Response> {
+    let path = match file_path(request, context) {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    invalidate(context, &path);
+    Ok(fs::remove_file(path).map_or_else(|_| Response::not_found(), |()| Response::no_content()))
+}
+
+/// Checks the conditions a client made its write to `path` on (RFC 9110 section 13.2.2), so it
+/// can't replace a version of the file it hasn't seen, answering 412 if any of them fail
+fn precondition_failed(request: &Request, path: &Path) -> Option<Response> {
+    let metadata = fs::metadata(path).ok().filter(fs::Metadata::is_file);
+    let etag = metadata.as_ref().map(files::etag);
+    let failed = Some(Response::new(StatusCode::PreconditionFailed));
+
+    if let Some(tags) = request.headers.get("if-match") {
+        if !etag.as_ref().is_some_and(|etag| http::if_match(tags, etag)) {
+            return failed;
+        }
+    } else if let Some(since) = request
+        .headers
+        .get("if-unmodified-since")
+        .and_then(http::parse_date)
+        && let Some(modified) = metadata.as_ref().and_then(|metadata| metadata.modified().ok())
+        // HTTP-dates are to the second, so what changed within it still counts as unmodified
+        && modified
+            .duration_since(since)
+            .is_ok_and(|after| after.as_secs() > 0)
+    {
+        return failed;
+    }
+    if let Some(tags) = request.headers.get("if-none-match")
+        && etag.is_some_and(|etag| http::if_none_match(tags, &etag))
+    {
+        return failed;
+    }
+
+    None
+}
+
+/// Writes the request's body to `path`, only ever creating the file for `If-None-Match: *`, so
+/// that of two clients racing to create it, one gets `AlreadyExists`
+fn write_body(request: &Request, path: &Path) -> std::io::Result<()> {
+    let create_only = request
+        .headers
+        .get("if-none-match")
+        .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == "*"));
+
+    files::write_replacing(path, create_only, |file| {
+        file.write_all(request.body.as_deref().unwrap_or_default())
+    })
+}
+
+/// Refuses with 403 under `--read-only`, so `handler` never gets to change anything
+fn writes(handler: impl Handler) -> impl Handler {
+    move |request: &Request, context: &RequestContext| {
+        if context.read_only {
+            return Ok(Response::new(StatusCode::Forbidden));
+        }
+
+        handler.call(request, context)
+    }
+}
+
+/// Drops any cached copy of a file that is being changed, rather than waiting for the next
+/// request to notice it is stale
+fn invalidate(context: &RequestContext, path: &Path) {
+    if let Some(cache) = context.file_cache {
+        cache.invalidate(path);
+    }
+}
+
+/// Maps a `/files/<name>` request target onto the configured directory, or the response to send
+/// if it can't be
+fn file_path(request: &Request, context: &RequestContext) -> Result<PathBuf, Response> {
+    // Safety: Router has already checked target starts_with
+    let filename = request.path.strip_prefix("/files/").unwrap();
+
+    let root = Path::new(context.directory.unwrap_or("."));
+    files::confined(root, filename, context.follow_symlinks).map_err(|err| {
+        if err.kind() == ErrorKind::PermissionDenied {
+            warn!(path = %request.path, "Refusing: {err}");
+            Response::new(StatusCode::Forbidden)
+        } else {
+            Response::not_found()
+        }
+    })
+}
@@ -0,0 +1,100 @@
+//! Sends files to plain TCP connections with `sendfile(2)` on Linux, so the kernel copies them
+//! straight from the page cache rather than through a buffer of ours
+//!
+//! Elsewhere, and for streams that have to see the bytes (eg, to encrypt them), files are copied
+//! like any other body.
+
+use std::{fs::File, io, net::TcpStream};
+
+/// Streams that may have a file sent to them without it passing through userspace
+pub trait SendFile {
+    /// The socket underneath, if what is written to the stream goes to it untouched
+    fn socket(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl SendFile for TcpStream {
+    fn socket(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+/// Sends up to `length` bytes of `file` to `socket` from the file's current position, returning
+/// how many were sent, or 0 if the file ended
+#[cfg(target_os = "linux")]
+pub fn send(file: &File, socket: &TcpStream, length: u64) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    let count = usize::try_from(length).unwrap_or(usize::MAX);
+    loop {
+        // Safety: Both descriptors are open for as long as they are borrowed, and passing no
+        // offset has the kernel read from (and advance) the file's own position
+        let sent = unsafe {
+            libc::sendfile(
+                socket.as_raw_fd(),
+                file.as_raw_fd(),
+                std::ptr::null_mut(),
+                count,
+            )
+        };
+        match u64::try_from(sent) {
+            Ok(sent) => return Ok(sent),
+            Err(_) => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Without `sendfile(2)` the file is copied through userspace after all
+#[cfg(not(target_os = "linux"))]
+pub fn send(file: &File, socket: &TcpStream, length: u64) -> io::Result<u64> {
+    use std::io::Read;
+
+    io::copy(&mut file.take(length), &mut &*socket)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::test_directory;
+    use std::{
+        fs,
+        io::{Read, Seek, SeekFrom},
+        net::TcpListener,
+        path::Path,
+        thread,
+    };
+
+    #[test]
+    fn sends_a_file_from_where_it_is() {
+        let path = Path::new(&test_directory("sends_a_file_from_where_it_is")).join("file");
+        let contents: Vec<u8> = (0..=255).cycle().take(300_000).collect();
+        fs::write(&path, &contents).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+
+        let receiver = thread::spawn(move || {
+            let mut received = vec![];
+            (&client).read_to_end(&mut received).unwrap();
+            received
+        });
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(100)).unwrap();
+        let mut remaining = 200_000;
+        while remaining > 0 {
+            let sent = send(&file, &socket, remaining).unwrap();
+            assert_ne!(sent, 0);
+            remaining -= sent;
+        }
+        drop(socket);
+
+        assert_eq!(receiver.join().unwrap(), &contents[100..200_100]);
+        let _ = fs::remove_file(path);
+    }
+}
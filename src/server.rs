@@ -0,0 +1,415 @@
+use crate::{
+    config::Config,
+    connection::{Connection, Shutdownable},
+    http::Header,
+    listener::{self, Listener, Stream},
+    response::{Response, StatusCode},
+    threadpool::{QueueFull, ThreadPool},
+};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{io, net::Shutdown, panic, sync::Arc, thread, time::Duration};
+use tracing::{debug, error, warn};
+
+// Only wait a maximum of 5 seconds for data for the client
+// This mitegates clients that connect and do nothing, while `Deadlines` deals with clients that
+// drip feed. Connections kept open wait for `KeepAlive::timeout` between requests instead.
+pub const RECEIVE_TIMEOUT: u64 = 5;
+
+// Clients turned away because the queue is full are asked to try again after this many seconds
+pub const RETRY_AFTER: u64 = 1;
+
+// Clients that stop reading a long response (eg, zero window) are treated as disconnected after
+// this many seconds, so the worker stops producing bytes nobody will read
+pub const SEND_TIMEOUT: u64 = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// Each connection has a worker to itself, for as long as it is open
+    #[default]
+    Threaded,
+    /// An event loop waits for requests, only handing a connection to a worker while one is
+    /// being answered (Unix only, and not with TLS or `--proxy-protocol`)
+    Evented,
+    /// Each connection is a tokio task reading requests and writing responses, with handlers run
+    /// on its blocking pool
+    #[cfg(feature = "async")]
+    Async,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueFullPolicy {
+    /// Stop accepting until there is space (the listen backlog absorbs the burst)
+    Block,
+    /// Respond with 503 Service Unavailable (and Retry-After) straight away
+    Shed,
+}
+
+/// Hands every connection the `listeners` accept to the pool, each on its own thread, until they
+/// all say there are no more, or the server is stopping
+///
+/// A listener failing doesn't stop the others, but its error is returned once they are done.
+/// The server reports itself ready for as long as all of them are accepting.
+pub fn serve<L: Listener + Send>(
+    listeners: Vec<L>,
+    config: &Arc<Config>,
+    pool: &ThreadPool,
+    on_queue_full: QueueFullPolicy,
+) -> io::Result<()> {
+    config.health.set_accepting(true);
+    let results = thread::scope(|scope| {
+        let loops = listeners
+            .into_iter()
+            .map(|listener| {
+                scope.spawn(move || {
+                    let result = accept_all(&listener, config, pool, on_queue_full);
+                    // Some clients can no longer connect
+                    config.health.set_accepting(false);
+                    if let Err(err) = &result {
+                        error!("Stopped accepting: {err}");
+                    }
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+
+        loops
+            .into_iter()
+            .map(|accepting| {
+                accepting
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    results.into_iter().collect()
+}
+
+fn accept_all<L: Listener>(
+    listener: &L,
+    config: &Arc<Config>,
+    pool: &ThreadPool,
+    on_queue_full: QueueFullPolicy,
+) -> io::Result<()> {
+    loop {
+        let stream = match listener.accept() {
+            Ok(Some(stream)) => stream,
+            Ok(None) => break,
+            // Connections already accepted carry on, and closing frees up what ran short
+            Err(err) if listener::is_transient(&err) => {
+                warn!("Error accepting a connection: {err}");
+                thread::sleep(listener::ACCEPT_BACKOFF);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        // Whoever woke the listener to notice is turned away
+        if config.health.is_stopping() {
+            let _ = stream.shutdown(Shutdown::Both);
+            break;
+        }
+        let (peer, read_policy) = (stream.peer(), stream.read_policy());
+        // Only this connection is given up on, the listener carries on
+        if let Err(err) = stream.set_timeouts(
+            Duration::from_secs(RECEIVE_TIMEOUT),
+            Duration::from_secs(SEND_TIMEOUT),
+        ) {
+            warn!(peer, "Dropping connection without timeouts: {err}");
+            continue;
+        }
+        // Whether a proxy's requests are let in depends on who each was forwarded for, and a
+        // load balancer's connections on who its PROXY header says it is for
+        if !config.proxy_protocol
+            && !config.trusted_proxies.trusts(&peer)
+            && !config.ip_filter.load().permits(&peer)
+        {
+            debug!(peer, "Refusing connection");
+            refuse(stream, StatusCode::Forbidden);
+            continue;
+        }
+        // Under `Block` the connections after this one are left in the listen backlog
+        let permit = match config.connections.when_full() {
+            QueueFullPolicy::Block => config.connections.acquire(),
+            QueueFullPolicy::Shed => {
+                let Some(permit) = config.connections.try_acquire() else {
+                    warn!(peer, "Too many connections, shedding connection");
+                    shed(stream);
+                    continue;
+                };
+                permit
+            }
+        };
+        let overflow = match stream.try_clone() {
+            Ok(overflow) => overflow,
+            Err(err) => {
+                warn!(peer, "Dropping connection that won't clone: {err}");
+                continue;
+            }
+        };
+        let mut connection = Connection::new(stream, Arc::clone(config))
+            .peer(peer)
+            .read_policy(read_policy)
+            .permit(permit);
+        let metrics = Arc::clone(&config.metrics);
+        let job = move || {
+            if let Err(err) = connection.process() {
+                error!("Connection error: {err}");
+                metrics.connection_aborted();
+            }
+        };
+
+        match on_queue_full {
+            QueueFullPolicy::Block => pool.execute(job),
+            QueueFullPolicy::Shed => {
+                if let Err(QueueFull(job)) = pool.try_execute(job) {
+                    let stats = pool.stats();
+                    warn!(
+                        waiting = stats.queued,
+                        oldest = ?stats.oldest,
+                        "Queue full, shedding connection"
+                    );
+                    shed(overflow);
+                    // Dropping the job shuts the connection down, so only once 503 is sent
+                    drop(job);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers with `status` before any of the request is read, for connections no worker will see
+pub fn refuse(stream: impl Stream, status: StatusCode) {
+    send(stream, Response::new(status));
+}
+
+/// Answers with 503 before any of the request is read, for connections turned away because the
+/// queue is full, saying when to try again
+pub fn shed(stream: impl Stream) {
+    send(
+        stream,
+        Response::new(StatusCode::ServiceUnavailable).header(Header::Custom(
+            "Retry-After".to_string(),
+            RETRY_AFTER.to_string(),
+        )),
+    );
+}
+
+fn send(mut stream: impl Stream, response: Response) {
+    if let Err(err) = stream.write_all(&response.encode()) {
+        warn!("Error refusing connection: {err}");
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{duplex::DuplexStream, limit::ConnectionLimit, listener::memory};
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    fn get(connector: &memory::Connector, path: &str) -> String {
+        let mut client = connector.connect().unwrap();
+        write!(client, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        String::from_utf8(client.read_all().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serves_connections_until_the_listener_is_done() {
+        let (listener, connector) = memory::listener();
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::builder(2).build().unwrap();
+            serve(
+                vec![listener],
+                &Arc::default(),
+                &pool,
+                QueueFullPolicy::Block,
+            )
+        });
+
+        assert_eq!(get(&connector, "/"), "HTTP/1.1 200 OK\r\n\r\n");
+        assert!(get(&connector, "/echo/hi").ends_with("\r\n\r\nhi"));
+
+        drop(connector);
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    /// Fails its first accept as if the process had run out of file descriptors
+    #[cfg(unix)]
+    struct Exhausted {
+        listener: memory::MemoryListener,
+        failed: AtomicBool,
+    }
+
+    #[cfg(unix)]
+    impl Listener for Exhausted {
+        type Stream = DuplexStream;
+
+        fn accept(&self) -> io::Result<Option<DuplexStream>> {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                return Err(io::Error::from_raw_os_error(libc::EMFILE));
+            }
+            self.listener.accept()
+        }
+
+        fn local_addr(&self) -> io::Result<String> {
+            self.listener.local_addr()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn running_out_of_file_descriptors_only_pauses_accepting() {
+        let (listener, connector) = memory::listener();
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::builder(2).build().unwrap();
+            let listener = Exhausted {
+                listener,
+                failed: AtomicBool::new(false),
+            };
+            serve(
+                vec![listener],
+                &Arc::default(),
+                &pool,
+                QueueFullPolicy::Block,
+            )
+        });
+
+        assert!(get(&connector, "/echo/hi").ends_with("\r\n\r\nhi"));
+
+        drop(connector);
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn serves_every_listener_with_one_pool() {
+        let (first, first_connector) = memory::listener();
+        let (second, second_connector) = memory::listener();
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::builder(2).build().unwrap();
+            serve(
+                vec![first, second],
+                &Arc::default(),
+                &pool,
+                QueueFullPolicy::Block,
+            )
+        });
+
+        assert!(get(&first_connector, "/echo/one").ends_with("\r\n\r\none"));
+        assert!(get(&second_connector, "/echo/two").ends_with("\r\n\r\ntwo"));
+        // One listener finishing leaves the other serving
+        drop(first_connector);
+        assert!(get(&second_connector, "/echo/two").ends_with("\r\n\r\ntwo"));
+
+        drop(second_connector);
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn sheds_connections_once_the_queue_is_full() {
+        let (listener, connector) = memory::listener();
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::builder(1)
+                .queue_capacity(Some(1))
+                .build()
+                .unwrap();
+            serve(
+                vec![listener],
+                &Arc::default(),
+                &pool,
+                QueueFullPolicy::Shed,
+            )
+        });
+
+        // One connection keeps the worker waiting for its request, and another waits for it
+        let busy = connector.connect().unwrap();
+        thread::sleep(Duration::from_millis(16));
+        let queued = connector.connect().unwrap();
+        thread::sleep(Duration::from_millis(16));
+
+        let mut shed = connector.connect().unwrap();
+        assert_eq!(
+            String::from_utf8(shed.read_all().unwrap()).unwrap(),
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\n\r\n"
+        );
+
+        for client in [busy, queued] {
+            client.shutdown(Shutdown::Write).unwrap();
+        }
+        drop(connector);
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn sheds_connections_beyond_max_connections() {
+        let config = Arc::new(Config {
+            connections: Arc::new(ConnectionLimit::new(Some(1), QueueFullPolicy::Shed)),
+            ..Config::default()
+        });
+        let (listener, connector) = memory::listener();
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::builder(2).build().unwrap();
+            serve(vec![listener], &config, &pool, QueueFullPolicy::Block)
+        });
+
+        let open = connector.connect().unwrap();
+        assert!(get(&connector, "/").starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        // Room again once the open one closes
+        open.shutdown(Shutdown::Write).unwrap();
+        thread::sleep(Duration::from_millis(16));
+        assert_eq!(get(&connector, "/"), "HTTP/1.1 200 OK\r\n\r\n");
+
+        drop(connector);
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn stops_accepting_once_stopping() {
+        let config = Arc::<Config>::default();
+        let (listener, connector) = memory::listener();
+        let server = thread::spawn({
+            let config = Arc::clone(&config);
+            move || {
+                let pool = ThreadPool::builder(2).build().unwrap();
+                serve(vec![listener], &config, &pool, QueueFullPolicy::Block)
+            }
+        });
+        assert!(get(&connector, "/echo/hi").ends_with("\r\n\r\nhi"));
+
+        config.health.stop();
+        // Wakes the listener to notice, without being answered
+        let mut client = connector.connect().unwrap();
+        assert_eq!(client.read_all().unwrap(), b"");
+        server.join().unwrap().unwrap();
+        assert!(connector.connect().is_err());
+    }
+
+    #[test]
+    fn ready_only_while_accepting() {
+        let config = Arc::<Config>::default();
+        let (listener, connector) = memory::listener();
+        let server = thread::spawn({
+            let config = Arc::clone(&config);
+            move || {
+                let pool = ThreadPool::builder(2).build().unwrap();
+                serve(vec![listener], &config, &pool, QueueFullPolicy::Block)
+            }
+        });
+
+        assert!(get(&connector, "/readyz").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        drop(connector);
+        server.join().unwrap().unwrap();
+        assert!(!config.health.is_accepting());
+    }
+}
@@ -0,0 +1,297 @@
+//! Sessions kept in memory, tied to clients with a signed cookie, so handlers can remember
+//! things between requests (eg, who logged in)
+//!
+//! A session is only stored, and its cookie only sent, once a handler puts something in it.
+//! Emptying it (eg, to log out) forgets it and has the client drop the cookie. Sessions expire
+//! when unused for the TTL, and are swept out of memory every so often.
+
+use crate::{auth::constant_time_eq, cookie::SetCookie, response::Response};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+pub const COOKIE: &str = "session";
+
+/// How often expired sessions are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+type Values = Arc<Mutex<HashMap<String, String>>>;
+
+/// One client's values, shared with the store so a handler's changes are kept
+#[derive(Clone, Debug)]
+pub struct Session {
+    /// Empty until a new session is saved, so requests that never use one cost nothing
+    id: String,
+    values: Values,
+    is_new: bool,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            id: String::new(),
+            values: Values::default(),
+            is_new: true,
+        }
+    }
+
+    fn values(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        self.values
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.values().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, value: &str) {
+        self.values().insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        self.values().remove(key)
+    }
+
+    /// Everything in the session, eg to show it
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.values().clone()
+    }
+}
+
+struct Stored {
+    values: Values,
+    expires: Instant,
+}
+
+struct Store {
+    sessions: HashMap<String, Stored>,
+    last_sweep: Instant,
+}
+
+pub struct Sessions {
+    store: Mutex<Store>,
+    /// Signs session IDs, so made up ones are turned away without a lookup
+    key: [u8; 32],
+    ttl: Duration,
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sessions")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(3600)).expect("the OS to have a random number generator")
+    }
+}
+
+impl Sessions {
+    /// Sessions live only as long as the process, so a new key each run loses nothing. Fails
+    /// when the OS has no random number generator, as the key would be guessable without one.
+    pub fn new(ttl: Duration) -> io::Result<Self> {
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(&random_bytes()?);
+        key[16..].copy_from_slice(&random_bytes()?);
+
+        Ok(Self {
+            store: Mutex::new(Store {
+                sessions: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+            key,
+            ttl,
+        })
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        self.store
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The session the cookie names, if it is genuine and hasn't expired, or else a new one
+    pub fn load(&self, cookies: &HashMap<String, String>) -> Session {
+        let now = Instant::now();
+        let mut store = self.store();
+        if now.duration_since(store.last_sweep) >= SWEEP_INTERVAL {
+            store.sessions.retain(|_, stored| stored.expires > now);
+            store.last_sweep = now;
+        }
+
+        let id = cookies.get(COOKIE).and_then(|cookie| {
+            let (id, signature) = cookie.split_once('.')?;
+            constant_time_eq(signature.as_bytes(), self.sign(id).as_bytes()).then_some(id)
+        });
+        match id.and_then(|id| store.sessions.get_mut(id).map(|stored| (id, stored))) {
+            Some((id, stored)) if stored.expires > now => {
+                stored.expires = now + self.ttl;
+                Session {
+                    id: id.to_string(),
+                    values: Arc::clone(&stored.values),
+                    is_new: false,
+                }
+            }
+            _ => Session::new(),
+        }
+    }
+
+    /// Keeps a session that was started and given values, or forgets one that was emptied,
+    /// telling the client through the cookie
+    pub fn save(&self, session: &Session, response: &mut Response) {
+        let is_empty = session.values().is_empty();
+        let cookie = match (session.is_new, is_empty) {
+            (true, false) => {
+                let id = match random_bytes() {
+                    Ok(bytes) => hex(&bytes),
+                    Err(err) => {
+                        error!("Not keeping a session without a random ID: {err}");
+                        return;
+                    }
+                };
+                let value = format!("{id}.{}", self.sign(&id));
+                self.store().sessions.insert(
+                    id,
+                    Stored {
+                        values: Arc::clone(&session.values),
+                        expires: Instant::now() + self.ttl,
+                    },
+                );
+                SetCookie::new(COOKIE, &value)
+                    .path("/")
+                    .http_only()
+                    .same_site(crate::cookie::SameSite::Lax)
+                    .max_age(i64::try_from(self.ttl.as_secs()).unwrap_or(i64::MAX))
+            }
+            (false, true) => {
+                self.store().sessions.remove(&session.id);
+                SetCookie::delete(COOKIE).path("/")
+            }
+            _ => return,
+        };
+
+        response.add_header(cookie.to_header());
+    }
+
+    /// HMAC-SHA1 (RFC 2104) of the session ID, in hex
+    fn sign(&self, id: &str) -> String {
+        const BLOCK: usize = 64;
+        let mut key = [0; BLOCK];
+        key[..self.key.len()].copy_from_slice(&self.key);
+
+        let mut inner = sha1_smol::Sha1::new();
+        inner.update(&key.map(|byte| byte ^ 0x36));
+        inner.update(id.as_bytes());
+        let mut outer = sha1_smol::Sha1::new();
+        outer.update(&key.map(|byte| byte ^ 0x5c));
+        outer.update(&inner.digest().bytes());
+
+        outer.digest().to_string()
+    }
+}
+
+/// From the OS's random number generator, as anything else could be guessed
+fn random_bytes() -> io::Result<[u8; 16]> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TestResponse;
+
+    /// The cookies a client would send back after `response`
+    fn cookies(response: Response) -> HashMap<String, String> {
+        TestResponse::new(response)
+            .header("Set-Cookie")
+            .map(|set_cookie| crate::cookie::parse(set_cookie.split(';').next().unwrap()))
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn sessions_are_kept_once_used() {
+        let sessions = Sessions::default();
+
+        let session = sessions.load(&HashMap::new());
+        let mut response = Response::ok();
+        sessions.save(&session, &mut response);
+        assert!(cookies(response).is_empty());
+
+        session.insert("user", "alice");
+        let mut response = Response::ok();
+        sessions.save(&session, &mut response);
+        let cookies = cookies(response);
+
+        let session = sessions.load(&cookies);
+        assert!(!session.is_new);
+        assert_eq!(session.get("user").as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn forged_and_expired_sessions_are_new() {
+        let sessions = Sessions::new(Duration::ZERO).unwrap();
+        let session = sessions.load(&HashMap::new());
+        session.insert("user", "alice");
+        let mut response = Response::ok();
+        sessions.save(&session, &mut response);
+        let mut cookies = cookies(response);
+
+        assert!(sessions.load(&cookies).is_new);
+        let (id, _) = cookies[COOKIE].split_once('.').unwrap();
+        let forged = format!("{id}.{}", "0".repeat(40));
+        cookies.insert(COOKIE.to_string(), forged);
+        assert!(Sessions::default().load(&cookies).is_new);
+    }
+
+    #[test]
+    fn emptied_sessions_are_forgotten() {
+        let sessions = Sessions::default();
+        let session = sessions.load(&HashMap::new());
+        session.insert("user", "alice");
+        let mut response = Response::ok();
+        sessions.save(&session, &mut response);
+        let cookies = cookies(response);
+
+        let session = sessions.load(&cookies);
+        session.remove("user");
+        let mut response = Response::ok();
+        sessions.save(&session, &mut response);
+
+        assert_eq!(
+            response.encode(),
+            b"HTTP/1.1 200 OK\r\nSet-Cookie: session=; Path=/; Max-Age=0\r\n\r\n"
+        );
+        assert!(sessions.load(&cookies).is_new);
+    }
+
+    #[test]
+    fn signatures_are_hmac_sha1() {
+        let mut key = [0; 32];
+        key[..20].fill(0x0b);
+        let sessions = Sessions {
+            key,
+            ..Sessions::default()
+        };
+
+        // RFC 2202 test case 1, with the key padded to the same block
+        assert_eq!(
+            sessions.sign("Hi There"),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! Stopping on SIGINT or SIGTERM without cutting off the requests being answered
+//!
+//! The listeners stop accepting, connections aren't kept open past their current response, and
+//! the pool finishes what it has been given before the server exits. A second signal exits
+//! straight away.
+
+use crate::config::Config;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{
+    io, net::TcpStream, os::unix::net::UnixStream, process, sync::Arc, thread, time::Duration,
+};
+use tracing::{debug, info, warn};
+
+/// How often the listeners are woken to notice they should stop, as there is no telling which of
+/// those sharing an address (`--acceptors`) a connection goes to
+const WAKE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Watches for the signals to stop on, for the server listening on `addresses` (as
+/// `Listener::local_addr` gives them)
+pub fn on_signal(config: Arc<Config>, addresses: Vec<String>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::Builder::new()
+        .name("shutdown".to_string())
+        .spawn(move || {
+            let mut signals = signals.forever();
+            if signals.next().is_none() {
+                return;
+            }
+            info!("Shutting down, waiting for requests being answered");
+            config.health.stop();
+            thread::spawn(move || {
+                loop {
+                    addresses.iter().for_each(|address| wake(address));
+                    thread::sleep(WAKE_INTERVAL);
+                }
+            });
+
+            if signals.next().is_some() {
+                warn!("Exiting without waiting for requests");
+                process::exit(1);
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Connects to the listener, so an accept loop blocked waiting for a client sees it is stopping
+fn wake(address: &str) {
+    let woken = match address.strip_prefix("unix:") {
+        Some(path) => UnixStream::connect(path).map(drop),
+        None => TcpStream::connect(address.trim_start_matches("https://")).map(drop),
+    };
+    if let Err(err) = woken {
+        debug!(address, "Unable to wake listener: {err}");
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{
+    http::Header,
+    response::{BodyWriter, Response, StatusCode},
+};
+use std::{
+    io::{self, Write},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+/// How long a stream may go quiet before a comment is sent, so proxies don't time it out and
+/// a client that has gone away is noticed
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A single Server-Sent Event
+#[derive(Debug, Default)]
+pub struct Event {
+    name: Option<String>,
+    id: Option<String>,
+    data: String,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    /// The `event` type, which clients listen for by name (instead of `message`)
+    #[must_use]
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(single_line(name));
+        self
+    }
+
+    /// Sent back by a reconnecting client as `Last-Event-ID`
+    #[must_use]
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        if let Some(name) = &self.name {
+            buf.extend(format!("event: {name}\n").as_bytes());
+        }
+        if let Some(id) = &self.id {
+            buf.extend(format!("id: {id}\n").as_bytes());
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\n', '\r']) {
+            buf.extend(format!("data: {line}\n").as_bytes());
+        }
+        buf.push(b'\n');
+    }
+}
+
+/// Fields can't span lines, as a line break ends them
+fn single_line(value: &str) -> String {
+    value.replace(['\n', '\r'], " ")
+}
+
+/// Writes events, and heartbeats, to the client as they happen
+pub struct EventWriter<'a, 'b> {
+    writer: &'a mut BodyWriter<'b>,
+}
+
+impl EventWriter<'_, '_> {
+    pub fn event(&mut self, event: &Event) -> io::Result<()> {
+        let mut buf = vec![];
+        event.encode(&mut buf);
+        self.writer.write_all(&buf)?;
+        self.writer.flush()
+    }
+
+    /// A comment, which clients ignore
+    pub fn comment(&mut self, text: &str) -> io::Result<()> {
+        write!(self.writer, ": {}\n\n", single_line(text))?;
+        self.writer.flush()
+    }
+}
+
+/// A `text/event-stream` response sending whatever `source` produces, until it returns
+///
+/// `source` runs on its own thread so heartbeats can go out while it waits for something to
+/// happen. Once the client disconnects sending fails, which is its cue to stop.
+pub fn stream<F>(source: F) -> Response
+where
+    F: FnOnce(&Sender<Event>) + Send + 'static,
+{
+    let mut response = Response::new(StatusCode::Ok)
+        .header(Header::ContentType("text/event-stream".to_string()))
+        .header(Header::Custom(
+            "Cache-Control".to_string(),
+            "no-cache".to_string(),
+        ));
+    response.stream(move |writer| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || source(&sender));
+
+        let mut events = EventWriter { writer };
+        loop {
+            match receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+                Ok(event) => events.event(&event)?,
+                Err(RecvTimeoutError::Timeout) => events.comment("heartbeat")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    });
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn events_are_encoded_field_by_field() {
+        let mut buf = vec![];
+        Event::new("one\ntwo").name("tick").id("7").encode(&mut buf);
+
+        assert_eq!(buf, b"event: tick\nid: 7\ndata: one\ndata: two\n\n");
+    }
+
+    #[test]
+    fn fields_cannot_inject_lines() {
+        let mut buf = vec![];
+        Event::new("").id("1\ndata: x").encode(&mut buf);
+
+        assert_eq!(buf, b"id: 1 data: x\ndata: \n\n");
+    }
+
+    #[test]
+    fn stream_sends_events_until_the_source_is_done() {
+        let response = stream(|events| {
+            let _ = events.send(Event::new("hello"));
+        });
+        let response = String::from_utf8(response.encode()).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/event-stream\r\n"));
+        assert!(response.contains("data: hello\n\n"));
+        assert!(response.ends_with("0\r\n\r\n"));
+    }
+}
@@ -0,0 +1,91 @@
+//! systemd socket activation, so systemd can own the port and start the server when the first
+//! client connects (see `sd_listen_fds(3)`)
+//!
+//! The sockets passed are used instead of binding `--address` or `--unix`. They have to be all
+//! TCP or all Unix sockets, as the listeners are served alike.
+
+use anyhow::{Context, Result, bail};
+use std::{
+    env,
+    net::TcpListener,
+    ops::Range,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixListener,
+    },
+};
+
+/// The first descriptor systemd passes, with the rest following on
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The listening sockets systemd passed
+pub enum Inherited {
+    Tcp(Vec<TcpListener>),
+    Unix(Vec<UnixListener>),
+}
+
+/// Takes ownership of the sockets systemd passed, or `None` if it didn't pass any
+///
+/// Must only be called once, as the descriptors are closed when the listeners are dropped.
+pub fn listeners() -> Result<Option<Inherited>> {
+    let Some(fds) = passed_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?
+    else {
+        return Ok(None);
+    };
+
+    let (mut tcp, mut unix) = (vec![], vec![]);
+    for fd in fds {
+        // SAFETY: systemd opened these for this process, and nothing else in it knows about them
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        // Only an internet socket has an address as `TcpListener` understands it
+        if listener.local_addr().is_ok() {
+            tcp.push(listener);
+        } else {
+            unix.push(UnixListener::from(OwnedFd::from(listener)));
+        }
+    }
+
+    match (tcp.is_empty(), unix.is_empty()) {
+        (false, true) => Ok(Some(Inherited::Tcp(tcp))),
+        (true, false) => Ok(Some(Inherited::Unix(unix))),
+        _ => bail!("LISTEN_FDS can't mix TCP and Unix sockets"),
+    }
+}
+
+/// The descriptors passed to process `pid`, going by `LISTEN_PID` and `LISTEN_FDS`
+///
+/// Sockets meant for another process (eg, the shell that started this one) are ignored.
+fn passed_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Result<Option<Range<RawFd>>> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(None);
+    };
+    if listen_pid.parse::<u32>().context("LISTEN_PID")? != pid {
+        return Ok(None);
+    }
+    let count = listen_fds.parse::<RawFd>().context("LISTEN_FDS")?;
+
+    Ok((count > 0).then(|| LISTEN_FDS_START..LISTEN_FDS_START + count))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_fds_for_this_process_are_taken() {
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42).unwrap(), Some(3..5));
+        assert_eq!(passed_fds(Some("41"), Some("2"), 42).unwrap(), None);
+        assert_eq!(passed_fds(Some("42"), Some("0"), 42).unwrap(), None);
+        assert_eq!(passed_fds(None, Some("2"), 42).unwrap(), None);
+        assert_eq!(passed_fds(Some("42"), None, 42).unwrap(), None);
+        assert!(passed_fds(Some("42"), Some("two"), 42).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+//! Builds requests and checks responses, so handlers can be tested without sockets or raw bytes
+//!
+//! ```ignore
+//! TestRequest::get("/echo/hi")
+//!     .header("Accept-Encoding", "gzip")
+//!     .send(&RequestContext::default())
+//!     .assert_status(StatusCode::Ok)
+//!     .assert_header("Content-Encoding", "gzip");
+//! ```
+//!
+//! `TestServer` runs the whole server on an ephemeral port instead, for what only shows over a
+//! real connection (eg, keep-alive):
+//!
+//! ```ignore
+//! let server = TestServer::start(Config::default());
+//! server.client().get("/echo/hi").assert_body("hi");
+//! ```
+
+use crate::{
+    config::Config,
+    fields::Fields,
+    http::{Header, Headers},
+    request::{Method, Request},
+    response::{Response, StatusCode},
+    router::{Handler, RequestContext},
+    routes,
+    server::{self, QueueFullPolicy},
+    threadpool::ThreadPool,
+};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+pub struct TestRequest {
+    method: Method,
+    target: String,
+    headers: Fields,
+    body: Option<Vec<u8>>,
+}
+
+impl TestRequest {
+    /// `target` may include a query string, and is percent-decoded like any other
+    pub fn new(method: Method, target: &str) -> Self {
+        Self {
+            method,
+            target: target.to_string(),
+            headers: Fields::new(),
+            body: None,
+        }
+    }
+
+    pub fn get(target: &str) -> Self {
+        Self::new(Method::Get, target)
+    }
+
+    pub fn post(target: &str) -> Self {
+        Self::new(Method::Post, target)
+    }
+
+    pub fn put(target: &str) -> Self {
+        Self::new(Method::Put, target)
+    }
+
+    pub fn delete(target: &str) -> Self {
+        Self::new(Method::Delete, target)
+    }
+
+    #[must_use]
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Sets the `Content-Length` to match
+    #[must_use]
+    pub fn body(self, body: impl Into<Vec<u8>>) -> Self {
+        let body = body.into();
+        let mut request = self.header("Content-Length", &body.len().to_string());
+        request.body = Some(body);
+        request
+    }
+
+    /// The request as a client would send it, with a `Host` unless one was given
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes =
+            format!("{} {} HTTP/1.1\r\n", self.method.as_str(), self.target).into_bytes();
+        if !self.headers.contains_key("host") {
+            bytes.extend_from_slice(b"host: localhost\r\n");
+        }
+        for (name, value) in &self.headers {
+            bytes.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(self.body.as_deref().unwrap_or_default());
+
+        bytes
+    }
+
+    pub fn build(self) -> Request {
+        Request::from_parts(self.method, &self.target, self.headers, self.body)
+            .expect("valid request target")
+    }
+
+    /// Hands the request straight to `handler`, skipping the router
+    pub fn call(self, handler: impl Handler, context: &RequestContext) -> TestResponse {
+        TestResponse::new(
+            handler
+                .call(&self.build(), context)
+                .expect("handler succeeds"),
+        )
+    }
+
+    /// Routes the request as the server would, so the method and path have to match too
+    pub fn send(self, context: &RequestContext) -> TestResponse {
+        let response = routes::router()
+            .dispatch(&self.build(), context)
+            .expect("handler succeeds");
+
+        TestResponse::new(response)
+    }
+}
+
+/// A response read back in full, with assertions that return `self` so they can be chained
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status_code: StatusCode,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn new(response: Response) -> Self {
+        let (status_code, headers, body) = response.into_parts().expect("readable body");
+
+        Self {
+            status_code,
+            headers,
+            body,
+        }
+    }
+
+    /// The value of the header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).expect("UTF-8 body")
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("JSON body")
+    }
+
+    /// Only the codes are compared, as responses read off the wire have `StatusCode::Custom`
+    #[track_caller]
+    pub fn assert_status(&self, status_code: StatusCode) -> &Self {
+        assert_eq!(
+            self.status_code.code(),
+            status_code.code(),
+            "status of {self:?}"
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_header(&self, name: &str, value: &str) -> &Self {
+        assert_eq!(self.header(name), Some(value), "{name} header of {self:?}");
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_no_header(&self, name: &str) -> &Self {
+        assert_eq!(self.header(name), None, "{name} header of {self:?}");
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_body(&self, body: impl AsRef<[u8]>) -> &Self {
+        assert_eq!(
+            String::from_utf8_lossy(&self.body),
+            String::from_utf8_lossy(body.as_ref()),
+            "body"
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_body_contains(&self, text: &str) -> &Self {
+        assert!(
+            self.text().contains(text),
+            "body {:?} doesn't contain {text:?}",
+            self.text()
+        );
+        self
+    }
+}
+
+/// The server, answering on an ephemeral port of 127.0.0.1 until it is dropped
+pub struct TestServer {
+    address: SocketAddr,
+    config: Arc<Config>,
+    serving: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl TestServer {
+    pub fn start(config: Config) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("ephemeral port");
+        let address = listener.local_addr().expect("bound address");
+        let config = Arc::new(config);
+        let serving = thread::spawn({
+            let config = Arc::clone(&config);
+            move || {
+                let pool = Arc::new(ThreadPool::builder(4).build()?);
+                config.admin.serving(Some(&pool));
+                server::serve(vec![listener], &config, &pool, QueueFullPolicy::Block)
+            }
+        });
+
+        Self {
+            address,
+            config,
+            serving: Some(serving),
+        }
+    }
+
+    /// A new connection to the server, which stays open for as long as the server keeps it
+    pub fn client(&self) -> TestClient {
+        let stream = TcpStream::connect(self.address).expect("connects to the server");
+        // A test waiting on a response that never comes fails rather than hangs
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .expect("read timeout");
+
+        TestClient {
+            stream: BufReader::new(stream),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    /// Clients still open when the server is dropped are waited on until they close
+    fn drop(&mut self) {
+        self.config.health.stop();
+        // The listener only sees that it should stop once it accepts something
+        let _ = TcpStream::connect(self.address);
+        if let Some(serving) = self.serving.take() {
+            let result = serving.join().expect("server doesn't panic");
+            if !thread::panicking() {
+                result.expect("server stops cleanly");
+            }
+        }
+    }
+}
+
+/// A connection to a `TestServer`, sending requests one after another like a real client
+pub struct TestClient {
+    stream: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    pub fn get(&mut self, target: &str) -> TestResponse {
+        self.send(TestRequest::get(target))
+    }
+
+    /// Sends `request` and reads its response, with any chunked body decoded
+    pub fn send(&mut self, request: TestRequest) -> TestResponse {
+        let head = matches!(request.method, Method::Head);
+        self.stream
+            .get_mut()
+            .write_all(&request.encode())
+            .expect("request sent");
+
+        self.receive(head)
+    }
+
+    /// Whether the server has closed the connection, once everything it sent has been read
+    pub fn is_closed(&mut self) -> bool {
+        self.stream.fill_buf().map_or(true, <[u8]>::is_empty)
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.stream.read_line(&mut line).expect("response line");
+        assert!(line.ends_with("\r\n"), "line {line:?} ends with CRLF");
+        line.truncate(line.len() - 2);
+
+        line
+    }
+
+    fn receive(&mut self, head: bool) -> TestResponse {
+        let status_line = self.line();
+        let mut parts = status_line.splitn(3, ' ');
+        assert_eq!(
+            parts.next(),
+            Some("HTTP/1.1"),
+            "status line {status_line:?}"
+        );
+        let code = parts
+            .next()
+            .and_then(|code| code.parse().ok())
+            .expect("status code");
+        let status_code = StatusCode::Custom(code, parts.next().unwrap_or_default().to_string());
+
+        let mut headers = Headers::new();
+        loop {
+            let line = self.line();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').expect("header has a colon");
+            headers.append(Header::Custom(name.to_string(), value.trim().to_string()));
+        }
+        let mut response = TestResponse {
+            status_code,
+            headers,
+            body: vec![],
+        };
+
+        if head || matches!(code, 100..=199 | 204 | 304) {
+            return response;
+        }
+        if response
+            .header("Transfer-Encoding")
+            .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+        {
+            response.body = self.chunks();
+        } else if let Some(length) = response.header("Content-Length") {
+            let mut body = vec![0; length.parse().expect("numeric Content-Length")];
+            self.stream.read_exact(&mut body).expect("whole body");
+            response.body = body;
+        } else {
+            self.stream
+                .read_to_end(&mut response.body)
+                .expect("body up to close");
+        }
+
+        response
+    }
+
+    /// Reads a chunked body, skipping any trailers after it
+    fn chunks(&mut self) -> Vec<u8> {
+        let mut body = vec![];
+        loop {
+            let line = self.line();
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16).expect("hex chunk size");
+            if size == 0 {
+                while !self.line().is_empty() {}
+                return body;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            self.stream
+                .read_exact(&mut body[start..])
+                .expect("whole chunk");
+            assert!(self.line().is_empty(), "chunk ends with CRLF");
+        }
+    }
+}
+
+/// A scratch directory per test, named after it and emptied first, so tests can run in parallel
+/// without tripping over each other or what an earlier run left behind
+pub fn test_directory(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("http-server-test-{name}"));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_built_like_decoded_ones() {
+        let request = TestRequest::put("/files/a%20b?x=1")
+            .header("User-Agent", "test")
+            .body("hi")
+            .build();
+
+        assert_eq!(request.method, Method::Put);
+        assert_eq!(request.path, "/files/a b");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+        assert_eq!(&request.headers["user-agent"], "test");
+        assert_eq!(&request.headers["content-length"], "2");
+        assert_eq!(request.body.as_deref(), Some(&b"hi"[..]));
+    }
+
+    #[test]
+    fn responses_can_be_checked() {
+        TestRequest::get("/user-agent")
+            .header("User-Agent", "test")
+            .send(&RequestContext::default())
+            .assert_status(StatusCode::Ok)
+            .assert_header("content-type", "text/plain")
+            .assert_no_header("Content-Encoding")
+            .assert_body("test")
+            .assert_body_contains("es");
+    }
+
+    #[test]
+    #[should_panic(expected = "status of")]
+    fn mismatches_fail() {
+        TestRequest::get("/missing")
+            .send(&RequestContext::default())
+            .assert_status(StatusCode::Ok);
+    }
+}
@@ -1,75 +1,502 @@
+use crate::{affinity, metrics::Metrics};
 use std::{
-    sync::{mpsc, Arc, Mutex},
-    thread::JoinHandle,
+    any::Any,
+    collections::VecDeque,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Barrier, Condvar, Mutex, MutexGuard,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
+use tracing::{error, warn};
 
-// Taken straight from the Rust Book
+// Originally taken straight from the Rust Book
 // See: https://doc.rust-lang.org/book/ch20-02-multithreaded.html)
+// The channel has since been replaced with a queue we can inspect and bound
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
-#[allow(dead_code)]
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    workers: Mutex<Vec<Worker>>,
+    shared: Arc<Shared>,
 }
 
 impl ThreadPool {
-    pub fn new(size: usize) -> Self {
-        assert!(size > 0);
-
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+    pub const fn builder(size: usize) -> Builder {
+        Builder {
+            size,
+            max_size: None,
+            idle_timeout: Builder::DEFAULT_IDLE_TIMEOUT,
+            stack_size: None,
+            warm_up: false,
+            queue_capacity: None,
+            cpus: vec![],
+            metrics: None,
+        }
+    }
 
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Queues `f` to be run by a worker, waiting for space if the queue is full
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.lock();
+        while self.shared.is_full(&state) && !state.shutting_down {
+            state = self.shared.space_available.wait(state).unwrap();
         }
 
-        Self { workers, sender }
+        self.push(state, Box::new(f));
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Queues `f` to be run by a worker, handing it back if the queue is full so the caller can
+    /// decide what to do instead (eg, shed the load)
+    pub fn try_execute<F>(&self, f: F) -> Result<(), QueueFull<F>>
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        let state = self.shared.lock();
+        if self.shared.is_full(&state) {
+            return Err(QueueFull(f));
+        }
+
+        self.push(state, Box::new(f));
+        Ok(())
+    }
 
-        self.sender.send(job).unwrap();
+    pub fn stats(&self) -> Stats {
+        let state = self.shared.lock();
+        let executed = self.shared.executed.load(Ordering::Relaxed);
+        let waited = Duration::from_micros(self.shared.waited_micros.load(Ordering::Relaxed));
+
+        Stats {
+            queued: state.jobs.len(),
+            oldest: state.jobs.front().map(|queued| queued.enqueued.elapsed()),
+            size: state.size,
+            workers: state.workers,
+            idle: state.idle,
+            executed,
+            panicked: self.shared.panicked.load(Ordering::Relaxed),
+            average_wait: waited
+                .checked_div(u32::try_from(executed).unwrap_or(u32::MAX))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Changes how many workers are kept even when there is nothing for them to do, starting
+    /// more straight away, or retiring the surplus as each finishes the job it is on
+    ///
+    /// The pool still grows past `size` when jobs back up, up to its maximum size (raised to
+    /// `size` if it was smaller).
+    pub fn resize(&self, size: usize) {
+        assert!(size > 0);
+        let mut state = self.shared.lock();
+        if state.shutting_down {
+            return;
+        }
+        let previous = std::mem::replace(&mut state.size, size);
+        state.max_size = state.max_size.max(size);
+
+        if size < previous {
+            state.retiring += previous - size;
+            drop(state);
+            self.shared.job_available.notify_all();
+            return;
+        }
+        // Workers yet to retire are kept on instead of starting new ones
+        let kept = state.retiring.min(size - previous);
+        state.retiring -= kept;
+        let started = size - previous - kept;
+        state.workers += started;
+        drop(state);
+
+        for _ in 0..started {
+            self.spawn();
+        }
+    }
+
+    /// Stops taking jobs, then waits for the workers to finish the ones already queued
+    ///
+    /// Jobs given to the pool from then on are dropped without being run.
+    pub fn shutdown(&self) {
+        self.shared.lock().shutting_down = true;
+        self.shared.job_available.notify_all();
+        self.shared.space_available.notify_all();
+
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        for worker in workers {
+            if worker.join_handle.join().is_err() {
+                error!(worker = worker.id, "Worker panicked");
+            }
+        }
+    }
+
+    /// Queues the job, adding an elastic worker if it has backed up past the idle workers
+    fn push(&self, mut state: MutexGuard<'_, State>, job: Job) {
+        if state.shutting_down {
+            warn!("Thread pool is shutting down, dropping job");
+            return;
+        }
+        state.jobs.push_back(Queued {
+            job,
+            enqueued: Instant::now(),
+        });
+        self.shared.report_queue_depth(&state);
+
+        let grow = state.jobs.len() > state.idle && state.workers < state.max_size;
+        if grow {
+            state.workers += 1;
+        }
+        drop(state);
+        self.shared.job_available.notify_one();
+
+        if grow {
+            self.spawn();
+        }
+    }
+
+    /// Starts a worker already counted in `State::workers`
+    fn spawn(&self) {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        match Worker::new(id, Arc::clone(&self.shared), None) {
+            Ok(worker) => {
+                let mut workers = self.workers.lock().unwrap();
+                workers.retain(|worker| !worker.join_handle.is_finished());
+                workers.push(worker);
+            }
+            Err(err) => {
+                error!("Unable to grow thread pool: {err}");
+                self.shared.lock().workers -= 1;
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Returned by `ThreadPool::try_execute` with the job that could not be queued
+pub struct QueueFull<F>(pub F);
+
+/// A snapshot of how backed up the pool is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Jobs waiting for a worker
+    pub queued: usize,
+    /// How long the job at the front of the queue has been waiting
+    pub oldest: Option<Duration>,
+    /// Workers kept even when idle
+    pub size: usize,
+    /// Worker threads currently running, including elastic ones
+    pub workers: usize,
+    /// Workers waiting for a job
+    pub idle: usize,
+    /// Jobs taken off the queue by a worker
+    pub executed: u64,
+    /// Jobs that panicked, each caught by its worker so it could carry on with the next
+    pub panicked: u64,
+    /// Mean time jobs spent in the queue before a worker picked them up
+    pub average_wait: Duration,
+}
+
+pub struct Builder {
+    size: usize,
+    max_size: Option<usize>,
+    idle_timeout: Duration,
+    stack_size: Option<usize>,
+    warm_up: bool,
+    queue_capacity: Option<usize>,
+    cpus: Vec<usize>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Builder {
+    const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Allow the pool to grow up to `max_size` workers when jobs back up in the queue,
+    /// otherwise it stays at its size
+    pub const fn max_size(mut self, max_size: Option<usize>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// How long a worker added beyond the pool's size waits for a job before it is retired
+    pub const fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Size in bytes of each worker's stack, otherwise the platform default is used
+    pub const fn stack_size(mut self, stack_size: Option<usize>) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
+
+    /// Fault in each worker's stack and wait for every worker to be running before `build`
+    /// returns, so the first requests don't pay for it
+    pub const fn warm_up(mut self, warm_up: bool) -> Self {
+        self.warm_up = warm_up;
+        self
+    }
+
+    /// Maximum number of jobs waiting for a worker, otherwise the queue is unbounded
+    pub const fn queue_capacity(mut self, queue_capacity: Option<usize>) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// Pin worker N to `cpus[N % cpus.len()]`, otherwise workers can run anywhere
+    pub fn cpus(mut self, cpus: Vec<usize>) -> Self {
+        self.cpus = cpus;
+        self
+    }
+
+    /// Keep the queue depth and panicked jobs reported in `metrics` up to date
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn build(self) -> io::Result<ThreadPool> {
+        assert!(self.size > 0);
+        let max_size = self.max_size.unwrap_or(self.size);
+        assert!(max_size >= self.size);
+        if let (Some(metrics), Some(capacity)) = (&self.metrics, self.queue_capacity) {
+            metrics.set_queue_capacity(capacity);
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                size: self.size,
+                max_size,
+                workers: self.size,
+                idle: 0,
+                retiring: 0,
+                shutting_down: false,
+            }),
+            job_available: Condvar::new(),
+            space_available: Condvar::new(),
+            capacity: self.queue_capacity,
+            idle_timeout: self.idle_timeout,
+            stack_size: self.stack_size,
+            cpus: self.cpus,
+            metrics: self.metrics,
+            next_id: AtomicUsize::new(self.size),
+            executed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            waited_micros: AtomicU64::new(0),
+        });
+        let ready = self.warm_up.then(|| Arc::new(Barrier::new(self.size + 1)));
+
+        let mut workers = Vec::with_capacity(self.size);
+        for id in 0..self.size {
+            workers.push(Worker::new(id, Arc::clone(&shared), ready.clone())?);
+        }
+
+        if let Some(ready) = ready {
+            ready.wait();
+        }
+
+        Ok(ThreadPool {
+            workers: Mutex::new(workers),
+            shared,
+        })
+    }
+}
+
+struct Queued {
+    job: Job,
+    enqueued: Instant,
+}
+
+struct State {
+    jobs: VecDeque<Queued>,
+    /// Workers kept even when idle, those beyond it retiring after `Shared::idle_timeout`
+    size: usize,
+    max_size: usize,
+    workers: usize,
+    idle: usize,
+    /// Workers to leave as soon as they are between jobs, after the pool was made smaller
+    retiring: usize,
+    /// Workers leave once the queue is empty, rather than waiting for more
+    shutting_down: bool,
+}
+
+/// State shared between the pool and its workers
+struct Shared {
+    state: Mutex<State>,
+    job_available: Condvar,
+    space_available: Condvar,
+    capacity: Option<usize>,
+    idle_timeout: Duration,
+    stack_size: Option<usize>,
+    cpus: Vec<usize>,
+    metrics: Option<Arc<Metrics>>,
+    next_id: AtomicUsize,
+    executed: AtomicU64,
+    panicked: AtomicU64,
+    waited_micros: AtomicU64,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn is_full(&self, state: &State) -> bool {
+        self.capacity
+            .is_some_and(|capacity| state.jobs.len() >= capacity)
+    }
+
+    /// Called with the lock held, so updates land in the order the queue changed
+    fn report_queue_depth(&self, state: &State) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_queue_depth(state.jobs.len());
+        }
+    }
+
+    fn panicked(&self, worker: usize, payload: &(dyn Any + Send)) {
+        let message = panic_message(payload);
+        error!(worker, "Job panicked: {message}");
+
+        self.panicked.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.worker_panicked();
+        }
+    }
+
+    /// Blocks until there is a job to run, or gives up with `None` once the pool is shutting
+    /// down or the worker is retiring, including once it has been idle for `idle_timeout` while
+    /// there are more workers than the pool's size
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.lock();
+        state.idle += 1;
+        let queued = loop {
+            if state.retiring > 0 {
+                state.retiring -= 1;
+                state.workers -= 1;
+                break None;
+            }
+            if let Some(queued) = state.jobs.pop_front() {
+                self.report_queue_depth(&state);
+                break Some(queued);
+            }
+            if state.shutting_down {
+                state.workers -= 1;
+                break None;
+            }
+
+            if state.workers > state.size {
+                let (guard, result) = self
+                    .job_available
+                    .wait_timeout(state, self.idle_timeout)
+                    .unwrap();
+                state = guard;
+                if result.timed_out() && state.jobs.is_empty() && state.workers > state.size {
+                    state.workers -= 1;
+                    break None;
+                }
+            } else {
+                state = self.job_available.wait(state).unwrap();
+            }
+        };
+        state.idle -= 1;
+        drop(state);
+
+        let queued = queued?;
+        self.space_available.notify_one();
+
+        let waited = u64::try_from(queued.enqueued.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.executed.fetch_add(1, Ordering::Relaxed);
+        self.waited_micros.fetch_add(waited, Ordering::Relaxed);
+
+        Some(queued.job)
     }
 }
 
-#[allow(dead_code)]
 struct Worker {
     id: usize,
     join_handle: JoinHandle<()>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
-        let join_handle = std::thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
-            job();
-        });
+    /// How much of the stack to fault in when warming up
+    const WARM_UP_STACK: usize = 64 * 1024;
+
+    fn new(id: usize, shared: Arc<Shared>, ready: Option<Arc<Barrier>>) -> io::Result<Self> {
+        let stack_size = shared.stack_size;
+        let mut builder = thread::Builder::new().name(format!("worker-{id}"));
+        if let Some(stack_size) = stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let cpu = (!shared.cpus.is_empty()).then(|| shared.cpus[id % shared.cpus.len()]);
+        let join_handle = builder.spawn(move || {
+            if let Some(cpu) = cpu
+                && !affinity::pin_current_thread(cpu)
+            {
+                warn!(worker = id, cpu, "Unable to pin worker to CPU");
+            }
+
+            if let Some(ready) = ready {
+                // Leave headroom for the frames already on the stack
+                let warm_up = stack_size.map_or(Self::WARM_UP_STACK, |size| size / 2);
+                touch_stack(warm_up.min(Self::WARM_UP_STACK));
+                ready.wait();
+            }
 
-        Self { id, join_handle }
+            while let Some(job) = shared.pop() {
+                // The job's connection goes with it, but the worker lives on for the next one
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    shared.panicked(id, &*payload);
+                }
+            }
+        })?;
+
+        Ok(Self { id, join_handle })
     }
 }
 
+/// Writes to `bytes` worth of stack so the pages are faulted in now
+#[inline(never)]
+fn touch_stack(bytes: usize) {
+    let mut page = [0u8; 4096];
+    std::hint::black_box(&mut page);
+
+    if bytes > page.len() {
+        touch_stack(bytes - page.len());
+    }
+}
+
+/// What a panic was called with, as `panic!` formats it
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::{thread, time::Duration};
+    use std::{sync::mpsc, thread, time::Duration};
 
     #[test]
     fn panic_when_zero_size() {
-        let result = std::panic::catch_unwind(|| ThreadPool::new(0));
+        let result = std::panic::catch_unwind(|| ThreadPool::builder(0).build());
         assert!(result.is_err());
     }
 
     #[test]
     fn it_works() {
         let (sender, receiver) = mpsc::channel::<()>();
-        let pool = ThreadPool::new(1);
+        let pool = ThreadPool::builder(1).build().unwrap();
         pool.execute(move || {
             let _ = sender.send(());
         });
@@ -78,4 +505,232 @@ mod test {
 
         assert!(receiver.try_recv().is_ok());
     }
+
+    #[test]
+    fn workers_are_named() {
+        let (sender, receiver) = mpsc::channel();
+        let pool = ThreadPool::builder(1).build().unwrap();
+        pool.execute(move || {
+            let _ = sender.send(thread::current().name().map(str::to_string));
+        });
+
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(1)),
+            Ok(Some("worker-0".to_string()))
+        );
+    }
+
+    #[test]
+    fn warmed_up_with_custom_stack_size() -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let pool = ThreadPool::builder(2)
+            .stack_size(Some(256 * 1024))
+            .warm_up(true)
+            .build()?;
+        pool.execute(move || {
+            let _ = sender.send(());
+        });
+
+        assert!(receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+        Ok(())
+    }
+
+    /// Occupies the only worker until the returned sender is dropped or sent to
+    fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = started_sender.send(());
+            let _ = released.recv();
+        });
+        started.recv().unwrap();
+
+        release
+    }
+
+    #[test]
+    fn try_execute_hands_back_job_when_full() -> io::Result<()> {
+        let pool = ThreadPool::builder(1).queue_capacity(Some(1)).build()?;
+        let release = block_worker(&pool);
+
+        assert!(pool.try_execute(|| ()).is_ok());
+        let (sender, receiver) = mpsc::channel();
+        let Err(QueueFull(job)) = pool.try_execute(move || {
+            let _ = sender.send(());
+        }) else {
+            panic!("queue should be full");
+        };
+
+        // The caller still owns the job, so can run it themselves
+        job();
+        assert!(receiver.try_recv().is_ok());
+
+        drop(release);
+        Ok(())
+    }
+
+    #[test]
+    fn workers_can_be_pinned() -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let pool = ThreadPool::builder(2).cpus(vec![0]).build()?;
+        pool.execute(move || {
+            let _ = sender.send(());
+        });
+
+        assert!(receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn grows_when_backed_up_and_shrinks_when_idle() -> io::Result<()> {
+        let pool = ThreadPool::builder(1)
+            .max_size(Some(2))
+            .idle_timeout(Duration::from_millis(50))
+            .build()?;
+        let release = block_worker(&pool);
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || {
+            let _ = sender.send(());
+        });
+
+        // Picked up by an elastic worker, despite the only original worker being busy
+        assert!(receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert_eq!(pool.stats().workers, 2);
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(pool.stats().workers, 1);
+
+        drop(release);
+        Ok(())
+    }
+
+    #[test]
+    fn does_not_grow_past_max_size() -> io::Result<()> {
+        let pool = ThreadPool::builder(1).max_size(Some(2)).build()?;
+        let first = block_worker(&pool);
+        let second = block_worker(&pool);
+
+        pool.execute(|| ());
+        pool.execute(|| ());
+
+        let stats = pool.stats();
+        assert_eq!(stats.workers, 2);
+        assert_eq!(stats.queued, 2);
+
+        drop((first, second));
+        Ok(())
+    }
+
+    #[test]
+    fn resizes_while_running() -> io::Result<()> {
+        let pool = ThreadPool::builder(2).build()?;
+        pool.resize(4);
+        assert_eq!((pool.stats().size, pool.stats().workers), (4, 4));
+
+        // The surplus leave once they are done with what they are doing
+        let release = block_worker(&pool);
+        pool.resize(1);
+        thread::sleep(Duration::from_millis(16));
+        assert_eq!((pool.stats().size, pool.stats().workers), (1, 1));
+        drop(release);
+
+        let (sender, receiver) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = sender.send(());
+        });
+        assert!(receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_workers_yet_to_retire_when_grown_again() -> io::Result<()> {
+        let pool = ThreadPool::builder(2).build()?;
+        let first = block_worker(&pool);
+        let second = block_worker(&pool);
+
+        pool.resize(1);
+        pool.resize(2);
+        drop((first, second));
+        thread::sleep(Duration::from_millis(16));
+        assert_eq!((pool.stats().size, pool.stats().workers), (2, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_finishes_queued_jobs() -> io::Result<()> {
+        let pool = ThreadPool::builder(1).max_size(Some(2)).build()?;
+        let release = block_worker(&pool);
+        let finished = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let finished = Arc::clone(&finished);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                finished.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+
+        drop(release);
+        pool.shutdown();
+        assert_eq!(finished.load(Ordering::Relaxed), 3);
+        assert_eq!(pool.stats().workers, 0);
+
+        // Too late to be run
+        let finished_late = Arc::clone(&finished);
+        pool.execute(move || {
+            finished_late.fetch_add(1, Ordering::Relaxed);
+        });
+        thread::sleep(Duration::from_millis(16));
+        assert_eq!(finished.load(Ordering::Relaxed), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn survives_panicking_jobs() -> io::Result<()> {
+        let metrics = Arc::new(Metrics::default());
+        let pool = ThreadPool::builder(1)
+            .metrics(Arc::clone(&metrics))
+            .build()?;
+        pool.execute(|| panic!("handler bug"));
+        pool.execute(|| panic!("another"));
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || {
+            let _ = sender.send(thread::current().name().map(str::to_string));
+        });
+
+        // Run by the same worker, rather than the pool being left without one
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(1)),
+            Ok(Some("worker-0".to_string()))
+        );
+        let stats = pool.stats();
+        assert_eq!((stats.workers, stats.panicked), (1, 2));
+        assert!(metrics.render().contains("threadpool_panics_total 2"));
+        Ok(())
+    }
+
+    #[test]
+    fn stats_report_queue_length_and_age() -> io::Result<()> {
+        let pool = ThreadPool::builder(1).build()?;
+        let release = block_worker(&pool);
+
+        pool.execute(|| ());
+        pool.execute(|| ());
+        thread::sleep(Duration::from_millis(5));
+
+        let stats = pool.stats();
+        assert_eq!(stats.queued, 2);
+        assert_eq!(stats.executed, 1);
+        assert!(stats.oldest.unwrap() >= Duration::from_millis(5));
+
+        drop(release);
+        thread::sleep(Duration::from_millis(16));
+
+        let stats = pool.stats();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.oldest, None);
+        assert_eq!(stats.executed, 3);
+        Ok(())
+    }
 }
@@ -0,0 +1,221 @@
+//! HTTPS for development (`--tls-self-signed`), with a certificate for `localhost` made at
+//! startup, so features that need a secure context (eg, `Secure` cookies, HSTS) can be tried
+//! without any setup
+//!
+//! The certificate is only kept in memory, so it is new every run and browsers will warn about
+//! it each time. The handshake happens on the worker, on the first read or write, so a slow
+//! client doesn't hold up accepting others.
+
+use crate::{
+    connection::{ReadTimeout, Shutdownable},
+    listener::{Listener, Stream},
+    request::ReadPolicy,
+    sendfile::SendFile,
+};
+use anyhow::Result;
+use rustls::{
+    ServerConfig, ServerConnection, StreamOwned,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// The names the certificate is good for
+const NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// Makes a self-signed certificate for `localhost`, offering `h2` as well as HTTP/1.1 when
+/// `http2` is on
+pub fn self_signed(http2: bool) -> Result<Arc<ServerConfig>> {
+    let (certificate, key) = certificate()?;
+    server_config(certificate, key, http2)
+}
+
+fn certificate() -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let certified = rcgen::generate_simple_self_signed(NAMES.map(String::from))?;
+    let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+
+    Ok((certified.cert.der().clone(), PrivateKeyDer::Pkcs8(key)))
+}
+
+fn server_config(
+    certificate: CertificateDer<'static>,
+    key: PrivateKeyDer<'static>,
+    http2: bool,
+) -> Result<Arc<ServerConfig>> {
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate], key)?;
+    if http2 {
+        config.alpn_protocols.push(b"h2".to_vec());
+    }
+    config.alpn_protocols.push(b"http/1.1".to_vec());
+
+    Ok(Arc::new(config))
+}
+
+pub struct TlsListener {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+impl TlsListener {
+    pub const fn new(listener: TcpListener, config: Arc<ServerConfig>) -> Self {
+        Self { listener, config }
+    }
+}
+
+impl Listener for TlsListener {
+    type Stream = TlsStream;
+
+    fn accept(&self) -> io::Result<Option<TlsStream>> {
+        let (tcp, _) = self.listener.accept()?;
+        let connection =
+            ServerConnection::new(Arc::clone(&self.config)).map_err(io::Error::other)?;
+
+        Ok(Some(TlsStream {
+            tls: Arc::new(Mutex::new(StreamOwned::new(connection, tcp.try_clone()?))),
+            tcp,
+        }))
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        self.listener
+            .local_addr()
+            .map(|address| format!("https://{address}"))
+    }
+}
+
+/// A connection over TLS, shared by its clones as there is only one session to encrypt with
+pub struct TlsStream {
+    tls: Arc<Mutex<StreamOwned<ServerConnection, TcpStream>>>,
+    /// The socket underneath, for what doesn't need the session
+    tcp: TcpStream,
+}
+
+impl TlsStream {
+    fn tls(&self) -> MutexGuard<'_, StreamOwned<ServerConnection, TcpStream>> {
+        self.tls
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsStream")
+            .field("tcp", &self.tcp)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tls().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tls().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tls().flush()
+    }
+}
+
+impl Shutdownable for TlsStream {
+    /// Says goodbye with `close_notify` before closing the socket for writing, so the client
+    /// can tell the response wasn't cut short
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            let mut guard = self.tls();
+            let tls = &mut *guard;
+            tls.conn.send_close_notify();
+            // Nothing more can be sent if the client has gone, which is fine
+            let _ = tls.conn.complete_io(&mut tls.sock);
+        }
+
+        self.tcp.shutdown(how)
+    }
+}
+
+/// Files have to be encrypted on their way out, so are copied
+impl SendFile for TlsStream {}
+
+impl ReadTimeout for TlsStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.tcp.set_read_timeout(Some(timeout))
+    }
+}
+
+impl Stream for TlsStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            tls: Arc::clone(&self.tls),
+            tcp: self.tcp.try_clone()?,
+        })
+    }
+
+    fn set_timeouts(&self, read: Duration, write: Duration) -> io::Result<()> {
+        self.tcp.set_timeouts(read, write)
+    }
+
+    fn peer(&self) -> String {
+        self.tcp.peer()
+    }
+
+    fn read_policy(&self) -> ReadPolicy {
+        ReadPolicy::BLOCKING
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::Config, connection::Connection};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, pki_types::ServerName};
+    use std::thread;
+
+    #[test]
+    fn requests_are_served_over_tls() -> Result<()> {
+        let (certificate, key) = certificate()?;
+        let listener = TlsListener::new(
+            TcpListener::bind("127.0.0.1:0")?,
+            server_config(certificate.clone(), key, false)?,
+        );
+        let address = listener.listener.local_addr()?;
+        let server = thread::spawn(move || -> io::Result<()> {
+            let stream = listener.accept()?.expect("connection");
+            Connection::new(stream, Arc::new(Config::default()))
+                .process()
+                .map_err(io::Error::other)
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate)?;
+        let client =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let connection =
+            ClientConnection::new(Arc::new(client), ServerName::try_from("localhost")?)?;
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(address)?);
+        stream.write_all(b"GET /echo/secret HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        server.join().unwrap()?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nsecret"), "{response}");
+        Ok(())
+    }
+}
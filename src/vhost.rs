@@ -0,0 +1,56 @@
+//! Virtual hosts (`--vhost example.local=/srv/example`), serving a different static site for each
+//! name the server is reached by
+//!
+//! Requests for a virtual host only get its site. Any other host, including the server's own
+//! address, gets every route, with `--static-root` as the site.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct VirtualHosts(HashMap<String, String>);
+
+impl VirtualHosts {
+    /// Serves the site at `root` to requests for `host`, which is matched without any port
+    #[must_use]
+    pub fn add(mut self, host: &str, root: String) -> Self {
+        self.0.insert(hostname(host), root);
+        self
+    }
+
+    /// Where the site for the request's `Host` header is, if it is a virtual host
+    pub fn root(&self, host: &str) -> Option<&str> {
+        self.0.get(&hostname(host)).map(String::as_str)
+    }
+}
+
+/// The `Host` header without its port, lowercase, as names are case-insensitive
+fn hostname(host: &str) -> String {
+    let host = host.trim();
+    let name = match host.strip_prefix('[') {
+        // An IPv6 literal, whose colons aren't a port
+        Some(v6) => v6
+            .split_once(']')
+            .map_or(host, |(address, _)| &host[..address.len() + 2]),
+        None => host.split_once(':').map_or(host, |(name, _)| name),
+    };
+
+    name.to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hosts_are_matched_by_name() {
+        let hosts = VirtualHosts::default()
+            .add("Example.local", "/srv/example".to_string())
+            .add("[::1]:8080", "/srv/local".to_string());
+
+        assert_eq!(hosts.root("example.local"), Some("/srv/example"));
+        assert_eq!(hosts.root("EXAMPLE.local:4221"), Some("/srv/example"));
+        assert_eq!(hosts.root("[::1]"), Some("/srv/local"));
+        assert_eq!(hosts.root("other.local"), None);
+        assert_eq!(hosts.root("localhost:4221"), None);
+    }
+}
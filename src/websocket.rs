@@ -0,0 +1,375 @@
+//! The WebSocket protocol (RFC 6455), enough to echo messages back on `/ws`
+
+use crate::{
+    http::Header,
+    request::Request,
+    response::{Duplex, Response, StatusCode},
+    router::RequestContext,
+};
+use anyhow::Result;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Appended to the client's key before hashing, to prove the server speaks WebSocket
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message accepted, as messages are held in memory to be echoed
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// Opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// Close codes
+const NORMAL_CLOSURE: u16 = 1000;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_PAYLOAD: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// The `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+
+    BASE64_STANDARD.encode(sha1.digest().bytes())
+}
+
+/// `GET /ws` upgrades to a WebSocket that echoes every message back
+pub fn echo(request: &Request, _: &RequestContext) -> Result<Response> {
+    if !request.has_token("upgrade", "websocket") || !request.has_token("connection", "upgrade") {
+        return Ok(
+            Response::new(StatusCode::UpgradeRequired).header(Header::Custom(
+                "Upgrade".to_string(),
+                "websocket".to_string(),
+            )),
+        );
+    }
+    if request
+        .headers
+        .get("sec-websocket-version")
+        .is_none_or(|version| version != "13")
+    {
+        return Ok(
+            Response::new(StatusCode::UpgradeRequired).header(Header::Custom(
+                "Sec-WebSocket-Version".to_string(),
+                "13".to_string(),
+            )),
+        );
+    }
+    let Some(key) = request.headers.get("sec-websocket-key").filter(|key| {
+        BASE64_STANDARD
+            .decode(key)
+            .is_ok_and(|nonce| nonce.len() == 16)
+    }) else {
+        return Ok(Response::bad_request());
+    };
+
+    Ok(
+        Response::switching_protocols("websocket", |stream| echo_messages(stream)).header(
+            Header::Custom("Sec-WebSocket-Accept".to_string(), accept_key(key)),
+        ),
+    )
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Why a frame couldn't be read, which decides how the connection is closed
+#[derive(Debug)]
+enum ReadError {
+    /// Nothing arrived before the read timeout, at a point where that is fine
+    Idle,
+    Protocol(u16),
+    Io(io::Error),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// Reads a frame sent by a client, which must be masked
+fn read_frame(reader: &mut impl Read) -> Result<Frame, ReadError> {
+    let mut head = [0; 2];
+    // A timeout before a frame has started is the client having nothing to say
+    match reader.read(&mut head[..1]) {
+        Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+        Ok(_) => {}
+        Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+            return Err(ReadError::Idle);
+        }
+        Err(err) => return Err(err.into()),
+    }
+    reader.read_exact(&mut head[1..])?;
+
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    // No extensions are negotiated, so the reserved bits must be clear
+    if head[0] & 0x70 != 0 || head[1] & 0x80 == 0 {
+        return Err(ReadError::Protocol(PROTOCOL_ERROR));
+    }
+
+    let length = match head[1] & 0x7f {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length)?;
+            u64::from(u16::from_be_bytes(length))
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => u64::from(length),
+    };
+    if opcode >= CLOSE && (length > 125 || !fin) {
+        return Err(ReadError::Protocol(PROTOCOL_ERROR));
+    }
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|&length| length <= MAX_MESSAGE_SIZE)
+        .ok_or(ReadError::Protocol(MESSAGE_TOO_BIG))?;
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// Writes a whole message as a single, unmasked, frame
+pub fn write_frame(writer: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        length @ 0..=125 => frame.push(u8::try_from(length).unwrap_or_default()),
+        length => {
+            if let Ok(length) = u16::try_from(length) {
+                frame.push(126);
+                frame.extend_from_slice(&length.to_be_bytes());
+            } else {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+    }
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+fn close(writer: &mut impl Write, code: u16) -> io::Result<()> {
+    write_frame(writer, CLOSE, &code.to_be_bytes())
+}
+
+/// Echoes messages until the client closes the connection (or breaks the protocol)
+fn echo_messages(stream: &mut dyn Duplex) -> io::Result<()> {
+    let mut stream = stream;
+    // A message being reassembled from fragments, and its opcode
+    let mut message: Option<(u8, Vec<u8>)> = None;
+
+    loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(frame) => frame,
+            Err(ReadError::Idle) => {
+                // Checks the client is still there, rather than holding a worker forever
+                write_frame(&mut stream, PING, b"")?;
+                continue;
+            }
+            Err(ReadError::Protocol(code)) => return close(&mut stream, code),
+            Err(ReadError::Io(err)) => return Err(err),
+        };
+
+        match (frame.opcode, &mut message) {
+            (PING, _) => write_frame(&mut stream, PONG, &frame.payload)?,
+            (PONG, _) => {}
+            (CLOSE, _) => {
+                // Echo the status code, as RFC 6455 suggests
+                let code = frame.payload.get(..2).map_or(NORMAL_CLOSURE, |code| {
+                    u16::from_be_bytes([code[0], code[1]])
+                });
+                return close(&mut stream, code);
+            }
+            (TEXT | BINARY, None) => message = Some((frame.opcode, frame.payload)),
+            (CONTINUATION, Some((_, payload))) => {
+                if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return close(&mut stream, MESSAGE_TOO_BIG);
+                }
+                payload.extend_from_slice(&frame.payload);
+            }
+            _ => return close(&mut stream, PROTOCOL_ERROR),
+        }
+
+        // Control frames can arrive in the middle of a fragmented message
+        if frame.fin
+            && frame.opcode < CLOSE
+            && let Some((opcode, payload)) = message.take()
+        {
+            if opcode == TEXT && std::str::from_utf8(&payload).is_err() {
+                return close(&mut stream, INVALID_PAYLOAD);
+            }
+            write_frame(&mut stream, opcode, &payload)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A frame as a client would send it, masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![u8::from(fin) << 7 | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | u8::try_from(payload.len()).unwrap());
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend(u16::try_from(payload.len()).unwrap().to_be_bytes());
+        }
+        frame.extend(mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn echoed(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut duplex = Duplex {
+            input: Cursor::new(frames.concat()),
+            output: vec![],
+        };
+        echo_messages(&mut duplex).unwrap();
+
+        duplex.output
+    }
+
+    #[test]
+    fn accept_key_from_rfc_6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn handshake_requires_version_13() {
+        let request = Request::decode(
+            &b"GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n"[..],
+        )
+        .unwrap();
+        let response = echo(&request, &RequestContext::default()).unwrap();
+
+        assert_eq!(
+            response.encode(),
+            b"HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let long = vec![b'x'; 300];
+        let mut input = Cursor::new(
+            [
+                client_frame(true, TEXT, b"Hello"),
+                client_frame(true, BINARY, &long),
+            ]
+            .concat(),
+        );
+
+        assert_eq!(
+            read_frame(&mut input).unwrap(),
+            Frame {
+                fin: true,
+                opcode: TEXT,
+                payload: b"Hello".to_vec()
+            }
+        );
+        assert_eq!(read_frame(&mut input).unwrap().payload, long);
+
+        let mut output = vec![];
+        write_frame(&mut output, TEXT, b"Hello").unwrap();
+        assert_eq!(output, b"\x81\x05Hello");
+    }
+
+    #[test]
+    fn echoes_messages_and_fragments() {
+        let output = echoed(&[
+            client_frame(true, TEXT, b"Hello"),
+            client_frame(false, BINARY, b"frag"),
+            client_frame(true, PING, b"?"),
+            client_frame(true, CONTINUATION, b"mented"),
+            client_frame(true, CLOSE, &NORMAL_CLOSURE.to_be_bytes()),
+        ]);
+
+        assert_eq!(
+            output,
+            [
+                &b"\x81\x05Hello"[..],
+                b"\x8a\x01?",
+                b"\x82\x0afragmented",
+                b"\x88\x02\x03\xe8",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn unmasked_frames_are_a_protocol_error() {
+        let output = echoed(&[b"\x81\x05Hello".to_vec()]);
+
+        assert_eq!(output, b"\x88\x02\x03\xea");
+    }
+
+    #[test]
+    fn invalid_utf8_text_is_refused() {
+        let output = echoed(&[client_frame(true, TEXT, b"\xff")]);
+
+        assert_eq!(output, b"\x88\x02\x03\xef");
+    }
+}
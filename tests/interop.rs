@@ -0,0 +1,211 @@
+//! Drives real clients against the server binary, catching issues the mock based unit tests
+//! can't (eg, framing mistakes only a real client notices)
+//!
+//! Run with `cargo test --features interop`, requires `curl` (and optionally `hurl`) on the PATH.
+
+use std::{
+    fs,
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+struct Server {
+    child: Child,
+    address: String,
+    directory: PathBuf,
+}
+
+impl Server {
+    fn start(name: &str) -> Self {
+        let directory = std::env::temp_dir().join(format!("http-server-interop-{name}"));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        // Let the OS pick a free port, then hand it to the server
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_codecrafters-http-server"))
+            .args(["--address", &address, "--http2", "--directory"])
+            .arg(&directory)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while TcpStream::connect(&address).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server did not start"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Self {
+            child,
+            address,
+            directory,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.address)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn curl(args: &[&str]) -> Output {
+    let output = Command::new("curl")
+        .args(["--silent", "--show-error", "--max-time", "10"])
+        .args(args)
+        .output()
+        .expect("curl must be installed to run the interop tests");
+    assert!(
+        output.status.success(),
+        "curl {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    output
+}
+
+/// Returns the response headers (lowercased) and body
+fn curl_with_headers(args: &[&str]) -> (String, Vec<u8>) {
+    let mut all = vec!["--include"];
+    all.extend_from_slice(args);
+    let output = curl(&all).stdout;
+
+    let split = output
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .expect("response has a header section");
+    (
+        String::from_utf8_lossy(&output[..split]).to_lowercase(),
+        output[split + 4..].to_vec(),
+    )
+}
+
+#[test]
+fn echo() {
+    let server = Server::start("echo");
+
+    assert_eq!(curl(&[&server.url("/echo/interop")]).stdout, b"interop");
+}
+
+#[test]
+fn http2_prior_knowledge() {
+    let server = Server::start("http2");
+    let output = curl(&[
+        "--http2-prior-knowledge",
+        "--write-out",
+        " %{http_version}",
+        &server.url("/echo/over%20h2c"),
+    ]);
+
+    assert_eq!(output.stdout, b"over h2c 2");
+}
+
+#[test]
+fn gzip_compression() {
+    let server = Server::start("gzip");
+    // Only gzip, as curl also offers br and zstd, which the server prefers when built with them
+    let (headers, body) = curl_with_headers(&[
+        "--compressed",
+        "--header",
+        "Accept-Encoding: gzip",
+        &server.url("/echo/squash"),
+    ]);
+
+    assert!(headers.contains("content-encoding: gzip"));
+    assert_eq!(body, b"squash");
+}
+
+#[test]
+fn upload_then_download() {
+    let server = Server::start("upload");
+    let upload = server.directory.join("upload-source");
+    fs::write(&upload, b"uploaded by curl").unwrap();
+
+    curl(&[
+        "--upload-file",
+        upload.to_str().unwrap(),
+        &server.url("/files/uploaded"),
+    ]);
+
+    assert_eq!(
+        curl(&[&server.url("/files/uploaded")]).stdout,
+        b"uploaded by curl"
+    );
+}
+
+#[test]
+fn ranges() {
+    let server = Server::start("ranges");
+    fs::write(server.directory.join("digits"), b"0123456789").unwrap();
+
+    let (headers, body) = curl_with_headers(&["--range", "2-5", &server.url("/files/digits")]);
+
+    assert!(headers.starts_with("http/1.1 206"));
+    assert!(headers.contains("content-range: bytes 2-5/10"));
+    assert_eq!(body, b"2345");
+}
+
+#[test]
+fn chunked_streaming() {
+    let server = Server::start("chunked");
+    let (headers, body) = curl_with_headers(&["--no-buffer", &server.url("/progress/2")]);
+
+    assert!(headers.contains("transfer-encoding: chunked"));
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.contains("step 1 of 2"));
+    assert!(body.contains("step 2 of 2"));
+}
+
+#[test]
+fn several_requests_from_one_client() {
+    let server = Server::start("several");
+    let output = curl(&[&server.url("/echo/one"), &server.url("/echo/two")]).stdout;
+
+    assert_eq!(output, b"onetwo");
+}
+
+/// Runs each `tests/interop/*.hurl` script, when hurl is installed
+#[test]
+fn hurl_scripts() {
+    if Command::new("hurl").arg("--version").output().is_err() {
+        eprintln!("hurl not installed, skipping");
+        return;
+    }
+
+    let server = Server::start("hurl");
+    let scripts = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/interop");
+    for script in fs::read_dir(scripts).unwrap() {
+        let script = script.unwrap().path();
+        if script
+            .extension()
+            .is_none_or(|extension| extension != "hurl")
+        {
+            continue;
+        }
+
+        let status = Command::new("hurl")
+            .args(["--test", "--variable"])
+            .arg(format!("base_url={}", server.url("")))
+            .arg(&script)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} failed", script.display());
+    }
+}